@@ -2,12 +2,16 @@
 use std::process::Command;
 
 fn main() {
+    // keep map encoding deterministic, idempotency keys compare encoded requests
+    let mut config = prost_build::Config::new();
+    config.btree_map(["."]);
+
     // fs::create_dir_all("src/pb").unwrap();
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
         .out_dir("src/pb")
         .type_attribute("reservation.ReservationStatus", "#[derive(sqlx::Type)]")
-        .compile(&["protos/reservation.proto"], &["protos"])
+        .compile_with_config(config, &["protos/reservation.proto"], &["protos"])
         .unwrap();
 
     // fs::remove_file("src/pb/google.protobuf.rs").unwrap();
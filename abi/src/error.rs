@@ -1,15 +1,152 @@
+use sqlx::postgres::PgDatabaseError;
 use thiserror::Error;
+use tonic_types::{ErrorDetails, StatusExt};
+
+use crate::FieldViolation;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Database error")]
-    DbError(#[from] sqlx::Error),
+    DbError(sqlx::Error),
+    #[error("Conflict reservation: {0}")]
+    ConflictReservation(String),
+    #[error("Version mismatch: expected {expected}, current is {current}")]
+    VersionMismatch { expected: i64, current: i64 },
     #[error("Invalid userid {0}")]
     InvalidUserId(String),
     #[error("Invalid resourceid {0}")]
     InvalidResourceId(String),
+    #[error("Invalid status {0}")]
+    InvalidStatus(String),
+    #[error("Invalid timestamp {0}")]
+    InvalidTimestamp(String),
+    #[error("Invalid duration {0}")]
+    InvalidDuration(String),
+    #[error("Invalid request: {}", join(.0))]
+    InvalidRequest(Vec<FieldViolation>),
     #[error("Invalid start or end time for the reservation")]
     InvalidReservation,
+    #[error("Update mask is empty")]
+    EmptyUpdateMask,
+    #[error("Field {0} can't be updated")]
+    ImmutableField(String),
+    #[error("Unknown field {0}")]
+    UnknownField(String),
+    #[error("Invalid buffer, it must not be negative")]
+    InvalidBuffer,
+    #[error("Invalid booking rule, durations must not be negative")]
+    InvalidBookingRule,
+    #[error("Booking rule {0} violated: {1}")]
+    BookingRuleViolation(&'static str, String),
+    #[error("Invalid quota policy: {0}")]
+    InvalidQuotaPolicy(String),
+    #[error("Quota {kind} exceeded: {used} of {limit} used")]
+    QuotaExceeded {
+        policy_id: i64,
+        kind: String,
+        used: f64,
+        limit: i64,
+    },
+    #[error("Idempotency key {0} was used for a different request")]
+    IdempotencyKeyReused(String),
+    #[error("Request with idempotency key {0} is still in progress")]
+    IdempotencyKeyInProgress(String),
+    #[error("Missing field {0}")]
+    MissingField(String),
+    #[error("Invalid webhook: {0}")]
+    InvalidWebhook(String),
+    #[error("Invalid recurrence: {0}")]
+    InvalidRecurrence(String),
+    #[error("Invalid calendar feed: {0}")]
+    InvalidCalendarFeed(String),
+    #[error("Invalid calendar: {0}")]
+    InvalidCalendar(String),
+    #[error("Calendar feeds are disabled")]
+    CalendarFeedsDisabled,
+    #[error("Invalid or unknown token")]
+    Unauthenticated,
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Failed to read configuration file")]
+    ConfigReadError,
+    #[error("Failed to parse configuration file")]
+    ConfigParseError,
     #[error("unknown data store error")]
     Unknown,
 }
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            // exclusion_violation, raised by the reservations_conflict constraint
+            sqlx::Error::Database(db) if db.code().as_deref() == Some("23P01") => {
+                let detail = db
+                    .try_downcast_ref::<PgDatabaseError>()
+                    .and_then(|e| e.detail())
+                    .unwrap_or_else(|| db.message());
+                Error::ConflictReservation(detail.to_string())
+            }
+            _ => Error::DbError(e),
+        }
+    }
+}
+
+impl From<Error> for tonic::Status {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::InvalidRequest(ref violations) => {
+                let mut details = ErrorDetails::new();
+                for v in violations {
+                    let description = format!("{} ({})", v.message, v.code);
+                    details.add_bad_request_violation(v.field.clone(), description);
+                }
+                tonic::Status::with_error_details(
+                    tonic::Code::InvalidArgument,
+                    e.to_string(),
+                    details,
+                )
+            }
+            Error::DbError(sqlx::Error::RowNotFound) => {
+                tonic::Status::not_found("No reservation found by the given condition")
+            }
+            Error::DbError(_)
+            | Error::ConfigReadError
+            | Error::ConfigParseError
+            | Error::Unknown => tonic::Status::internal(e.to_string()),
+            Error::ConflictReservation(_) => tonic::Status::already_exists(e.to_string()),
+            Error::VersionMismatch { .. }
+            | Error::BookingRuleViolation(..)
+            | Error::CalendarFeedsDisabled => tonic::Status::failed_precondition(e.to_string()),
+            Error::QuotaExceeded { .. } => tonic::Status::resource_exhausted(e.to_string()),
+            Error::IdempotencyKeyInProgress(_) => tonic::Status::aborted(e.to_string()),
+            Error::Unauthenticated => tonic::Status::unauthenticated(e.to_string()),
+            Error::PermissionDenied(_) => tonic::Status::permission_denied(e.to_string()),
+            Error::InvalidUserId(_)
+            | Error::InvalidResourceId(_)
+            | Error::InvalidStatus(_)
+            | Error::InvalidTimestamp(_)
+            | Error::InvalidDuration(_)
+            | Error::InvalidReservation
+            | Error::EmptyUpdateMask
+            | Error::ImmutableField(_)
+            | Error::UnknownField(_)
+            | Error::InvalidBuffer
+            | Error::InvalidBookingRule
+            | Error::InvalidQuotaPolicy(_)
+            | Error::IdempotencyKeyReused(_)
+            | Error::MissingField(_)
+            | Error::InvalidWebhook(_)
+            | Error::InvalidRecurrence(_)
+            | Error::InvalidCalendarFeed(_)
+            | Error::InvalidCalendar(_) => tonic::Status::invalid_argument(e.to_string()),
+        }
+    }
+}
+
+fn join(violations: &[FieldViolation]) -> String {
+    violations
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
@@ -0,0 +1,251 @@
+//! fuzz-style tests: no payload a client can send may panic while it's validated or converted
+
+use chrono::{TimeZone, Utc};
+use proptest::prelude::*;
+use prost_types::Timestamp;
+
+use crate::{
+    convert_to_duration, convert_to_pb_duration, convert_to_timestamp, convert_to_utc_timestamp,
+    utils::{MAX_DURATION_SECONDS, MAX_TIMESTAMP_SECONDS, MIN_TIMESTAMP_SECONDS, NANOS_PER_SECOND},
+    AvailabilityRequest, BlockWindow, HistoryQuery, ImportCalendarRequest, Recurrence,
+    RescheduleRequest, Reservation, ReservationQuery, Resource,
+};
+
+/// seconds around the edges of the ranges, and anything else
+fn seconds() -> impl Strategy<Value = i64> {
+    prop_oneof![
+        Just(i64::MIN),
+        Just(i64::MAX),
+        Just(0),
+        MIN_TIMESTAMP_SECONDS - 2..MIN_TIMESTAMP_SECONDS + 2,
+        MAX_TIMESTAMP_SECONDS - 2..MAX_TIMESTAMP_SECONDS + 2,
+        -MAX_DURATION_SECONDS - 2..-MAX_DURATION_SECONDS + 2,
+        MAX_DURATION_SECONDS - 2..MAX_DURATION_SECONDS + 2,
+        any::<i64>(),
+    ]
+}
+
+fn nanos() -> impl Strategy<Value = i32> {
+    prop_oneof![
+        Just(i32::MIN),
+        Just(i32::MAX),
+        Just(0),
+        -NANOS_PER_SECOND - 1..NANOS_PER_SECOND + 1,
+        any::<i32>(),
+    ]
+}
+
+fn timestamp() -> impl Strategy<Value = Option<Timestamp>> {
+    proptest::option::of(
+        (seconds(), nanos()).prop_map(|(seconds, nanos)| Timestamp { seconds, nanos }),
+    )
+}
+
+fn duration() -> impl Strategy<Value = Option<prost_types::Duration>> {
+    proptest::option::of(
+        (seconds(), nanos()).prop_map(|(seconds, nanos)| prost_types::Duration { seconds, nanos }),
+    )
+}
+
+fn recurrence() -> impl Strategy<Value = Option<Recurrence>> {
+    proptest::option::of(
+        (any::<i32>(), any::<u32>(), 0u32..2000, timestamp()).prop_map(
+            |(frequency, interval, count, until)| Recurrence {
+                frequency,
+                interval,
+                count,
+                until,
+            },
+        ),
+    )
+}
+
+/// an iCalendar file made of fragments that are almost right
+fn ics() -> impl Strategy<Value = String> {
+    let date = prop_oneof![
+        Just("99991231T235959Z".to_string()),
+        Just("00010101T000000Z".to_string()),
+        Just("99991231".to_string()),
+        "[0-9]{8}",
+        "[0-9]{8}T[0-9]{6}Z?",
+        "[+-]?[0-9]{1,12}",
+    ];
+    let duration = prop_oneof![
+        "[+-]?P[0-9]{1,20}[WD]",
+        "[+-]?PT[0-9]{1,20}[HMS]",
+        "[+-]?P([0-9]{1,6}[WD])?(T([0-9]{1,20}[HMS]){0,3})?",
+    ];
+    let rrule = (
+        prop_oneof![
+            Just("DAILY"),
+            Just("WEEKLY"),
+            Just("MONTHLY"),
+            Just("YEARLY")
+        ],
+        "[0-9]{1,20}",
+        proptest::option::of("[0-9]{1,20}"),
+        proptest::option::of(date.clone()),
+        proptest::option::of("(MO|TU|WE|TH|FR|SA|SU)(,(MO|TU|WE|TH|FR|SA|SU)){0,3}"),
+    )
+        .prop_map(|(freq, interval, count, until, by_day)| {
+            let mut rule = format!("FREQ={freq};INTERVAL={interval}");
+            if let Some(count) = count {
+                rule.push_str(&format!(";COUNT={count}"));
+            }
+            if let Some(until) = until {
+                rule.push_str(&format!(";UNTIL={until}"));
+            }
+            if let Some(by_day) = by_day {
+                rule.push_str(&format!(";BYDAY={by_day}"));
+            }
+            rule
+        });
+    let tzid = prop_oneof![
+        Just(""),
+        Just(";TZID=Europe/Berlin"),
+        Just(";TZID=America/New_York"),
+        Just(";TZID=Nowhere/Special"),
+    ];
+    (
+        date.clone(),
+        proptest::option::of(date),
+        proptest::option::of(duration),
+        proptest::option::of(rrule),
+        tzid,
+        "\\PC{0,20}",
+    )
+        .prop_map(|(start, end, duration, rrule, tzid, summary)| {
+            let mut lines = vec![
+                "BEGIN:VCALENDAR".to_string(),
+                "BEGIN:VEVENT".to_string(),
+                "UID:fuzz".to_string(),
+                format!("DTSTART{tzid}:{start}"),
+                format!("SUMMARY:{summary}"),
+            ];
+            lines.extend(end.map(|end| format!("DTEND{tzid}:{end}")));
+            lines.extend(duration.map(|duration| format!("DURATION:{duration}")));
+            lines.extend(rrule.map(|rrule| format!("RRULE:{rrule}")));
+            lines.push("END:VEVENT".to_string());
+            lines.push("END:VCALENDAR".to_string());
+            lines.join("\r\n")
+        })
+}
+
+proptest! {
+    #[test]
+    fn timestamps_should_convert_or_fail(ts in timestamp()) {
+        if let Some(Ok(dt)) = ts.clone().map(convert_to_utc_timestamp) {
+            prop_assert_eq!(Some(convert_to_timestamp(dt)), ts);
+        }
+    }
+
+    #[test]
+    fn durations_should_convert_or_fail(d in duration()) {
+        if let Some(Ok(duration)) = d.clone().map(convert_to_duration) {
+            prop_assert_eq!(Some(convert_to_pb_duration(duration)), d);
+        }
+    }
+
+    #[test]
+    fn reservations_should_validate_without_panic(start in timestamp(), end in timestamp()) {
+        let rsvp = Reservation {
+            resource_id: "room-1".to_string(),
+            start,
+            end,
+            ..Default::default()
+        };
+        if rsvp.validate().is_ok() {
+            prop_assert!(rsvp.get_timestamp().is_ok());
+        }
+    }
+
+    #[test]
+    fn queries_should_convert_without_panic(start in timestamp(), end in timestamp()) {
+        let query = ReservationQuery { start: start.clone(), end: end.clone(), ..Default::default() };
+        let _ = query.get_timespan();
+        let query = HistoryQuery { start, end, ..Default::default() };
+        let _ = query.get_timespan();
+    }
+
+    #[test]
+    fn timespan_requests_should_convert_without_panic(start in timestamp(), end in timestamp()) {
+        let req = RescheduleRequest { start: start.clone(), end: end.clone(), ..Default::default() };
+        let _ = req.get_timespan();
+        let req = AvailabilityRequest {
+            resource_id: "room-1".to_string(),
+            start,
+            end,
+        };
+        let _ = req.get_timespan();
+    }
+
+    #[test]
+    fn block_windows_should_expand_without_panic(
+        start in timestamp(),
+        end in timestamp(),
+        recurrence in recurrence(),
+    ) {
+        let window = BlockWindow {
+            resource_ids: vec!["room-1".to_string()],
+            start,
+            end,
+            recurrence,
+            ..Default::default()
+        };
+        let _ = window.get_timespans();
+    }
+
+    #[test]
+    fn resources_should_validate_without_panic(
+        pre_buffer in duration(),
+        post_buffer in duration(),
+        min_duration in duration(),
+        max_duration in duration(),
+        booking_horizon in duration(),
+        lead_time in duration(),
+        start in timestamp(),
+        end in timestamp(),
+    ) {
+        let resource = Resource {
+            id: "room-1".to_string(),
+            pre_buffer,
+            post_buffer,
+            min_duration,
+            max_duration,
+            booking_horizon,
+            lead_time,
+            ..Default::default()
+        };
+        if resource.validate().is_ok() {
+            let rsvp = Reservation { start, end, ..Default::default() };
+            if let Ok((start, end)) = rsvp.get_timespan() {
+                let _ = resource.check_booking_rules(start, end, Utc::now());
+                let _ = resource.free_slots(start, end, &[]);
+            }
+        }
+    }
+
+    #[test]
+    fn calendars_should_import_without_panic(ics in ics(), until in timestamp()) {
+        let req = ImportCalendarRequest {
+            ics: ics.into_bytes(),
+            resource_id: "room-1".to_string(),
+            user_id: "user1".to_string(),
+            mode: crate::ImportMode::DryRun as i32,
+            until,
+            ..Default::default()
+        };
+        let _ = req.get_results(Utc.with_ymd_and_hms(2099, 1, 1, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn any_bytes_should_import_without_panic(ics in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let req = ImportCalendarRequest {
+            ics,
+            resource_id: "room-1".to_string(),
+            mode: crate::ImportMode::DryRun as i32,
+            ..Default::default()
+        };
+        let _ = req.get_results(Utc::now());
+    }
+}
@@ -1,17 +1,58 @@
+mod config;
 mod error;
+#[cfg(test)]
+mod fuzz;
 mod pb;
 mod types;
 mod utils;
+mod validation;
 
+pub use config::*;
 pub use error::Error;
 pub use pb::*;
+pub use types::*;
 pub use utils::*;
+pub use validation::*;
 
-#[derive(Clone, Debug, Copy, PartialEq, Eq, sqlx::Type)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, sqlx::Type, serde::Deserialize)]
 #[sqlx(type_name = "reservation_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum RsvpStatus {
     Unknown,
     Pending,
     Confirmed,
     Blocked,
 }
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "reservation_update_type", rename_all = "lowercase")]
+pub enum RsvpUpdateType {
+    Unknown,
+    Create,
+    Update,
+    Delete,
+}
+
+impl sqlx::postgres::PgHasArrayType for RsvpUpdateType {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_reservation_update_type")
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "webhook_delivery_status", rename_all = "lowercase")]
+pub enum RsvpWebhookDeliveryStatus {
+    Unknown,
+    Pending,
+    Delivered,
+    Dead,
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "quota_kind", rename_all = "snake_case")]
+pub enum RsvpQuotaKind {
+    Unknown,
+    MaxActive,
+    MaxWeeklyHours,
+    MaxFuturePerKind,
+}
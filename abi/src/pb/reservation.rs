@@ -24,6 +24,15 @@ pub struct Reservation {
     /// extra note
     #[prost(string, tag = "7")]
     pub note: ::prost::alloc::string::String,
+    /// free-form labels, e.g. department or cost center
+    #[prost(btree_map = "string, string", tag = "8")]
+    pub labels: ::prost::alloc::collections::BTreeMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
+    /// increased on every change. Pass it back on mutations to detect concurrent edits
+    #[prost(int64, tag = "9")]
+    pub version: i64,
 }
 /// To make a reservation, send a ReservationRequest with Reservation object (id should be empty)
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -31,6 +40,12 @@ pub struct Reservation {
 pub struct ReserveRequest {
     #[prost(message, optional, tag = "1")]
     pub reservation: ::core::option::Option<Reservation>,
+    /// skip the booking rules of the resource (admin only)
+    #[prost(bool, tag = "2")]
+    pub bypass_rules: bool,
+    /// optional, a retried request with the same key and payload gets the original response
+    #[prost(string, tag = "3")]
+    pub idempotency_key: ::prost::alloc::string::String,
 }
 /// Created reservation will be returned in ReserveResponse
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -39,14 +54,50 @@ pub struct ReserveResponse {
     #[prost(message, optional, tag = "1")]
     pub reservation: ::core::option::Option<Reservation>,
 }
-/// To update a reservation, send an UpdateRequest. Only note is updatable.
+/// To make several reservations at once, send a ReserveBatchRequest. Either all of them are made or none
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct UpdateRequest {
-    #[prost(int64, tag = "1")]
-    pub id: i64,
+pub struct ReserveBatchRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub reservations: ::prost::alloc::vec::Vec<Reservation>,
+    /// optional, a retried request with the same key and payload gets the original response
     #[prost(string, tag = "2")]
-    pub note: ::prost::alloc::string::String,
+    pub idempotency_key: ::prost::alloc::string::String,
+}
+/// result of a reservation in a batch
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReserveBatchResult {
+    /// the reservation, with id if the batch was committed
+    #[prost(message, optional, tag = "1")]
+    pub reservation: ::core::option::Option<Reservation>,
+    /// why the reservation failed, empty if it succeeded (or would have)
+    #[prost(string, tag = "2")]
+    pub error: ::prost::alloc::string::String,
+}
+/// Results in request order will be returned in ReserveBatchResponse
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReserveBatchResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub results: ::prost::alloc::vec::Vec<ReserveBatchResult>,
+    /// false if any reservation failed, in which case nothing was reserved
+    #[prost(bool, tag = "2")]
+    pub committed: bool,
+}
+/// To update a reservation, send an UpdateRequest with the reservation id, the new values and
+/// the paths of the fields to change. Updatable fields are note, start, end, user_id and labels.
+/// If the reservation version is not 0, the update fails unless it matches the current version.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpdateRequest {
+    #[prost(message, optional, tag = "3")]
+    pub reservation: ::core::option::Option<Reservation>,
+    #[prost(message, optional, tag = "4")]
+    pub update_mask: ::core::option::Option<::prost_types::FieldMask>,
+    /// optional, a retried request with the same key and payload gets the original response
+    #[prost(string, tag = "5")]
+    pub idempotency_key: ::prost::alloc::string::String,
 }
 /// Updated reservation will be returned in UpdateResponse
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -55,12 +106,44 @@ pub struct UpdateResponse {
     #[prost(message, optional, tag = "1")]
     pub reservation: ::core::option::Option<Reservation>,
 }
+/// To move or resize a reservation, send a RescheduleRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RescheduleRequest {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    /// new start time for the reservation
+    #[prost(message, optional, tag = "2")]
+    pub start: ::core::option::Option<::prost_types::Timestamp>,
+    /// new end time for the reservation
+    #[prost(message, optional, tag = "3")]
+    pub end: ::core::option::Option<::prost_types::Timestamp>,
+    /// move the reservation to another resource, if empty, keep the current one
+    #[prost(string, tag = "4")]
+    pub resource_id: ::prost::alloc::string::String,
+    /// optional, a retried request with the same key and payload gets the original response
+    #[prost(string, tag = "5")]
+    pub idempotency_key: ::prost::alloc::string::String,
+    /// if not 0, fail unless the reservation is still at this version
+    #[prost(int64, tag = "6")]
+    pub expected_version: i64,
+}
+/// Rescheduled reservation will be returned in RescheduleResponse
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RescheduleResponse {
+    #[prost(message, optional, tag = "1")]
+    pub reservation: ::core::option::Option<Reservation>,
+}
 /// To change a reservation from pending to confirmed, send a ConfirmRequest
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ConfirmRequest {
     #[prost(int64, tag = "1")]
     pub id: i64,
+    /// if not 0, fail unless the reservation is still at this version
+    #[prost(int64, tag = "2")]
+    pub expected_version: i64,
 }
 /// Confirmed reservation will be returned in ConfirmResponse
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -75,6 +158,9 @@ pub struct ConfirmResponse {
 pub struct CancelRequest {
     #[prost(int64, tag = "1")]
     pub id: i64,
+    /// if not 0, fail unless the reservation is still at this version
+    #[prost(int64, tag = "2")]
+    pub expected_version: i64,
 }
 /// Canceled reservation will be returned in CancelResponse
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -178,392 +264,1983 @@ pub struct FilterResponse {
 /// Client can listen to reservation updates by sending a ListenRequest
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct ListenRequest {}
-/// Server will send ListenResponse to client in streaming response
+pub struct ListenRequest {
+    /// replay every change after this sequence number before going live. To resume after a
+    /// disconnect, pass the sequence of the last change received. If not set, only new changes
+    #[prost(int64, optional, tag = "1")]
+    pub since: ::core::option::Option<i64>,
+    /// only changes of these resources. If empty, all resources
+    #[prost(string, repeated, tag = "2")]
+    pub resource_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// only changes of this user's reservations. If empty, all users
+    #[prost(string, tag = "3")]
+    pub user_id: ::prost::alloc::string::String,
+    /// only reservations with this status. If UNKNOWN, all statuses
+    #[prost(enumeration = "ReservationStatus", tag = "4")]
+    pub status: i32,
+    /// only these kinds of changes. If empty, all of them
+    #[prost(enumeration = "ReservationUpdateType", repeated, tag = "5")]
+    pub ops: ::prost::alloc::vec::Vec<i32>,
+}
+/// Server will send ListenResponse to client in streaming response. Changes are delivered
+/// at least once, in sequence order
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ListenResponse {
     /// update type
     #[prost(enumeration = "ReservationUpdateType", tag = "1")]
     pub op: i32,
-    /// id for updated reservation
+    /// the reservation as of the change, changes recorded before the feed existed only have the id
     #[prost(message, optional, tag = "2")]
     pub reservation: ::core::option::Option<Reservation>,
+    /// monotonically increasing sequence number of the change
+    #[prost(int64, tag = "3")]
+    pub sequence: i64,
+    /// when the change was committed
+    #[prost(message, optional, tag = "4")]
+    pub committed_at: ::core::option::Option<::prost_types::Timestamp>,
 }
-/// reservation status for a given time period
-#[derive(
-    sqlx::Type, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration,
-)]
-#[repr(i32)]
-pub enum ReservationStatus {
-    Unknown = 0,
-    Pending = 1,
-    Confirmed = 2,
-    Blocked = 3,
+/// one recorded change of a reservation
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReservationHistoryEntry {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(int64, tag = "2")]
+    pub reservation_id: i64,
+    #[prost(enumeration = "ReservationUpdateType", tag = "3")]
+    pub op: i32,
+    /// who made the change, empty if unknown
+    #[prost(string, tag = "4")]
+    pub actor: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "5")]
+    pub changed_at: ::core::option::Option<::prost_types::Timestamp>,
+    /// reservation before the change, empty for CREATE
+    #[prost(message, optional, tag = "6")]
+    pub before: ::core::option::Option<Reservation>,
+    /// reservation after the change, empty for DELETE
+    #[prost(message, optional, tag = "7")]
+    pub after: ::core::option::Option<Reservation>,
 }
-impl ReservationStatus {
-    /// String value of the enum field names used in the ProtoBuf definition.
-    ///
-    /// The values are not transformed in any way and thus are considered stable
-    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
-    pub fn as_str_name(&self) -> &'static str {
-        match self {
-            ReservationStatus::Unknown => "RESERVATION_STATUS_UNKNOWN",
-            ReservationStatus::Pending => "RESERVATION_STATUS_PENDING",
-            ReservationStatus::Confirmed => "RESERVATION_STATUS_CONFIRMED",
-            ReservationStatus::Blocked => "RESERVATION_STATUS_BLOCKED",
-        }
-    }
+/// To get the change history of a reservation, send a GetHistoryRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetHistoryRequest {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
 }
-/// when reservation is updated, record the update type
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
-#[repr(i32)]
-pub enum ReservationUpdateType {
-    Unknown = 0,
-    Create = 1,
-    Update = 2,
-    Delete = 3,
+/// changes are returned oldest first
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetHistoryResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: ::prost::alloc::vec::Vec<ReservationHistoryEntry>,
 }
-impl ReservationUpdateType {
-    /// String value of the enum field names used in the ProtoBuf definition.
-    ///
-    /// The values are not transformed in any way and thus are considered stable
-    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
-    pub fn as_str_name(&self) -> &'static str {
-        match self {
-            ReservationUpdateType::Unknown => "RESERVATION_UPDATE_TYPE_UNKNOWN",
-            ReservationUpdateType::Create => "RESERVATION_UPDATE_TYPE_CREATE",
-            ReservationUpdateType::Update => "RESERVATION_UPDATE_TYPE_UPDATE",
-            ReservationUpdateType::Delete => "RESERVATION_UPDATE_TYPE_DELETE",
-        }
-    }
+/// query changes by actor and time range
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HistoryQuery {
+    /// if empty, query all actors
+    #[prost(string, tag = "1")]
+    pub actor: ::prost::alloc::string::String,
+    /// if 0, use Infinity for start time
+    #[prost(message, optional, tag = "2")]
+    pub start: ::core::option::Option<::prost_types::Timestamp>,
+    /// if 0, use Infinity for end time
+    #[prost(message, optional, tag = "3")]
+    pub end: ::core::option::Option<::prost_types::Timestamp>,
+    /// sort direction
+    #[prost(bool, tag = "4")]
+    pub desc: bool,
 }
-/// Generated client implementations.
-pub mod reservation_service_client {
-    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
-    use tonic::codegen::http::Uri;
-    use tonic::codegen::*;
-    /// Reservation service
-    #[derive(Debug, Clone)]
-    pub struct ReservationServiceClient<T> {
-        inner: tonic::client::Grpc<T>,
-    }
-    impl ReservationServiceClient<tonic::transport::Channel> {
-        /// Attempt to create a new client by connecting to a given endpoint.
-        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
-        where
-            D: std::convert::TryInto<tonic::transport::Endpoint>,
-            D::Error: Into<StdError>,
-        {
-            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
-            Ok(Self::new(conn))
-        }
-    }
-    impl<T> ReservationServiceClient<T>
-    where
-        T: tonic::client::GrpcService<tonic::body::BoxBody>,
-        T::Error: Into<StdError>,
-        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
-        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
-    {
-        pub fn new(inner: T) -> Self {
-            let inner = tonic::client::Grpc::new(inner);
-            Self { inner }
-        }
-        pub fn with_origin(inner: T, origin: Uri) -> Self {
-            let inner = tonic::client::Grpc::with_origin(inner, origin);
-            Self { inner }
-        }
-        pub fn with_interceptor<F>(
-            inner: T,
-            interceptor: F,
-        ) -> ReservationServiceClient<InterceptedService<T, F>>
-        where
-            F: tonic::service::Interceptor,
-            T::ResponseBody: Default,
-            T: tonic::codegen::Service<
-                http::Request<tonic::body::BoxBody>,
-                Response = http::Response<
-                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
-                >,
-            >,
-            <T as tonic::codegen::Service<http::Request<tonic::body::BoxBody>>>::Error:
-                Into<StdError> + Send + Sync,
-        {
-            ReservationServiceClient::new(InterceptedService::new(inner, interceptor))
-        }
-        /// Compress requests with the given encoding.
-        ///
-        /// This requires the server to support it otherwise it might respond with an
-        /// error.
-        #[must_use]
-        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
-            self.inner = self.inner.send_compressed(encoding);
-            self
-        }
-        /// Enable decompressing responses.
-        #[must_use]
-        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
-            self.inner = self.inner.accept_compressed(encoding);
-            self
-        }
-        /// make a reservation
-        pub async fn reserve(
-            &mut self,
-            request: impl tonic::IntoRequest<super::ReserveRequest>,
-        ) -> Result<tonic::Response<super::ReserveResponse>, tonic::Status> {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::new(
-                    tonic::Code::Unknown,
-                    format!("Service was not ready: {}", e.into()),
-                )
-            })?;
-            let codec = tonic::codec::ProstCodec::default();
-            let path =
-                http::uri::PathAndQuery::from_static("/reservation.ReservationService/reserve");
-            self.inner.unary(request.into_request(), path, codec).await
-        }
-        /// confirm a pending reservation, if reservation is not pending, do nothing
-        pub async fn confirm(
-            &mut self,
-            request: impl tonic::IntoRequest<super::ConfirmRequest>,
-        ) -> Result<tonic::Response<super::ConfirmResponse>, tonic::Status> {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::new(
-                    tonic::Code::Unknown,
-                    format!("Service was not ready: {}", e.into()),
-                )
-            })?;
-            let codec = tonic::codec::ProstCodec::default();
-            let path =
-                http::uri::PathAndQuery::from_static("/reservation.ReservationService/confirm");
-            self.inner.unary(request.into_request(), path, codec).await
-        }
-        /// update the reservation note
-        pub async fn update(
-            &mut self,
-            request: impl tonic::IntoRequest<super::UpdateRequest>,
-        ) -> Result<tonic::Response<super::UpdateResponse>, tonic::Status> {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::new(
-                    tonic::Code::Unknown,
-                    format!("Service was not ready: {}", e.into()),
-                )
-            })?;
-            let codec = tonic::codec::ProstCodec::default();
-            let path =
-                http::uri::PathAndQuery::from_static("/reservation.ReservationService/update");
-            self.inner.unary(request.into_request(), path, codec).await
-        }
-        /// cancel a reservation
-        pub async fn cancel(
-            &mut self,
-            request: impl tonic::IntoRequest<super::CancelRequest>,
-        ) -> Result<tonic::Response<super::CancelResponse>, tonic::Status> {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::new(
-                    tonic::Code::Unknown,
-                    format!("Service was not ready: {}", e.into()),
-                )
-            })?;
-            let codec = tonic::codec::ProstCodec::default();
-            let path =
-                http::uri::PathAndQuery::from_static("/reservation.ReservationService/cancel");
-            self.inner.unary(request.into_request(), path, codec).await
-        }
-        /// get a reservation by id
-        pub async fn get(
-            &mut self,
-            request: impl tonic::IntoRequest<super::GetRequest>,
-        ) -> Result<tonic::Response<super::GetResponse>, tonic::Status> {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::new(
-                    tonic::Code::Unknown,
-                    format!("Service was not ready: {}", e.into()),
-                )
-            })?;
-            let codec = tonic::codec::ProstCodec::default();
-            let path = http::uri::PathAndQuery::from_static("/reservation.ReservationService/get");
-            self.inner.unary(request.into_request(), path, codec).await
-        }
-        /// query reservations by resource id, user id, status, start time, end time
-        pub async fn query(
-            &mut self,
-            request: impl tonic::IntoRequest<super::QueryRequest>,
-        ) -> Result<tonic::Response<tonic::codec::Streaming<super::Reservation>>, tonic::Status>
-        {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::new(
-                    tonic::Code::Unknown,
-                    format!("Service was not ready: {}", e.into()),
-                )
-            })?;
-            let codec = tonic::codec::ProstCodec::default();
-            let path =
-                http::uri::PathAndQuery::from_static("/reservation.ReservationService/query");
-            self.inner
-                .server_streaming(request.into_request(), path, codec)
-                .await
-        }
-        /// filter reservations, order by reservation id
-        pub async fn filter(
-            &mut self,
-            request: impl tonic::IntoRequest<super::FilterRequest>,
-        ) -> Result<tonic::Response<super::FilterResponse>, tonic::Status> {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::new(
-                    tonic::Code::Unknown,
-                    format!("Service was not ready: {}", e.into()),
-                )
-            })?;
-            let codec = tonic::codec::ProstCodec::default();
-            let path =
-                http::uri::PathAndQuery::from_static("/reservation.ReservationService/filter");
-            self.inner.unary(request.into_request(), path, codec).await
-        }
-        /// another system could monitor newly added/confirmed/cancelled reservations
-        pub async fn listen(
-            &mut self,
-            request: impl tonic::IntoRequest<super::ListenRequest>,
-        ) -> Result<tonic::Response<tonic::codec::Streaming<super::Reservation>>, tonic::Status>
-        {
-            self.inner.ready().await.map_err(|e| {
-                tonic::Status::new(
-                    tonic::Code::Unknown,
-                    format!("Service was not ready: {}", e.into()),
-                )
-            })?;
-            let codec = tonic::codec::ProstCodec::default();
-            let path =
-                http::uri::PathAndQuery::from_static("/reservation.ReservationService/listen");
-            self.inner
-                .server_streaming(request.into_request(), path, codec)
-                .await
-        }
-    }
+/// To query the change history, send a QueryHistoryRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct QueryHistoryRequest {
+    #[prost(message, optional, tag = "1")]
+    pub query: ::core::option::Option<HistoryQuery>,
 }
-/// Generated server implementations.
-pub mod reservation_service_server {
-    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
-    use tonic::codegen::*;
-    /// Generated trait containing gRPC methods that should be implemented for use with ReservationServiceServer.
-    #[async_trait]
-    pub trait ReservationService: Send + Sync + 'static {
-        /// make a reservation
-        async fn reserve(
-            &self,
-            request: tonic::Request<super::ReserveRequest>,
-        ) -> Result<tonic::Response<super::ReserveResponse>, tonic::Status>;
-        /// confirm a pending reservation, if reservation is not pending, do nothing
-        async fn confirm(
-            &self,
-            request: tonic::Request<super::ConfirmRequest>,
-        ) -> Result<tonic::Response<super::ConfirmResponse>, tonic::Status>;
-        /// update the reservation note
-        async fn update(
-            &self,
-            request: tonic::Request<super::UpdateRequest>,
-        ) -> Result<tonic::Response<super::UpdateResponse>, tonic::Status>;
-        /// cancel a reservation
-        async fn cancel(
-            &self,
-            request: tonic::Request<super::CancelRequest>,
-        ) -> Result<tonic::Response<super::CancelResponse>, tonic::Status>;
-        /// get a reservation by id
-        async fn get(
-            &self,
-            request: tonic::Request<super::GetRequest>,
-        ) -> Result<tonic::Response<super::GetResponse>, tonic::Status>;
-        /// Server streaming response type for the query method.
-        type queryStream: futures_core::Stream<Item = Result<super::Reservation, tonic::Status>>
-            + Send
-            + 'static;
-        /// query reservations by resource id, user id, status, start time, end time
-        async fn query(
-            &self,
-            request: tonic::Request<super::QueryRequest>,
-        ) -> Result<tonic::Response<Self::queryStream>, tonic::Status>;
-        /// filter reservations, order by reservation id
-        async fn filter(
-            &self,
-            request: tonic::Request<super::FilterRequest>,
-        ) -> Result<tonic::Response<super::FilterResponse>, tonic::Status>;
-        /// Server streaming response type for the listen method.
-        type listenStream: futures_core::Stream<Item = Result<super::Reservation, tonic::Status>>
-            + Send
-            + 'static;
-        /// another system could monitor newly added/confirmed/cancelled reservations
-        async fn listen(
-            &self,
-            request: tonic::Request<super::ListenRequest>,
-        ) -> Result<tonic::Response<Self::listenStream>, tonic::Status>;
-    }
-    /// Reservation service
-    #[derive(Debug)]
-    pub struct ReservationServiceServer<T: ReservationService> {
-        inner: _Inner<T>,
-        accept_compression_encodings: EnabledCompressionEncodings,
-        send_compression_encodings: EnabledCompressionEncodings,
-    }
-    struct _Inner<T>(Arc<T>);
-    impl<T: ReservationService> ReservationServiceServer<T> {
-        pub fn new(inner: T) -> Self {
-            Self::from_arc(Arc::new(inner))
-        }
-        pub fn from_arc(inner: Arc<T>) -> Self {
-            let inner = _Inner(inner);
-            Self {
-                inner,
-                accept_compression_encodings: Default::default(),
-                send_compression_encodings: Default::default(),
-            }
-        }
-        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
-        where
-            F: tonic::service::Interceptor,
-        {
-            InterceptedService::new(Self::new(inner), interceptor)
-        }
-        /// Enable decompressing requests with the given encoding.
-        #[must_use]
-        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
-            self.accept_compression_encodings.enable(encoding);
-            self
-        }
-        /// Compress responses with the given encoding, if the client supports it.
-        #[must_use]
-        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
-            self.send_compression_encodings.enable(encoding);
-            self
-        }
-    }
-    impl<T, B> tonic::codegen::Service<http::Request<B>> for ReservationServiceServer<T>
-    where
-        T: ReservationService,
-        B: Body + Send + 'static,
-        B::Error: Into<StdError> + Send + 'static,
-    {
-        type Response = http::Response<tonic::body::BoxBody>;
-        type Error = std::convert::Infallible;
-        type Future = BoxFuture<Self::Response, Self::Error>;
-        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-            Poll::Ready(Ok(()))
-        }
-        fn call(&mut self, req: http::Request<B>) -> Self::Future {
-            let inner = self.inner.clone();
-            match req.uri().path() {
-                "/reservation.ReservationService/reserve" => {
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct QueryHistoryResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: ::prost::alloc::vec::Vec<ReservationHistoryEntry>,
+}
+/// POST a JSON event to an HTTP endpoint for every matching reservation change
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Webhook {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    /// endpoint receiving the events
+    #[prost(string, tag = "2")]
+    pub url: ::prost::alloc::string::String,
+    /// key for the HMAC-SHA256 signature of each request. Never returned, keep the old one if
+    /// empty on update
+    #[prost(string, tag = "3")]
+    pub secret: ::prost::alloc::string::String,
+    /// only changes of these resources. If empty, all resources
+    #[prost(string, repeated, tag = "4")]
+    pub resource_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// only changes of this user's reservations. If empty, all users
+    #[prost(string, tag = "5")]
+    pub user_id: ::prost::alloc::string::String,
+    /// only reservations with this status. If UNKNOWN, all statuses
+    #[prost(enumeration = "ReservationStatus", tag = "6")]
+    pub status: i32,
+    /// only these kinds of changes. If empty, all of them
+    #[prost(enumeration = "ReservationUpdateType", repeated, tag = "7")]
+    pub ops: ::prost::alloc::vec::Vec<i32>,
+}
+/// delivery of one change to one webhook
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebhookDelivery {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(int64, tag = "2")]
+    pub webhook_id: i64,
+    /// sequence number of the change
+    #[prost(int64, tag = "3")]
+    pub sequence: i64,
+    #[prost(enumeration = "WebhookDeliveryStatus", tag = "4")]
+    pub status: i32,
+    #[prost(int32, tag = "5")]
+    pub attempts: i32,
+    /// error of the last failed attempt
+    #[prost(string, tag = "6")]
+    pub last_error: ::prost::alloc::string::String,
+    /// HTTP status of the last attempt, 0 if there was no response
+    #[prost(int32, tag = "7")]
+    pub response_status: i32,
+    #[prost(message, optional, tag = "8")]
+    pub created_at: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "9")]
+    pub next_attempt_at: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "10")]
+    pub delivered_at: ::core::option::Option<::prost_types::Timestamp>,
+}
+/// create a webhook if id is 0, otherwise update it
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpsertWebhookRequest {
+    #[prost(message, optional, tag = "1")]
+    pub webhook: ::core::option::Option<Webhook>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpsertWebhookResponse {
+    #[prost(message, optional, tag = "1")]
+    pub webhook: ::core::option::Option<Webhook>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteWebhookRequest {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteWebhookResponse {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListWebhooksRequest {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListWebhooksResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub webhooks: ::prost::alloc::vec::Vec<Webhook>,
+}
+/// delivery log of a webhook, newest first
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListWebhookDeliveriesRequest {
+    #[prost(int64, tag = "1")]
+    pub webhook_id: i64,
+    /// if UNKNOWN, deliveries in any status
+    #[prost(enumeration = "WebhookDeliveryStatus", tag = "2")]
+    pub status: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListWebhookDeliveriesResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub deliveries: ::prost::alloc::vec::Vec<WebhookDelivery>,
+}
+/// put a dead delivery back into the queue
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RetryWebhookDeliveryRequest {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RetryWebhookDeliveryResponse {
+    #[prost(message, optional, tag = "1")]
+    pub delivery: ::core::option::Option<WebhookDelivery>,
+}
+/// To export reservations as an iCalendar (RFC 5545) file, send an ExportCalendarRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExportCalendarRequest {
+    #[prost(message, optional, tag = "1")]
+    pub query: ::core::option::Option<ReservationQuery>,
+}
+/// one VEVENT per reservation matching the query
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExportCalendarResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub ics: ::prost::alloc::vec::Vec<u8>,
+}
+/// To get the subscribable calendar feed of a user or a resource, send a GetCalendarFeedRequest.
+/// Exactly one of user_id and resource_id must be set. The feed of a user is handed out to the
+/// user and admins, the feed of a resource only to admins
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetCalendarFeedRequest {
+    #[prost(string, tag = "1")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub resource_id: ::prost::alloc::string::String,
+}
+/// path of the feed on the calendar server, including its secret token
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetCalendarFeedResponse {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// a calendar feed handed out, its path works until the token is revoked
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CalendarFeedToken {
+    /// random, every feed handed out gets a token of its own
+    #[prost(string, tag = "1")]
+    pub nonce: ::prost::alloc::string::String,
+    /// exactly one of user_id and resource_id is set
+    #[prost(string, tag = "2")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub resource_id: ::prost::alloc::string::String,
+}
+/// To stop a calendar feed from working, send a RevokeCalendarFeedRequest. The other feeds of the
+/// user or resource keep working
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RevokeCalendarFeedRequest {
+    /// the path GetCalendarFeedResponse handed out
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RevokeCalendarFeedResponse {}
+/// To import the VEVENTs of an iCalendar file (RFC 5545) onto a resource, send an
+/// ImportCalendarRequest. Recurring events (RRULE, EXDATE) are expanded into their occurrences
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ImportCalendarRequest {
+    /// the .ics file
+    #[prost(bytes = "vec", tag = "1")]
+    pub ics: ::prost::alloc::vec::Vec<u8>,
+    /// resource the events are imported onto
+    #[prost(string, tag = "2")]
+    pub resource_id: ::prost::alloc::string::String,
+    /// if set, events become pending reservations of this user, otherwise blocked windows
+    #[prost(string, tag = "3")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(enumeration = "ImportMode", tag = "4")]
+    pub mode: i32,
+    /// IANA time zone of floating times and all-day events, UTC if empty
+    #[prost(string, tag = "5")]
+    pub timezone: ::prost::alloc::string::String,
+    /// recurrences without COUNT or UNTIL stop here, a year from now if not set
+    #[prost(message, optional, tag = "6")]
+    pub until: ::core::option::Option<::prost_types::Timestamp>,
+}
+/// result of an imported occurrence
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ImportCalendarResult {
+    /// UID of the VEVENT, a recurring event has a result per occurrence
+    #[prost(string, tag = "1")]
+    pub uid: ::prost::alloc::string::String,
+    /// the reservation or blocked window, with id if it was created
+    #[prost(message, optional, tag = "2")]
+    pub reservation: ::core::option::Option<Reservation>,
+    /// why it failed, e.g. a conflict. Empty if it was (or would have been) created
+    #[prost(string, tag = "3")]
+    pub error: ::prost::alloc::string::String,
+}
+/// Results in file order will be returned in ImportCalendarResponse
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ImportCalendarResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub results: ::prost::alloc::vec::Vec<ImportCalendarResult>,
+    /// number of reservations and blocked windows created, 0 for a dry run
+    #[prost(int32, tag = "2")]
+    pub created: i32,
+}
+/// Repeat a window every `interval` days or weeks. Either count or until must be set
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Recurrence {
+    #[prost(enumeration = "RecurrenceFrequency", tag = "1")]
+    pub frequency: i32,
+    /// repeat every n days/weeks, 0 is treated as 1
+    #[prost(uint32, tag = "2")]
+    pub interval: u32,
+    /// max number of occurrences (including the first one), 0 means no limit
+    #[prost(uint32, tag = "3")]
+    pub count: u32,
+    /// no occurrence starts after this time
+    #[prost(message, optional, tag = "4")]
+    pub until: ::core::option::Option<::prost_types::Timestamp>,
+}
+/// A blocked window makes resources unavailable, e.g. for maintenance or a blackout period.
+/// Blocked windows are stored as reservations with BLOCKED status, no user id and the reason as note
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BlockWindow {
+    /// resources to block, at least one is required
+    #[prost(string, repeated, tag = "1")]
+    pub resource_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// start time of the (first) window
+    #[prost(message, optional, tag = "2")]
+    pub start: ::core::option::Option<::prost_types::Timestamp>,
+    /// end time of the (first) window
+    #[prost(message, optional, tag = "3")]
+    pub end: ::core::option::Option<::prost_types::Timestamp>,
+    /// why the resources are blocked
+    #[prost(string, tag = "4")]
+    pub reason: ::prost::alloc::string::String,
+    /// if set, the window is repeated
+    #[prost(message, optional, tag = "5")]
+    pub recurrence: ::core::option::Option<Recurrence>,
+    /// if true, overlapping pending reservations are cancelled, otherwise they cause a conflict
+    #[prost(bool, tag = "6")]
+    pub cancel_pending: bool,
+}
+/// To block resources, send a BlockRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BlockRequest {
+    #[prost(message, optional, tag = "1")]
+    pub window: ::core::option::Option<BlockWindow>,
+    /// optional, a retried request with the same key and payload gets the original response
+    #[prost(string, tag = "2")]
+    pub idempotency_key: ::prost::alloc::string::String,
+}
+/// Created blocked windows and force-cancelled reservations will be returned in BlockResponse
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BlockResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub blocks: ::prost::alloc::vec::Vec<Reservation>,
+    #[prost(message, repeated, tag = "2")]
+    pub cancelled: ::prost::alloc::vec::Vec<Reservation>,
+}
+/// Per-resource settings. Resources without settings have no buffers and no booking rules
+/// other than that reservations can't start in the past
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Resource {
+    /// resource id
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+    /// time kept free before each reservation, e.g. for setup
+    #[prost(message, optional, tag = "2")]
+    pub pre_buffer: ::core::option::Option<::prost_types::Duration>,
+    /// time kept free after each reservation, e.g. for cleaning or refuelling
+    #[prost(message, optional, tag = "3")]
+    pub post_buffer: ::core::option::Option<::prost_types::Duration>,
+    /// minimum duration of a reservation, no limit if not set
+    #[prost(message, optional, tag = "4")]
+    pub min_duration: ::core::option::Option<::prost_types::Duration>,
+    /// maximum duration of a reservation, no limit if not set
+    #[prost(message, optional, tag = "5")]
+    pub max_duration: ::core::option::Option<::prost_types::Duration>,
+    /// how far in advance booking opens, e.g. 14 days. No limit if not set
+    #[prost(message, optional, tag = "6")]
+    pub booking_horizon: ::core::option::Option<::prost_types::Duration>,
+    /// minimum time between making a reservation and its start, no limit if not set
+    #[prost(message, optional, tag = "7")]
+    pub lead_time: ::core::option::Option<::prost_types::Duration>,
+    /// kind of the resource, e.g. room or vehicle, used by quota policies
+    #[prost(string, tag = "8")]
+    pub kind: ::prost::alloc::string::String,
+}
+/// To create or change resource settings, send an UpsertResourceRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpsertResourceRequest {
+    #[prost(message, optional, tag = "1")]
+    pub resource: ::core::option::Option<Resource>,
+}
+/// Saved resource settings will be returned in UpsertResourceResponse
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpsertResourceResponse {
+    #[prost(message, optional, tag = "1")]
+    pub resource: ::core::option::Option<Resource>,
+}
+/// To get resource settings, send a GetResourceRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetResourceRequest {
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+}
+/// Resource settings will be returned in GetResourceResponse
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetResourceResponse {
+    #[prost(message, optional, tag = "1")]
+    pub resource: ::core::option::Option<Resource>,
+}
+/// a time period, both start and end are required
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TimeSlot {
+    #[prost(message, optional, tag = "1")]
+    pub start: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "2")]
+    pub end: ::core::option::Option<::prost_types::Timestamp>,
+}
+/// To find free time slots of a resource between start and end, send an AvailabilityRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AvailabilityRequest {
+    #[prost(string, tag = "1")]
+    pub resource_id: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub start: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "3")]
+    pub end: ::core::option::Option<::prost_types::Timestamp>,
+}
+/// Free slots will be returned in AvailabilityResponse. Any reservation within a slot
+/// (including the resource buffers) will not conflict with existing reservations
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AvailabilityResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub slots: ::prost::alloc::vec::Vec<TimeSlot>,
+}
+/// A quota policy applies to a user, or to every member of a group individually.
+/// Exactly one of user_id and group_id must be set
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct QuotaPolicy {
+    /// unique id for the policy, if empty a new policy is created
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(enumeration = "QuotaKind", tag = "2")]
+    pub kind: i32,
+    #[prost(string, tag = "3")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub group_id: ::prost::alloc::string::String,
+    /// number of reservations, or hours for MAX_WEEKLY_HOURS
+    #[prost(int64, tag = "5")]
+    pub limit: i64,
+    /// resource kind, only used by MAX_FUTURE_PER_KIND
+    #[prost(string, tag = "6")]
+    pub resource_kind: ::prost::alloc::string::String,
+}
+/// current usage of a quota policy for a user
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct QuotaUsage {
+    #[prost(message, optional, tag = "1")]
+    pub policy: ::core::option::Option<QuotaPolicy>,
+    /// number of reservations, or hours for MAX_WEEKLY_HOURS
+    #[prost(double, tag = "2")]
+    pub used: f64,
+    #[prost(double, tag = "3")]
+    pub remaining: f64,
+}
+/// To create or update a quota policy, send an UpsertQuotaPolicyRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpsertQuotaPolicyRequest {
+    #[prost(message, optional, tag = "1")]
+    pub policy: ::core::option::Option<QuotaPolicy>,
+}
+/// Saved policy will be returned in UpsertQuotaPolicyResponse
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpsertQuotaPolicyResponse {
+    #[prost(message, optional, tag = "1")]
+    pub policy: ::core::option::Option<QuotaPolicy>,
+}
+/// To delete a quota policy, send a DeleteQuotaPolicyRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteQuotaPolicyRequest {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteQuotaPolicyResponse {}
+/// To replace the groups a user belongs to, send a SetUserGroupsRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetUserGroupsRequest {
+    #[prost(string, tag = "1")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "2")]
+    pub group_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetUserGroupsResponse {}
+/// To get the quota usage of a user, send a GetQuotaRequest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetQuotaRequest {
+    #[prost(string, tag = "1")]
+    pub user_id: ::prost::alloc::string::String,
+}
+/// Usage of every policy applying to the user will be returned in GetQuotaResponse
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetQuotaResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub usages: ::prost::alloc::vec::Vec<QuotaUsage>,
+}
+/// reservation status for a given time period
+#[derive(
+    sqlx::Type, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration,
+)]
+#[repr(i32)]
+pub enum ReservationStatus {
+    Unknown = 0,
+    Pending = 1,
+    Confirmed = 2,
+    Blocked = 3,
+}
+impl ReservationStatus {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ReservationStatus::Unknown => "RESERVATION_STATUS_UNKNOWN",
+            ReservationStatus::Pending => "RESERVATION_STATUS_PENDING",
+            ReservationStatus::Confirmed => "RESERVATION_STATUS_CONFIRMED",
+            ReservationStatus::Blocked => "RESERVATION_STATUS_BLOCKED",
+        }
+    }
+}
+/// when reservation is updated, record the update type
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ReservationUpdateType {
+    Unknown = 0,
+    Create = 1,
+    Update = 2,
+    Delete = 3,
+}
+impl ReservationUpdateType {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ReservationUpdateType::Unknown => "RESERVATION_UPDATE_TYPE_UNKNOWN",
+            ReservationUpdateType::Create => "RESERVATION_UPDATE_TYPE_CREATE",
+            ReservationUpdateType::Update => "RESERVATION_UPDATE_TYPE_UPDATE",
+            ReservationUpdateType::Delete => "RESERVATION_UPDATE_TYPE_DELETE",
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum WebhookDeliveryStatus {
+    Unknown = 0,
+    /// waiting for the next attempt
+    Pending = 1,
+    Delivered = 2,
+    /// given up after too many failed attempts, can be retried by hand
+    Dead = 3,
+}
+impl WebhookDeliveryStatus {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            WebhookDeliveryStatus::Unknown => "WEBHOOK_DELIVERY_STATUS_UNKNOWN",
+            WebhookDeliveryStatus::Pending => "WEBHOOK_DELIVERY_STATUS_PENDING",
+            WebhookDeliveryStatus::Delivered => "WEBHOOK_DELIVERY_STATUS_DELIVERED",
+            WebhookDeliveryStatus::Dead => "WEBHOOK_DELIVERY_STATUS_DEAD",
+        }
+    }
+}
+/// how an import is applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ImportMode {
+    Unknown = 0,
+    /// nothing is created, the response reports what would be and what conflicts
+    DryRun = 1,
+    /// everything is created, or nothing if anything fails
+    Transactional = 2,
+    /// whatever doesn't fail is created
+    BestEffort = 3,
+}
+impl ImportMode {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ImportMode::Unknown => "IMPORT_MODE_UNKNOWN",
+            ImportMode::DryRun => "IMPORT_MODE_DRY_RUN",
+            ImportMode::Transactional => "IMPORT_MODE_TRANSACTIONAL",
+            ImportMode::BestEffort => "IMPORT_MODE_BEST_EFFORT",
+        }
+    }
+}
+/// how often a blocked window repeats
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum RecurrenceFrequency {
+    Unknown = 0,
+    Daily = 1,
+    Weekly = 2,
+}
+impl RecurrenceFrequency {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            RecurrenceFrequency::Unknown => "RECURRENCE_FREQUENCY_UNKNOWN",
+            RecurrenceFrequency::Daily => "RECURRENCE_FREQUENCY_DAILY",
+            RecurrenceFrequency::Weekly => "RECURRENCE_FREQUENCY_WEEKLY",
+        }
+    }
+}
+/// what a quota policy limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum QuotaKind {
+    Unknown = 0,
+    /// max number of pending or confirmed reservations that haven't ended yet
+    MaxActive = 1,
+    /// max hours of pending or confirmed reservations per week (Monday to Sunday, UTC)
+    MaxWeeklyHours = 2,
+    /// max number of pending or confirmed reservations that haven't started yet, on resources of a kind
+    MaxFuturePerKind = 3,
+}
+impl QuotaKind {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            QuotaKind::Unknown => "QUOTA_KIND_UNKNOWN",
+            QuotaKind::MaxActive => "QUOTA_KIND_MAX_ACTIVE",
+            QuotaKind::MaxWeeklyHours => "QUOTA_KIND_MAX_WEEKLY_HOURS",
+            QuotaKind::MaxFuturePerKind => "QUOTA_KIND_MAX_FUTURE_PER_KIND",
+        }
+    }
+}
+/// Generated client implementations.
+pub mod reservation_service_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::http::Uri;
+    use tonic::codegen::*;
+    /// Reservation service
+    #[derive(Debug, Clone)]
+    pub struct ReservationServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl ReservationServiceClient<tonic::transport::Channel> {
+        /// Attempt to create a new client by connecting to a given endpoint.
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> ReservationServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_origin(inner: T, origin: Uri) -> Self {
+            let inner = tonic::client::Grpc::with_origin(inner, origin);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> ReservationServiceClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T::ResponseBody: Default,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<http::Request<tonic::body::BoxBody>>>::Error:
+                Into<StdError> + Send + Sync,
+        {
+            ReservationServiceClient::new(InterceptedService::new(inner, interceptor))
+        }
+        /// Compress requests with the given encoding.
+        ///
+        /// This requires the server to support it otherwise it might respond with an
+        /// error.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.inner = self.inner.send_compressed(encoding);
+            self
+        }
+        /// Enable decompressing responses.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.inner = self.inner.accept_compressed(encoding);
+            self
+        }
+        /// make a reservation (the user or an admin, blocked windows admin only)
+        pub async fn reserve(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ReserveRequest>,
+        ) -> Result<tonic::Response<super::ReserveResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/reserve");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// make several reservations in one transaction, all or nothing (like reserve)
+        pub async fn reserve_batch(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ReserveBatchRequest>,
+        ) -> Result<tonic::Response<super::ReserveBatchResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/reserve_batch",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// confirm a pending reservation, if reservation is not pending, do nothing. This and the
+        /// other changes of a reservation are left to its user or an admin, of a blocked window to
+        /// an admin
+        pub async fn confirm(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConfirmRequest>,
+        ) -> Result<tonic::Response<super::ConfirmResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/confirm");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// update the fields of a reservation listed in the update mask
+        pub async fn update(
+            &mut self,
+            request: impl tonic::IntoRequest<super::UpdateRequest>,
+        ) -> Result<tonic::Response<super::UpdateResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/update");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// move or resize a reservation atomically, fails if the new window is taken
+        pub async fn reschedule(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RescheduleRequest>,
+        ) -> Result<tonic::Response<super::RescheduleResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/reschedule");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// cancel a reservation
+        pub async fn cancel(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CancelRequest>,
+        ) -> Result<tonic::Response<super::CancelResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/cancel");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// get a reservation by id
+        pub async fn get(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetRequest>,
+        ) -> Result<tonic::Response<super::GetResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/reservation.ReservationService/get");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// query reservations by resource id, user id, status, start time, end time
+        pub async fn query(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QueryRequest>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::Reservation>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/query");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+        /// filter reservations, order by reservation id
+        pub async fn filter(
+            &mut self,
+            request: impl tonic::IntoRequest<super::FilterRequest>,
+        ) -> Result<tonic::Response<super::FilterResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/filter");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// another system could monitor newly added/confirmed/cancelled reservations
+        pub async fn listen(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListenRequest>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::ListenResponse>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/listen");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+        /// block resources for maintenance or blackout periods (admin only)
+        pub async fn block(
+            &mut self,
+            request: impl tonic::IntoRequest<super::BlockRequest>,
+        ) -> Result<tonic::Response<super::BlockResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/block");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// create or update resource settings (admin only)
+        pub async fn upsert_resource(
+            &mut self,
+            request: impl tonic::IntoRequest<super::UpsertResourceRequest>,
+        ) -> Result<tonic::Response<super::UpsertResourceResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/upsert_resource",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// get resource settings
+        pub async fn get_resource(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetResourceRequest>,
+        ) -> Result<tonic::Response<super::GetResourceResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/get_resource",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// find free time slots of a resource
+        pub async fn availability(
+            &mut self,
+            request: impl tonic::IntoRequest<super::AvailabilityRequest>,
+        ) -> Result<tonic::Response<super::AvailabilityResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/availability",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// create or update a quota policy (admin only)
+        pub async fn upsert_quota_policy(
+            &mut self,
+            request: impl tonic::IntoRequest<super::UpsertQuotaPolicyRequest>,
+        ) -> Result<tonic::Response<super::UpsertQuotaPolicyResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/upsert_quota_policy",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// delete a quota policy (admin only)
+        pub async fn delete_quota_policy(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DeleteQuotaPolicyRequest>,
+        ) -> Result<tonic::Response<super::DeleteQuotaPolicyResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/delete_quota_policy",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// replace the groups a user belongs to (admin only)
+        pub async fn set_user_groups(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SetUserGroupsRequest>,
+        ) -> Result<tonic::Response<super::SetUserGroupsResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/set_user_groups",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// get the current usage and remaining quota of a user
+        pub async fn get_quota(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetQuotaRequest>,
+        ) -> Result<tonic::Response<super::GetQuotaResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/get_quota");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// get the change history of a reservation
+        pub async fn get_history(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetHistoryRequest>,
+        ) -> Result<tonic::Response<super::GetHistoryResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/reservation.ReservationService/get_history");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// query the change history by actor and time range
+        pub async fn query_history(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QueryHistoryRequest>,
+        ) -> Result<tonic::Response<super::QueryHistoryResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/query_history",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// create or update a webhook subscription
+        pub async fn upsert_webhook(
+            &mut self,
+            request: impl tonic::IntoRequest<super::UpsertWebhookRequest>,
+        ) -> Result<tonic::Response<super::UpsertWebhookResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/upsert_webhook",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// delete a webhook subscription and its delivery log
+        pub async fn delete_webhook(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DeleteWebhookRequest>,
+        ) -> Result<tonic::Response<super::DeleteWebhookResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/delete_webhook",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// list webhook subscriptions, without secrets
+        pub async fn list_webhooks(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListWebhooksRequest>,
+        ) -> Result<tonic::Response<super::ListWebhooksResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/list_webhooks",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// get the delivery log of a webhook
+        pub async fn list_webhook_deliveries(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListWebhookDeliveriesRequest>,
+        ) -> Result<tonic::Response<super::ListWebhookDeliveriesResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/list_webhook_deliveries",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// retry a dead delivery
+        pub async fn retry_webhook_delivery(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RetryWebhookDeliveryRequest>,
+        ) -> Result<tonic::Response<super::RetryWebhookDeliveryResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/retry_webhook_delivery",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// export reservations as an iCalendar file, those of a user to the user or an admin, any
+        /// others to an admin only
+        pub async fn export_calendar(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ExportCalendarRequest>,
+        ) -> Result<tonic::Response<super::ExportCalendarResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/export_calendar",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// get the calendar feed of a user or a resource
+        pub async fn get_calendar_feed(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetCalendarFeedRequest>,
+        ) -> Result<tonic::Response<super::GetCalendarFeedResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/get_calendar_feed",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// revoke a calendar feed handed out before
+        pub async fn revoke_calendar_feed(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RevokeCalendarFeedRequest>,
+        ) -> Result<tonic::Response<super::RevokeCalendarFeedResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/revoke_calendar_feed",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// import reservations or blocked windows from an iCalendar file
+        pub async fn import_calendar(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ImportCalendarRequest>,
+        ) -> Result<tonic::Response<super::ImportCalendarResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/reservation.ReservationService/import_calendar",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+    }
+}
+/// Generated server implementations.
+pub mod reservation_service_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    /// Generated trait containing gRPC methods that should be implemented for use with ReservationServiceServer.
+    #[async_trait]
+    pub trait ReservationService: Send + Sync + 'static {
+        /// make a reservation (the user or an admin, blocked windows admin only)
+        async fn reserve(
+            &self,
+            request: tonic::Request<super::ReserveRequest>,
+        ) -> Result<tonic::Response<super::ReserveResponse>, tonic::Status>;
+        /// make several reservations in one transaction, all or nothing (like reserve)
+        async fn reserve_batch(
+            &self,
+            request: tonic::Request<super::ReserveBatchRequest>,
+        ) -> Result<tonic::Response<super::ReserveBatchResponse>, tonic::Status>;
+        /// confirm a pending reservation, if reservation is not pending, do nothing. This and the
+        /// other changes of a reservation are left to its user or an admin, of a blocked window to
+        /// an admin
+        async fn confirm(
+            &self,
+            request: tonic::Request<super::ConfirmRequest>,
+        ) -> Result<tonic::Response<super::ConfirmResponse>, tonic::Status>;
+        /// update the fields of a reservation listed in the update mask
+        async fn update(
+            &self,
+            request: tonic::Request<super::UpdateRequest>,
+        ) -> Result<tonic::Response<super::UpdateResponse>, tonic::Status>;
+        /// move or resize a reservation atomically, fails if the new window is taken
+        async fn reschedule(
+            &self,
+            request: tonic::Request<super::RescheduleRequest>,
+        ) -> Result<tonic::Response<super::RescheduleResponse>, tonic::Status>;
+        /// cancel a reservation
+        async fn cancel(
+            &self,
+            request: tonic::Request<super::CancelRequest>,
+        ) -> Result<tonic::Response<super::CancelResponse>, tonic::Status>;
+        /// get a reservation by id
+        async fn get(
+            &self,
+            request: tonic::Request<super::GetRequest>,
+        ) -> Result<tonic::Response<super::GetResponse>, tonic::Status>;
+        /// Server streaming response type for the query method.
+        type queryStream: futures_core::Stream<Item = Result<super::Reservation, tonic::Status>>
+            + Send
+            + 'static;
+        /// query reservations by resource id, user id, status, start time, end time
+        async fn query(
+            &self,
+            request: tonic::Request<super::QueryRequest>,
+        ) -> Result<tonic::Response<Self::queryStream>, tonic::Status>;
+        /// filter reservations, order by reservation id
+        async fn filter(
+            &self,
+            request: tonic::Request<super::FilterRequest>,
+        ) -> Result<tonic::Response<super::FilterResponse>, tonic::Status>;
+        /// Server streaming response type for the listen method.
+        type listenStream: futures_core::Stream<Item = Result<super::ListenResponse, tonic::Status>>
+            + Send
+            + 'static;
+        /// another system could monitor newly added/confirmed/cancelled reservations
+        async fn listen(
+            &self,
+            request: tonic::Request<super::ListenRequest>,
+        ) -> Result<tonic::Response<Self::listenStream>, tonic::Status>;
+        /// block resources for maintenance or blackout periods (admin only)
+        async fn block(
+            &self,
+            request: tonic::Request<super::BlockRequest>,
+        ) -> Result<tonic::Response<super::BlockResponse>, tonic::Status>;
+        /// create or update resource settings (admin only)
+        async fn upsert_resource(
+            &self,
+            request: tonic::Request<super::UpsertResourceRequest>,
+        ) -> Result<tonic::Response<super::UpsertResourceResponse>, tonic::Status>;
+        /// get resource settings
+        async fn get_resource(
+            &self,
+            request: tonic::Request<super::GetResourceRequest>,
+        ) -> Result<tonic::Response<super::GetResourceResponse>, tonic::Status>;
+        /// find free time slots of a resource
+        async fn availability(
+            &self,
+            request: tonic::Request<super::AvailabilityRequest>,
+        ) -> Result<tonic::Response<super::AvailabilityResponse>, tonic::Status>;
+        /// create or update a quota policy (admin only)
+        async fn upsert_quota_policy(
+            &self,
+            request: tonic::Request<super::UpsertQuotaPolicyRequest>,
+        ) -> Result<tonic::Response<super::UpsertQuotaPolicyResponse>, tonic::Status>;
+        /// delete a quota policy (admin only)
+        async fn delete_quota_policy(
+            &self,
+            request: tonic::Request<super::DeleteQuotaPolicyRequest>,
+        ) -> Result<tonic::Response<super::DeleteQuotaPolicyResponse>, tonic::Status>;
+        /// replace the groups a user belongs to (admin only)
+        async fn set_user_groups(
+            &self,
+            request: tonic::Request<super::SetUserGroupsRequest>,
+        ) -> Result<tonic::Response<super::SetUserGroupsResponse>, tonic::Status>;
+        /// get the current usage and remaining quota of a user
+        async fn get_quota(
+            &self,
+            request: tonic::Request<super::GetQuotaRequest>,
+        ) -> Result<tonic::Response<super::GetQuotaResponse>, tonic::Status>;
+        /// get the change history of a reservation
+        async fn get_history(
+            &self,
+            request: tonic::Request<super::GetHistoryRequest>,
+        ) -> Result<tonic::Response<super::GetHistoryResponse>, tonic::Status>;
+        /// query the change history by actor and time range
+        async fn query_history(
+            &self,
+            request: tonic::Request<super::QueryHistoryRequest>,
+        ) -> Result<tonic::Response<super::QueryHistoryResponse>, tonic::Status>;
+        /// create or update a webhook subscription
+        async fn upsert_webhook(
+            &self,
+            request: tonic::Request<super::UpsertWebhookRequest>,
+        ) -> Result<tonic::Response<super::UpsertWebhookResponse>, tonic::Status>;
+        /// delete a webhook subscription and its delivery log
+        async fn delete_webhook(
+            &self,
+            request: tonic::Request<super::DeleteWebhookRequest>,
+        ) -> Result<tonic::Response<super::DeleteWebhookResponse>, tonic::Status>;
+        /// list webhook subscriptions, without secrets
+        async fn list_webhooks(
+            &self,
+            request: tonic::Request<super::ListWebhooksRequest>,
+        ) -> Result<tonic::Response<super::ListWebhooksResponse>, tonic::Status>;
+        /// get the delivery log of a webhook
+        async fn list_webhook_deliveries(
+            &self,
+            request: tonic::Request<super::ListWebhookDeliveriesRequest>,
+        ) -> Result<tonic::Response<super::ListWebhookDeliveriesResponse>, tonic::Status>;
+        /// retry a dead delivery
+        async fn retry_webhook_delivery(
+            &self,
+            request: tonic::Request<super::RetryWebhookDeliveryRequest>,
+        ) -> Result<tonic::Response<super::RetryWebhookDeliveryResponse>, tonic::Status>;
+        /// export reservations as an iCalendar file, those of a user to the user or an admin, any
+        /// others to an admin only
+        async fn export_calendar(
+            &self,
+            request: tonic::Request<super::ExportCalendarRequest>,
+        ) -> Result<tonic::Response<super::ExportCalendarResponse>, tonic::Status>;
+        /// get the calendar feed of a user or a resource
+        async fn get_calendar_feed(
+            &self,
+            request: tonic::Request<super::GetCalendarFeedRequest>,
+        ) -> Result<tonic::Response<super::GetCalendarFeedResponse>, tonic::Status>;
+        /// revoke a calendar feed handed out before
+        async fn revoke_calendar_feed(
+            &self,
+            request: tonic::Request<super::RevokeCalendarFeedRequest>,
+        ) -> Result<tonic::Response<super::RevokeCalendarFeedResponse>, tonic::Status>;
+        /// import reservations or blocked windows from an iCalendar file
+        async fn import_calendar(
+            &self,
+            request: tonic::Request<super::ImportCalendarRequest>,
+        ) -> Result<tonic::Response<super::ImportCalendarResponse>, tonic::Status>;
+    }
+    /// Reservation service
+    #[derive(Debug)]
+    pub struct ReservationServiceServer<T: ReservationService> {
+        inner: _Inner<T>,
+        accept_compression_encodings: EnabledCompressionEncodings,
+        send_compression_encodings: EnabledCompressionEncodings,
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: ReservationService> ReservationServiceServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+        /// Enable decompressing requests with the given encoding.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.accept_compression_encodings.enable(encoding);
+            self
+        }
+        /// Compress responses with the given encoding, if the client supports it.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.send_compression_encodings.enable(encoding);
+            self
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for ReservationServiceServer<T>
+    where
+        T: ReservationService,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/reservation.ReservationService/reserve" => {
+                    #[allow(non_camel_case_types)]
+                    struct reserveSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService> tonic::server::UnaryService<super::ReserveRequest> for reserveSvc<T> {
+                        type Response = super::ReserveResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ReserveRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).reserve(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = reserveSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/reserve_batch" => {
+                    #[allow(non_camel_case_types)]
+                    struct reserve_batchSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::ReserveBatchRequest>
+                        for reserve_batchSvc<T>
+                    {
+                        type Response = super::ReserveBatchResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ReserveBatchRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).reserve_batch(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = reserve_batchSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/confirm" => {
+                    #[allow(non_camel_case_types)]
+                    struct confirmSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService> tonic::server::UnaryService<super::ConfirmRequest> for confirmSvc<T> {
+                        type Response = super::ConfirmResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConfirmRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).confirm(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = confirmSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/update" => {
+                    #[allow(non_camel_case_types)]
+                    struct updateSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService> tonic::server::UnaryService<super::UpdateRequest> for updateSvc<T> {
+                        type Response = super::UpdateResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::UpdateRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).update(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = updateSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/reschedule" => {
+                    #[allow(non_camel_case_types)]
+                    struct rescheduleSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::RescheduleRequest> for rescheduleSvc<T>
+                    {
+                        type Response = super::RescheduleResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RescheduleRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).reschedule(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = rescheduleSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/cancel" => {
+                    #[allow(non_camel_case_types)]
+                    struct cancelSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService> tonic::server::UnaryService<super::CancelRequest> for cancelSvc<T> {
+                        type Response = super::CancelResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CancelRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).cancel(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = cancelSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/get" => {
+                    #[allow(non_camel_case_types)]
+                    struct getSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService> tonic::server::UnaryService<super::GetRequest> for getSvc<T> {
+                        type Response = super::GetResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).get(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = getSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/query" => {
+                    #[allow(non_camel_case_types)]
+                    struct querySvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::ServerStreamingService<super::QueryRequest> for querySvc<T>
+                    {
+                        type Response = super::Reservation;
+                        type ResponseStream = T::queryStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::QueryRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).query(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = querySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/filter" => {
+                    #[allow(non_camel_case_types)]
+                    struct filterSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService> tonic::server::UnaryService<super::FilterRequest> for filterSvc<T> {
+                        type Response = super::FilterResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::FilterRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).filter(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = filterSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/listen" => {
+                    #[allow(non_camel_case_types)]
+                    struct listenSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::ServerStreamingService<super::ListenRequest>
+                        for listenSvc<T>
+                    {
+                        type Response = super::ListenResponse;
+                        type ResponseStream = T::listenStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListenRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).listen(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = listenSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/block" => {
+                    #[allow(non_camel_case_types)]
+                    struct blockSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService> tonic::server::UnaryService<super::BlockRequest> for blockSvc<T> {
+                        type Response = super::BlockResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::BlockRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).block(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = blockSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/upsert_resource" => {
+                    #[allow(non_camel_case_types)]
+                    struct upsert_resourceSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::UpsertResourceRequest>
+                        for upsert_resourceSvc<T>
+                    {
+                        type Response = super::UpsertResourceResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::UpsertResourceRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).upsert_resource(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = upsert_resourceSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/get_resource" => {
+                    #[allow(non_camel_case_types)]
+                    struct get_resourceSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::GetResourceRequest>
+                        for get_resourceSvc<T>
+                    {
+                        type Response = super::GetResourceResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetResourceRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).get_resource(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = get_resourceSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/availability" => {
+                    #[allow(non_camel_case_types)]
+                    struct availabilitySvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::AvailabilityRequest>
+                        for availabilitySvc<T>
+                    {
+                        type Response = super::AvailabilityResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::AvailabilityRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).availability(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = availabilitySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/upsert_quota_policy" => {
+                    #[allow(non_camel_case_types)]
+                    struct upsert_quota_policySvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::UpsertQuotaPolicyRequest>
+                        for upsert_quota_policySvc<T>
+                    {
+                        type Response = super::UpsertQuotaPolicyResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::UpsertQuotaPolicyRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).upsert_quota_policy(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = upsert_quota_policySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/delete_quota_policy" => {
                     #[allow(non_camel_case_types)]
-                    struct reserveSvc<T: ReservationService>(pub Arc<T>);
-                    impl<T: ReservationService> tonic::server::UnaryService<super::ReserveRequest> for reserveSvc<T> {
-                        type Response = super::ReserveResponse;
+                    struct delete_quota_policySvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::DeleteQuotaPolicyRequest>
+                        for delete_quota_policySvc<T>
+                    {
+                        type Response = super::DeleteQuotaPolicyResponse;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::ReserveRequest>,
+                            request: tonic::Request<super::DeleteQuotaPolicyRequest>,
                         ) -> Self::Future {
                             let inner = self.0.clone();
-                            let fut = async move { (*inner).reserve(request).await };
+                            let fut = async move { (*inner).delete_quota_policy(request).await };
                             Box::pin(fut)
                         }
                     }
@@ -572,7 +2249,7 @@ pub mod reservation_service_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = reserveSvc(inner);
+                        let method = delete_quota_policySvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
                             accept_compression_encodings,
@@ -583,18 +2260,21 @@ pub mod reservation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/reservation.ReservationService/confirm" => {
+                "/reservation.ReservationService/set_user_groups" => {
                     #[allow(non_camel_case_types)]
-                    struct confirmSvc<T: ReservationService>(pub Arc<T>);
-                    impl<T: ReservationService> tonic::server::UnaryService<super::ConfirmRequest> for confirmSvc<T> {
-                        type Response = super::ConfirmResponse;
+                    struct set_user_groupsSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::SetUserGroupsRequest>
+                        for set_user_groupsSvc<T>
+                    {
+                        type Response = super::SetUserGroupsResponse;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::ConfirmRequest>,
+                            request: tonic::Request<super::SetUserGroupsRequest>,
                         ) -> Self::Future {
                             let inner = self.0.clone();
-                            let fut = async move { (*inner).confirm(request).await };
+                            let fut = async move { (*inner).set_user_groups(request).await };
                             Box::pin(fut)
                         }
                     }
@@ -603,7 +2283,7 @@ pub mod reservation_service_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = confirmSvc(inner);
+                        let method = set_user_groupsSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
                             accept_compression_encodings,
@@ -614,18 +2294,20 @@ pub mod reservation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/reservation.ReservationService/update" => {
+                "/reservation.ReservationService/get_quota" => {
                     #[allow(non_camel_case_types)]
-                    struct updateSvc<T: ReservationService>(pub Arc<T>);
-                    impl<T: ReservationService> tonic::server::UnaryService<super::UpdateRequest> for updateSvc<T> {
-                        type Response = super::UpdateResponse;
+                    struct get_quotaSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService> tonic::server::UnaryService<super::GetQuotaRequest>
+                        for get_quotaSvc<T>
+                    {
+                        type Response = super::GetQuotaResponse;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::UpdateRequest>,
+                            request: tonic::Request<super::GetQuotaRequest>,
                         ) -> Self::Future {
                             let inner = self.0.clone();
-                            let fut = async move { (*inner).update(request).await };
+                            let fut = async move { (*inner).get_quota(request).await };
                             Box::pin(fut)
                         }
                     }
@@ -634,7 +2316,7 @@ pub mod reservation_service_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = updateSvc(inner);
+                        let method = get_quotaSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
                             accept_compression_encodings,
@@ -645,18 +2327,21 @@ pub mod reservation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/reservation.ReservationService/cancel" => {
+                "/reservation.ReservationService/get_history" => {
                     #[allow(non_camel_case_types)]
-                    struct cancelSvc<T: ReservationService>(pub Arc<T>);
-                    impl<T: ReservationService> tonic::server::UnaryService<super::CancelRequest> for cancelSvc<T> {
-                        type Response = super::CancelResponse;
+                    struct get_historySvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::GetHistoryRequest>
+                        for get_historySvc<T>
+                    {
+                        type Response = super::GetHistoryResponse;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::CancelRequest>,
+                            request: tonic::Request<super::GetHistoryRequest>,
                         ) -> Self::Future {
                             let inner = self.0.clone();
-                            let fut = async move { (*inner).cancel(request).await };
+                            let fut = async move { (*inner).get_history(request).await };
                             Box::pin(fut)
                         }
                     }
@@ -665,7 +2350,7 @@ pub mod reservation_service_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = cancelSvc(inner);
+                        let method = get_historySvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
                             accept_compression_encodings,
@@ -676,18 +2361,21 @@ pub mod reservation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/reservation.ReservationService/get" => {
+                "/reservation.ReservationService/query_history" => {
                     #[allow(non_camel_case_types)]
-                    struct getSvc<T: ReservationService>(pub Arc<T>);
-                    impl<T: ReservationService> tonic::server::UnaryService<super::GetRequest> for getSvc<T> {
-                        type Response = super::GetResponse;
+                    struct query_historySvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::QueryHistoryRequest>
+                        for query_historySvc<T>
+                    {
+                        type Response = super::QueryHistoryResponse;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::GetRequest>,
+                            request: tonic::Request<super::QueryHistoryRequest>,
                         ) -> Self::Future {
                             let inner = self.0.clone();
-                            let fut = async move { (*inner).get(request).await };
+                            let fut = async move { (*inner).query_history(request).await };
                             Box::pin(fut)
                         }
                     }
@@ -696,7 +2384,7 @@ pub mod reservation_service_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = getSvc(inner);
+                        let method = query_historySvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
                             accept_compression_encodings,
@@ -707,22 +2395,21 @@ pub mod reservation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/reservation.ReservationService/query" => {
+                "/reservation.ReservationService/upsert_webhook" => {
                     #[allow(non_camel_case_types)]
-                    struct querySvc<T: ReservationService>(pub Arc<T>);
+                    struct upsert_webhookSvc<T: ReservationService>(pub Arc<T>);
                     impl<T: ReservationService>
-                        tonic::server::ServerStreamingService<super::QueryRequest> for querySvc<T>
+                        tonic::server::UnaryService<super::UpsertWebhookRequest>
+                        for upsert_webhookSvc<T>
                     {
-                        type Response = super::Reservation;
-                        type ResponseStream = T::queryStream;
-                        type Future =
-                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        type Response = super::UpsertWebhookResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::QueryRequest>,
+                            request: tonic::Request<super::UpsertWebhookRequest>,
                         ) -> Self::Future {
                             let inner = self.0.clone();
-                            let fut = async move { (*inner).query(request).await };
+                            let fut = async move { (*inner).upsert_webhook(request).await };
                             Box::pin(fut)
                         }
                     }
@@ -731,29 +2418,32 @@ pub mod reservation_service_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = querySvc(inner);
+                        let method = upsert_webhookSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
                             accept_compression_encodings,
                             send_compression_encodings,
                         );
-                        let res = grpc.server_streaming(method, req).await;
+                        let res = grpc.unary(method, req).await;
                         Ok(res)
                     };
                     Box::pin(fut)
                 }
-                "/reservation.ReservationService/filter" => {
+                "/reservation.ReservationService/delete_webhook" => {
                     #[allow(non_camel_case_types)]
-                    struct filterSvc<T: ReservationService>(pub Arc<T>);
-                    impl<T: ReservationService> tonic::server::UnaryService<super::FilterRequest> for filterSvc<T> {
-                        type Response = super::FilterResponse;
+                    struct delete_webhookSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::DeleteWebhookRequest>
+                        for delete_webhookSvc<T>
+                    {
+                        type Response = super::DeleteWebhookResponse;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::FilterRequest>,
+                            request: tonic::Request<super::DeleteWebhookRequest>,
                         ) -> Self::Future {
                             let inner = self.0.clone();
-                            let fut = async move { (*inner).filter(request).await };
+                            let fut = async move { (*inner).delete_webhook(request).await };
                             Box::pin(fut)
                         }
                     }
@@ -762,7 +2452,7 @@ pub mod reservation_service_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = filterSvc(inner);
+                        let method = delete_webhookSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
                             accept_compression_encodings,
@@ -773,23 +2463,21 @@ pub mod reservation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/reservation.ReservationService/listen" => {
+                "/reservation.ReservationService/list_webhooks" => {
                     #[allow(non_camel_case_types)]
-                    struct listenSvc<T: ReservationService>(pub Arc<T>);
+                    struct list_webhooksSvc<T: ReservationService>(pub Arc<T>);
                     impl<T: ReservationService>
-                        tonic::server::ServerStreamingService<super::ListenRequest>
-                        for listenSvc<T>
+                        tonic::server::UnaryService<super::ListWebhooksRequest>
+                        for list_webhooksSvc<T>
                     {
-                        type Response = super::Reservation;
-                        type ResponseStream = T::listenStream;
-                        type Future =
-                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        type Response = super::ListWebhooksResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::ListenRequest>,
+                            request: tonic::Request<super::ListWebhooksRequest>,
                         ) -> Self::Future {
                             let inner = self.0.clone();
-                            let fut = async move { (*inner).listen(request).await };
+                            let fut = async move { (*inner).list_webhooks(request).await };
                             Box::pin(fut)
                         }
                     }
@@ -798,13 +2486,218 @@ pub mod reservation_service_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = listenSvc(inner);
+                        let method = list_webhooksSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
                             accept_compression_encodings,
                             send_compression_encodings,
                         );
-                        let res = grpc.server_streaming(method, req).await;
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/list_webhook_deliveries" => {
+                    #[allow(non_camel_case_types)]
+                    struct list_webhook_deliveriesSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::ListWebhookDeliveriesRequest>
+                        for list_webhook_deliveriesSvc<T>
+                    {
+                        type Response = super::ListWebhookDeliveriesResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListWebhookDeliveriesRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut =
+                                async move { (*inner).list_webhook_deliveries(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = list_webhook_deliveriesSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/retry_webhook_delivery" => {
+                    #[allow(non_camel_case_types)]
+                    struct retry_webhook_deliverySvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::RetryWebhookDeliveryRequest>
+                        for retry_webhook_deliverySvc<T>
+                    {
+                        type Response = super::RetryWebhookDeliveryResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RetryWebhookDeliveryRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).retry_webhook_delivery(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = retry_webhook_deliverySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/export_calendar" => {
+                    #[allow(non_camel_case_types)]
+                    struct export_calendarSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::ExportCalendarRequest>
+                        for export_calendarSvc<T>
+                    {
+                        type Response = super::ExportCalendarResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ExportCalendarRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).export_calendar(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = export_calendarSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/get_calendar_feed" => {
+                    #[allow(non_camel_case_types)]
+                    struct get_calendar_feedSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::GetCalendarFeedRequest>
+                        for get_calendar_feedSvc<T>
+                    {
+                        type Response = super::GetCalendarFeedResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetCalendarFeedRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).get_calendar_feed(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = get_calendar_feedSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/revoke_calendar_feed" => {
+                    #[allow(non_camel_case_types)]
+                    struct revoke_calendar_feedSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::RevokeCalendarFeedRequest>
+                        for revoke_calendar_feedSvc<T>
+                    {
+                        type Response = super::RevokeCalendarFeedResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RevokeCalendarFeedRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).revoke_calendar_feed(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = revoke_calendar_feedSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/reservation.ReservationService/import_calendar" => {
+                    #[allow(non_camel_case_types)]
+                    struct import_calendarSvc<T: ReservationService>(pub Arc<T>);
+                    impl<T: ReservationService>
+                        tonic::server::UnaryService<super::ImportCalendarRequest>
+                        for import_calendarSvc<T>
+                    {
+                        type Response = super::ImportCalendarResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ImportCalendarRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).import_calendar(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = import_calendarSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
                         Ok(res)
                     };
                     Box::pin(fut)
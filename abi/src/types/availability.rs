@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    convert_to_timestamp, convert_to_utc_timespan, validate_timespan, AvailabilityRequest, Error,
+    Resource, TimeSlot,
+};
+
+impl AvailabilityRequest {
+    /// get the requested (start, end)
+    pub fn get_timespan(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+        if self.resource_id.is_empty() {
+            return Err(Error::InvalidResourceId(self.resource_id.clone()));
+        }
+        let (start, end) = convert_to_utc_timespan(self.start.as_ref(), self.end.as_ref())?;
+        validate_timespan(start, end)?;
+        Ok((start, end))
+    }
+}
+
+impl Resource {
+    /// find free slots between start and end. `busy` are the buffered timespans of existing
+    /// reservations sorted by start. A new reservation fits into a slot only if its own buffers
+    /// don't overlap any busy timespan.
+    pub fn free_slots(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        busy: &[(DateTime<Utc>, DateTime<Utc>)],
+    ) -> Vec<TimeSlot> {
+        let pre = self.get_pre_buffer();
+        let post = self.get_post_buffer();
+
+        let mut slots = Vec::new();
+        let mut cursor = start;
+        for (busy_start, busy_end) in busy {
+            let slot_end = end.min(*busy_start - post);
+            if cursor < slot_end {
+                slots.push(TimeSlot::new(cursor, slot_end));
+            }
+            cursor = cursor.max(*busy_end + pre);
+        }
+        if cursor < end {
+            slots.push(TimeSlot::new(cursor, end));
+        }
+        slots
+    }
+}
+
+impl TimeSlot {
+    pub fn new(start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        TimeSlot {
+            start: Some(convert_to_timestamp(start)),
+            end: Some(convert_to_timestamp(end)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, FixedOffset};
+
+    use super::*;
+
+    #[test]
+    fn free_slots_without_reservations_should_be_the_whole_window() {
+        let resource = Resource::new("room-1", Duration::zero(), Duration::zero());
+        let slots = resource.free_slots(parse("09:00"), parse("17:00"), &[]);
+        assert_eq!(slots, vec![TimeSlot::new(parse("09:00"), parse("17:00"))]);
+    }
+
+    #[test]
+    fn free_slots_should_account_for_buffers() {
+        let resource = Resource::new("room-1", Duration::minutes(5), Duration::minutes(15));
+        // existing reservation 10:00-11:00 stored with its buffers as 09:55-11:15
+        let busy = vec![(parse("09:55"), parse("11:15"))];
+        let slots = resource.free_slots(parse("09:00"), parse("17:00"), &busy);
+        assert_eq!(
+            slots,
+            vec![
+                TimeSlot::new(parse("09:00"), parse("09:40")),
+                TimeSlot::new(parse("11:20"), parse("17:00")),
+            ]
+        );
+    }
+
+    #[test]
+    fn free_slots_should_skip_gaps_smaller_than_buffers() {
+        let resource = Resource::new("room-1", Duration::zero(), Duration::minutes(30));
+        let busy = vec![
+            (parse("09:00"), parse("10:30")),
+            (parse("10:50"), parse("12:30")),
+        ];
+        let slots = resource.free_slots(parse("08:00"), parse("13:00"), &busy);
+        assert_eq!(
+            slots,
+            vec![
+                TimeSlot::new(parse("08:00"), parse("08:30")),
+                TimeSlot::new(parse("12:30"), parse("13:00")),
+            ]
+        );
+    }
+
+    fn parse(time: &str) -> DateTime<Utc> {
+        format!("2023-01-02T{}:00-0700", time)
+            .parse::<DateTime<FixedOffset>>()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+}
@@ -0,0 +1,194 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::postgres::types::PgRange;
+
+use crate::{
+    convert_to_utc_timespan, convert_to_utc_timestamp, validate_timespan, BlockWindow, Error,
+    Recurrence, RecurrenceFrequency,
+};
+
+/// a recurring block can't expand to more windows than this
+const MAX_OCCURRENCES: usize = 1000;
+
+impl BlockWindow {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.resource_ids.is_empty() {
+            return Err(Error::InvalidResourceId("".to_string()));
+        }
+        if let Some(rid) = self.resource_ids.iter().find(|rid| rid.is_empty()) {
+            return Err(Error::InvalidResourceId(rid.clone()));
+        }
+        let (start, end) = convert_to_utc_timespan(self.start.as_ref(), self.end.as_ref())?;
+        validate_timespan(start, end)?;
+        if let Some(recurrence) = self.recurrence.as_ref() {
+            recurrence.validate()?;
+        }
+        Ok(())
+    }
+
+    /// expand the window (and its recurrence if any) into the timespans to block
+    pub fn get_timespans(&self) -> Result<Vec<PgRange<DateTime<Utc>>>, Error> {
+        self.validate()?;
+        let (start, end) = convert_to_utc_timespan(self.start.as_ref(), self.end.as_ref())?;
+
+        let recurrence = match self.recurrence.as_ref() {
+            Some(recurrence) => recurrence,
+            None => return Ok(vec![(start..end).into()]),
+        };
+
+        let step = recurrence.step();
+        let count = match recurrence.count {
+            0 => MAX_OCCURRENCES + 1,
+            n => n as usize,
+        };
+        let until = recurrence
+            .until
+            .clone()
+            .map(convert_to_utc_timestamp)
+            .transpose()?;
+
+        let mut spans = Vec::new();
+        let (mut s, mut e) = (start, end);
+        while spans.len() < count && until.is_none_or(|until| s <= until) {
+            if spans.len() == MAX_OCCURRENCES {
+                return Err(Error::InvalidRecurrence(format!(
+                    "more than {} occurrences",
+                    MAX_OCCURRENCES
+                )));
+            }
+            spans.push((s..e).into());
+            match (s.checked_add_signed(step), e.checked_add_signed(step)) {
+                (Some(next_s), Some(next_e)) => (s, e) = (next_s, next_e),
+                // past any `until`, so only a count can ask for more
+                _ if spans.len() < count && until.is_none() => {
+                    return Err(Error::InvalidRecurrence("out of range".to_string()))
+                }
+                _ => break,
+            }
+        }
+        Ok(spans)
+    }
+}
+
+impl Recurrence {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.frequency() == RecurrenceFrequency::Unknown {
+            return Err(Error::InvalidRecurrence("unknown frequency".to_string()));
+        }
+        if self.count == 0 && self.until.is_none() {
+            return Err(Error::InvalidRecurrence(
+                "either count or until is required".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn step(&self) -> Duration {
+        let interval = self.interval.max(1) as i64;
+        match self.frequency() {
+            RecurrenceFrequency::Weekly => Duration::weeks(interval),
+            _ => Duration::days(interval),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use chrono::FixedOffset;
+
+    use super::*;
+    use crate::convert_to_timestamp;
+
+    #[test]
+    fn single_window_should_expand_to_one_timespan() {
+        let window = make_window(None);
+        let spans = window.get_timespans().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(
+            spans[0].start,
+            Bound::Included(parse("2023-01-02T08:00:00-0700"))
+        );
+        assert_eq!(
+            spans[0].end,
+            Bound::Excluded(parse("2023-01-02T10:00:00-0700"))
+        );
+    }
+
+    #[test]
+    fn recurring_window_should_stop_at_count() {
+        let window = make_window(Some(Recurrence {
+            frequency: RecurrenceFrequency::Weekly as i32,
+            interval: 2,
+            count: 3,
+            until: None,
+        }));
+        let spans = window.get_timespans().unwrap();
+        assert_eq!(spans.len(), 3);
+        assert_eq!(
+            spans[2].start,
+            Bound::Included(parse("2023-01-30T08:00:00-0700"))
+        );
+    }
+
+    #[test]
+    fn recurring_window_should_stop_at_until() {
+        let window = make_window(Some(Recurrence {
+            frequency: RecurrenceFrequency::Daily as i32,
+            interval: 0,
+            count: 0,
+            until: Some(convert_to_timestamp(parse("2023-01-05T08:00:00-0700"))),
+        }));
+        let spans = window.get_timespans().unwrap();
+        assert_eq!(spans.len(), 4);
+        assert_eq!(
+            spans[3].end,
+            Bound::Excluded(parse("2023-01-05T10:00:00-0700"))
+        );
+    }
+
+    #[test]
+    fn unbounded_recurrence_should_be_rejected() {
+        let window = make_window(Some(Recurrence {
+            frequency: RecurrenceFrequency::Daily as i32,
+            interval: 1,
+            count: 0,
+            until: None,
+        }));
+        assert!(matches!(
+            window.get_timespans(),
+            Err(Error::InvalidRecurrence(_))
+        ));
+    }
+
+    #[test]
+    fn too_many_occurrences_should_be_rejected() {
+        let window = make_window(Some(Recurrence {
+            frequency: RecurrenceFrequency::Daily as i32,
+            interval: 1,
+            count: 0,
+            until: Some(convert_to_timestamp(parse("2033-01-01T00:00:00-0700"))),
+        }));
+        assert!(matches!(
+            window.get_timespans(),
+            Err(Error::InvalidRecurrence(_))
+        ));
+    }
+
+    fn parse(s: &str) -> DateTime<Utc> {
+        s.parse::<DateTime<FixedOffset>>()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn make_window(recurrence: Option<Recurrence>) -> BlockWindow {
+        BlockWindow {
+            resource_ids: vec!["room-1".to_string()],
+            start: Some(convert_to_timestamp(parse("2023-01-02T08:00:00-0700"))),
+            end: Some(convert_to_timestamp(parse("2023-01-02T10:00:00-0700"))),
+            reason: "maintenance".to_string(),
+            recurrence,
+            cancel_pending: false,
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use chrono::{DateTime, Utc};
+
+use crate::{convert_to_utc_timestamp, Reservation, ReservationStatus};
+
+// content lines longer than this many octets are folded
+const MAX_LINE_LEN: usize = 75;
+
+/// render the reservations as an iCalendar (RFC 5545) file with one VEVENT each. `now` is the
+/// DTSTAMP of the events
+pub fn to_ics(name: &str, rsvps: &[Reservation], now: DateTime<Utc>) -> String {
+    let stamp = format_time(now);
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//reservation//reservation service//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        format!("X-WR-CALNAME:{}", escape(name)),
+    ];
+    for rsvp in rsvps {
+        rsvp.push_vevent(&stamp, &mut lines);
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold(line) + "\r\n").collect()
+}
+
+impl Reservation {
+    fn push_vevent(&self, stamp: &str, lines: &mut Vec<String>) {
+        let summary = if self.is_blocked() {
+            format!("{} blocked", self.resource_id)
+        } else {
+            format!("{} ({})", self.resource_id, self.user_id)
+        };
+        lines.push("BEGIN:VEVENT".to_string());
+        // the id is stable across updates, calendar apps replace the event instead of adding one
+        lines.push(format!("UID:reservation-{}@rsvp", self.id));
+        lines.push(format!("DTSTAMP:{stamp}"));
+        if let Some(Ok(start)) = self.start.clone().map(convert_to_utc_timestamp) {
+            lines.push(format!("DTSTART:{}", format_time(start)));
+        }
+        if let Some(Ok(end)) = self.end.clone().map(convert_to_utc_timestamp) {
+            lines.push(format!("DTEND:{}", format_time(end)));
+        }
+        // versions start at 1, sequences at 0
+        lines.push(format!("SEQUENCE:{}", (self.version - 1).max(0)));
+        lines.push(format!("SUMMARY:{}", escape(&summary)));
+        lines.push(format!("LOCATION:{}", escape(&self.resource_id)));
+        if !self.note.is_empty() {
+            lines.push(format!("DESCRIPTION:{}", escape(&self.note)));
+        }
+        let status = match ReservationStatus::from_i32(self.status) {
+            Some(ReservationStatus::Pending) => Some("TENTATIVE"),
+            Some(ReservationStatus::Confirmed | ReservationStatus::Blocked) => Some("CONFIRMED"),
+            _ => None,
+        };
+        if let Some(status) = status {
+            lines.push(format!("STATUS:{status}"));
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+}
+
+fn format_time(dt: DateTime<Utc>) -> String {
+    dt.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// escape a TEXT value
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// split a content line into lines of at most 75 octets, continuation lines start with a space.
+/// Multi-byte characters are never split
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > MAX_LINE_LEN {
+            folded.push_str("\r\n ");
+            len = 1;
+        }
+        folded.push(c);
+        len += c.len_utf8();
+    }
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_should_be_rendered_as_vevents() {
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let mut rsvp = Reservation::new_pending("user1", "room-1", start, end, "hello, world");
+        rsvp.id = 42;
+        rsvp.version = 3;
+        rsvp.status = ReservationStatus::Confirmed as i32;
+        let mut block = Reservation::new_blocked("room-1", start, end, "");
+        block.id = 43;
+        block.version = 1;
+        let now = "2099-12-01T00:00:00Z".parse().unwrap();
+
+        let ics = to_ics("user1", &[rsvp, block], now);
+        let lines: Vec<&str> = ics.split_terminator("\r\n").collect();
+        assert_eq!(
+            lines,
+            vec![
+                "BEGIN:VCALENDAR",
+                "VERSION:2.0",
+                "PRODID:-//reservation//reservation service//EN",
+                "CALSCALE:GREGORIAN",
+                "METHOD:PUBLISH",
+                "X-WR-CALNAME:user1",
+                "BEGIN:VEVENT",
+                "UID:reservation-42@rsvp",
+                "DTSTAMP:20991201T000000Z",
+                "DTSTART:20991225T220000Z",
+                "DTEND:20991226T010000Z",
+                "SEQUENCE:2",
+                "SUMMARY:room-1 (user1)",
+                "LOCATION:room-1",
+                "DESCRIPTION:hello\\, world",
+                "STATUS:CONFIRMED",
+                "END:VEVENT",
+                "BEGIN:VEVENT",
+                "UID:reservation-43@rsvp",
+                "DTSTAMP:20991201T000000Z",
+                "DTSTART:20991225T220000Z",
+                "DTEND:20991226T010000Z",
+                "SEQUENCE:0",
+                "SUMMARY:room-1 blocked",
+                "LOCATION:room-1",
+                "STATUS:CONFIRMED",
+                "END:VEVENT",
+                "END:VCALENDAR",
+            ]
+        );
+    }
+
+    #[test]
+    fn text_should_be_escaped() {
+        assert_eq!(escape("a;b,c\\d\r\ne"), "a\\;b\\,c\\\\d\\ne");
+    }
+
+    #[test]
+    fn long_lines_should_be_folded_at_75_octets() {
+        let line = format!("DESCRIPTION:{}", "é".repeat(40));
+        let folded = fold(&line);
+        let lines: Vec<&str> = folded.split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|l| l.len() <= MAX_LINE_LEN));
+        assert!(lines[1].starts_with(' '));
+        assert_eq!(folded.replace("\r\n ", ""), line);
+    }
+}
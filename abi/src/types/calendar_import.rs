@@ -0,0 +1,810 @@
+use std::collections::HashSet;
+
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+
+use crate::{
+    convert_to_utc_timestamp, utils::MAX_DURATION_SECONDS, Error, ImportCalendarRequest,
+    ImportCalendarResult, ImportMode, Reservation,
+};
+
+/// a recurring event can't expand to more occurrences than this
+const MAX_OCCURRENCES: usize = 1000;
+const DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+// days (or months) between periods beyond any date chrono can represent
+const MAX_STEP: i64 = 1 << 28;
+
+impl ImportCalendarRequest {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.resource_id.is_empty() {
+            return Err(Error::InvalidResourceId(self.resource_id.clone()));
+        }
+        if self.mode() == ImportMode::Unknown {
+            return Err(Error::InvalidCalendar("unknown import mode".to_string()));
+        }
+        self.get_timezone()?;
+        Ok(())
+    }
+
+    /// time zone of floating times and all-day events
+    pub fn get_timezone(&self) -> Result<Tz, Error> {
+        if self.timezone.is_empty() {
+            return Ok(Tz::UTC);
+        }
+        self.timezone
+            .parse()
+            .map_err(|_| Error::InvalidCalendar(format!("unknown time zone {}", self.timezone)))
+    }
+
+    /// parse the file into a result per occurrence, with the reservation (or blocked window
+    /// without user id) to create. The UID of the event is kept in the ics_uid label
+    pub fn get_results(&self, now: DateTime<Utc>) -> Result<Vec<ImportCalendarResult>, Error> {
+        self.validate()?;
+        let ics = std::str::from_utf8(&self.ics)
+            .map_err(|_| Error::InvalidCalendar("the file is not UTF-8".to_string()))?;
+        let horizon = match self.until.clone() {
+            Some(until) => convert_to_utc_timestamp(until)?,
+            None => now + Duration::days(365),
+        };
+
+        let occurrences = parse_ics(ics, self.get_timezone()?, horizon)?;
+        let results = occurrences
+            .into_iter()
+            .map(|occurrence| {
+                let rsvp = occurrence.to_reservation(&self.resource_id, &self.user_id);
+                ImportCalendarResult {
+                    uid: occurrence.uid,
+                    reservation: Some(rsvp),
+                    error: String::new(),
+                }
+            })
+            .collect();
+        Ok(results)
+    }
+}
+
+/// an occurrence of a VEVENT
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Occurrence {
+    uid: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    summary: String,
+    description: String,
+}
+
+impl Occurrence {
+    fn to_reservation(&self, rid: &str, user_id: &str) -> Reservation {
+        let note = match (self.summary.is_empty(), self.description.is_empty()) {
+            (_, true) => self.summary.clone(),
+            (true, false) => self.description.clone(),
+            (false, false) => format!("{}\n{}", self.summary, self.description),
+        };
+        let (start, end) = (self.start.into(), self.end.into());
+        let mut rsvp = if user_id.is_empty() {
+            Reservation::new_blocked(rid, start, end, note)
+        } else {
+            Reservation::new_pending(user_id, rid, start, end, note)
+        };
+        rsvp.labels.insert("ics_uid".to_string(), self.uid.clone());
+        rsvp
+    }
+}
+
+/// the occurrences of the VEVENTs in the file. Floating times and dates are in `tz`,
+/// recurrences without COUNT or UNTIL stop at `horizon`
+fn parse_ics(ics: &str, tz: Tz, horizon: DateTime<Utc>) -> Result<Vec<Occurrence>, Error> {
+    let events = parse_components(ics)?
+        .iter()
+        .map(|props| Vevent::new(props, tz))
+        .collect::<Result<Vec<_>, _>>()?;
+    // instances of recurring events moved or cancelled by another VEVENT with the same UID
+    let overridden: HashSet<(&str, DateTime<Utc>)> = events
+        .iter()
+        .filter_map(|e| Some((e.uid.as_str(), e.recurrence_id?)))
+        .collect();
+
+    let mut occurrences = Vec::new();
+    for event in events.iter().filter(|e| !e.cancelled) {
+        let starts = match (&event.rrule, event.recurrence_id) {
+            (Some(rrule), None) => rrule.expand(&event.start, horizon)?,
+            _ => vec![event.start],
+        };
+        // occurrences keep the wall clock duration across DST changes
+        let duration = if event.start.tz == event.end.tz {
+            event.end.local - event.start.local
+        } else {
+            event.end.utc() - event.start.utc()
+        };
+        for start in starts {
+            let end = start.add(duration)?.utc();
+            let start = start.utc();
+            if event.exdates.contains(&start)
+                || (event.recurrence_id.is_none()
+                    && overridden.contains(&(event.uid.as_str(), start)))
+            {
+                continue;
+            }
+            occurrences.push(Occurrence {
+                uid: event.uid.clone(),
+                start,
+                end,
+                summary: event.summary.clone(),
+                description: event.description.clone(),
+            });
+        }
+    }
+    Ok(occurrences)
+}
+
+/// the properties of every VEVENT, properties of nested components like VALARM are dropped
+fn parse_components(ics: &str) -> Result<Vec<Vec<Property>>, Error> {
+    let mut events = Vec::new();
+    let mut calendar = false;
+    let mut current: Option<Vec<Property>> = None;
+    let mut depth = 0;
+    for line in unfold(ics) {
+        let prop = Property::parse(&line)?;
+        let value = prop.value.to_ascii_uppercase();
+        match (prop.name.as_str(), value.as_str(), current.as_mut()) {
+            ("BEGIN", "VCALENDAR", None) => calendar = true,
+            ("BEGIN", "VEVENT", None) => current = Some(Vec::new()),
+            ("BEGIN", _, Some(_)) => depth += 1,
+            ("END", "VEVENT", Some(_)) if depth == 0 => events.extend(current.take()),
+            ("END", _, Some(_)) => depth -= 1,
+            (_, _, Some(props)) if depth == 0 => props.push(prop),
+            _ => {}
+        }
+    }
+    if !calendar {
+        return Err(Error::InvalidCalendar("VCALENDAR is missing".to_string()));
+    }
+    if current.is_some() {
+        return Err(Error::InvalidCalendar("unterminated VEVENT".to_string()));
+    }
+    Ok(events)
+}
+
+/// join folded content lines
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in ics.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (
+            line.strip_prefix(|c| c == ' ' || c == '\t'),
+            lines.last_mut(),
+        ) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ if !line.is_empty() => lines.push(line.to_string()),
+            _ => {}
+        }
+    }
+    lines
+}
+
+/// a content line, "NAME;PARAM=VALUE:value"
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn parse(line: &str) -> Result<Self, Error> {
+        // the value starts at the first colon outside of a quoted parameter value
+        let mut quoted = false;
+        let colon = line.char_indices().find(|(_, c)| {
+            if *c == '"' {
+                quoted = !quoted;
+            }
+            *c == ':' && !quoted
+        });
+        let Some((i, _)) = colon else {
+            return Err(Error::InvalidCalendar(format!("invalid line {line}")));
+        };
+        let mut head = line[..i].split(';');
+        let name = head.next().unwrap_or_default().to_ascii_uppercase();
+        let params = head
+            .filter_map(|param| param.split_once('='))
+            .map(|(k, v)| (k.to_ascii_uppercase(), v.trim_matches('"').to_string()))
+            .collect();
+        Ok(Property {
+            name,
+            params,
+            value: line[i + 1..].to_string(),
+        })
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// the DATE or DATE-TIME values of the property
+    fn moments(&self, tz: Tz) -> Result<Vec<Moment>, Error> {
+        self.value
+            .split(',')
+            .map(|value| Moment::parse(value, self.param("TZID"), tz))
+            .collect()
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::with_capacity(self.value.len());
+        let mut chars = self.value.chars();
+        while let Some(c) = chars.next() {
+            match (c, c == '\\') {
+                (_, true) => match chars.next() {
+                    Some('n' | 'N') => text.push('\n'),
+                    Some(c) => text.push(c),
+                    None => {}
+                },
+                (c, false) => text.push(c),
+            }
+        }
+        text
+    }
+}
+
+/// a DATE or DATE-TIME value, kept in local time so recurrences follow the wall clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Moment {
+    local: NaiveDateTime,
+    tz: Tz,
+    all_day: bool,
+}
+
+impl Moment {
+    fn parse(value: &str, tzid: Option<&str>, tz: Tz) -> Result<Self, Error> {
+        let invalid = || Error::InvalidCalendar(format!("invalid date {value}"));
+        if let Some(utc) = value.strip_suffix('Z') {
+            let local =
+                NaiveDateTime::parse_from_str(utc, DATE_TIME_FORMAT).map_err(|_| invalid())?;
+            return Ok(Moment {
+                local,
+                tz: Tz::UTC,
+                all_day: false,
+            });
+        }
+        if value.len() == 8 {
+            let date = NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| invalid())?;
+            return Ok(Moment {
+                local: date.and_hms_opt(0, 0, 0).unwrap(),
+                tz,
+                all_day: true,
+            });
+        }
+        let tz = match tzid {
+            Some(id) => id
+                .parse()
+                .map_err(|_| Error::InvalidCalendar(format!("unknown time zone {id}")))?,
+            None => tz,
+        };
+        let local =
+            NaiveDateTime::parse_from_str(value, DATE_TIME_FORMAT).map_err(|_| invalid())?;
+        Ok(Moment {
+            local,
+            tz,
+            all_day: false,
+        })
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        match self.tz.from_local_datetime(&self.local) {
+            LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt.with_timezone(&Utc),
+            // skipped by a DST change, read it with the offset from before the change
+            LocalResult::None => self
+                .local
+                .checked_sub_signed(Duration::hours(1))
+                .and_then(|local| self.tz.from_local_datetime(&local).earliest())
+                .and_then(|dt| {
+                    dt.with_timezone(&Utc)
+                        .checked_add_signed(Duration::hours(1))
+                })
+                .unwrap_or_else(|| Utc.from_utc_datetime(&self.local)),
+        }
+    }
+
+    fn add(&self, duration: Duration) -> Result<Self, Error> {
+        let local = self
+            .local
+            .checked_add_signed(duration)
+            .ok_or_else(|| Error::InvalidCalendar(format!("{} is out of range", self.local)))?;
+        Ok(Moment { local, ..*self })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Vevent {
+    uid: String,
+    start: Moment,
+    end: Moment,
+    summary: String,
+    description: String,
+    rrule: Option<Rrule>,
+    exdates: Vec<DateTime<Utc>>,
+    // set on an instance of a recurring event that was moved or cancelled
+    recurrence_id: Option<DateTime<Utc>>,
+    cancelled: bool,
+}
+
+impl Vevent {
+    fn new(props: &[Property], tz: Tz) -> Result<Self, Error> {
+        let get = |name: &str| props.iter().find(|p| p.name == name);
+        let uid = get("UID").map(|p| p.value.clone()).unwrap_or_default();
+        let invalid = |msg: &str| Error::InvalidCalendar(format!("event {uid}: {msg}"));
+        let single = |prop: &Property| -> Result<Moment, Error> {
+            let moments = prop.moments(tz)?;
+            match moments[..] {
+                [moment] => Ok(moment),
+                _ => Err(invalid(&format!("{} must have one value", prop.name))),
+            }
+        };
+
+        let start = single(get("DTSTART").ok_or_else(|| invalid("DTSTART is missing"))?)?;
+        let end = match (get("DTEND"), get("DURATION")) {
+            (Some(end), _) => single(end)?,
+            (None, Some(duration)) => start.add(parse_duration(&duration.value)?)?,
+            (None, None) if start.all_day => start.add(Duration::days(1))?,
+            (None, None) => return Err(invalid("DTEND or DURATION is required")),
+        };
+        if end.utc() <= start.utc() {
+            return Err(invalid("it must end after it starts"));
+        }
+
+        let mut exdates = Vec::new();
+        for prop in props.iter().filter(|p| p.name == "EXDATE") {
+            exdates.extend(prop.moments(tz)?.iter().map(Moment::utc));
+        }
+        Ok(Vevent {
+            start,
+            end,
+            summary: get("SUMMARY").map(Property::text).unwrap_or_default(),
+            description: get("DESCRIPTION").map(Property::text).unwrap_or_default(),
+            rrule: get("RRULE")
+                .map(|p| Rrule::parse(&p.value, &start))
+                .transpose()?,
+            exdates,
+            recurrence_id: get("RECURRENCE-ID")
+                .map(|p| single(p).map(|m| m.utc()))
+                .transpose()?,
+            cancelled: get("STATUS").is_some_and(|p| p.value.eq_ignore_ascii_case("CANCELLED")),
+            uid,
+        })
+    }
+}
+
+/// "[+-]P1W", "P1DT2H30M" etc.
+fn parse_duration(value: &str) -> Result<Duration, Error> {
+    let invalid = || Error::InvalidCalendar(format!("invalid duration {value}"));
+    let (sign, rest) = match value.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    let mut time = false;
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        if c == 'T' && number.is_empty() && !time {
+            time = true;
+            continue;
+        }
+        let n: i64 = number.parse().map_err(|_| invalid())?;
+        number.clear();
+        let unit = match (c, time) {
+            ('W', false) => 7 * 24 * 3600,
+            ('D', false) => 24 * 3600,
+            ('H', true) => 3600,
+            ('M', true) => 60,
+            ('S', true) => 1,
+            _ => return Err(invalid()),
+        };
+        seconds = n
+            .checked_mul(unit)
+            .and_then(|n| seconds.checked_add(n))
+            .filter(|seconds| *seconds <= MAX_DURATION_SECONDS)
+            .ok_or_else(invalid)?;
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::seconds(seconds * sign))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// the supported subset of RRULE: every n days, weeks (on some weekdays), months or years
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rrule {
+    frequency: Frequency,
+    interval: i64,
+    count: Option<usize>,
+    until: Option<DateTime<Utc>>,
+    // DAILY and WEEKLY only, weekdays without ordinals
+    by_day: Vec<Weekday>,
+}
+
+impl Rrule {
+    fn parse(value: &str, start: &Moment) -> Result<Self, Error> {
+        let unsupported = |part: &str| Error::InvalidRecurrence(format!("unsupported {part}"));
+        let invalid = |part: &str| Error::InvalidRecurrence(format!("invalid {part}"));
+
+        let mut rrule = Rrule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+        };
+        let mut frequency = None;
+        for part in value.split(';').filter(|part| !part.is_empty()) {
+            let (name, v) = part.split_once('=').ok_or_else(|| invalid(part))?;
+            match name.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = Some(match v.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => return Err(unsupported(part)),
+                    })
+                }
+                "INTERVAL" => {
+                    rrule.interval = v
+                        .parse()
+                        .ok()
+                        .filter(|i| *i > 0)
+                        .ok_or_else(|| invalid(part))?
+                }
+                "COUNT" => rrule.count = Some(v.parse().map_err(|_| invalid(part))?),
+                "UNTIL" => {
+                    let until = Moment::parse(v, None, start.tz)?;
+                    // a date includes the whole day
+                    let until = match until.all_day {
+                        true => until.add(Duration::days(1) - Duration::seconds(1))?,
+                        false => until,
+                    };
+                    rrule.until = Some(until.utc());
+                }
+                "BYDAY" => {
+                    rrule.by_day = v
+                        .split(',')
+                        .map(|day| parse_weekday(day).ok_or_else(|| unsupported(part)))
+                        .collect::<Result<_, _>>()?
+                }
+                // only as a repetition of DTSTART
+                "BYMONTH" if v == start.local.month().to_string() => {}
+                "BYMONTHDAY" if v == start.local.day().to_string() => {}
+                "WKST" => {}
+                _ => return Err(unsupported(part)),
+            }
+        }
+        rrule.frequency = frequency.ok_or_else(|| invalid("RRULE without FREQ"))?;
+        if !rrule.by_day.is_empty()
+            && matches!(rrule.frequency, Frequency::Monthly | Frequency::Yearly)
+        {
+            return Err(unsupported("BYDAY for monthly or yearly rules"));
+        }
+        Ok(rrule)
+    }
+
+    /// the starts of the occurrences, including the first one. Open-ended rules stop at
+    /// `horizon`
+    fn expand(&self, start: &Moment, horizon: DateTime<Utc>) -> Result<Vec<Moment>, Error> {
+        let until = match (self.until, self.count) {
+            (Some(until), _) => Some(until),
+            (None, Some(_)) => None,
+            (None, None) => Some(horizon),
+        };
+        let count = self.count.unwrap_or(usize::MAX);
+
+        let mut starts = Vec::new();
+        for n in 0.. {
+            // later periods can't be represented
+            let Some((period_start, candidates)) = self.period(start.local, n) else {
+                break;
+            };
+            let period_start = Moment {
+                local: period_start.and_hms_opt(0, 0, 0).unwrap(),
+                ..*start
+            };
+            if until.is_some_and(|until| period_start.utc() > until) {
+                break;
+            }
+            for local in candidates.into_iter().filter(|local| *local >= start.local) {
+                let candidate = Moment { local, ..*start };
+                if starts.len() == count || until.is_some_and(|until| candidate.utc() > until) {
+                    return Ok(starts);
+                }
+                if starts.len() == MAX_OCCURRENCES {
+                    return Err(Error::InvalidRecurrence(format!(
+                        "more than {} occurrences",
+                        MAX_OCCURRENCES
+                    )));
+                }
+                starts.push(candidate);
+            }
+        }
+        Ok(starts)
+    }
+
+    /// the first day of the nth period and the occurrences in it, none if it's out of range.
+    /// Dates that don't exist, like February 30, are skipped
+    fn period(&self, start: NaiveDateTime, n: i64) -> Option<(NaiveDate, Vec<NaiveDateTime>)> {
+        // no period after this many days or months fits into a date
+        let step = n
+            .checked_mul(self.interval)
+            .filter(|step| *step < MAX_STEP)?;
+        let (first, dates) = match self.frequency {
+            Frequency::Daily => {
+                let date = start.date().checked_add_signed(Duration::days(step))?;
+                let matches = self.by_day.is_empty() || self.by_day.contains(&date.weekday());
+                (date, if matches { vec![date] } else { vec![] })
+            }
+            Frequency::Weekly => {
+                let monday = (start.date()
+                    - Duration::days(start.weekday().num_days_from_monday() as i64))
+                .checked_add_signed(Duration::days(step.checked_mul(7)?))?;
+                let mut days = match self.by_day.is_empty() {
+                    true => vec![start.weekday()],
+                    false => self.by_day.clone(),
+                };
+                days.sort_by_key(|day| day.num_days_from_monday());
+                days.dedup();
+                let dates = days
+                    .iter()
+                    .filter_map(|day| {
+                        monday.checked_add_signed(Duration::days(day.num_days_from_monday() as i64))
+                    })
+                    .collect();
+                (monday, dates)
+            }
+            Frequency::Monthly => {
+                let months = start.month0() as i64 + step;
+                let year = i32::try_from(start.year() as i64 + months / 12).ok()?;
+                let month = (months % 12 + 1) as u32;
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let date = NaiveDate::from_ymd_opt(year, month, start.day());
+                (first, date.into_iter().collect())
+            }
+            Frequency::Yearly => {
+                let year = i32::try_from(start.year() as i64 + step).ok()?;
+                let first = NaiveDate::from_ymd_opt(year, 1, 1)?;
+                let date = NaiveDate::from_ymd_opt(year, start.month(), start.day());
+                (first, date.into_iter().collect())
+            }
+        };
+        let time = start.time();
+        Some((first, dates.into_iter().map(|d| d.and_time(time)).collect()))
+    }
+}
+
+fn parse_weekday(day: &str) -> Option<Weekday> {
+    match day.to_ascii_uppercase().as_str() {
+        "MO" => Some(Weekday::Mon),
+        "TU" => Some(Weekday::Tue),
+        "WE" => Some(Weekday::Wed),
+        "TH" => Some(Weekday::Thu),
+        "FR" => Some(Weekday::Fri),
+        "SA" => Some(Weekday::Sat),
+        "SU" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_should_be_parsed_with_their_time_zones() {
+        let ics = calendar(
+            "BEGIN:VEVENT\r\n\
+            UID:standup\r\n\
+            DTSTART;TZID=America/New_York:20990105T090000\r\n\
+            DTEND;TZID=America/New_York:20990105T091500\r\n\
+            SUMMARY:Standup\\, daily\r\n\
+            DESCRIPTION:first line\\nsecond \r\n line\r\n\
+            BEGIN:VALARM\r\n\
+            DESCRIPTION:ignored\r\n\
+            END:VALARM\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:new-year\r\n\
+            DTSTART;VALUE=DATE:20990101\r\n\
+            SUMMARY:New year\r\n\
+            END:VEVENT\r\n",
+        );
+        let events = parse_ics(&ics, Tz::Europe__Berlin, horizon()).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                Occurrence {
+                    uid: "standup".to_string(),
+                    start: utc("2099-01-05T14:00:00Z"),
+                    end: utc("2099-01-05T14:15:00Z"),
+                    summary: "Standup, daily".to_string(),
+                    description: "first line\nsecond line".to_string(),
+                },
+                // all-day events default to a day, in the time zone of the import
+                Occurrence {
+                    uid: "new-year".to_string(),
+                    start: utc("2098-12-31T23:00:00Z"),
+                    end: utc("2099-01-01T23:00:00Z"),
+                    summary: "New year".to_string(),
+                    description: String::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn weekly_rule_should_expand_by_day_without_exdates() {
+        let ics = calendar(
+            "BEGIN:VEVENT\r\n\
+            UID:gym\r\n\
+            DTSTART:20990105T170000Z\r\n\
+            DURATION:PT1H30M\r\n\
+            RRULE:FREQ=WEEKLY;BYDAY=MO,WE;COUNT=4\r\n\
+            EXDATE:20990107T170000Z\r\n\
+            END:VEVENT\r\n",
+        );
+        let events = parse_ics(&ics, Tz::UTC, horizon()).unwrap();
+        let starts: Vec<_> = events.iter().map(|e| e.start).collect();
+        // 2099-01-05 is a Monday. The excluded date still counts
+        assert_eq!(
+            starts,
+            vec![
+                utc("2099-01-05T17:00:00Z"),
+                utc("2099-01-12T17:00:00Z"),
+                utc("2099-01-14T17:00:00Z"),
+            ]
+        );
+        assert_eq!(events[0].end, utc("2099-01-05T18:30:00Z"));
+    }
+
+    #[test]
+    fn recurrences_should_follow_the_wall_clock_across_dst() {
+        let ics = calendar(
+            "BEGIN:VEVENT\r\n\
+            UID:review\r\n\
+            DTSTART;TZID=America/New_York:20990302T090000\r\n\
+            DTEND;TZID=America/New_York:20990302T100000\r\n\
+            RRULE:FREQ=WEEKLY;UNTIL=20990323\r\n\
+            END:VEVENT\r\n",
+        );
+        let events = parse_ics(&ics, Tz::UTC, horizon()).unwrap();
+        assert_eq!(events.len(), 4);
+        // EST before the change, EDT after it
+        assert_eq!(events[0].start, utc("2099-03-02T14:00:00Z"));
+        assert_eq!(events[3].start, utc("2099-03-23T13:00:00Z"));
+        assert_eq!(events[3].end, utc("2099-03-23T14:00:00Z"));
+    }
+
+    #[test]
+    fn open_ended_rule_should_stop_at_the_horizon() {
+        let ics = calendar(
+            "BEGIN:VEVENT\r\n\
+            UID:rent\r\n\
+            DTSTART;VALUE=DATE:20990131\r\n\
+            RRULE:FREQ=MONTHLY\r\n\
+            END:VEVENT\r\n",
+        );
+        let events = parse_ics(&ics, Tz::UTC, utc("2099-06-01T00:00:00Z")).unwrap();
+        let starts: Vec<_> = events.iter().map(|e| e.start).collect();
+        // months without a 31st are skipped
+        assert_eq!(
+            starts,
+            vec![
+                utc("2099-01-31T00:00:00Z"),
+                utc("2099-03-31T00:00:00Z"),
+                utc("2099-05-31T00:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn overridden_instances_should_replace_the_recurring_ones() {
+        let ics = calendar(
+            "BEGIN:VEVENT\r\n\
+            UID:sync\r\n\
+            DTSTART:20990105T100000Z\r\n\
+            DTEND:20990105T110000Z\r\n\
+            RRULE:FREQ=DAILY;COUNT=3\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:sync\r\n\
+            RECURRENCE-ID:20990106T100000Z\r\n\
+            DTSTART:20990106T150000Z\r\n\
+            DTEND:20990106T160000Z\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:sync\r\n\
+            RECURRENCE-ID:20990107T100000Z\r\n\
+            DTSTART:20990107T100000Z\r\n\
+            DTEND:20990107T110000Z\r\n\
+            STATUS:CANCELLED\r\n\
+            END:VEVENT\r\n",
+        );
+        let events = parse_ics(&ics, Tz::UTC, horizon()).unwrap();
+        let starts: Vec<_> = events.iter().map(|e| e.start).collect();
+        assert_eq!(
+            starts,
+            vec![utc("2099-01-05T10:00:00Z"), utc("2099-01-06T15:00:00Z")]
+        );
+    }
+
+    #[test]
+    fn unsupported_rules_should_be_rejected() {
+        for rule in [
+            "FREQ=MONTHLY;BYDAY=1MO",
+            "FREQ=HOURLY",
+            "FREQ=YEARLY;BYSETPOS=1",
+        ] {
+            let ics = calendar(&format!(
+                "BEGIN:VEVENT\r\nUID:x\r\nDTSTART:20990105T100000Z\r\n\
+                DTEND:20990105T110000Z\r\nRRULE:{rule}\r\nEND:VEVENT\r\n"
+            ));
+            assert!(matches!(
+                parse_ics(&ics, Tz::UTC, horizon()),
+                Err(Error::InvalidRecurrence(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn events_should_become_reservations_or_blocked_windows() {
+        let ics = calendar(
+            "BEGIN:VEVENT\r\nUID:holiday\r\nDTSTART;VALUE=DATE:20991225\r\n\
+            SUMMARY:Christmas\r\nEND:VEVENT\r\n",
+        );
+        let mut req = ImportCalendarRequest {
+            ics: ics.into_bytes(),
+            resource_id: "room-1".to_string(),
+            mode: ImportMode::DryRun as i32,
+            ..Default::default()
+        };
+        let results = req.get_results(utc("2099-01-01T00:00:00Z")).unwrap();
+        let block = results[0].reservation.as_ref().unwrap();
+        assert!(block.is_blocked());
+        assert_eq!(block.note, "Christmas");
+        assert_eq!(block.labels["ics_uid"], "holiday");
+
+        req.user_id = "user1".to_string();
+        let results = req.get_results(utc("2099-01-01T00:00:00Z")).unwrap();
+        let rsvp = results[0].reservation.as_ref().unwrap();
+        assert_eq!(rsvp.user_id, "user1");
+        assert!(!rsvp.is_blocked());
+
+        req.mode = ImportMode::Unknown as i32;
+        assert!(req.get_results(Utc::now()).is_err());
+    }
+
+    fn calendar(events: &str) -> String {
+        format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{events}END:VCALENDAR\r\n")
+    }
+
+    fn horizon() -> DateTime<Utc> {
+        utc("2100-01-01T00:00:00Z")
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+}
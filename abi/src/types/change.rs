@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgRow, types::Json, FromRow, Row};
+
+use super::history::Snapshot;
+use crate::{
+    convert_to_timestamp, ListenRequest, ListenResponse, Reservation, ReservationUpdateType,
+    RsvpUpdateType,
+};
+
+impl ListenRequest {
+    /// names of the operations to listen to, unknown ones are ignored
+    pub fn get_ops(&self) -> Vec<String> {
+        op_names(&self.ops)
+    }
+}
+
+pub(crate) fn op_names(ops: &[i32]) -> Vec<String> {
+    ops.iter()
+        .filter_map(|op| ReservationUpdateType::from_i32(*op))
+        .filter(|op| *op != ReservationUpdateType::Unknown)
+        .map(|op| op.to_string())
+        .collect()
+}
+
+impl FromRow<'_, PgRow> for ListenResponse {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let op: RsvpUpdateType = row.try_get("op")?;
+        let committed_at: DateTime<Utc> = row.try_get("committed_at")?;
+        let snapshot: Option<Json<Snapshot>> = row.try_get("reservation")?;
+        let reservation = match snapshot {
+            Some(snapshot) => snapshot.0.into(),
+            // recorded before snapshots were kept
+            None => Reservation {
+                id: row.try_get("reservation_id")?,
+                ..Default::default()
+            },
+        };
+        Ok(ListenResponse {
+            op: ReservationUpdateType::from(op) as i32,
+            reservation: Some(reservation),
+            sequence: row.try_get("id")?,
+            committed_at: Some(convert_to_timestamp(committed_at)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_request_should_ignore_unknown_ops() {
+        let req = ListenRequest {
+            ops: vec![
+                ReservationUpdateType::Create as i32,
+                ReservationUpdateType::Unknown as i32,
+                42,
+                ReservationUpdateType::Delete as i32,
+            ],
+            ..Default::default()
+        };
+        assert_eq!(req.get_ops(), vec!["create", "delete"]);
+    }
+}
@@ -0,0 +1,92 @@
+use std::{collections::BTreeMap, ops::Bound};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{
+    postgres::{types::PgRange, PgRow},
+    types::Json,
+    FromRow, Row,
+};
+
+use crate::{
+    convert_to_timestamp, convert_to_utc_timestamp, Error, HistoryQuery, Reservation,
+    ReservationHistoryEntry, ReservationStatus, ReservationUpdateType, RsvpStatus, RsvpUpdateType,
+};
+
+/// reservation as written by rsvp.reservation_snapshot()
+#[derive(Debug, Deserialize)]
+pub(crate) struct Snapshot {
+    id: i64,
+    user_id: Option<String>,
+    resource_id: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    note: Option<String>,
+    status: RsvpStatus,
+    labels: BTreeMap<String, String>,
+    version: i64,
+}
+
+impl From<Snapshot> for Reservation {
+    fn from(s: Snapshot) -> Self {
+        Reservation {
+            id: s.id,
+            user_id: s.user_id.unwrap_or_default(),
+            status: ReservationStatus::from(s.status) as i32,
+            resource_id: s.resource_id,
+            start: Some(convert_to_timestamp(s.start)),
+            end: Some(convert_to_timestamp(s.end)),
+            note: s.note.unwrap_or_default(),
+            labels: s.labels,
+            version: s.version,
+        }
+    }
+}
+
+impl FromRow<'_, PgRow> for ReservationHistoryEntry {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let snapshot = |name: &str| -> Result<Option<Reservation>, sqlx::Error> {
+            let snapshot: Option<Json<Snapshot>> = row.try_get(name)?;
+            Ok(snapshot.map(|s| s.0.into()))
+        };
+        let op: RsvpUpdateType = row.try_get("op")?;
+        let changed_at: DateTime<Utc> = row.try_get("changed_at")?;
+        Ok(ReservationHistoryEntry {
+            id: row.try_get("id")?,
+            reservation_id: row.try_get("reservation_id")?,
+            op: ReservationUpdateType::from(op) as i32,
+            actor: row
+                .try_get::<Option<String>, _>("actor")?
+                .unwrap_or_default(),
+            changed_at: Some(convert_to_timestamp(changed_at)),
+            before: snapshot("before")?,
+            after: snapshot("after")?,
+        })
+    }
+}
+
+impl From<RsvpUpdateType> for ReservationUpdateType {
+    fn from(op: RsvpUpdateType) -> Self {
+        match op {
+            RsvpUpdateType::Unknown => ReservationUpdateType::Unknown,
+            RsvpUpdateType::Create => ReservationUpdateType::Create,
+            RsvpUpdateType::Update => ReservationUpdateType::Update,
+            RsvpUpdateType::Delete => ReservationUpdateType::Delete,
+        }
+    }
+}
+
+impl HistoryQuery {
+    /// time range of the changes to query, a missing start or end is treated as infinity
+    pub fn get_timespan(&self) -> Result<PgRange<DateTime<Utc>>, Error> {
+        let start = match self.start.as_ref() {
+            Some(start) => Bound::Included(convert_to_utc_timestamp(start.clone())?),
+            None => Bound::Unbounded,
+        };
+        let end = match self.end.as_ref() {
+            Some(end) => Bound::Excluded(convert_to_utc_timestamp(end.clone())?),
+            None => Bound::Unbounded,
+        };
+        Ok(PgRange { start, end })
+    }
+}
@@ -1,2 +1,20 @@
+mod availability;
+mod block_window;
+mod calendar;
+mod calendar_import;
+mod change;
+mod history;
+mod quota;
+mod reschedule;
 mod reservation;
+mod reservation_filter;
+mod reservation_query;
 mod reservation_status;
+mod resource;
+mod update;
+mod webhook;
+
+pub use calendar::to_ics;
+pub use reservation_filter::MAX_PAGE_SIZE;
+pub use update::UpdateField;
+pub use webhook::is_private_address;
@@ -0,0 +1,80 @@
+use std::fmt;
+
+use sqlx::{postgres::PgRow, FromRow, Row};
+
+use crate::{Error, QuotaKind, QuotaPolicy, QuotaUsage, RsvpQuotaKind};
+
+impl QuotaPolicy {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.kind() == QuotaKind::Unknown {
+            return Err(Error::InvalidQuotaPolicy("unknown kind".to_string()));
+        }
+        if self.user_id.is_empty() == self.group_id.is_empty() {
+            return Err(Error::InvalidQuotaPolicy(
+                "exactly one of user_id and group_id is required".to_string(),
+            ));
+        }
+        if self.limit < 0 {
+            return Err(Error::InvalidQuotaPolicy(
+                "limit must not be negative".to_string(),
+            ));
+        }
+        if self.kind() == QuotaKind::MaxFuturePerKind && self.resource_kind.is_empty() {
+            return Err(Error::InvalidQuotaPolicy(
+                "resource_kind is required".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl QuotaUsage {
+    pub fn new(policy: QuotaPolicy, used: f64) -> Self {
+        let remaining = (policy.limit as f64 - used).max(0.0);
+        QuotaUsage {
+            policy: Some(policy),
+            used,
+            remaining,
+        }
+    }
+}
+
+impl FromRow<'_, PgRow> for QuotaPolicy {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let kind: RsvpQuotaKind = row.try_get("kind")?;
+        Ok(QuotaPolicy {
+            id: row.try_get("id")?,
+            kind: QuotaKind::from(kind) as i32,
+            user_id: row
+                .try_get::<Option<String>, _>("user_id")?
+                .unwrap_or_default(),
+            group_id: row
+                .try_get::<Option<String>, _>("group_id")?
+                .unwrap_or_default(),
+            limit: row.try_get("quota_limit")?,
+            resource_kind: row.try_get("resource_kind")?,
+        })
+    }
+}
+
+impl From<RsvpQuotaKind> for QuotaKind {
+    fn from(kind: RsvpQuotaKind) -> Self {
+        match kind {
+            RsvpQuotaKind::Unknown => QuotaKind::Unknown,
+            RsvpQuotaKind::MaxActive => QuotaKind::MaxActive,
+            RsvpQuotaKind::MaxWeeklyHours => QuotaKind::MaxWeeklyHours,
+            RsvpQuotaKind::MaxFuturePerKind => QuotaKind::MaxFuturePerKind,
+        }
+    }
+}
+
+impl fmt::Display for QuotaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaKind::MaxActive => write!(f, "max_active"),
+            QuotaKind::MaxWeeklyHours => write!(f, "max_weekly_hours"),
+            QuotaKind::MaxFuturePerKind => write!(f, "max_future_per_kind"),
+            QuotaKind::Unknown => write!(f, "unknown"),
+        }
+    }
+}
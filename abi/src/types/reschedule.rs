@@ -0,0 +1,12 @@
+use chrono::{DateTime, Utc};
+
+use crate::{convert_to_utc_timespan, validate_timespan, Error, RescheduleRequest};
+
+impl RescheduleRequest {
+    /// get the new (start, end)
+    pub fn get_timespan(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+        let (start, end) = convert_to_utc_timespan(self.start.as_ref(), self.end.as_ref())?;
+        validate_timespan(start, end)?;
+        Ok((start, end))
+    }
+}
@@ -1,14 +1,15 @@
-use std::ops::Bound;
+use std::{collections::BTreeMap, ops::Bound};
 
 use chrono::{DateTime, FixedOffset, Utc};
 use sqlx::{
     postgres::{types::PgRange, PgRow},
+    types::Json,
     FromRow, Row,
 };
 
 use crate::{
-    convert_to_timestamp, convert_to_utc_timestamp, Error, Reservation, ReservationStatus,
-    RsvpStatus,
+    convert_to_timestamp, convert_to_utc_timespan, Error, Reservation, ReservationStatus,
+    RsvpStatus, ViolationCode, Violations,
 };
 
 impl Reservation {
@@ -27,31 +28,71 @@ impl Reservation {
             end: Some(convert_to_timestamp(end.with_timezone(&Utc))),
             note: note.into(),
             status: ReservationStatus::Pending as i32,
+            labels: BTreeMap::new(),
+            version: 0,
         }
     }
 
+    /// a blocked window has no user, the reason is kept as note
+    pub fn new_blocked(
+        rid: impl Into<String>,
+        start: DateTime<FixedOffset>,
+        end: DateTime<FixedOffset>,
+        reason: impl Into<String>,
+    ) -> Self {
+        Reservation {
+            id: 0,
+            user_id: String::new(),
+            resource_id: rid.into(),
+            start: Some(convert_to_timestamp(start.with_timezone(&Utc))),
+            end: Some(convert_to_timestamp(end.with_timezone(&Utc))),
+            note: reason.into(),
+            status: ReservationStatus::Blocked as i32,
+            labels: BTreeMap::new(),
+            version: 0,
+        }
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.status == ReservationStatus::Blocked as i32
+    }
+
+    /// check a new reservation, every invalid field is reported
     pub fn validate(&self) -> Result<(), Error> {
+        let mut v = Violations::default();
         if self.id != 0 {
-            return Err(Error::InvalidUserId(self.id.to_string()));
+            v.add(
+                "id",
+                ViolationCode::NotAllowed,
+                "must be 0 for a new reservation",
+            );
         }
-        if self.resource_id.is_empty() {
-            return Err(Error::InvalidResourceId(self.resource_id.clone()));
+        v.check_status("status", self.status);
+        // only blocked windows have no user
+        if self.is_blocked() && !self.user_id.is_empty() {
+            v.add(
+                "user_id",
+                ViolationCode::NotAllowed,
+                "must be empty for a blocked window",
+            );
+        } else {
+            v.check_id("user_id", &self.user_id, !self.is_blocked());
         }
-        if self.start.is_none() || self.end.is_none() {
-            return Err(Error::InvalidReservation);
-        }
-        let start = convert_to_utc_timestamp(self.start.as_ref().unwrap().clone());
-        let end = convert_to_utc_timestamp(self.end.as_ref().unwrap().clone());
-        if start > end {
-            return Err(Error::InvalidReservation);
-        }
-        Ok(())
+        v.check_id("resource_id", &self.resource_id, true);
+        let start = v.check_timestamp("start", self.start.as_ref(), true);
+        let end = v.check_timestamp("end", self.end.as_ref(), true);
+        v.check_timespan(start, end);
+        v.into_result()
+    }
+
+    /// the (start, end) of the reservation, fails if either is missing or out of range
+    pub fn get_timespan(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+        convert_to_utc_timespan(self.start.as_ref(), self.end.as_ref())
     }
 
-    pub fn get_timestamp(&self) -> PgRange<DateTime<Utc>> {
-        let start = convert_to_utc_timestamp(self.start.as_ref().unwrap().clone());
-        let end = convert_to_utc_timestamp(self.end.as_ref().unwrap().clone());
-        (start..end).into()
+    pub fn get_timestamp(&self) -> Result<PgRange<DateTime<Utc>>, Error> {
+        let (start, end) = self.get_timespan()?;
+        Ok((start..end).into())
     }
 }
 
@@ -59,18 +100,29 @@ impl FromRow<'_, PgRow> for Reservation {
     fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
         let range: PgRange<DateTime<Utc>> = row.try_get("timespan")?;
         let range: NaiveRange<DateTime<Utc>> = range.into();
-        let start = range.start.unwrap();
-        let end = range.end.unwrap();
+        let (start, end) = match (range.start, range.end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Err(sqlx::Error::Decode("timespan must be bounded".into())),
+        };
         let status: RsvpStatus = row.get("status");
 
         Ok(Reservation {
             id: row.try_get("id")?,
-            user_id: row.try_get("user_id")?,
+            // blocked windows have no user
+            user_id: row
+                .try_get::<Option<String>, _>("user_id")?
+                .unwrap_or_default(),
             resource_id: row.try_get("resource_id")?,
             start: Some(convert_to_timestamp(start)),
             end: Some(convert_to_timestamp(end)),
-            note: row.try_get("note")?,
+            note: row
+                .try_get::<Option<String>, _>("note")?
+                .unwrap_or_default(),
             status: ReservationStatus::from(status) as i32,
+            labels: row
+                .try_get::<Json<BTreeMap<String, String>>, _>("labels")?
+                .0,
+            version: row.try_get("version")?,
         })
     }
 }
@@ -93,3 +145,52 @@ impl<T> From<PgRange<T>> for NaiveRange<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_should_report_every_violation() {
+        let rsvp = Reservation {
+            id: 42,
+            resource_id: "r".repeat(65),
+            start: Some(convert_to_timestamp(
+                "2099-12-25T18:00:00Z".parse().unwrap(),
+            )),
+            end: Some(convert_to_timestamp(
+                "2099-12-25T15:00:00Z".parse().unwrap(),
+            )),
+            ..Default::default()
+        };
+        let Err(Error::InvalidRequest(violations)) = rsvp.validate() else {
+            panic!("expected violations");
+        };
+        let fields: Vec<_> = violations
+            .iter()
+            .map(|v| (v.field.as_str(), v.code))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("id", ViolationCode::NotAllowed),
+                ("user_id", ViolationCode::Required),
+                ("resource_id", ViolationCode::TooLong),
+                ("end", ViolationCode::Invalid),
+            ]
+        );
+    }
+
+    #[test]
+    fn blocked_window_should_have_no_user() {
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let mut block = Reservation::new_blocked("room-1", start, end, "maintenance");
+        assert!(block.validate().is_ok());
+        block.user_id = "user1".to_string();
+        assert!(matches!(
+            block.validate(),
+            Err(Error::InvalidRequest(violations)) if violations[0].field == "user_id"
+        ));
+    }
+}
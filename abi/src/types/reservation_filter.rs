@@ -0,0 +1,44 @@
+use crate::{Error, ReservationFilter, ViolationCode, Violations};
+
+/// largest page a filter can ask for
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+impl ReservationFilter {
+    /// empty ids and unknown status match everything, a page size of 0 means the default
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut v = Violations::default();
+        v.check_id("user_id", &self.user_id, false);
+        v.check_id("resource_id", &self.resource_id, false);
+        v.check_status("status", self.status);
+        if self.cursor.is_some_and(|cursor| cursor < 0) {
+            v.add("cursor", ViolationCode::OutOfRange, "must not be negative");
+        }
+        if !(0..=MAX_PAGE_SIZE).contains(&self.page_size) {
+            let message = format!("must be between 0 and {}", MAX_PAGE_SIZE);
+            v.add("page_size", ViolationCode::OutOfRange, message);
+        }
+        v.into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_should_report_every_violation() {
+        let filter = ReservationFilter {
+            user_id: "u".repeat(65),
+            status: 9,
+            cursor: Some(-1),
+            page_size: 1000,
+            ..Default::default()
+        };
+        let Err(Error::InvalidRequest(violations)) = filter.validate() else {
+            panic!("expected violations");
+        };
+        let fields: Vec<_> = violations.iter().map(|v| v.field.as_str()).collect();
+        assert_eq!(fields, vec!["user_id", "status", "cursor", "page_size"]);
+        assert!(ReservationFilter::default().validate().is_ok());
+    }
+}
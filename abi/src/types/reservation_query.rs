@@ -0,0 +1,37 @@
+use std::ops::Bound;
+
+use chrono::{DateTime, Utc};
+use sqlx::postgres::types::PgRange;
+
+use crate::{convert_to_utc_timestamp, Error, ReservationQuery, ViolationCode, Violations};
+
+impl ReservationQuery {
+    /// empty ids, unknown status and missing start or end match everything
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut v = Violations::default();
+        v.check_id("user_id", &self.user_id, false);
+        v.check_id("resource_id", &self.resource_id, false);
+        v.check_status("status", self.status);
+        let start = v.check_timestamp("start", self.start.as_ref(), false);
+        let end = v.check_timestamp("end", self.end.as_ref(), false);
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                v.add("end", ViolationCode::Invalid, "must not be before start");
+            }
+        }
+        v.into_result()
+    }
+
+    /// timespan to query, a missing start or end is treated as infinity
+    pub fn get_timespan(&self) -> Result<PgRange<DateTime<Utc>>, Error> {
+        let start = match self.start.as_ref() {
+            Some(start) => Bound::Included(convert_to_utc_timestamp(start.clone())?),
+            None => Bound::Unbounded,
+        };
+        let end = match self.end.as_ref() {
+            Some(end) => Bound::Excluded(convert_to_utc_timestamp(end.clone())?),
+            None => Bound::Unbounded,
+        };
+        Ok(PgRange { start, end })
+    }
+}
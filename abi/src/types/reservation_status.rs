@@ -1,5 +1,5 @@
-use crate::{ReservationStatus, RsvpStatus};
-use std::fmt;
+use crate::{Error, ReservationStatus, ReservationUpdateType, RsvpStatus};
+use std::{fmt, str::FromStr};
 
 impl From<RsvpStatus> for ReservationStatus {
     fn from(status: RsvpStatus) -> Self {
@@ -22,3 +22,27 @@ impl fmt::Display for ReservationStatus {
         }
     }
 }
+
+impl FromStr for ReservationStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ReservationStatus::Pending),
+            "blocked" => Ok(ReservationStatus::Blocked),
+            "confirmed" => Ok(ReservationStatus::Confirmed),
+            _ => Err(Error::InvalidStatus(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ReservationUpdateType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReservationUpdateType::Create => write!(f, "create"),
+            ReservationUpdateType::Update => write!(f, "update"),
+            ReservationUpdateType::Delete => write!(f, "delete"),
+            ReservationUpdateType::Unknown => write!(f, "unknown"),
+        }
+    }
+}
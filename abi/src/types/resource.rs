@@ -0,0 +1,254 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{
+    postgres::{types::PgInterval, PgRow},
+    FromRow, Row,
+};
+
+use crate::{convert_to_duration, convert_to_pb_duration, Error, Resource};
+
+impl Resource {
+    pub fn new(id: impl Into<String>, pre_buffer: Duration, post_buffer: Duration) -> Self {
+        Resource {
+            id: id.into(),
+            pre_buffer: Some(convert_to_pb_duration(pre_buffer)),
+            post_buffer: Some(convert_to_pb_duration(post_buffer)),
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.id.is_empty() {
+            return Err(Error::InvalidResourceId(self.id.clone()));
+        }
+        // every duration is stored as an INTERVAL, which has whole microseconds and ends at
+        // about 292 years
+        let durations = [
+            &self.pre_buffer,
+            &self.post_buffer,
+            &self.min_duration,
+            &self.max_duration,
+            &self.booking_horizon,
+            &self.lead_time,
+        ];
+        for duration in durations.into_iter().flatten() {
+            let duration = convert_to_duration(duration.clone())?;
+            PgInterval::try_from(duration).map_err(|e| Error::InvalidDuration(e.to_string()))?;
+        }
+        if self.get_pre_buffer() < Duration::zero() || self.get_post_buffer() < Duration::zero() {
+            return Err(Error::InvalidBuffer);
+        }
+        let rules = [
+            self.get_min_duration(),
+            self.get_max_duration(),
+            self.get_booking_horizon(),
+            self.get_lead_time(),
+        ];
+        if rules.iter().flatten().any(|d| *d < Duration::zero()) {
+            return Err(Error::InvalidBookingRule);
+        }
+        Ok(())
+    }
+
+    // the getters skip durations out of range, `validate` rejects them
+
+    pub fn get_pre_buffer(&self) -> Duration {
+        get_duration(&self.pre_buffer).unwrap_or_else(Duration::zero)
+    }
+
+    pub fn get_post_buffer(&self) -> Duration {
+        get_duration(&self.post_buffer).unwrap_or_else(Duration::zero)
+    }
+
+    pub fn get_min_duration(&self) -> Option<Duration> {
+        get_duration(&self.min_duration)
+    }
+
+    pub fn get_max_duration(&self) -> Option<Duration> {
+        get_duration(&self.max_duration)
+    }
+
+    pub fn get_booking_horizon(&self) -> Option<Duration> {
+        get_duration(&self.booking_horizon)
+    }
+
+    pub fn get_lead_time(&self) -> Option<Duration> {
+        get_duration(&self.lead_time)
+    }
+
+    /// check a reservation from start to end made at `now` against the booking rules
+    pub fn check_booking_rules(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        if start < now {
+            return Err(Error::BookingRuleViolation(
+                "past",
+                "reservation can't start in the past".to_string(),
+            ));
+        }
+        self.check_booking_rules_allowing_past(start, end, now)
+    }
+
+    /// check moving a reservation that starts at `from` to start..end. One that keeps its start
+    /// may have started already, it can still be moved or extended
+    pub fn check_booking_rules_for_move(
+        &self,
+        from: DateTime<Utc>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        if start == from {
+            self.check_booking_rules_allowing_past(start, end, now)
+        } else {
+            self.check_booking_rules(start, end, now)
+        }
+    }
+
+    /// the booking rules except the one against starting in the past, for reservations that
+    /// already started or are imported from the past
+    pub fn check_booking_rules_allowing_past(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        if let Some(lead_time) = self.get_lead_time() {
+            if start < now + lead_time {
+                return Err(Error::BookingRuleViolation(
+                    "lead_time",
+                    format!("reservation must start at least {} from now", lead_time),
+                ));
+            }
+        }
+        if let Some(horizon) = self.get_booking_horizon() {
+            if start > now + horizon {
+                return Err(Error::BookingRuleViolation(
+                    "booking_horizon",
+                    format!("reservation must start within {} from now", horizon),
+                ));
+            }
+        }
+        let duration = end - start;
+        if let Some(min) = self.get_min_duration() {
+            if duration < min {
+                return Err(Error::BookingRuleViolation(
+                    "min_duration",
+                    format!("reservation must last at least {}", min),
+                ));
+            }
+        }
+        if let Some(max) = self.get_max_duration() {
+            if duration > max {
+                return Err(Error::BookingRuleViolation(
+                    "max_duration",
+                    format!("reservation must last at most {}", max),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromRow<'_, PgRow> for Resource {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let get = |name: &str| -> Result<Option<prost_types::Duration>, sqlx::Error> {
+            let interval: Option<PgInterval> = row.try_get(name)?;
+            Ok(interval.map(|i| convert_to_pb_duration(interval_to_duration(i))))
+        };
+        Ok(Resource {
+            id: row.try_get("id")?,
+            pre_buffer: get("pre_buffer")?,
+            post_buffer: get("post_buffer")?,
+            min_duration: get("min_duration")?,
+            max_duration: get("max_duration")?,
+            booking_horizon: get("booking_horizon")?,
+            lead_time: get("lead_time")?,
+            kind: row.try_get("kind")?,
+        })
+    }
+}
+
+fn get_duration(duration: &Option<prost_types::Duration>) -> Option<Duration> {
+    convert_to_duration(duration.clone()?).ok()
+}
+
+/// durations are written as plain microseconds, months are only counted as 30 days to be safe
+fn interval_to_duration(interval: PgInterval) -> Duration {
+    Duration::days(interval.months as i64 * 30 + interval.days as i64)
+        + Duration::microseconds(interval.microseconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::FixedOffset;
+
+    use super::*;
+
+    #[test]
+    fn reservation_in_the_past_should_be_rejected() {
+        let resource = Resource::new("room-1", Duration::zero(), Duration::zero());
+        let now = parse("2023-01-02T09:00:00-0700");
+        let err = resource
+            .check_booking_rules(now - Duration::hours(1), now, now)
+            .unwrap_err();
+        assert!(matches!(err, Error::BookingRuleViolation("past", _)));
+        assert!(resource
+            .check_booking_rules_allowing_past(now - Duration::hours(1), now, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn booking_rules_should_be_checked() {
+        let resource = Resource {
+            min_duration: Some(convert_to_pb_duration(Duration::minutes(30))),
+            max_duration: Some(convert_to_pb_duration(Duration::hours(4))),
+            booking_horizon: Some(convert_to_pb_duration(Duration::days(14))),
+            lead_time: Some(convert_to_pb_duration(Duration::hours(1))),
+            ..Resource::new("room-1", Duration::zero(), Duration::zero())
+        };
+        let now = parse("2023-01-02T09:00:00-0700");
+        let check = |start: Duration, duration: Duration| {
+            resource.check_booking_rules(now + start, now + start + duration, now)
+        };
+
+        assert!(check(Duration::hours(1), Duration::minutes(30)).is_ok());
+        assert!(check(Duration::days(14), Duration::hours(4)).is_ok());
+        assert!(matches!(
+            check(Duration::minutes(59), Duration::hours(1)),
+            Err(Error::BookingRuleViolation("lead_time", _))
+        ));
+        assert!(matches!(
+            check(Duration::days(15), Duration::hours(1)),
+            Err(Error::BookingRuleViolation("booking_horizon", _))
+        ));
+        assert!(matches!(
+            check(Duration::hours(2), Duration::minutes(29)),
+            Err(Error::BookingRuleViolation("min_duration", _))
+        ));
+        assert!(matches!(
+            check(Duration::hours(2), Duration::hours(5)),
+            Err(Error::BookingRuleViolation("max_duration", _))
+        ));
+    }
+
+    #[test]
+    fn negative_booking_rule_should_be_invalid() {
+        let resource = Resource {
+            lead_time: Some(convert_to_pb_duration(-Duration::hours(1))),
+            ..Resource::new("room-1", Duration::zero(), Duration::zero())
+        };
+        assert!(matches!(
+            resource.validate(),
+            Err(Error::InvalidBookingRule)
+        ));
+    }
+
+    fn parse(s: &str) -> DateTime<Utc> {
+        s.parse::<DateTime<FixedOffset>>()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+}
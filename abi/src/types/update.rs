@@ -0,0 +1,181 @@
+use std::str::FromStr;
+
+use prost_types::FieldMask;
+
+use crate::{Error, UpdateRequest, ViolationCode, Violations};
+
+/// fields of a reservation that can be changed by an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateField {
+    Note,
+    Start,
+    End,
+    UserId,
+    Labels,
+}
+
+impl UpdateField {
+    /// parse the paths of an update mask, unknown or immutable paths are rejected
+    pub fn parse_mask(mask: &FieldMask) -> Result<Vec<UpdateField>, Error> {
+        if mask.paths.is_empty() {
+            return Err(Error::EmptyUpdateMask);
+        }
+        let mut fields = Vec::with_capacity(mask.paths.len());
+        for path in &mask.paths {
+            let field = path.parse()?;
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        Ok(fields)
+    }
+}
+
+impl UpdateRequest {
+    /// check the reservation id, the mask and the values of the masked fields
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut v = Violations::default();
+        let mut fields = Vec::new();
+        match self.update_mask.as_ref() {
+            Some(mask) if !mask.paths.is_empty() => {
+                for (i, path) in mask.paths.iter().enumerate() {
+                    match path.parse::<UpdateField>() {
+                        Ok(field) => fields.push(field),
+                        Err(e) => {
+                            let field = format!("update_mask.paths[{}]", i);
+                            v.add(&field, ViolationCode::NotAllowed, e.to_string());
+                        }
+                    }
+                }
+            }
+            _ => v.add(
+                "update_mask",
+                ViolationCode::Required,
+                "must list the fields to update",
+            ),
+        }
+
+        let Some(rsvp) = self.reservation.as_ref() else {
+            v.add("reservation", ViolationCode::Required, "must be set");
+            return v.into_result();
+        };
+        v.nested("reservation", |v| {
+            if rsvp.id <= 0 {
+                v.add(
+                    "id",
+                    ViolationCode::Required,
+                    "must be the id of a reservation",
+                );
+            }
+            if rsvp.version < 0 {
+                v.add("version", ViolationCode::OutOfRange, "must not be negative");
+            }
+            if fields.contains(&UpdateField::UserId) {
+                v.check_id("user_id", &rsvp.user_id, true);
+            }
+            // a time that isn't masked is kept, so it's checked against the current one later
+            let start = fields.contains(&UpdateField::Start);
+            let end = fields.contains(&UpdateField::End);
+            let start = start.then(|| v.check_timestamp("start", rsvp.start.as_ref(), true));
+            let end = end.then(|| v.check_timestamp("end", rsvp.end.as_ref(), true));
+            v.check_timespan(start.flatten(), end.flatten());
+        });
+        v.into_result()
+    }
+}
+
+impl FromStr for UpdateField {
+    type Err = Error;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        match path {
+            "note" => Ok(UpdateField::Note),
+            "start" => Ok(UpdateField::Start),
+            "end" => Ok(UpdateField::End),
+            "user_id" => Ok(UpdateField::UserId),
+            "labels" => Ok(UpdateField::Labels),
+            // status changes go through confirm, resource changes through reschedule
+            "id" | "status" | "resource_id" => Err(Error::ImmutableField(path.to_string())),
+            _ => Err(Error::UnknownField(path.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Reservation;
+
+    #[test]
+    fn parse_mask_should_work() {
+        let mask = FieldMask {
+            paths: vec!["note".into(), "labels".into(), "note".into()],
+        };
+        assert_eq!(
+            UpdateField::parse_mask(&mask).unwrap(),
+            vec![UpdateField::Note, UpdateField::Labels]
+        );
+    }
+
+    #[test]
+    fn parse_mask_should_reject_bad_paths() {
+        let mask = |path: &str| FieldMask {
+            paths: vec!["note".into(), path.into()],
+        };
+        assert!(matches!(
+            UpdateField::parse_mask(&mask("status")),
+            Err(Error::ImmutableField(path)) if path == "status"
+        ));
+        assert!(matches!(
+            UpdateField::parse_mask(&mask("notes")),
+            Err(Error::UnknownField(path)) if path == "notes"
+        ));
+        assert!(matches!(
+            UpdateField::parse_mask(&FieldMask::default()),
+            Err(Error::EmptyUpdateMask)
+        ));
+    }
+
+    #[test]
+    fn update_request_should_report_every_violation() {
+        let req = UpdateRequest {
+            reservation: Some(Reservation {
+                start: Some(prost_types::Timestamp {
+                    seconds: i64::MAX,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            }),
+            update_mask: Some(FieldMask {
+                paths: vec!["start".into(), "status".into(), "user_id".into()],
+            }),
+            ..Default::default()
+        };
+        let Err(Error::InvalidRequest(violations)) = req.validate() else {
+            panic!("expected violations");
+        };
+        let fields: Vec<_> = violations.iter().map(|v| v.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "update_mask.paths[1]",
+                "reservation.id",
+                "reservation.user_id",
+                "reservation.start"
+            ]
+        );
+
+        let req = UpdateRequest {
+            reservation: Some(Reservation {
+                id: 1,
+                note: "new note".into(),
+                ..Default::default()
+            }),
+            update_mask: Some(FieldMask {
+                paths: vec!["note".into()],
+            }),
+            ..Default::default()
+        };
+        assert!(req.validate().is_ok());
+    }
+}
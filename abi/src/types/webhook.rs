@@ -0,0 +1,230 @@
+use std::{fmt, net::IpAddr};
+
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgRow, FromRow, Row};
+
+use super::change::op_names;
+use crate::{
+    convert_to_timestamp, Error, ReservationStatus, ReservationUpdateType, RsvpStatus,
+    RsvpUpdateType, RsvpWebhookDeliveryStatus, Webhook, WebhookDelivery, WebhookDeliveryStatus,
+};
+
+impl Webhook {
+    /// the url must not point at loopback, link-local or private hosts unless
+    /// `allow_private_targets` is set
+    pub fn validate(&self, allow_private_targets: bool) -> Result<(), Error> {
+        let Some(rest) = self
+            .url
+            .strip_prefix("http://")
+            .or_else(|| self.url.strip_prefix("https://"))
+        else {
+            return Err(Error::InvalidWebhook(
+                "url must be http or https".to_string(),
+            ));
+        };
+        let Some(host) = url_host(rest) else {
+            return Err(Error::InvalidWebhook("url must have a host".to_string()));
+        };
+        if !allow_private_targets && is_private_host(host) {
+            return Err(Error::InvalidWebhook(
+                "url must not point at a private address".to_string(),
+            ));
+        }
+        if self.id == 0 && self.secret.is_empty() {
+            return Err(Error::InvalidWebhook("secret is required".to_string()));
+        }
+        Ok(())
+    }
+
+    /// names of the operations to deliver, unknown ones are ignored
+    pub fn get_ops(&self) -> Vec<String> {
+        op_names(&self.ops)
+    }
+}
+
+/// loopback, link-local, private and the other addresses that aren't on the internet
+pub fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0
+                // shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_address(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7, and link-local, fe80::/10
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+// the host of "authority/path?query", IPv6 addresses without their brackets
+fn url_host(rest: &str) -> Option<&str> {
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit('@').next()?;
+    let host = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    (!host.is_empty()).then_some(host)
+}
+
+// names are only caught if they're localhost, the dispatcher checks what they resolve to
+fn is_private_host(host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_private_address(ip),
+        Err(_) => host == "localhost" || host.ends_with(".localhost"),
+    }
+}
+
+impl FromRow<'_, PgRow> for Webhook {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let status: RsvpStatus = row.try_get("status")?;
+        let ops: Vec<RsvpUpdateType> = row.try_get("ops")?;
+        Ok(Webhook {
+            id: row.try_get("id")?,
+            url: row.try_get("url")?,
+            // secrets are write only
+            secret: String::new(),
+            resource_ids: row.try_get("resource_ids")?,
+            user_id: row.try_get("user_id")?,
+            status: ReservationStatus::from(status) as i32,
+            ops: ops
+                .into_iter()
+                .map(|op| ReservationUpdateType::from(op) as i32)
+                .collect(),
+        })
+    }
+}
+
+impl FromRow<'_, PgRow> for WebhookDelivery {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let status: RsvpWebhookDeliveryStatus = row.try_get("status")?;
+        let timestamp = |name: &str| -> Result<Option<prost_types::Timestamp>, sqlx::Error> {
+            let at: Option<DateTime<Utc>> = row.try_get(name)?;
+            Ok(at.map(convert_to_timestamp))
+        };
+        Ok(WebhookDelivery {
+            id: row.try_get("id")?,
+            webhook_id: row.try_get("webhook_id")?,
+            sequence: row.try_get("change_id")?,
+            status: WebhookDeliveryStatus::from(status) as i32,
+            attempts: row.try_get("attempts")?,
+            last_error: row
+                .try_get::<Option<String>, _>("last_error")?
+                .unwrap_or_default(),
+            response_status: row
+                .try_get::<Option<i32>, _>("response_status")?
+                .unwrap_or_default(),
+            created_at: timestamp("created_at")?,
+            next_attempt_at: timestamp("next_attempt_at")?,
+            delivered_at: timestamp("delivered_at")?,
+        })
+    }
+}
+
+impl From<RsvpWebhookDeliveryStatus> for WebhookDeliveryStatus {
+    fn from(status: RsvpWebhookDeliveryStatus) -> Self {
+        match status {
+            RsvpWebhookDeliveryStatus::Unknown => WebhookDeliveryStatus::Unknown,
+            RsvpWebhookDeliveryStatus::Pending => WebhookDeliveryStatus::Pending,
+            RsvpWebhookDeliveryStatus::Delivered => WebhookDeliveryStatus::Delivered,
+            RsvpWebhookDeliveryStatus::Dead => WebhookDeliveryStatus::Dead,
+        }
+    }
+}
+
+impl fmt::Display for WebhookDeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebhookDeliveryStatus::Pending => write!(f, "pending"),
+            WebhookDeliveryStatus::Delivered => write!(f, "delivered"),
+            WebhookDeliveryStatus::Dead => write!(f, "dead"),
+            WebhookDeliveryStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_should_need_http_url_and_secret() {
+        let webhook = Webhook {
+            url: "https://example.com/hook".to_string(),
+            secret: "s3cret".to_string(),
+            ..Default::default()
+        };
+        assert!(webhook.validate(false).is_ok());
+
+        let ftp = Webhook {
+            url: "ftp://example.com/hook".to_string(),
+            ..webhook.clone()
+        };
+        assert!(matches!(ftp.validate(false), Err(Error::InvalidWebhook(_))));
+        let no_secret = Webhook {
+            secret: String::new(),
+            ..webhook.clone()
+        };
+        assert!(matches!(
+            no_secret.validate(false),
+            Err(Error::InvalidWebhook(_))
+        ));
+        // an update keeps the old secret
+        let update = Webhook { id: 1, ..no_secret };
+        assert!(update.validate(false).is_ok());
+    }
+
+    #[test]
+    fn webhook_should_not_target_private_addresses_unless_allowed() {
+        let webhook = |url: &str| Webhook {
+            url: url.to_string(),
+            secret: "s3cret".to_string(),
+            ..Default::default()
+        };
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "https://api.localhost./hook",
+            "http://user@10.1.2.3/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://192.168.0.1?x=1",
+            "http://100.64.0.1/hook",
+            "http://[::1]:8080/hook",
+            "http://[fe80::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+            "http://0.0.0.0/hook",
+        ] {
+            let webhook = webhook(url);
+            assert!(
+                matches!(webhook.validate(false), Err(Error::InvalidWebhook(_))),
+                "{url}"
+            );
+            assert!(webhook.validate(true).is_ok(), "{url}");
+        }
+        assert!(webhook("https://93.184.216.34/hook")
+            .validate(false)
+            .is_ok());
+        assert!(webhook("https://[2606:2800:220:1::]/hook")
+            .validate(false)
+            .is_ok());
+        assert!(webhook("http:///hook").validate(true).is_err());
+    }
+}
@@ -1,11 +1,40 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use prost_types::Timestamp;
 
-pub fn convert_to_utc_timestamp(ts: Timestamp) -> DateTime<Utc> {
-    DateTime::<Utc>::from_utc(
-        NaiveDateTime::from_timestamp_opt(ts.seconds, ts.nanos as _).unwrap(),
-        Utc,
-    )
+use crate::Error;
+
+// the range of google.protobuf.Timestamp, 0001-01-01T00:00:00Z to 9999-12-31T23:59:59Z
+pub(crate) const MIN_TIMESTAMP_SECONDS: i64 = -62_135_596_800;
+pub(crate) const MAX_TIMESTAMP_SECONDS: i64 = 253_402_300_799;
+// the range of google.protobuf.Duration, about 10,000 years either way
+pub(crate) const MAX_DURATION_SECONDS: i64 = 315_576_000_000;
+pub(crate) const NANOS_PER_SECOND: i32 = 1_000_000_000;
+
+/// fails for timestamps outside the range protobuf allows or with nanos outside [0, 1e9)
+pub fn convert_to_utc_timestamp(ts: Timestamp) -> Result<DateTime<Utc>, Error> {
+    let invalid =
+        || Error::InvalidTimestamp(format!("(seconds: {}, nanos: {})", ts.seconds, ts.nanos));
+    if !(MIN_TIMESTAMP_SECONDS..=MAX_TIMESTAMP_SECONDS).contains(&ts.seconds)
+        || !(0..NANOS_PER_SECOND).contains(&ts.nanos)
+    {
+        return Err(invalid());
+    }
+    let dt = NaiveDateTime::from_timestamp_opt(ts.seconds, ts.nanos as u32).ok_or_else(invalid)?;
+    Ok(DateTime::<Utc>::from_utc(dt, Utc))
+}
+
+/// the (start, end) of a timespan, both of them are required
+pub fn convert_to_utc_timespan(
+    start: Option<&Timestamp>,
+    end: Option<&Timestamp>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+    match (start, end) {
+        (Some(start), Some(end)) => Ok((
+            convert_to_utc_timestamp(start.clone())?,
+            convert_to_utc_timestamp(end.clone())?,
+        )),
+        _ => Err(Error::InvalidReservation),
+    }
 }
 
 pub fn convert_to_timestamp(dt: DateTime<Utc>) -> Timestamp {
@@ -14,3 +43,78 @@ pub fn convert_to_timestamp(dt: DateTime<Utc>) -> Timestamp {
         nanos: dt.timestamp_subsec_nanos() as _,
     }
 }
+
+/// fails for durations outside the range protobuf allows or with nanos of the other sign
+pub fn convert_to_duration(d: prost_types::Duration) -> Result<Duration, Error> {
+    if d.seconds.unsigned_abs() > MAX_DURATION_SECONDS as u64
+        || d.nanos.unsigned_abs() >= NANOS_PER_SECOND as u32
+        || (d.seconds > 0 && d.nanos < 0)
+        || (d.seconds < 0 && d.nanos > 0)
+    {
+        return Err(Error::InvalidDuration(format!(
+            "(seconds: {}, nanos: {})",
+            d.seconds, d.nanos
+        )));
+    }
+    Ok(Duration::seconds(d.seconds) + Duration::nanoseconds(d.nanos as _))
+}
+
+pub fn convert_to_pb_duration(d: Duration) -> prost_types::Duration {
+    let seconds = d.num_seconds();
+    prost_types::Duration {
+        seconds,
+        nanos: (d - Duration::seconds(seconds))
+            .num_nanoseconds()
+            .unwrap_or(0) as _,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_should_be_in_the_protobuf_range() {
+        let ts = |seconds, nanos| Timestamp { seconds, nanos };
+        assert!(convert_to_utc_timestamp(ts(MIN_TIMESTAMP_SECONDS, 0)).is_ok());
+        assert!(convert_to_utc_timestamp(ts(MAX_TIMESTAMP_SECONDS, 999_999_999)).is_ok());
+        for invalid in [
+            ts(MIN_TIMESTAMP_SECONDS - 1, 0),
+            ts(MAX_TIMESTAMP_SECONDS + 1, 0),
+            ts(i64::MAX, 0),
+            ts(0, -1),
+            ts(0, NANOS_PER_SECOND),
+        ] {
+            assert!(matches!(
+                convert_to_utc_timestamp(invalid),
+                Err(Error::InvalidTimestamp(_))
+            ));
+        }
+        assert!(matches!(
+            convert_to_utc_timespan(Some(&ts(0, 0)), None),
+            Err(Error::InvalidReservation)
+        ));
+    }
+
+    #[test]
+    fn durations_should_be_in_the_protobuf_range() {
+        let d = |seconds, nanos| prost_types::Duration { seconds, nanos };
+        assert_eq!(
+            convert_to_duration(d(-1, -500_000_000)).unwrap(),
+            -Duration::milliseconds(1500)
+        );
+        for invalid in [
+            d(MAX_DURATION_SECONDS + 1, 0),
+            d(i64::MIN, 0),
+            d(0, i32::MIN),
+            d(1, -1),
+            d(-1, 1),
+            d(0, NANOS_PER_SECOND),
+        ] {
+            assert!(matches!(
+                convert_to_duration(invalid),
+                Err(Error::InvalidDuration(_))
+            ));
+        }
+    }
+}
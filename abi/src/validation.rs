@@ -0,0 +1,242 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use prost_types::Timestamp;
+use serde::Serialize;
+
+use crate::{convert_to_utc_timestamp, Error, ReservationStatus};
+
+/// longest user and resource id the database takes
+pub const MAX_ID_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationCode {
+    Required,
+    NotAllowed,
+    TooLong,
+    OutOfRange,
+    Invalid,
+}
+
+/// a field of a request that failed validation, nested fields are joined with dots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldViolation {
+    pub field: String,
+    pub code: ViolationCode,
+    pub message: String,
+}
+
+/// collects every violation of a request instead of stopping at the first
+#[derive(Debug, Default)]
+pub struct Violations {
+    prefix: String,
+    violations: Vec<FieldViolation>,
+}
+
+impl Violations {
+    pub fn add(&mut self, field: &str, code: ViolationCode, message: impl Into<String>) {
+        self.violations.push(FieldViolation {
+            field: format!("{}{}", self.prefix, field),
+            code,
+            message: message.into(),
+        });
+    }
+
+    /// check the fields of a nested message, their paths are prefixed with `field`
+    pub fn nested(&mut self, field: &str, f: impl FnOnce(&mut Self)) {
+        let len = self.prefix.len();
+        self.prefix.push_str(field);
+        self.prefix.push('.');
+        f(self);
+        self.prefix.truncate(len);
+    }
+
+    /// an id that fits in the database, optionally required
+    pub fn check_id(&mut self, field: &str, id: &str, required: bool) {
+        if required && id.is_empty() {
+            self.add(field, ViolationCode::Required, "must not be empty");
+        } else if id.len() > MAX_ID_LEN {
+            let message = format!("must be at most {} bytes", MAX_ID_LEN);
+            self.add(field, ViolationCode::TooLong, message);
+        }
+    }
+
+    /// a status that is defined, unknown is only allowed as "any status" in queries
+    pub fn check_status(&mut self, field: &str, status: i32) -> Option<ReservationStatus> {
+        let status = ReservationStatus::from_i32(status);
+        if status.is_none() {
+            self.add(field, ViolationCode::Invalid, "is not a reservation status");
+        }
+        status
+    }
+
+    /// convert a timestamp, `None` if it's missing or out of range
+    pub fn check_timestamp(
+        &mut self,
+        field: &str,
+        ts: Option<&Timestamp>,
+        required: bool,
+    ) -> Option<DateTime<Utc>> {
+        match ts.cloned().map(convert_to_utc_timestamp) {
+            Some(Ok(dt)) => Some(dt),
+            Some(Err(e)) => {
+                self.add(field, ViolationCode::OutOfRange, e.to_string());
+                None
+            }
+            None => {
+                if required {
+                    self.add(field, ViolationCode::Required, "must be set");
+                }
+                None
+            }
+        }
+    }
+
+    /// `end` must come after `start`, checked only if both of them are valid
+    pub fn check_timespan(&mut self, start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) {
+        if let (Some(start), Some(end)) = (start, end) {
+            if validate_timespan(start, end).is_err() {
+                self.add("end", ViolationCode::Invalid, "must be after start");
+            }
+        }
+    }
+
+    pub fn into_result(self) -> Result<(), Error> {
+        if self.violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidRequest(self.violations))
+        }
+    }
+}
+
+impl fmt::Display for FieldViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.message)
+    }
+}
+
+impl fmt::Display for ViolationCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViolationCode::Required => write!(f, "required"),
+            ViolationCode::NotAllowed => write!(f, "not_allowed"),
+            ViolationCode::TooLong => write!(f, "too_long"),
+            ViolationCode::OutOfRange => write!(f, "out_of_range"),
+            ViolationCode::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
+/// fails unless the timespan ends after it starts
+pub(crate) fn validate_timespan(start: DateTime<Utc>, end: DateTime<Utc>) -> Result<(), Error> {
+    if start < end {
+        Ok(())
+    } else {
+        Err(Error::InvalidReservation)
+    }
+}
+
+/// an RFC 7807 problem, the body of an `application/problem+json` response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<FieldViolation>,
+}
+
+impl From<Error> for Problem {
+    fn from(e: Error) -> Self {
+        let detail = e.to_string();
+        let violations = match &e {
+            Error::InvalidRequest(violations) => violations.clone(),
+            _ => vec![],
+        };
+        // a stale version lost a race with another writer, nothing in the request is invalid
+        let conflict = matches!(e, Error::VersionMismatch { .. });
+        let (status, title) = match tonic::Status::from(e).code() {
+            _ if conflict => (409, "Conflict"),
+            tonic::Code::InvalidArgument | tonic::Code::FailedPrecondition => (400, "Bad Request"),
+            tonic::Code::Unauthenticated => (401, "Unauthorized"),
+            tonic::Code::PermissionDenied => (403, "Forbidden"),
+            tonic::Code::NotFound => (404, "Not Found"),
+            tonic::Code::AlreadyExists | tonic::Code::Aborted => (409, "Conflict"),
+            tonic::Code::ResourceExhausted => (429, "Too Many Requests"),
+            _ => (500, "Internal Server Error"),
+        };
+        Problem {
+            kind: "about:blank".to_string(),
+            title: title.to_string(),
+            status,
+            detail,
+            violations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic_types::StatusExt;
+
+    #[test]
+    fn violations_should_be_collected_with_nested_paths() {
+        let mut v = Violations::default();
+        v.check_id("user_id", "", true);
+        v.nested("reservation", |v| {
+            v.check_id("resource_id", &"x".repeat(65), true);
+            v.check_timestamp("start", None, true);
+        });
+        v.check_status("status", 42);
+        let Err(Error::InvalidRequest(violations)) = v.into_result() else {
+            panic!("expected violations");
+        };
+        let fields: Vec<_> = violations.iter().map(|v| v.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "user_id",
+                "reservation.resource_id",
+                "reservation.start",
+                "status"
+            ]
+        );
+        assert_eq!(violations[1].code, ViolationCode::TooLong);
+    }
+
+    #[test]
+    fn violations_should_map_to_bad_request_and_problem() {
+        let mut v = Violations::default();
+        v.check_id("resource_id", "", true);
+        v.check_timestamp("end", None, true);
+        let Err(Error::InvalidRequest(violations)) = v.into_result() else {
+            panic!("expected violations");
+        };
+
+        let status = tonic::Status::from(Error::InvalidRequest(violations.clone()));
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let bad_request = status.get_details_bad_request().unwrap();
+        assert_eq!(bad_request.field_violations.len(), 2);
+        assert_eq!(bad_request.field_violations[0].field, "resource_id");
+
+        let problem = Problem::from(Error::InvalidRequest(violations));
+        assert_eq!(problem.status, 400);
+        assert_eq!(problem.violations.len(), 2);
+        assert_eq!(
+            problem.detail,
+            "Invalid request: resource_id must not be empty; end must be set"
+        );
+        // like google.rpc does, a failed precondition is the client's to fix
+        assert_eq!(Problem::from(Error::CalendarFeedsDisabled).status, 400);
+        let stale = Error::VersionMismatch {
+            expected: 1,
+            current: 2,
+        };
+        assert_eq!(Problem::from(stale).status, 409);
+    }
+}
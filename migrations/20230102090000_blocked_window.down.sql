@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE rsvp.reservations DROP CONSTRAINT reservations_blocked_user;
+DELETE FROM rsvp.reservations WHERE user_id IS NULL;
+ALTER TABLE rsvp.reservations ALTER COLUMN user_id SET NOT NULL;
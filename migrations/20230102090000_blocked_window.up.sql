@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- blocked windows (maintenance, blackout periods) have no user
+ALTER TABLE rsvp.reservations ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE rsvp.reservations ADD CONSTRAINT reservations_blocked_user
+    CHECK ((status = 'blocked') = (user_id IS NULL));
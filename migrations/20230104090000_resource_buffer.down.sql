@@ -0,0 +1,8 @@
+-- Add down migration script here
+ALTER TABLE rsvp.reservations DROP CONSTRAINT reservations_conflict;
+ALTER TABLE rsvp.reservations ADD CONSTRAINT reservations_conflict
+    EXCLUDE USING gist (resource_id WITH =, timespan WITH &&);
+DROP TRIGGER reservations_buffer ON rsvp.reservations;
+DROP FUNCTION rsvp.reservations_buffer();
+ALTER TABLE rsvp.reservations DROP COLUMN buffered_timespan;
+DROP TABLE rsvp.resources;
@@ -0,0 +1,38 @@
+-- Add up migration script here
+-- per-resource settings, resources without a row have no buffers
+CREATE TABLE rsvp.resources (
+    id VARCHAR(64) NOT NULL,
+    pre_buffer INTERVAL NOT NULL DEFAULT '0',
+    post_buffer INTERVAL NOT NULL DEFAULT '0',
+
+    CONSTRAINT resources_pkey PRIMARY KEY (id)
+);
+
+-- timespan extended by the resource buffers, used for conflict detection only
+ALTER TABLE rsvp.reservations ADD COLUMN buffered_timespan TSTZRANGE;
+UPDATE rsvp.reservations SET buffered_timespan = timespan;
+ALTER TABLE rsvp.reservations ALTER COLUMN buffered_timespan SET NOT NULL;
+
+-- buffers are applied when a reservation is made or moved, changing them later
+-- doesn't affect existing reservations
+CREATE OR REPLACE FUNCTION rsvp.reservations_buffer() RETURNS TRIGGER AS $$
+DECLARE
+    pre INTERVAL;
+    post INTERVAL;
+BEGIN
+    SELECT pre_buffer, post_buffer INTO pre, post FROM rsvp.resources WHERE id = NEW.resource_id;
+    NEW.buffered_timespan := tstzrange(
+        lower(NEW.timespan) - COALESCE(pre, '0'),
+        upper(NEW.timespan) + COALESCE(post, '0')
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER reservations_buffer
+    BEFORE INSERT OR UPDATE OF resource_id, timespan ON rsvp.reservations
+    FOR EACH ROW EXECUTE PROCEDURE rsvp.reservations_buffer();
+
+ALTER TABLE rsvp.reservations DROP CONSTRAINT reservations_conflict;
+ALTER TABLE rsvp.reservations ADD CONSTRAINT reservations_conflict
+    EXCLUDE USING gist (resource_id WITH =, buffered_timespan WITH &&);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE rsvp.resources
+    DROP COLUMN min_duration,
+    DROP COLUMN max_duration,
+    DROP COLUMN booking_horizon,
+    DROP COLUMN lead_time;
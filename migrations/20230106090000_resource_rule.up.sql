@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- booking rules, NULL means no limit
+ALTER TABLE rsvp.resources
+    ADD COLUMN min_duration INTERVAL,
+    ADD COLUMN max_duration INTERVAL,
+    ADD COLUMN booking_horizon INTERVAL,
+    ADD COLUMN lead_time INTERVAL;
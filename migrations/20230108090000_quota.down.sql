@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE rsvp.user_groups;
+DROP TABLE rsvp.quota_policies;
+DROP TYPE rsvp.quota_kind;
+ALTER TABLE rsvp.resources DROP COLUMN kind;
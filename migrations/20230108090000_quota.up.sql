@@ -0,0 +1,27 @@
+-- Add up migration script here
+ALTER TABLE rsvp.resources ADD COLUMN kind VARCHAR(64) NOT NULL DEFAULT '';
+CREATE INDEX resources_kind_idx ON rsvp.resources (kind);
+
+CREATE TYPE rsvp.quota_kind AS ENUM ('unknown', 'max_active', 'max_weekly_hours', 'max_future_per_kind');
+
+-- a policy applies to a user, or to every member of a group
+CREATE TABLE rsvp.quota_policies (
+    id BIGSERIAL NOT NULL,
+    kind rsvp.quota_kind NOT NULL,
+    user_id VARCHAR(64),
+    group_id VARCHAR(64),
+    quota_limit BIGINT NOT NULL,
+    resource_kind VARCHAR(64) NOT NULL DEFAULT '',
+
+    CONSTRAINT quota_policies_pkey PRIMARY KEY (id),
+    CONSTRAINT quota_policies_scope CHECK ((user_id IS NULL) <> (group_id IS NULL))
+);
+CREATE INDEX quota_policies_user_id_idx ON rsvp.quota_policies (user_id);
+CREATE INDEX quota_policies_group_id_idx ON rsvp.quota_policies (group_id);
+
+CREATE TABLE rsvp.user_groups (
+    user_id VARCHAR(64) NOT NULL,
+    group_id VARCHAR(64) NOT NULL,
+
+    CONSTRAINT user_groups_pkey PRIMARY KEY (user_id, group_id)
+);
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE rsvp.reservations ADD COLUMN labels JSONB NOT NULL DEFAULT '{}';
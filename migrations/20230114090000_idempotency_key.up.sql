@@ -0,0 +1,14 @@
+-- Add up migration script here
+-- outcome of mutating requests, kept for a retention window so retries can be replayed
+CREATE TABLE rsvp.idempotency_keys (
+    operation VARCHAR(64) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    -- encoded request, a reused key must come with the same request
+    request BYTEA NOT NULL,
+    -- encoded response, NULL while the request is in progress
+    response BYTEA,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT idempotency_keys_pkey PRIMARY KEY (operation, idempotency_key)
+);
+CREATE INDEX idempotency_keys_created_at_idx ON rsvp.idempotency_keys (created_at);
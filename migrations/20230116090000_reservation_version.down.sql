@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER reservations_version ON rsvp.reservations;
+DROP FUNCTION rsvp.reservations_version();
+ALTER TABLE rsvp.reservations DROP COLUMN version;
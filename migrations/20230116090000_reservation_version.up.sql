@@ -0,0 +1,17 @@
+-- Add up migration script here
+-- version for optimistic concurrency, increased whenever a reservation changes
+ALTER TABLE rsvp.reservations ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
+
+CREATE OR REPLACE FUNCTION rsvp.reservations_version() RETURNS TRIGGER AS $$
+BEGIN
+    IF ROW(NEW.*) IS DISTINCT FROM ROW(OLD.*) THEN
+        NEW.version := OLD.version + 1;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- BEFORE triggers run in name order, so the buffered timespan is already updated here
+CREATE TRIGGER reservations_version
+    BEFORE UPDATE ON rsvp.reservations
+    FOR EACH ROW EXECUTE PROCEDURE rsvp.reservations_version();
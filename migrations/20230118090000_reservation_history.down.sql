@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TRIGGER reservations_audit ON rsvp.reservations;
+DROP FUNCTION rsvp.reservations_audit();
+DROP FUNCTION rsvp.reservation_snapshot(rsvp.reservations);
+DROP TABLE rsvp.reservation_history;
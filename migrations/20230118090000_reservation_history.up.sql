@@ -0,0 +1,50 @@
+-- Add up migration script here
+-- audit trail of every change to a reservation, with snapshots before and after
+CREATE TABLE rsvp.reservation_history (
+    id BIGSERIAL NOT NULL,
+    reservation_id BIGINT NOT NULL,
+    op rsvp.reservation_update_type NOT NULL,
+    actor VARCHAR(64),
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    before JSONB,
+    after JSONB,
+
+    CONSTRAINT reservation_history_pkey PRIMARY KEY (id)
+);
+CREATE INDEX reservation_history_reservation_id_idx ON rsvp.reservation_history (reservation_id);
+CREATE INDEX reservation_history_actor_idx ON rsvp.reservation_history (actor, changed_at);
+CREATE INDEX reservation_history_changed_at_idx ON rsvp.reservation_history (changed_at);
+
+CREATE OR REPLACE FUNCTION rsvp.reservation_snapshot(r rsvp.reservations) RETURNS JSONB AS $$
+    SELECT jsonb_build_object(
+        'id', r.id, 'user_id', r.user_id, 'resource_id', r.resource_id,
+        'start', lower(r.timespan), 'end', upper(r.timespan), 'note', r.note,
+        'status', r.status, 'labels', r.labels, 'version', r.version
+    );
+$$ LANGUAGE sql IMMUTABLE;
+
+-- the actor is set per transaction with set_config('rsvp.actor', ..., true)
+CREATE OR REPLACE FUNCTION rsvp.reservations_audit() RETURNS TRIGGER AS $$
+DECLARE
+    actor VARCHAR(64) := NULLIF(current_setting('rsvp.actor', true), '');
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO rsvp.reservation_history (reservation_id, op, actor, after)
+        VALUES (NEW.id, 'create', actor, rsvp.reservation_snapshot(NEW));
+    ELSIF TG_OP = 'UPDATE' THEN
+        -- any change counts, not only status changes
+        IF ROW(OLD.*) IS DISTINCT FROM ROW(NEW.*) THEN
+            INSERT INTO rsvp.reservation_history (reservation_id, op, actor, before, after)
+            VALUES (NEW.id, 'update', actor, rsvp.reservation_snapshot(OLD), rsvp.reservation_snapshot(NEW));
+        END IF;
+    ELSIF TG_OP = 'DELETE' THEN
+        INSERT INTO rsvp.reservation_history (reservation_id, op, actor, before)
+        VALUES (OLD.id, 'delete', actor, rsvp.reservation_snapshot(OLD));
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER reservations_audit
+    AFTER INSERT OR UPDATE OR DELETE ON rsvp.reservations
+    FOR EACH ROW EXECUTE PROCEDURE rsvp.reservations_audit();
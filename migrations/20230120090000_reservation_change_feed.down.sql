@@ -0,0 +1,35 @@
+-- Add down migration script here
+DROP TRIGGER reservations_trigger ON rsvp.reservations;
+
+CREATE OR REPLACE FUNCTION rsvp.reservations_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        -- update reservation_changes
+        INSERT INTO rsvp.reservation_changes (reservation_id, op) VALUES (NEW.id, 'create');
+    ELSIF TG_OP = 'UPDATE' THEN
+        -- if status, resource or timespan changed, update reservation_changes
+        IF OLD.status <> NEW.status OR OLD.resource_id <> NEW.resource_id OR OLD.timespan <> NEW.timespan THEN
+            INSERT INTO rsvp.reservation_changes (reservation_id, op) VALUES (NEW.id, 'update');
+        END IF;
+    ELSIF TG_OP = 'DELETE' THEN
+        -- update reservation_changes
+        INSERT INTO rsvp.reservation_changes (reservation_id, op) VALUES (OLD.id, 'delete');
+    END IF;
+    -- notify a channel called reservation_update
+    NOTIFY reservation_update;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER reservations_trigger
+    AFTER INSERT OR UPDATE OR DELETE ON rsvp.reservations
+    FOR EACH ROW EXECUTE PROCEDURE rsvp.reservations_trigger();
+
+ALTER TABLE rsvp.reservation_changes DROP COLUMN reservation;
+ALTER TABLE rsvp.reservation_changes DROP COLUMN committed_at;
+CREATE SEQUENCE rsvp.reservation_changes_reservation_id_seq OWNED BY rsvp.reservation_changes.reservation_id;
+ALTER TABLE rsvp.reservation_changes
+    ALTER COLUMN reservation_id SET DEFAULT nextval('rsvp.reservation_changes_reservation_id_seq');
+ALTER TABLE rsvp.reservation_changes DROP CONSTRAINT reservation_changes_pkey;
+ALTER SEQUENCE rsvp.reservation_changes_id_seq AS INTEGER;
+ALTER TABLE rsvp.reservation_changes ALTER COLUMN id TYPE INTEGER;
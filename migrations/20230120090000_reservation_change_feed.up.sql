@@ -0,0 +1,42 @@
+-- Add up migration script here
+-- make reservation_changes a resumable feed: the id is the sequence number of a change
+ALTER TABLE rsvp.reservation_changes ALTER COLUMN id TYPE BIGINT;
+ALTER SEQUENCE rsvp.reservation_changes_id_seq AS BIGINT;
+ALTER TABLE rsvp.reservation_changes ADD CONSTRAINT reservation_changes_pkey PRIMARY KEY (id);
+ALTER TABLE rsvp.reservation_changes ALTER COLUMN reservation_id DROP DEFAULT;
+DROP SEQUENCE rsvp.reservation_changes_reservation_id_seq;
+ALTER TABLE rsvp.reservation_changes ADD COLUMN committed_at TIMESTAMPTZ NOT NULL DEFAULT now();
+-- the reservation as of the change, older changes don't have it
+ALTER TABLE rsvp.reservation_changes ADD COLUMN reservation JSONB;
+
+-- changes are recorded at commit. Writers take turns for the last moments before the commit,
+-- so sequence numbers become visible in order and a reader never skips one
+CREATE OR REPLACE FUNCTION rsvp.reservations_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_advisory_xact_lock('rsvp.reservation_changes'::regclass::int, 0);
+    IF TG_OP = 'INSERT' THEN
+        -- update reservation_changes
+        INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+        VALUES (NEW.id, 'create', clock_timestamp(), rsvp.reservation_snapshot(NEW));
+    ELSIF TG_OP = 'UPDATE' THEN
+        -- if status, resource or timespan changed, update reservation_changes
+        IF OLD.status <> NEW.status OR OLD.resource_id <> NEW.resource_id OR OLD.timespan <> NEW.timespan THEN
+            INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+            VALUES (NEW.id, 'update', clock_timestamp(), rsvp.reservation_snapshot(NEW));
+        END IF;
+    ELSIF TG_OP = 'DELETE' THEN
+        -- update reservation_changes
+        INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+        VALUES (OLD.id, 'delete', clock_timestamp(), rsvp.reservation_snapshot(OLD));
+    END IF;
+    -- notify a channel called reservation_update
+    NOTIFY reservation_update;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER reservations_trigger ON rsvp.reservations;
+CREATE CONSTRAINT TRIGGER reservations_trigger
+    AFTER INSERT OR UPDATE OR DELETE ON rsvp.reservations
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE PROCEDURE rsvp.reservations_trigger();
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE rsvp.webhook_deliveries;
+DROP TABLE rsvp.webhooks;
+DROP TYPE rsvp.webhook_delivery_status;
@@ -0,0 +1,36 @@
+-- Add up migration script here
+CREATE TYPE rsvp.webhook_delivery_status AS ENUM ('unknown', 'pending', 'delivered', 'dead');
+
+CREATE TABLE rsvp.webhooks (
+    id BIGSERIAL NOT NULL,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    -- filters, empty ones match everything
+    resource_ids VARCHAR(64)[] NOT NULL DEFAULT '{}',
+    user_id VARCHAR(64) NOT NULL DEFAULT '',
+    status rsvp.reservation_status NOT NULL DEFAULT 'unknown',
+    ops rsvp.reservation_update_type[] NOT NULL DEFAULT '{}',
+    -- changes up to this sequence number are queued for delivery
+    last_change_id BIGINT NOT NULL DEFAULT 0,
+
+    CONSTRAINT webhooks_pkey PRIMARY KEY (id)
+);
+
+-- deliveries double as the delivery log
+CREATE TABLE rsvp.webhook_deliveries (
+    id BIGSERIAL NOT NULL,
+    webhook_id BIGINT NOT NULL REFERENCES rsvp.webhooks (id) ON DELETE CASCADE,
+    change_id BIGINT NOT NULL REFERENCES rsvp.reservation_changes (id),
+    status rsvp.webhook_delivery_status NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    response_status INT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMPTZ,
+
+    CONSTRAINT webhook_deliveries_pkey PRIMARY KEY (id),
+    CONSTRAINT webhook_deliveries_change UNIQUE (webhook_id, change_id)
+);
+CREATE INDEX webhook_deliveries_due_idx ON rsvp.webhook_deliveries (next_attempt_at)
+    WHERE status = 'pending';
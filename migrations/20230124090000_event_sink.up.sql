@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- reservation_changes is written in the same transaction as the reservation, so it doubles as
+-- the outbox. Every sink keeps its own position in it
+CREATE TABLE rsvp.event_sink_offsets (
+    name VARCHAR(64) NOT NULL,
+    -- changes up to this sequence number are published
+    last_change_id BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT event_sink_offsets_pkey PRIMARY KEY (name)
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE rsvp.idempotency_keys
+    DROP COLUMN claimed_at,
+    ALTER COLUMN idempotency_key TYPE VARCHAR(255);
@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- a claim is held until claimed_at + the lease, after that a retry may take over the key of a
+-- request that never finished. Keys are scoped by caller now, so they get longer
+ALTER TABLE rsvp.idempotency_keys
+    ADD COLUMN claimed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ALTER COLUMN idempotency_key TYPE TEXT;
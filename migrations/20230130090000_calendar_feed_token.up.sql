@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- calendar feeds handed out, a feed stops working when its row is deleted
+CREATE TABLE rsvp.calendar_feed_tokens (
+    nonce VARCHAR(64) NOT NULL,
+    -- exactly one of them is set
+    user_id TEXT NOT NULL DEFAULT '',
+    resource_id TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT calendar_feed_tokens_pkey PRIMARY KEY (nonce)
+);
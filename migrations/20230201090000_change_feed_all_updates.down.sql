@@ -0,0 +1,24 @@
+-- Add down migration script here
+CREATE OR REPLACE FUNCTION rsvp.reservations_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_advisory_xact_lock('rsvp.reservation_changes'::regclass::int, 0);
+    IF TG_OP = 'INSERT' THEN
+        -- update reservation_changes
+        INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+        VALUES (NEW.id, 'create', clock_timestamp(), rsvp.reservation_snapshot(NEW));
+    ELSIF TG_OP = 'UPDATE' THEN
+        -- if status, resource or timespan changed, update reservation_changes
+        IF OLD.status <> NEW.status OR OLD.resource_id <> NEW.resource_id OR OLD.timespan <> NEW.timespan THEN
+            INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+            VALUES (NEW.id, 'update', clock_timestamp(), rsvp.reservation_snapshot(NEW));
+        END IF;
+    ELSIF TG_OP = 'DELETE' THEN
+        -- update reservation_changes
+        INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+        VALUES (OLD.id, 'delete', clock_timestamp(), rsvp.reservation_snapshot(OLD));
+    END IF;
+    -- notify a channel called reservation_update
+    NOTIFY reservation_update;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
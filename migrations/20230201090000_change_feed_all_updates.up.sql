@@ -0,0 +1,25 @@
+-- Add up migration script here
+-- edits of the note, user or labels are changes too, listeners and the cache need to see them
+CREATE OR REPLACE FUNCTION rsvp.reservations_trigger() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_advisory_xact_lock('rsvp.reservation_changes'::regclass::int, 0);
+    IF TG_OP = 'INSERT' THEN
+        -- update reservation_changes
+        INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+        VALUES (NEW.id, 'create', clock_timestamp(), rsvp.reservation_snapshot(NEW));
+    ELSIF TG_OP = 'UPDATE' THEN
+        -- any field that changed is a change, like in the history
+        IF ROW(OLD.*) IS DISTINCT FROM ROW(NEW.*) THEN
+            INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+            VALUES (NEW.id, 'update', clock_timestamp(), rsvp.reservation_snapshot(NEW));
+        END IF;
+    ELSIF TG_OP = 'DELETE' THEN
+        -- update reservation_changes
+        INSERT INTO rsvp.reservation_changes (reservation_id, op, committed_at, reservation)
+        VALUES (OLD.id, 'delete', clock_timestamp(), rsvp.reservation_snapshot(OLD));
+    END IF;
+    -- notify a channel called reservation_update
+    NOTIFY reservation_update;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
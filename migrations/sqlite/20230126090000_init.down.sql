@@ -0,0 +1,8 @@
+DROP TABLE idempotency_keys;
+DROP TABLE webhooks;
+DROP TABLE reservation_changes;
+DROP TABLE reservation_history;
+DROP TABLE user_groups;
+DROP TABLE quota_policies;
+DROP TABLE reservations;
+DROP TABLE resources;
@@ -0,0 +1,127 @@
+-- the schema of the SQLite backend. Times are microseconds since the epoch, durations are
+-- microseconds, statuses and operations are the values of their protobuf enums
+CREATE TABLE resources (
+    id VARCHAR(64) NOT NULL PRIMARY KEY,
+    pre_buffer INTEGER NOT NULL DEFAULT 0,
+    post_buffer INTEGER NOT NULL DEFAULT 0,
+    min_duration INTEGER,
+    max_duration INTEGER,
+    booking_horizon INTEGER,
+    lead_time INTEGER,
+    kind VARCHAR(64) NOT NULL DEFAULT ''
+);
+CREATE INDEX resources_kind_idx ON resources (kind);
+
+-- status: 1 pending, 2 confirmed, 3 blocked
+CREATE TABLE reservations (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id VARCHAR(64),
+    status INTEGER NOT NULL DEFAULT 1,
+    resource_id VARCHAR(64) NOT NULL,
+    start_at INTEGER NOT NULL,
+    end_at INTEGER NOT NULL,
+    -- the timespan extended by the buffers of the resource when it was made or moved
+    buffered_start INTEGER NOT NULL,
+    buffered_end INTEGER NOT NULL,
+    note TEXT NOT NULL DEFAULT '',
+    labels TEXT NOT NULL DEFAULT '{}',
+    version INTEGER NOT NULL DEFAULT 1,
+
+    CONSTRAINT reservations_timespan CHECK (start_at < end_at),
+    CONSTRAINT reservations_blocked_user CHECK ((status = 3) = (user_id IS NULL))
+);
+CREATE INDEX reservations_resource_id_idx ON reservations (resource_id, buffered_start);
+CREATE INDEX reservations_user_id_idx ON reservations (user_id);
+
+-- there is no exclusion constraint, so overlapping buffered timespans of a resource are
+-- rejected by triggers within the writing transaction
+CREATE TRIGGER reservations_conflict_insert
+    BEFORE INSERT ON reservations
+    WHEN EXISTS (
+        SELECT 1 FROM reservations
+        WHERE resource_id = NEW.resource_id
+        AND buffered_start < NEW.buffered_end AND buffered_end > NEW.buffered_start
+    )
+BEGIN
+    SELECT RAISE(ABORT, 'reservations_conflict');
+END;
+
+CREATE TRIGGER reservations_conflict_update
+    BEFORE UPDATE OF resource_id, buffered_start, buffered_end ON reservations
+    WHEN EXISTS (
+        SELECT 1 FROM reservations
+        WHERE resource_id = NEW.resource_id AND id <> NEW.id
+        AND buffered_start < NEW.buffered_end AND buffered_end > NEW.buffered_start
+    )
+BEGIN
+    SELECT RAISE(ABORT, 'reservations_conflict');
+END;
+
+-- kind: 1 max_active, 2 max_weekly_hours, 3 max_future_per_kind
+CREATE TABLE quota_policies (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind INTEGER NOT NULL,
+    user_id VARCHAR(64),
+    group_id VARCHAR(64),
+    quota_limit INTEGER NOT NULL,
+    resource_kind VARCHAR(64) NOT NULL DEFAULT '',
+
+    CONSTRAINT quota_policies_scope CHECK ((user_id IS NULL) <> (group_id IS NULL))
+);
+CREATE INDEX quota_policies_user_id_idx ON quota_policies (user_id);
+CREATE INDEX quota_policies_group_id_idx ON quota_policies (group_id);
+
+CREATE TABLE user_groups (
+    user_id VARCHAR(64) NOT NULL,
+    group_id VARCHAR(64) NOT NULL,
+
+    PRIMARY KEY (user_id, group_id)
+);
+
+-- op: 1 create, 2 update, 3 delete. Snapshots are encoded reservations
+CREATE TABLE reservation_history (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    reservation_id INTEGER NOT NULL,
+    op INTEGER NOT NULL,
+    actor VARCHAR(64),
+    changed_at INTEGER NOT NULL,
+    before BLOB,
+    after BLOB
+);
+CREATE INDEX reservation_history_reservation_id_idx ON reservation_history (reservation_id);
+CREATE INDEX reservation_history_actor_idx ON reservation_history (actor, changed_at);
+CREATE INDEX reservation_history_changed_at_idx ON reservation_history (changed_at);
+
+-- the fields listeners filter on are copied out of the snapshot
+CREATE TABLE reservation_changes (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    reservation_id INTEGER NOT NULL,
+    op INTEGER NOT NULL,
+    committed_at INTEGER NOT NULL,
+    resource_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL DEFAULT '',
+    status INTEGER NOT NULL,
+    reservation BLOB NOT NULL
+);
+
+-- resource_ids and ops are JSON arrays
+CREATE TABLE webhooks (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    resource_ids TEXT NOT NULL DEFAULT '[]',
+    user_id VARCHAR(64) NOT NULL DEFAULT '',
+    status INTEGER NOT NULL DEFAULT 0,
+    ops TEXT NOT NULL DEFAULT '[]'
+);
+
+CREATE TABLE idempotency_keys (
+    operation VARCHAR(64) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    request BLOB NOT NULL,
+    response BLOB,
+    created_at INTEGER NOT NULL,
+
+    PRIMARY KEY (operation, idempotency_key)
+);
+CREATE INDEX idempotency_keys_created_at_idx ON idempotency_keys (created_at);
@@ -0,0 +1,2 @@
+-- a claim is held until claimed_at + the lease, claims from before the lease can be taken over
+ALTER TABLE idempotency_keys ADD COLUMN claimed_at INTEGER NOT NULL DEFAULT 0;
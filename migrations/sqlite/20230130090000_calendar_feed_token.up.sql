@@ -0,0 +1,7 @@
+-- calendar feeds handed out, a feed stops working when its row is deleted
+CREATE TABLE calendar_feed_tokens (
+    nonce VARCHAR(64) NOT NULL PRIMARY KEY,
+    user_id TEXT NOT NULL DEFAULT '',
+    resource_id TEXT NOT NULL DEFAULT '',
+    created_at INTEGER NOT NULL
+);
@@ -0,0 +1,235 @@
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use tracing::instrument;
+
+use crate::{manager::query_sql, ReservationManager};
+
+/// outcome of a bulk import, rows that failed are reported by line and the others are kept
+#[derive(Debug, Default)]
+pub struct BulkImport {
+    pub created: u64,
+    pub errors: Vec<(u64, abi::Error)>,
+}
+
+impl ReservationManager {
+    /// stream the reservations matching the query, without loading them all into memory
+    pub fn query_stream(
+        &self,
+        query: abi::ReservationQuery,
+    ) -> Result<BoxStream<'_, Result<abi::Reservation, abi::Error>>, abi::Error> {
+        let status = abi::ReservationStatus::from_i32(query.status)
+            .unwrap_or(abi::ReservationStatus::Unknown);
+        let rsvps = sqlx::query_as(query_sql(query.desc))
+            .bind(query.user_id.clone())
+            .bind(query.resource_id.clone())
+            .bind(query.get_timespan()?)
+            .bind(status.to_string())
+            .fetch(&self.pool)
+            .map_err(abi::Error::from)
+            .boxed();
+        Ok(rsvps)
+    }
+
+    /// load reservations with COPY in one transaction. Every row is validated first, rows that
+    /// are invalid or conflict with an existing (or earlier) reservation are skipped and reported
+    /// with their line. Booking rules and quotas don't apply to imports
+    #[instrument(skip_all, fields(rows = rows.len()))]
+    pub async fn copy_in(
+        &self,
+        rows: Vec<(u64, abi::Reservation)>,
+    ) -> Result<BulkImport, abi::Error> {
+        let mut report = BulkImport::default();
+        let mut data = String::new();
+        let mut staged = 0;
+        for (line, rsvp) in rows {
+            match validate_row(&rsvp) {
+                Ok(timespan) => {
+                    push_row(&mut data, line, &rsvp, timespan);
+                    staged += 1;
+                }
+                Err(e) => report.errors.push((line, e)),
+            }
+        }
+        if staged == 0 {
+            return Ok(report);
+        }
+
+        let mut tx = self.begin().await?;
+        sqlx::query(
+            "CREATE TEMP TABLE reservation_import (
+                line BIGINT NOT NULL,
+                user_id VARCHAR(64),
+                resource_id VARCHAR(64) NOT NULL,
+                timespan TSTZRANGE NOT NULL,
+                note TEXT NOT NULL,
+                status rsvp.reservation_status NOT NULL,
+                labels JSONB NOT NULL
+            ) ON COMMIT DROP",
+        )
+        .execute(&mut tx)
+        .await?;
+        let mut copy = tx.copy_in_raw("COPY reservation_import FROM STDIN").await?;
+        copy.send(data.into_bytes()).await?;
+        copy.finish().await?;
+
+        // ids are drawn up front, so the lines of the rows that weren't inserted can be told
+        // apart from the returned ones
+        let skipped: Vec<i64> = sqlx::query_scalar(
+            "WITH staged AS MATERIALIZED (
+                SELECT nextval(pg_get_serial_sequence('rsvp.reservations', 'id')) AS id, *
+                FROM reservation_import ORDER BY line
+            ), inserted AS (
+                INSERT INTO rsvp.reservations (id, user_id, resource_id, timespan, note, status, labels)
+                SELECT id, user_id, resource_id, timespan, note, status, labels FROM staged
+                ORDER BY line
+                ON CONFLICT DO NOTHING RETURNING id
+            )
+            SELECT line FROM staged WHERE id NOT IN (SELECT id FROM inserted) ORDER BY line",
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        report.created = staged - skipped.len() as u64;
+        for line in skipped {
+            report.errors.push((
+                line as u64,
+                abi::Error::ConflictReservation(
+                    "overlaps an existing reservation of the resource".to_string(),
+                ),
+            ));
+        }
+        report.errors.sort_by_key(|(line, _)| *line);
+        Ok(report)
+    }
+}
+
+/// the checks the database would otherwise fail the whole COPY on
+fn validate_row(rsvp: &abi::Reservation) -> Result<(DateTime<Utc>, DateTime<Utc>), abi::Error> {
+    rsvp.validate()?;
+    // imported rows don't default to pending
+    if rsvp.status == abi::ReservationStatus::Unknown as i32 {
+        return Err(abi::Error::InvalidStatus("unknown".to_string()));
+    }
+    rsvp.get_timespan()
+}
+
+/// append the row in COPY text format, columns are separated by tabs
+fn push_row(
+    data: &mut String,
+    line: u64,
+    rsvp: &abi::Reservation,
+    (start, end): (DateTime<Utc>, DateTime<Utc>),
+) {
+    let user_id = if rsvp.is_blocked() {
+        "\\N".to_string()
+    } else {
+        escape(&rsvp.user_id)
+    };
+    let status = abi::ReservationStatus::from_i32(rsvp.status).unwrap();
+    let labels = serde_json::to_string(&rsvp.labels).unwrap();
+    data.push_str(&format!(
+        "{line}\t{user_id}\t{}\t[\"{}\",\"{}\")\t{}\t{status}\t{}\n",
+        escape(&rsvp.resource_id),
+        start.to_rfc3339(),
+        end.to_rfc3339(),
+        escape(&rsvp.note),
+        escape(&labels),
+    ));
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rsvp;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn copy_in_should_skip_and_report_bad_rows() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        manager.reserve(make_pending(20, "room-1")).await.unwrap();
+
+        let mut noted = make_pending(21, "room-1");
+        noted.note = "tab\there\\\nnewline".to_string();
+        noted
+            .labels
+            .insert("cost-center".to_string(), "42".to_string());
+        let mut saved = make_pending(25, "room-2");
+        saved.id = 7;
+        let block = abi::Reservation::new_blocked(
+            "room-2",
+            "2099-12-22T15:00:00-0700".parse().unwrap(),
+            "2099-12-22T18:00:00-0700".parse().unwrap(),
+            "maintenance",
+        );
+        let mut no_user = make_pending(23, "room-2");
+        no_user.user_id.clear();
+        let rows = vec![
+            (2, make_pending(20, "room-1")),
+            (3, noted.clone()),
+            (4, make_pending(21, "room-1")),
+            (5, saved),
+            (6, block),
+            (7, no_user),
+        ];
+
+        let report = manager.copy_in(rows).await.unwrap();
+        assert_eq!(report.created, 2);
+        let lines: Vec<u64> = report.errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![2, 4, 5, 7]);
+        assert!(matches!(
+            report.errors[0].1,
+            abi::Error::ConflictReservation(_)
+        ));
+        assert!(matches!(report.errors[3].1, abi::Error::InvalidRequest(_)));
+
+        let rsvps = manager.query(Default::default()).await.unwrap();
+        assert_eq!(rsvps.len(), 3);
+        assert_eq!(rsvps[1].note, noted.note);
+        assert_eq!(rsvps[1].labels, noted.labels);
+        assert_eq!(rsvps[1].version, 1);
+        assert!(rsvps[2].is_blocked());
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn query_stream_should_match_query() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        for day in [24, 20, 22] {
+            manager.reserve(make_pending(day, "room-1")).await.unwrap();
+        }
+        manager.reserve(make_pending(21, "room-2")).await.unwrap();
+
+        let query = abi::ReservationQuery {
+            resource_id: "room-1".to_string(),
+            desc: true,
+            ..Default::default()
+        };
+        let streamed: Vec<abi::Reservation> = manager
+            .query_stream(query.clone())
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 3);
+        assert_eq!(streamed, manager.query(query).await.unwrap());
+    }
+
+    fn make_pending(day: u32, resource_id: &str) -> abi::Reservation {
+        let start = format!("2099-12-{day}T15:00:00-0700").parse().unwrap();
+        let end = format!("2099-12-{day}T18:00:00-0700").parse().unwrap();
+        abi::Reservation::new_pending("user1", resource_id, start, end, "just note")
+    }
+}
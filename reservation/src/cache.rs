@@ -0,0 +1,689 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use prost::Message;
+
+use crate::{ChangeReceiver, Rsvp, Storage};
+
+// how long to wait before subscribing to the change feed again after it broke off
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+type Observer = Arc<dyn Fn(&'static str, bool) + Send + Sync>;
+
+/// keeps the results of `get` and of queries of a resource window in memory. Changes made
+/// through it invalidate them right away, the ones made elsewhere (any edit, on any replica) once
+/// the change feed brings them. Entries expire after the ttl regardless, and while the feed is
+/// down nothing is cached
+#[derive(Clone)]
+pub struct CachedManager<S> {
+    inner: S,
+    cache: Arc<Mutex<Cache>>,
+    // told about every lookup with "get" or "query" and whether it was a hit
+    observer: Option<Observer>,
+}
+
+#[derive(Debug)]
+struct Cache {
+    ttl: Duration,
+    // bumped by every invalidation, results read before it are not cached
+    epoch: u64,
+    // whether the change feed is up
+    live: bool,
+    gets: Entries<i64, abi::Reservation>,
+    queries: Entries<Vec<u8>, CachedQuery>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedQuery {
+    resource_id: String,
+    rsvps: Vec<abi::Reservation>,
+}
+
+/// a map of at most `capacity` entries, the oldest one is dropped to make room
+#[derive(Debug)]
+struct Entries<K, V> {
+    capacity: usize,
+    map: HashMap<K, (V, Instant)>,
+    // insertion order, keys that are gone from the map are skipped
+    order: VecDeque<K>,
+}
+
+impl<S: Storage> CachedManager<S> {
+    /// cache up to `capacity` reservations and as many queries for `ttl`. Fails if the change
+    /// feed of `inner` can't be listened to
+    pub async fn new(inner: S, capacity: usize, ttl: Duration) -> Result<Self, abi::Error> {
+        let cache = Arc::new(Mutex::new(Cache {
+            ttl,
+            epoch: 0,
+            live: true,
+            gets: Entries::new(capacity),
+            queries: Entries::new(capacity),
+        }));
+        // subscribed before anything is cached, so no change can slip through
+        let changes = inner.listen(abi::ListenRequest::default()).await?;
+        tokio::spawn(invalidate_on_changes(
+            inner.clone(),
+            changes,
+            Arc::downgrade(&cache),
+        ));
+
+        Ok(Self {
+            inner,
+            cache,
+            observer: None,
+        })
+    }
+
+    /// call `f` with "get" or "query" and whether it was a hit for every lookup, e.g. to count
+    /// them
+    pub fn with_observer(mut self, f: impl Fn(&'static str, bool) + Send + Sync + 'static) -> Self {
+        self.observer = Some(Arc::new(f));
+        self
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap()
+    }
+
+    fn observe(&self, kind: &'static str, hit: bool) {
+        if let Some(observer) = &self.observer {
+            observer(kind, hit);
+        }
+    }
+
+    fn changed(&self, rsvp: &abi::Reservation) {
+        self.cache().invalidate(Some(rsvp));
+    }
+
+    fn clear(&self) {
+        self.cache().invalidate(None);
+    }
+}
+
+async fn invalidate_on_changes<S: Rsvp>(
+    inner: S,
+    mut changes: ChangeReceiver,
+    weak: Weak<Mutex<Cache>>,
+) {
+    loop {
+        let change = changes.recv().await;
+        let Some(cache) = weak.upgrade() else {
+            return;
+        };
+        match change {
+            Some(Ok(change)) => cache
+                .lock()
+                .unwrap()
+                .invalidate(change.reservation.as_ref()),
+            // changes might be missed until the feed is back, the cache is bypassed till then
+            _ => {
+                cache.lock().unwrap().live = false;
+                drop(cache);
+                changes = loop {
+                    tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                    match inner.listen(abi::ListenRequest::default()).await {
+                        Ok(changes) => break changes,
+                        Err(e) => {
+                            tracing::warn!(error = %e, "subscribing to the change feed failed")
+                        }
+                    }
+                };
+                let Some(cache) = weak.upgrade() else {
+                    return;
+                };
+                let mut cache = cache.lock().unwrap();
+                cache.invalidate(None);
+                cache.live = true;
+            }
+        }
+    }
+}
+
+impl Cache {
+    // the epoch the result of a miss has to be put back with
+    fn get(&mut self, id: i64) -> Result<abi::Reservation, u64> {
+        match self.gets.get(&id, self.ttl) {
+            Some(rsvp) if self.live => Ok(rsvp),
+            _ => Err(self.epoch),
+        }
+    }
+
+    fn query(&mut self, key: &[u8]) -> Result<Vec<abi::Reservation>, u64> {
+        match self.queries.get(key, self.ttl) {
+            Some(query) if self.live => Ok(query.rsvps),
+            _ => Err(self.epoch),
+        }
+    }
+
+    fn put_get(&mut self, epoch: u64, rsvp: abi::Reservation) {
+        if self.live && epoch == self.epoch {
+            self.gets.insert(rsvp.id, rsvp);
+        }
+    }
+
+    fn put_query(&mut self, epoch: u64, key: Vec<u8>, query: CachedQuery) {
+        if self.live && epoch == self.epoch {
+            self.queries.insert(key, query);
+        }
+    }
+
+    /// forget the reservation and every query it might change, everything without one
+    fn invalidate(&mut self, rsvp: Option<&abi::Reservation>) {
+        self.epoch += 1;
+        let Some(rsvp) = rsvp else {
+            self.gets.clear();
+            self.queries.clear();
+            return;
+        };
+        self.gets.remove(&rsvp.id);
+        // changes recorded before the feed existed only have the id
+        if rsvp.resource_id.is_empty() {
+            self.queries.clear();
+        } else {
+            // a reservation moved to another resource is in the queries of the old one
+            self.queries.retain(|query| {
+                query.resource_id != rsvp.resource_id && query.rsvps.iter().all(|r| r.id != rsvp.id)
+            });
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Entries<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            map: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get<Q>(&mut self, key: &Q, ttl: Duration) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let (value, inserted) = self.map.get(key)?;
+        if inserted.elapsed() < ttl {
+            return Some(value.clone());
+        }
+        self.map.remove(key);
+        None
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.map.contains_key(&key) {
+            while self.map.len() >= self.capacity {
+                match self.order.pop_front() {
+                    Some(oldest) => {
+                        self.map.remove(&oldest);
+                    }
+                    None => break,
+                }
+            }
+            self.order.push_back(key.clone());
+        }
+        self.map.insert(key, (value, Instant::now()));
+        // keep the keys of removed entries from piling up
+        if self.order.len() > self.capacity * 2 {
+            let map = &self.map;
+            self.order.retain(|key| map.contains_key(key));
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.map.remove(key);
+    }
+
+    fn retain(&mut self, f: impl Fn(&V) -> bool) {
+        self.map.retain(|_, (value, _)| f(value));
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+}
+
+/// whether a query is one of a resource window, the only ones that are cached
+fn cacheable(query: &abi::ReservationQuery) -> bool {
+    !query.resource_id.is_empty() && query.start.is_some() && query.end.is_some()
+}
+
+impl<S: fmt::Debug> fmt::Debug for CachedManager<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedManager")
+            .field("inner", &self.inner)
+            .field("cache", &self.cache)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Rsvp for CachedManager<S> {
+    // make a reservation, it's in the queries of its resource from now on
+    async fn reserve(&self, rsvp: abi::Reservation) -> Result<abi::Reservation, abi::Error> {
+        let rsvp = self.inner.reserve(rsvp).await?;
+        self.changed(&rsvp);
+        Ok(rsvp)
+    }
+    // make a reservation without checking the booking rules of the resource
+    async fn reserve_bypassing_rules(
+        &self,
+        rsvp: abi::Reservation,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let rsvp = self.inner.reserve_bypassing_rules(rsvp).await?;
+        self.changed(&rsvp);
+        Ok(rsvp)
+    }
+    // make several reservations in one transaction
+    async fn reserve_batch(
+        &self,
+        rsvps: Vec<abi::Reservation>,
+    ) -> Result<abi::ReserveBatchResponse, abi::Error> {
+        let resp = self.inner.reserve_batch(rsvps).await?;
+        self.clear();
+        Ok(resp)
+    }
+    // change reservation status
+    async fn change_status(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let rsvp = self.inner.change_status(id, expected_version).await?;
+        self.changed(&rsvp);
+        Ok(rsvp)
+    }
+    // update the fields of a reservation listed in the mask
+    async fn update(
+        &self,
+        rsvp: abi::Reservation,
+        mask: prost_types::FieldMask,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let rsvp = self.inner.update(rsvp, mask).await?;
+        self.changed(&rsvp);
+        Ok(rsvp)
+    }
+    // move or resize a reservation
+    async fn reschedule(
+        &self,
+        req: abi::RescheduleRequest,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let rsvp = self.inner.reschedule(req).await?;
+        self.changed(&rsvp);
+        Ok(rsvp)
+    }
+    // delete reservation
+    async fn delete(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let rsvp = self.inner.delete(id, expected_version).await?;
+        self.changed(&rsvp);
+        Ok(rsvp)
+    }
+    // get reservation by id, from the cache if it's there
+    async fn get(&self, id: i64) -> Result<abi::Reservation, abi::Error> {
+        let lookup = self.cache().get(id);
+        self.observe("get", lookup.is_ok());
+        let epoch = match lookup {
+            Ok(rsvp) => return Ok(rsvp),
+            Err(epoch) => epoch,
+        };
+        let rsvp = self.inner.get(id).await?;
+        self.cache().put_get(epoch, rsvp.clone());
+        Ok(rsvp)
+    }
+    // query reservations, the ones of a resource window from the cache if they're there
+    async fn query(
+        &self,
+        query: abi::ReservationQuery,
+    ) -> Result<Vec<abi::Reservation>, abi::Error> {
+        if !cacheable(&query) {
+            return self.inner.query(query).await;
+        }
+        let key = query.encode_to_vec();
+        let lookup = self.cache().query(&key);
+        self.observe("query", lookup.is_ok());
+        let epoch = match lookup {
+            Ok(rsvps) => return Ok(rsvps),
+            Err(epoch) => epoch,
+        };
+        let resource_id = query.resource_id.clone();
+        let rsvps = self.inner.query(query).await?;
+        let cached = CachedQuery {
+            resource_id,
+            rsvps: rsvps.clone(),
+        };
+        self.cache().put_query(epoch, key, cached);
+        Ok(rsvps)
+    }
+    // listen to changes matching the request filters
+    async fn listen(&self, req: abi::ListenRequest) -> Result<ChangeReceiver, abi::Error> {
+        self.inner.listen(req).await
+    }
+    // block resources, cancelled reservations are dropped from the cache with everything else
+    async fn block(&self, window: abi::BlockWindow) -> Result<abi::BlockResponse, abi::Error> {
+        let resp = self.inner.block(window).await?;
+        self.clear();
+        Ok(resp)
+    }
+    // create or update resource settings
+    async fn upsert_resource(&self, resource: abi::Resource) -> Result<abi::Resource, abi::Error> {
+        self.inner.upsert_resource(resource).await
+    }
+    // get resource settings
+    async fn get_resource(&self, id: String) -> Result<abi::Resource, abi::Error> {
+        self.inner.get_resource(id).await
+    }
+    // find free time slots of a resource
+    async fn availability(
+        &self,
+        req: abi::AvailabilityRequest,
+    ) -> Result<Vec<abi::TimeSlot>, abi::Error> {
+        self.inner.availability(req).await
+    }
+    // create or update a quota policy
+    async fn upsert_quota_policy(
+        &self,
+        policy: abi::QuotaPolicy,
+    ) -> Result<abi::QuotaPolicy, abi::Error> {
+        self.inner.upsert_quota_policy(policy).await
+    }
+    // delete a quota policy
+    async fn delete_quota_policy(&self, id: i64) -> Result<(), abi::Error> {
+        self.inner.delete_quota_policy(id).await
+    }
+    // replace the groups a user belongs to
+    async fn set_user_groups(
+        &self,
+        user_id: String,
+        group_ids: Vec<String>,
+    ) -> Result<(), abi::Error> {
+        self.inner.set_user_groups(user_id, group_ids).await
+    }
+    // get the current usage and remaining quota of a user
+    async fn get_quota(&self, user_id: String) -> Result<Vec<abi::QuotaUsage>, abi::Error> {
+        self.inner.get_quota(user_id).await
+    }
+    // get the change history of a reservation
+    async fn get_history(&self, id: i64) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error> {
+        self.inner.get_history(id).await
+    }
+    // query the change history by actor and time range
+    async fn query_history(
+        &self,
+        query: abi::HistoryQuery,
+    ) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error> {
+        self.inner.query_history(query).await
+    }
+    // create or update a webhook subscription
+    async fn upsert_webhook(&self, webhook: abi::Webhook) -> Result<abi::Webhook, abi::Error> {
+        self.inner.upsert_webhook(webhook).await
+    }
+    // delete a webhook subscription
+    async fn delete_webhook(&self, id: i64) -> Result<(), abi::Error> {
+        self.inner.delete_webhook(id).await
+    }
+    // list webhook subscriptions
+    async fn list_webhooks(&self) -> Result<Vec<abi::Webhook>, abi::Error> {
+        self.inner.list_webhooks().await
+    }
+    // get the delivery log of a webhook
+    async fn list_webhook_deliveries(
+        &self,
+        req: abi::ListWebhookDeliveriesRequest,
+    ) -> Result<Vec<abi::WebhookDelivery>, abi::Error> {
+        self.inner.list_webhook_deliveries(req).await
+    }
+    // put a dead delivery back into the queue
+    async fn retry_webhook_delivery(&self, id: i64) -> Result<abi::WebhookDelivery, abi::Error> {
+        self.inner.retry_webhook_delivery(id).await
+    }
+    // import the events of an iCalendar file, everything is dropped from the cache after it
+    async fn import_calendar(
+        &self,
+        req: abi::ImportCalendarRequest,
+    ) -> Result<abi::ImportCalendarResponse, abi::Error> {
+        let resp = self.inner.import_calendar(req).await?;
+        self.clear();
+        Ok(resp)
+    }
+    // remember a calendar feed token that was handed out
+    async fn add_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error> {
+        self.inner.add_calendar_feed_token(token).await
+    }
+    // whether the calendar feed token is valid, not cached so a revoked one stops working at once
+    async fn has_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<bool, abi::Error> {
+        self.inner.has_calendar_feed_token(token).await
+    }
+    // revoke a calendar feed token
+    async fn revoke_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error> {
+        self.inner.revoke_calendar_feed_token(token).await
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for CachedManager<S> {
+    // a handle whose changes are recorded as made by `actor`, sharing the cache
+    fn acting_as(&self, actor: &str) -> Self {
+        Self {
+            inner: self.inner.acting_as(actor),
+            cache: self.cache.clone(),
+            observer: self.observer.clone(),
+        }
+    }
+    // run `f` at most once per operation and idempotency key
+    async fn idempotent<Req, Resp, F, Fut>(
+        &self,
+        operation: &str,
+        key: &str,
+        req: &Req,
+        f: F,
+    ) -> Result<Resp, abi::Error>
+    where
+        Req: Message,
+        Resp: Message + Default,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<Resp, abi::Error>> + Send,
+    {
+        self.inner.idempotent(operation, key, req, f).await
+    }
+    // remove the idempotency keys older than the retention window
+    async fn purge_idempotency_keys(&self) -> Result<u64, abi::Error> {
+        self.inner.purge_idempotency_keys().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_types::FieldMask;
+
+    use super::*;
+    use crate::{InMemoryManager, ReservationManager};
+
+    // the lookups seen by the observer, in order
+    type Lookups = Arc<Mutex<Vec<(&'static str, bool)>>>;
+
+    async fn cached() -> (CachedManager<InMemoryManager>, InMemoryManager, Lookups) {
+        let inner = InMemoryManager::new();
+        let lookups = Lookups::default();
+        let seen = lookups.clone();
+        let manager = CachedManager::new(inner.clone(), 100, Duration::from_secs(60))
+            .await
+            .unwrap()
+            .with_observer(move |kind, hit| seen.lock().unwrap().push((kind, hit)));
+        (manager, inner, lookups)
+    }
+
+    fn rsvp(resource_id: &str, start: &str, end: &str) -> abi::Reservation {
+        let start = start.parse().unwrap();
+        let end = end.parse().unwrap();
+        abi::Reservation::new_pending("user1", resource_id, start, end, "just note")
+    }
+
+    // changes made elsewhere take a moment to come through the feed
+    async fn eventually<F, Fut>(f: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        for _ in 0..100 {
+            if f().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the cache wasn't invalidated");
+    }
+
+    // wait for the feed to bring the changes made so far, `epoch` counts the invalidations
+    async fn caught_up(manager: &CachedManager<InMemoryManager>, epoch: u64) {
+        eventually(|| async { manager.cache().epoch == epoch }).await;
+    }
+
+    #[tokio::test]
+    async fn get_should_be_cached_until_the_reservation_changes() {
+        let (manager, inner, lookups) = cached().await;
+        let rsvp = manager
+            .reserve(rsvp(
+                "room-1",
+                "2099-12-25T15:00:00Z",
+                "2099-12-25T18:00:00Z",
+            ))
+            .await
+            .unwrap();
+        caught_up(&manager, 2).await;
+        manager.get(rsvp.id).await.unwrap();
+        manager.get(rsvp.id).await.unwrap();
+        assert_eq!(*lookups.lock().unwrap(), [("get", false), ("get", true)]);
+
+        // changes made through the cache are seen right away
+        let confirmed = manager.change_status(rsvp.id, None).await.unwrap();
+        assert_eq!(manager.get(rsvp.id).await.unwrap(), confirmed);
+
+        // the ones made elsewhere once the feed brings them
+        inner.delete(rsvp.id, None).await.unwrap();
+        eventually(|| async { manager.get(rsvp.id).await.is_err() }).await;
+    }
+
+    #[tokio::test]
+    async fn resource_window_queries_should_be_invalidated_by_changes_of_the_resource() {
+        let (manager, inner, lookups) = cached().await;
+        manager
+            .reserve(rsvp(
+                "room-1",
+                "2099-12-25T15:00:00Z",
+                "2099-12-25T18:00:00Z",
+            ))
+            .await
+            .unwrap();
+        caught_up(&manager, 2).await;
+        let query = abi::ReservationQuery {
+            resource_id: "room-1".to_string(),
+            start: Some("2099-12-25T00:00:00Z".parse().unwrap()),
+            end: Some("2099-12-26T00:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(manager.query(query.clone()).await.unwrap().len(), 1);
+        assert_eq!(manager.query(query.clone()).await.unwrap().len(), 1);
+
+        // changes of another resource don't matter
+        manager
+            .reserve(rsvp(
+                "room-2",
+                "2099-12-25T15:00:00Z",
+                "2099-12-25T18:00:00Z",
+            ))
+            .await
+            .unwrap();
+        caught_up(&manager, 4).await;
+        assert_eq!(manager.query(query.clone()).await.unwrap().len(), 1);
+        assert_eq!(
+            *lookups.lock().unwrap(),
+            [("query", false), ("query", true), ("query", true)]
+        );
+
+        inner
+            .reserve(rsvp(
+                "room-1",
+                "2099-12-25T19:00:00Z",
+                "2099-12-25T20:00:00Z",
+            ))
+            .await
+            .unwrap();
+        eventually(|| async { manager.query(query.clone()).await.unwrap().len() == 2 }).await;
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn edits_made_on_another_replica_should_invalidate_the_cache() {
+        let replica = || async {
+            let manager = ReservationManager::new(migrated_pool.clone()).await;
+            CachedManager::new(manager, 100, Duration::from_secs(60))
+                .await
+                .unwrap()
+        };
+        let lookups = Lookups::default();
+        let seen = lookups.clone();
+        let (one, other) = (replica().await, replica().await);
+        let other = other.with_observer(move |kind, hit| seen.lock().unwrap().push((kind, hit)));
+        let rsvp = one
+            .reserve(rsvp(
+                "room-1",
+                "2099-12-25T15:00:00Z",
+                "2099-12-25T18:00:00Z",
+            ))
+            .await
+            .unwrap();
+        // the other replica has it cached
+        eventually(|| async {
+            other.get(rsvp.id).await.is_ok()
+                && lookups.lock().unwrap().last() == Some(&("get", true))
+        })
+        .await;
+
+        let noted = abi::Reservation {
+            note: "new note".to_string(),
+            ..rsvp.clone()
+        };
+        let mask = FieldMask {
+            paths: vec!["note".to_string()],
+        };
+        one.update(noted, mask).await.unwrap();
+        eventually(|| async { other.get(rsvp.id).await.unwrap().note == "new note" }).await;
+    }
+
+    #[test]
+    fn entries_should_expire_and_make_room() {
+        let mut entries = Entries::new(2);
+        let ttl = Duration::from_secs(60);
+        entries.insert(1, "a");
+        entries.insert(2, "b");
+        entries.insert(3, "c");
+        assert_eq!(entries.get(&1, ttl), None);
+        assert_eq!(entries.get(&2, ttl), Some("b"));
+        assert_eq!(entries.get(&3, ttl), Some("c"));
+        assert_eq!(entries.get(&3, Duration::ZERO), None);
+        assert_eq!(entries.map.len(), 1);
+    }
+}
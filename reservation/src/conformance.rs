@@ -0,0 +1,952 @@
+//! Cases every storage backend has to pass, run against Postgres, the in-memory backend (on its
+//! own and behind the cache) and SQLite when it's enabled
+
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, FixedOffset, SubsecRound, Utc};
+use prost_types::FieldMask;
+
+use crate::Storage;
+
+macro_rules! conformance {
+    ($($case:ident),* $(,)?) => {
+        mod postgres {
+            use crate::ReservationManager;
+
+            $(
+                #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+                async fn $case() {
+                    super::$case(ReservationManager::new(migrated_pool.clone()).await).await;
+                }
+            )*
+        }
+
+        mod memory {
+            use crate::InMemoryManager;
+
+            $(
+                #[tokio::test]
+                async fn $case() {
+                    super::$case(InMemoryManager::new()).await;
+                }
+            )*
+        }
+
+        mod cached {
+            use std::time::Duration;
+
+            use crate::{CachedManager, InMemoryManager};
+
+            $(
+                #[tokio::test]
+                async fn $case() {
+                    let cached = CachedManager::new(InMemoryManager::new(), 100, Duration::from_secs(60));
+                    super::$case(cached.await.unwrap()).await;
+                }
+            )*
+        }
+
+        #[cfg(feature = "sqlite")]
+        mod sqlite {
+            use crate::SqliteManager;
+
+            $(
+                #[tokio::test]
+                async fn $case() {
+                    let manager = SqliteManager::connect("sqlite::memory:", 1).await.unwrap();
+                    super::$case(manager).await;
+                }
+            )*
+        }
+    };
+}
+
+conformance!(
+    reserve_should_conflict_within_buffers,
+    version_should_increase_only_on_change,
+    stale_version_should_be_rejected,
+    update_should_only_change_masked_fields,
+    update_should_check_quotas_and_blocked_users,
+    reschedule_should_move_reservation,
+    reschedule_should_check_quotas,
+    reschedule_should_only_check_the_past_when_start_moves,
+    deleted_reservation_should_be_gone,
+    query_should_filter_and_order,
+    reserve_batch_should_be_all_or_nothing,
+    block_should_cancel_pending_and_be_all_or_nothing,
+    block_should_repeat_recurring_windows,
+    availability_should_account_for_buffers,
+    reserve_should_check_booking_rules,
+    reserve_should_check_quotas,
+    history_should_record_actors,
+    listen_should_replay_then_go_live,
+    import_calendar_should_honour_mode,
+    import_calendar_should_only_allow_the_past,
+    idempotent_should_replay_responses,
+    webhooks_should_hide_secrets,
+    calendar_feed_tokens_should_be_revocable,
+);
+
+async fn reserve_should_conflict_within_buffers<S: Storage>(s: S) {
+    // unknown resources have no buffers
+    let unknown = s.get_resource("room-1".to_string()).await.unwrap();
+    assert_eq!(unknown.get_pre_buffer(), Duration::zero());
+    assert_eq!(unknown.get_post_buffer(), Duration::zero());
+
+    let resource = abi::Resource::new("room-1", Duration::zero(), Duration::minutes(15));
+    let resource = s.upsert_resource(resource).await.unwrap();
+    assert_eq!(resource.get_post_buffer(), Duration::minutes(15));
+    assert_eq!(
+        s.get_resource("room-1".to_string()).await.unwrap(),
+        resource
+    );
+
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    assert!(rsvp.id > 0);
+    assert_eq!(rsvp.version, 1);
+    assert_eq!(rsvp.status, abi::ReservationStatus::Pending as i32);
+    assert_eq!(s.get(rsvp.id).await.unwrap(), rsvp);
+
+    let late = abi::Reservation {
+        start: Some(abi::convert_to_timestamp(at(11) + Duration::minutes(10))),
+        ..pending("user2", "room-1", 11, 12)
+    };
+    let err = s.reserve(late).await.unwrap_err();
+    assert!(matches!(err, abi::Error::ConflictReservation(_)));
+
+    let after_cleaning = abi::Reservation {
+        start: Some(abi::convert_to_timestamp(at(11) + Duration::minutes(15))),
+        ..pending("user2", "room-1", 11, 12)
+    };
+    s.reserve(after_cleaning).await.unwrap();
+    // resources without buffers can be booked back to back, and resources don't conflict
+    s.reserve(pending("user1", "room-2", 10, 11)).await.unwrap();
+    s.reserve(pending("user2", "room-2", 11, 12)).await.unwrap();
+    s.reserve(pending("user3", "room-3", 10, 12)).await.unwrap();
+}
+
+async fn version_should_increase_only_on_change<S: Storage>(s: S) {
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    let confirmed = s.change_status(rsvp.id, Some(1)).await.unwrap();
+    assert_eq!(confirmed.status, abi::ReservationStatus::Confirmed as i32);
+    assert_eq!(confirmed.version, 2);
+    // already confirmed, nothing changes
+    let again = s.change_status(rsvp.id, None).await.unwrap();
+    assert_eq!(again, confirmed);
+}
+
+async fn stale_version_should_be_rejected<S: Storage>(s: S) {
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    s.change_status(rsvp.id, None).await.unwrap();
+
+    let err = s.change_status(rsvp.id, Some(1)).await.unwrap_err();
+    assert!(matches!(
+        err,
+        abi::Error::VersionMismatch {
+            expected: 1,
+            current: 2
+        }
+    ));
+    let err = s.delete(rsvp.id, Some(1)).await.unwrap_err();
+    assert!(matches!(err, abi::Error::VersionMismatch { .. }));
+    let err = s.update(rsvp.clone(), mask(&["note"])).await.unwrap_err();
+    assert!(matches!(err, abi::Error::VersionMismatch { .. }));
+    s.delete(rsvp.id, Some(2)).await.unwrap();
+}
+
+async fn update_should_only_change_masked_fields<S: Storage>(s: S) {
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    let other = s.reserve(pending("user1", "room-1", 12, 13)).await.unwrap();
+
+    let changes = abi::Reservation {
+        note: "new note".to_string(),
+        user_id: "user2".to_string(),
+        version: 0,
+        ..rsvp.clone()
+    };
+    let noted = s.update(changes, mask(&["note"])).await.unwrap();
+    assert_eq!(noted.note, "new note");
+    assert_eq!(noted.user_id, "user1");
+    assert_eq!(noted.version, 2);
+
+    // the end is merged with the current start, and checked for conflicts
+    let longer = abi::Reservation {
+        end: Some(abi::convert_to_timestamp(at(12) + Duration::minutes(30))),
+        ..noted.clone()
+    };
+    let err = s.update(longer, mask(&["end"])).await.unwrap_err();
+    assert!(matches!(err, abi::Error::ConflictReservation(_)));
+    assert_eq!(s.get(rsvp.id).await.unwrap(), noted);
+    assert_eq!(s.get(other.id).await.unwrap(), other);
+
+    let longer = abi::Reservation {
+        end: Some(abi::convert_to_timestamp(at(12))),
+        ..noted.clone()
+    };
+    let longer = s.update(longer, mask(&["end"])).await.unwrap();
+    assert_eq!(longer.start, noted.start);
+    assert_eq!(longer.version, 3);
+
+    let err = s.update(longer, mask(&["status"])).await.unwrap_err();
+    assert!(matches!(err, abi::Error::ImmutableField(_)));
+}
+
+async fn update_should_check_quotas_and_blocked_users<S: Storage>(s: S) {
+    let policy = abi::QuotaPolicy {
+        kind: abi::QuotaKind::MaxWeeklyHours as i32,
+        user_id: "user1".to_string(),
+        limit: 3,
+        ..Default::default()
+    };
+    s.upsert_quota_policy(policy).await.unwrap();
+    let policy = abi::QuotaPolicy {
+        kind: abi::QuotaKind::MaxActive as i32,
+        user_id: "user2".to_string(),
+        limit: 1,
+        ..Default::default()
+    };
+    s.upsert_quota_policy(policy).await.unwrap();
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 12)).await.unwrap();
+    s.reserve(pending("user2", "room-2", 10, 12)).await.unwrap();
+
+    let longer = |hour| abi::Reservation {
+        end: Some(abi::convert_to_timestamp(at(hour))),
+        version: 0,
+        ..rsvp.clone()
+    };
+    s.update(longer(13), mask(&["end"])).await.unwrap();
+    let err = s.update(longer(14), mask(&["end"])).await.unwrap_err();
+    assert!(matches!(err, abi::Error::QuotaExceeded { limit: 3, .. }));
+    let handed_over = abi::Reservation {
+        user_id: "user2".to_string(),
+        ..longer(13)
+    };
+    let err = s.update(handed_over, mask(&["user_id"])).await.unwrap_err();
+    assert!(matches!(err, abi::Error::QuotaExceeded { limit: 1, .. }));
+
+    let resp = s.block(block_window(vec!["room-3"], false)).await.unwrap();
+    let block = abi::Reservation {
+        user_id: "user1".to_string(),
+        ..resp.blocks[0].clone()
+    };
+    let err = s.update(block, mask(&["user_id"])).await.unwrap_err();
+    assert!(matches!(err, abi::Error::InvalidUserId(_)));
+}
+
+async fn reschedule_should_move_reservation<S: Storage>(s: S) {
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 12)).await.unwrap();
+    let other = s.reserve(pending("user2", "room-1", 13, 14)).await.unwrap();
+
+    // overlapping its own current window is fine
+    let moved = s.reschedule(reschedule(rsvp.id, 11, 13)).await.unwrap();
+    assert_eq!(moved.start, Some(abi::convert_to_timestamp(at(11))));
+    assert_eq!(moved.version, 2);
+
+    let err = s.reschedule(reschedule(rsvp.id, 12, 14)).await.unwrap_err();
+    assert!(matches!(err, abi::Error::ConflictReservation(_)));
+    assert_eq!(s.get(rsvp.id).await.unwrap(), moved);
+
+    let req = abi::RescheduleRequest {
+        resource_id: "room-2".to_string(),
+        ..reschedule(rsvp.id, 13, 14)
+    };
+    let moved = s.reschedule(req).await.unwrap();
+    assert_eq!(moved.resource_id, "room-2");
+    // its old window is free again
+    s.reserve(pending("user3", "room-1", 11, 13)).await.unwrap();
+    assert_eq!(s.get(other.id).await.unwrap(), other);
+}
+
+async fn reschedule_should_check_quotas<S: Storage>(s: S) {
+    let policy = abi::QuotaPolicy {
+        kind: abi::QuotaKind::MaxWeeklyHours as i32,
+        user_id: "user1".to_string(),
+        limit: 3,
+        ..Default::default()
+    };
+    s.upsert_quota_policy(policy).await.unwrap();
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 12)).await.unwrap();
+
+    // the hours it had before don't count
+    s.reschedule(reschedule(rsvp.id, 10, 13)).await.unwrap();
+    let err = s.reschedule(reschedule(rsvp.id, 10, 14)).await.unwrap_err();
+    assert!(matches!(err, abi::Error::QuotaExceeded { limit: 3, .. }));
+    let req = abi::RescheduleRequest {
+        resource_id: "room-2".to_string(),
+        ..reschedule(rsvp.id, 11, 14)
+    };
+    s.reschedule(req).await.unwrap();
+}
+
+async fn reschedule_should_only_check_the_past_when_start_moves<S: Storage>(s: S) {
+    let now = Utc::now().trunc_subsecs(0);
+    let ts = |hours| Some(abi::convert_to_timestamp(now + Duration::hours(hours)));
+    let started = abi::Reservation {
+        start: ts(-1),
+        end: ts(1),
+        ..pending("user1", "room-1", 10, 11)
+    };
+    let started = s.reserve_bypassing_rules(started).await.unwrap();
+
+    let extend = abi::RescheduleRequest {
+        id: started.id,
+        start: ts(-1),
+        end: ts(2),
+        ..Default::default()
+    };
+    s.reschedule(extend).await.unwrap();
+    let earlier = abi::RescheduleRequest {
+        id: started.id,
+        start: ts(-2),
+        end: ts(2),
+        ..Default::default()
+    };
+    let err = s.reschedule(earlier).await.unwrap_err();
+    assert!(matches!(err, abi::Error::BookingRuleViolation("past", _)));
+
+    let err = s
+        .reschedule(reschedule(started.id, 12, 12))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, abi::Error::InvalidReservation));
+}
+
+async fn deleted_reservation_should_be_gone<S: Storage>(s: S) {
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    assert_eq!(s.delete(rsvp.id, None).await.unwrap(), rsvp);
+
+    for err in [
+        s.get(rsvp.id).await.unwrap_err(),
+        s.delete(rsvp.id, None).await.unwrap_err(),
+        s.change_status(rsvp.id, None).await.unwrap_err(),
+    ] {
+        assert!(matches!(err, abi::Error::DbError(sqlx::Error::RowNotFound)));
+    }
+    // the window can be booked again
+    s.reserve(pending("user2", "room-1", 10, 11)).await.unwrap();
+}
+
+async fn query_should_filter_and_order<S: Storage>(s: S) {
+    let late = s.reserve(pending("user1", "room-1", 14, 15)).await.unwrap();
+    let early = s.reserve(pending("user1", "room-2", 8, 9)).await.unwrap();
+    let other = s.reserve(pending("user2", "room-1", 10, 11)).await.unwrap();
+    let early = s.change_status(early.id, None).await.unwrap();
+
+    let query = |user_id: &str, resource_id: &str, status, desc| abi::ReservationQuery {
+        user_id: user_id.to_string(),
+        resource_id: resource_id.to_string(),
+        status: status as i32,
+        desc,
+        ..Default::default()
+    };
+    let unknown = abi::ReservationStatus::Unknown;
+    let all = s.query(query("", "", unknown, false)).await.unwrap();
+    assert_eq!(all, vec![early.clone(), other.clone(), late.clone()]);
+    let desc = s.query(query("", "", unknown, true)).await.unwrap();
+    assert_eq!(desc, vec![late.clone(), other.clone(), early.clone()]);
+
+    let rsvps = s.query(query("user1", "", unknown, false)).await.unwrap();
+    assert_eq!(rsvps, vec![early.clone(), late.clone()]);
+    let rsvps = s.query(query("", "room-1", unknown, false)).await.unwrap();
+    assert_eq!(rsvps, vec![other.clone(), late.clone()]);
+    let confirmed = abi::ReservationStatus::Confirmed;
+    let rsvps = s.query(query("", "", confirmed, false)).await.unwrap();
+    assert_eq!(rsvps, vec![early]);
+
+    // [start, end), touching reservations don't match
+    let window = abi::ReservationQuery {
+        start: Some(abi::convert_to_timestamp(at(9))),
+        end: Some(abi::convert_to_timestamp(at(14))),
+        ..Default::default()
+    };
+    assert_eq!(s.query(window).await.unwrap(), vec![other]);
+    let open_ended = abi::ReservationQuery {
+        start: Some(abi::convert_to_timestamp(at(12))),
+        ..Default::default()
+    };
+    assert_eq!(s.query(open_ended).await.unwrap(), vec![late]);
+}
+
+async fn reserve_batch_should_be_all_or_nothing<S: Storage>(s: S) {
+    let rsvps = vec![
+        pending("user1", "room-1", 10, 11),
+        pending("user1", "room-1", 10, 12),
+        pending("user1", "room-2", 10, 11),
+    ];
+    let resp = s.reserve_batch(rsvps).await.unwrap();
+    assert!(!resp.committed);
+    let errors: Vec<bool> = resp.results.iter().map(|r| r.error.is_empty()).collect();
+    assert_eq!(errors, vec![true, false, true]);
+    assert!(resp
+        .results
+        .iter()
+        .all(|r| r.reservation.as_ref().unwrap().id == 0));
+    assert!(s.query(Default::default()).await.unwrap().is_empty());
+
+    let rsvps = vec![
+        pending("user1", "room-1", 10, 11),
+        pending("user1", "room-2", 10, 11),
+    ];
+    let resp = s.reserve_batch(rsvps).await.unwrap();
+    assert!(resp.committed);
+    for result in resp.results {
+        let rsvp = result.reservation.unwrap();
+        assert_eq!(s.get(rsvp.id).await.unwrap(), rsvp);
+    }
+}
+
+async fn block_should_cancel_pending_and_be_all_or_nothing<S: Storage>(s: S) {
+    let rsvp = s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    let confirmed = s.reserve(pending("user1", "room-2", 10, 11)).await.unwrap();
+    let confirmed = s.change_status(confirmed.id, None).await.unwrap();
+
+    // room-2 is taken, so room-3 isn't blocked either and nothing is cancelled
+    let window = block_window(vec!["room-1", "room-3", "room-2"], true);
+    let err = s.block(window).await.unwrap_err();
+    assert!(matches!(err, abi::Error::ConflictReservation(_)));
+    assert_eq!(s.get(rsvp.id).await.unwrap(), rsvp);
+    let blocked = abi::ReservationQuery {
+        status: abi::ReservationStatus::Blocked as i32,
+        ..Default::default()
+    };
+    assert!(s.query(blocked.clone()).await.unwrap().is_empty());
+
+    let resp = s
+        .block(block_window(vec!["room-1", "room-3"], true))
+        .await
+        .unwrap();
+    assert_eq!(resp.cancelled, vec![rsvp]);
+    assert_eq!(resp.blocks.len(), 2);
+    let block = &resp.blocks[0];
+    assert!(block.is_blocked() && block.user_id.is_empty());
+    assert_eq!(block.note, "maintenance");
+    assert_eq!(s.query(blocked).await.unwrap(), resp.blocks);
+    assert_eq!(s.get(confirmed.id).await.unwrap(), confirmed);
+}
+
+async fn block_should_repeat_recurring_windows<S: Storage>(s: S) {
+    let window = abi::BlockWindow {
+        recurrence: Some(abi::Recurrence {
+            frequency: abi::RecurrenceFrequency::Weekly as i32,
+            interval: 1,
+            count: 3,
+            until: None,
+        }),
+        ..block_window(vec!["room-1", "room-2"], false)
+    };
+    let resp = s.block(window).await.unwrap();
+    assert_eq!(resp.blocks.len(), 6);
+    assert!(resp.cancelled.is_empty());
+
+    let query = abi::ReservationQuery {
+        resource_id: "room-2".to_string(),
+        status: abi::ReservationStatus::Blocked as i32,
+        ..Default::default()
+    };
+    let blocks = s.query(query).await.unwrap();
+    let starts: Vec<_> = blocks.iter().map(|b| b.start.clone().unwrap()).collect();
+    let weekly: Vec<_> = [0, 1, 2]
+        .iter()
+        .map(|week| abi::convert_to_timestamp(at(8) + Duration::weeks(*week)))
+        .collect();
+    assert_eq!(starts, weekly);
+}
+
+async fn availability_should_account_for_buffers<S: Storage>(s: S) {
+    s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    let resource = abi::Resource::new("room-1", Duration::hours(1), Duration::hours(2));
+    s.upsert_resource(resource).await.unwrap();
+    // buffers of existing reservations are fixed when they are made
+    s.reserve(pending("user1", "room-1", 16, 17)).await.unwrap();
+
+    let req = abi::AvailabilityRequest {
+        resource_id: "room-1".to_string(),
+        start: Some(abi::convert_to_timestamp(at(6))),
+        end: Some(abi::convert_to_timestamp(at(22))),
+    };
+    let slots = s.availability(req).await.unwrap();
+    // new reservations need room for their own buffers too
+    assert_eq!(
+        slots,
+        vec![
+            abi::TimeSlot::new(at(6), at(8)),
+            abi::TimeSlot::new(at(12), at(13)),
+            abi::TimeSlot::new(at(20), at(22)),
+        ]
+    );
+}
+
+async fn reserve_should_check_booking_rules<S: Storage>(s: S) {
+    let resource = abi::Resource {
+        max_duration: Some(abi::convert_to_pb_duration(Duration::hours(2))),
+        ..abi::Resource::new("room-1", Duration::zero(), Duration::zero())
+    };
+    s.upsert_resource(resource).await.unwrap();
+
+    let rsvp = pending("user1", "room-1", 10, 13);
+    let err = s.reserve(rsvp.clone()).await.unwrap_err();
+    assert!(matches!(
+        err,
+        abi::Error::BookingRuleViolation("max_duration", _)
+    ));
+    let rsvp = s.reserve_bypassing_rules(rsvp).await.unwrap();
+    let err = s.reschedule(reschedule(rsvp.id, 14, 17)).await.unwrap_err();
+    assert!(matches!(err, abi::Error::BookingRuleViolation(..)));
+
+    let past = abi::Reservation {
+        start: Some(abi::convert_to_timestamp(Utc::now() - Duration::days(2))),
+        end: Some(abi::convert_to_timestamp(Utc::now() - Duration::days(1))),
+        ..pending("user1", "room-2", 10, 11)
+    };
+    let err = s.reserve(past).await.unwrap_err();
+    assert!(matches!(err, abi::Error::BookingRuleViolation("past", _)));
+}
+
+async fn reserve_should_check_quotas<S: Storage>(s: S) {
+    let policy = abi::QuotaPolicy {
+        kind: abi::QuotaKind::MaxActive as i32,
+        user_id: "user1".to_string(),
+        limit: 1,
+        ..Default::default()
+    };
+    let policy = s.upsert_quota_policy(policy).await.unwrap();
+    s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    let err = s
+        .reserve(pending("user1", "room-2", 10, 11))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        abi::Error::QuotaExceeded { used, limit: 1, .. } if used == 1.0
+    ));
+    let usages = s.get_quota("user1".to_string()).await.unwrap();
+    assert_eq!(usages, vec![abi::QuotaUsage::new(policy.clone(), 1.0)]);
+    s.delete_quota_policy(policy.id).await.unwrap();
+
+    s.set_user_groups("user2".to_string(), vec!["interns".to_string()])
+        .await
+        .unwrap();
+    let policy = abi::QuotaPolicy {
+        kind: abi::QuotaKind::MaxWeeklyHours as i32,
+        group_id: "interns".to_string(),
+        limit: 3,
+        ..Default::default()
+    };
+    s.upsert_quota_policy(policy).await.unwrap();
+    s.reserve(pending("user2", "room-1", 12, 14)).await.unwrap();
+    let err = s
+        .reserve(pending("user2", "room-2", 12, 14))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, abi::Error::QuotaExceeded { limit: 3, .. }));
+    s.reserve(pending("user1", "room-2", 12, 14)).await.unwrap();
+
+    // only reservations of resources of the policy's kind count
+    for id in ["car-1", "car-2"] {
+        let resource = abi::Resource {
+            kind: "vehicle".to_string(),
+            ..abi::Resource::new(id, Duration::zero(), Duration::zero())
+        };
+        s.upsert_resource(resource).await.unwrap();
+    }
+    let policy = abi::QuotaPolicy {
+        kind: abi::QuotaKind::MaxFuturePerKind as i32,
+        user_id: "user4".to_string(),
+        limit: 1,
+        resource_kind: "vehicle".to_string(),
+        ..Default::default()
+    };
+    s.upsert_quota_policy(policy).await.unwrap();
+    s.reserve(pending("user4", "car-1", 10, 11)).await.unwrap();
+    s.reserve(pending("user4", "room-5", 10, 11)).await.unwrap();
+    let err = s
+        .reserve(pending("user4", "car-2", 10, 11))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, abi::Error::QuotaExceeded { limit: 1, .. }));
+
+    // the hours of a reservation crossing into the next week (at 48) count in both weeks
+    let policy = abi::QuotaPolicy {
+        kind: abi::QuotaKind::MaxWeeklyHours as i32,
+        user_id: "user3".to_string(),
+        limit: 3,
+        ..Default::default()
+    };
+    s.upsert_quota_policy(policy).await.unwrap();
+    s.reserve(pending("user3", "room-3", 49, 51)).await.unwrap();
+    let err = s
+        .reserve(pending("user3", "room-4", 47, 50))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        abi::Error::QuotaExceeded { used, limit: 3, .. } if used == 2.0
+    ));
+    s.reserve(pending("user3", "room-4", 46, 49)).await.unwrap();
+
+    let err = s.set_user_groups(String::new(), vec![]).await.unwrap_err();
+    assert!(matches!(err, abi::Error::InvalidUserId(_)));
+}
+
+async fn history_should_record_actors<S: Storage>(s: S) {
+    let alice = s.acting_as("alice");
+    let rsvp = alice
+        .reserve(pending("user1", "room-1", 10, 11))
+        .await
+        .unwrap();
+    let confirmed = s
+        .acting_as("bob")
+        .change_status(rsvp.id, None)
+        .await
+        .unwrap();
+    s.delete(rsvp.id, None).await.unwrap();
+
+    let history = s.get_history(rsvp.id).await.unwrap();
+    let ops: Vec<_> = history.iter().map(|e| e.op()).collect();
+    assert_eq!(
+        ops,
+        vec![
+            abi::ReservationUpdateType::Create,
+            abi::ReservationUpdateType::Update,
+            abi::ReservationUpdateType::Delete,
+        ]
+    );
+    let actors: Vec<_> = history.iter().map(|e| e.actor.as_str()).collect();
+    assert_eq!(actors, vec!["alice", "bob", ""]);
+    assert_eq!(history[1].before.as_ref(), Some(&rsvp));
+    assert_eq!(history[1].after.as_ref(), Some(&confirmed));
+    assert_eq!(history[2].after, None);
+
+    let query = abi::HistoryQuery {
+        actor: "bob".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(
+        s.query_history(query).await.unwrap(),
+        vec![history[1].clone()]
+    );
+    let query = abi::HistoryQuery {
+        end: Some(abi::convert_to_timestamp(Utc::now() - Duration::hours(1))),
+        ..Default::default()
+    };
+    assert!(s.query_history(query).await.unwrap().is_empty());
+    let query = abi::HistoryQuery {
+        desc: true,
+        ..Default::default()
+    };
+    let newest_first: Vec<_> = history.into_iter().rev().collect();
+    assert_eq!(s.query_history(query).await.unwrap(), newest_first);
+}
+
+async fn listen_should_replay_then_go_live<S: Storage>(s: S) {
+    let first = s.reserve(pending("user1", "room-1", 10, 11)).await.unwrap();
+    let noted = abi::Reservation {
+        note: "new note".to_string(),
+        ..first.clone()
+    };
+    // any edit is a change, not only those of the status or the time
+    s.update(noted, mask(&["note"])).await.unwrap();
+    s.change_status(first.id, None).await.unwrap();
+
+    let mut rx = s.listen(since(0)).await.unwrap();
+    let created = recv(&mut rx).await;
+    assert_eq!(created.op(), abi::ReservationUpdateType::Create);
+    assert_eq!(created.reservation.as_ref().unwrap().id, first.id);
+    let noted = recv(&mut rx).await;
+    assert_eq!(noted.op(), abi::ReservationUpdateType::Update);
+    assert_eq!(noted.reservation.as_ref().unwrap().note, "new note");
+    let confirmed = recv(&mut rx).await;
+    assert_eq!(confirmed.op(), abi::ReservationUpdateType::Update);
+    assert!(confirmed.sequence > created.sequence);
+    assert_eq!(
+        confirmed.reservation.as_ref().unwrap().status,
+        abi::ReservationStatus::Confirmed as i32
+    );
+
+    let mut live = s.listen(Default::default()).await.unwrap();
+    let filtered = abi::ListenRequest {
+        resource_ids: vec!["room-2".to_string()],
+        ops: vec![abi::ReservationUpdateType::Delete as i32],
+        ..since(0)
+    };
+    let mut filtered = s.listen(filtered).await.unwrap();
+    let second = s.reserve(pending("user1", "room-2", 10, 11)).await.unwrap();
+    s.delete(second.id, None).await.unwrap();
+    for rx in [&mut rx, &mut live] {
+        let change = recv(rx).await;
+        assert_eq!(change.op(), abi::ReservationUpdateType::Create);
+        assert_eq!(change.reservation.unwrap().id, second.id);
+        assert_eq!(recv(rx).await.op(), abi::ReservationUpdateType::Delete);
+    }
+    let deleted = recv(&mut filtered).await;
+    assert_eq!(deleted.op(), abi::ReservationUpdateType::Delete);
+    assert_eq!(deleted.reservation.unwrap().id, second.id);
+
+    // the new user of a reservation sees it coming
+    let mut user2 = s
+        .listen(abi::ListenRequest {
+            user_id: "user2".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let given = abi::Reservation {
+        user_id: "user2".to_string(),
+        ..s.get(first.id).await.unwrap()
+    };
+    s.update(given, mask(&["user_id"])).await.unwrap();
+    let given = recv(&mut user2).await;
+    assert_eq!(given.op(), abi::ReservationUpdateType::Update);
+    assert_eq!(given.reservation.unwrap().id, first.id);
+}
+
+async fn import_calendar_should_honour_mode<S: Storage>(s: S) {
+    // the 25th and 26th overlap it
+    let rsvp = abi::Reservation {
+        start: Some(abi::convert_to_timestamp(at(10) - Duration::hours(30))),
+        ..pending("user1", "room-1", 10, 11)
+    };
+    let rsvp = s.reserve(rsvp).await.unwrap();
+
+    let resp = s
+        .import_calendar(import(abi::ImportMode::DryRun, 4))
+        .await
+        .unwrap();
+    assert_eq!(resp.created, 0);
+    let errors: Vec<bool> = resp.results.iter().map(|r| r.error.is_empty()).collect();
+    assert_eq!(errors, vec![true, true, false, false]);
+    assert!(resp.results[2].error.starts_with("Conflict reservation"));
+    let block = resp.results[0].reservation.as_ref().unwrap();
+    assert!(block.is_blocked() && block.id == 0);
+    assert_eq!(block.labels["ics_uid"], "holidays");
+
+    let resp = s
+        .import_calendar(import(abi::ImportMode::Transactional, 4))
+        .await
+        .unwrap();
+    assert_eq!(resp.created, 0);
+    assert_eq!(s.query(Default::default()).await.unwrap(), vec![rsvp]);
+
+    let resp = s
+        .import_calendar(import(abi::ImportMode::BestEffort, 4))
+        .await
+        .unwrap();
+    assert_eq!(resp.created, 2);
+    let query = abi::ReservationQuery {
+        status: abi::ReservationStatus::Blocked as i32,
+        ..Default::default()
+    };
+    let blocks = s.query(query).await.unwrap();
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].note, "Holidays");
+}
+
+async fn import_calendar_should_only_allow_the_past<S: Storage>(s: S) {
+    let resource = abi::Resource {
+        max_duration: Some(abi::convert_to_pb_duration(Duration::hours(2))),
+        ..abi::Resource::new("room-1", Duration::zero(), Duration::zero())
+    };
+    s.upsert_resource(resource).await.unwrap();
+
+    let event = |uid: &str, start: &str, end: &str| {
+        format!("BEGIN:VEVENT\r\nUID:{uid}\r\nDTSTART:{start}\r\nDTEND:{end}\r\nEND:VEVENT\r\n")
+    };
+    let ics = format!(
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{}{}END:VCALENDAR\r\n",
+        event("standup", "20200106T100000Z", "20200106T110000Z"),
+        event("offsite", "20200107T100000Z", "20200107T170000Z"),
+    );
+    let req = abi::ImportCalendarRequest {
+        ics: ics.into_bytes(),
+        resource_id: "room-1".to_string(),
+        user_id: "user1".to_string(),
+        mode: abi::ImportMode::BestEffort as i32,
+        ..Default::default()
+    };
+    let resp = s.import_calendar(req).await.unwrap();
+    assert_eq!(resp.created, 1);
+    assert!(resp.results[0].error.is_empty());
+    assert!(resp.results[1].error.contains("max_duration"));
+}
+
+async fn idempotent_should_replay_responses<S: Storage>(s: S) {
+    let req = abi::ReserveRequest {
+        reservation: Some(pending("user1", "room-1", 10, 11)),
+        idempotency_key: "key-1".to_string(),
+        ..Default::default()
+    };
+    let reserve = |req: abi::ReserveRequest| {
+        let s = s.clone();
+        async move {
+            s.idempotent("reserve", &req.idempotency_key, &req, || async {
+                let rsvp = s.reserve(req.reservation.clone().unwrap()).await?;
+                Ok(abi::ReserveResponse {
+                    reservation: Some(rsvp),
+                })
+            })
+            .await
+        }
+    };
+
+    let resp = reserve(req.clone()).await.unwrap();
+    // without the key the retry would conflict with the first reservation
+    assert_eq!(reserve(req.clone()).await.unwrap(), resp);
+
+    let other = abi::ReserveRequest {
+        reservation: Some(pending("user1", "room-1", 12, 13)),
+        ..req.clone()
+    };
+    let err = reserve(other).await.unwrap_err();
+    assert!(matches!(err, abi::Error::IdempotencyKeyReused(_)));
+
+    // a failed attempt releases its key
+    let taken = abi::ReserveRequest {
+        idempotency_key: "key-2".to_string(),
+        ..req
+    };
+    assert!(reserve(taken.clone()).await.is_err());
+    let freed = abi::ReserveRequest {
+        reservation: Some(pending("user1", "room-1", 12, 13)),
+        ..taken
+    };
+    reserve(freed).await.unwrap();
+}
+
+async fn webhooks_should_hide_secrets<S: Storage>(s: S) {
+    let webhook = abi::Webhook {
+        url: "https://example.com/hook".to_string(),
+        secret: "s3cret".to_string(),
+        resource_ids: vec!["room-1".to_string()],
+        ops: vec![abi::ReservationUpdateType::Create as i32],
+        ..Default::default()
+    };
+    let created = s.upsert_webhook(webhook.clone()).await.unwrap();
+    assert!(created.id > 0);
+    assert!(created.secret.is_empty());
+    assert_eq!(created.ops, webhook.ops);
+    assert_eq!(s.list_webhooks().await.unwrap(), vec![created.clone()]);
+
+    // an empty secret keeps the current one
+    let updated = abi::Webhook {
+        url: "https://example.com/other".to_string(),
+        ..created.clone()
+    };
+    let updated = s.upsert_webhook(updated).await.unwrap();
+    assert_eq!(updated.id, created.id);
+    assert_eq!(updated.url, "https://example.com/other");
+
+    let missing = abi::Webhook {
+        id: updated.id + 1,
+        ..updated.clone()
+    };
+    let err = s.upsert_webhook(missing).await.unwrap_err();
+    assert!(matches!(err, abi::Error::DbError(sqlx::Error::RowNotFound)));
+    let err = s.upsert_webhook(abi::Webhook::default()).await.unwrap_err();
+    assert!(matches!(err, abi::Error::InvalidWebhook(_)));
+
+    s.delete_webhook(created.id).await.unwrap();
+    assert!(s.list_webhooks().await.unwrap().is_empty());
+}
+
+async fn calendar_feed_tokens_should_be_revocable<S: Storage>(s: S) {
+    let token = |nonce: &str, user_id: &str| abi::CalendarFeedToken {
+        nonce: nonce.to_string(),
+        user_id: user_id.to_string(),
+        resource_id: String::new(),
+    };
+    s.add_calendar_feed_token(token("n1", "alice"))
+        .await
+        .unwrap();
+    s.add_calendar_feed_token(token("n2", "alice"))
+        .await
+        .unwrap();
+    assert!(s
+        .has_calendar_feed_token(token("n1", "alice"))
+        .await
+        .unwrap());
+    // a nonce only goes with the feed it was handed out for
+    assert!(!s.has_calendar_feed_token(token("n1", "bob")).await.unwrap());
+
+    s.revoke_calendar_feed_token(token("n1", "alice"))
+        .await
+        .unwrap();
+    assert!(!s
+        .has_calendar_feed_token(token("n1", "alice"))
+        .await
+        .unwrap());
+    assert!(s
+        .has_calendar_feed_token(token("n2", "alice"))
+        .await
+        .unwrap());
+    let err = s
+        .revoke_calendar_feed_token(token("n1", "alice"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, abi::Error::DbError(sqlx::Error::RowNotFound)));
+}
+
+/// 2099-12-26 at `hour`, UTC
+fn at(hour: i64) -> DateTime<Utc> {
+    let day: DateTime<FixedOffset> = "2099-12-26T00:00:00+0000".parse().unwrap();
+    day.with_timezone(&Utc) + Duration::hours(hour)
+}
+
+fn pending(uid: &str, rid: &str, start: i64, end: i64) -> abi::Reservation {
+    abi::Reservation::new_pending(uid, rid, at(start).into(), at(end).into(), "just note")
+}
+
+fn reschedule(id: i64, start: i64, end: i64) -> abi::RescheduleRequest {
+    abi::RescheduleRequest {
+        id,
+        start: Some(abi::convert_to_timestamp(at(start))),
+        end: Some(abi::convert_to_timestamp(at(end))),
+        ..Default::default()
+    }
+}
+
+fn mask(paths: &[&str]) -> FieldMask {
+    FieldMask {
+        paths: paths.iter().map(|path| path.to_string()).collect(),
+    }
+}
+
+fn block_window(rids: Vec<&str>, cancel_pending: bool) -> abi::BlockWindow {
+    abi::BlockWindow {
+        resource_ids: rids.into_iter().map(|rid| rid.to_string()).collect(),
+        start: Some(abi::convert_to_timestamp(at(8))),
+        end: Some(abi::convert_to_timestamp(at(18))),
+        reason: "maintenance".to_string(),
+        recurrence: None,
+        cancel_pending,
+    }
+}
+
+fn import(mode: abi::ImportMode, count: u32) -> abi::ImportCalendarRequest {
+    let ics = format!(
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:holidays\r\n\
+        DTSTART;VALUE=DATE:20991223\r\nRRULE:FREQ=DAILY;COUNT={count}\r\n\
+        SUMMARY:Holidays\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n"
+    );
+    abi::ImportCalendarRequest {
+        ics: ics.into_bytes(),
+        resource_id: "room-1".to_string(),
+        mode: mode as i32,
+        timezone: "UTC".to_string(),
+        ..Default::default()
+    }
+}
+
+fn since(sequence: i64) -> abi::ListenRequest {
+    abi::ListenRequest {
+        since: Some(sequence),
+        ..Default::default()
+    }
+}
+
+async fn recv(rx: &mut crate::ChangeReceiver) -> abi::ListenResponse {
+    tokio::time::timeout(StdDuration::from_secs(5), rx.recv())
+        .await
+        .expect("no change within 5s")
+        .unwrap()
+        .unwrap()
+}
@@ -0,0 +1,271 @@
+use std::future::Future;
+
+use chrono::{DateTime, Utc};
+use prost::Message;
+
+use async_trait::async_trait;
+use tracing::instrument;
+
+use crate::{ReservationManager, Storage};
+
+impl ReservationManager {
+    /// Run `f` at most once per operation and idempotency key. A retry with the same key and
+    /// request gets the stored response, a different request with the same key is rejected.
+    /// Only successful responses are kept, a failed attempt releases the key, and a retry may
+    /// take over the key of an attempt that didn't finish within the lease. An empty key
+    /// disables the check.
+    #[instrument(skip(self, req, f))]
+    pub async fn idempotent<Req, Resp, F, Fut>(
+        &self,
+        operation: &str,
+        key: &str,
+        req: &Req,
+        f: F,
+    ) -> Result<Resp, abi::Error>
+    where
+        Req: Message,
+        Resp: Message + Default,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Resp, abi::Error>>,
+    {
+        if key.is_empty() {
+            return f().await;
+        }
+
+        let request = req.encode_to_vec();
+        let expired_before = Utc::now() - self.idempotency_retention;
+        sqlx::query(
+            "DELETE FROM rsvp.idempotency_keys
+            WHERE operation = $1 AND idempotency_key = $2 AND created_at < $3",
+        )
+        .bind(operation)
+        .bind(key)
+        .bind(expired_before)
+        .execute(&self.pool)
+        .await?;
+
+        // claim the key, only one request can hold it at a time. A claim older than the lease
+        // belongs to a request that never finished, a retry of the same request takes it over
+        let claimed_at: Option<DateTime<Utc>> = sqlx::query_scalar(
+            "INSERT INTO rsvp.idempotency_keys (operation, idempotency_key, request) VALUES ($1, $2, $3)
+            ON CONFLICT (operation, idempotency_key) DO UPDATE SET claimed_at = now()
+            WHERE idempotency_keys.response IS NULL AND idempotency_keys.claimed_at < $4
+                AND idempotency_keys.request = EXCLUDED.request
+            RETURNING claimed_at",
+        )
+        .bind(operation)
+        .bind(key)
+        .bind(&request)
+        .bind(Utc::now() - self.idempotency_lease)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(claimed_at) = claimed_at else {
+            let (stored, response): (Vec<u8>, Option<Vec<u8>>) = sqlx::query_as(
+                "SELECT request, response FROM rsvp.idempotency_keys
+                WHERE operation = $1 AND idempotency_key = $2",
+            )
+            .bind(operation)
+            .bind(key)
+            .fetch_one(&self.pool)
+            .await?;
+            if stored != request {
+                return Err(abi::Error::IdempotencyKeyReused(key.to_string()));
+            }
+            return match response {
+                Some(response) => {
+                    Resp::decode(response.as_slice()).map_err(|_| abi::Error::Unknown)
+                }
+                None => Err(abi::Error::IdempotencyKeyInProgress(key.to_string())),
+            };
+        };
+
+        // the claim may have been taken over in the meantime, then it's not ours to settle
+        let result = f().await;
+        let sql = match result {
+            Ok(_) => {
+                "UPDATE rsvp.idempotency_keys SET response = $4
+                WHERE operation = $1 AND idempotency_key = $2 AND claimed_at = $3"
+            }
+            Err(_) => {
+                "DELETE FROM rsvp.idempotency_keys
+                WHERE operation = $1 AND idempotency_key = $2 AND claimed_at = $3"
+            }
+        };
+        sqlx::query(sql)
+            .bind(operation)
+            .bind(key)
+            .bind(claimed_at)
+            .bind(result.as_ref().ok().map(|resp| resp.encode_to_vec()))
+            .execute(&self.pool)
+            .await?;
+
+        result
+    }
+}
+
+#[async_trait]
+impl Storage for ReservationManager {
+    // a handle whose changes are recorded as made by `actor`
+    fn acting_as(&self, actor: &str) -> Self {
+        self.clone().with_actor(actor)
+    }
+    // run `f` at most once per operation and idempotency key, an empty key disables the check
+    async fn idempotent<Req, Resp, F, Fut>(
+        &self,
+        operation: &str,
+        key: &str,
+        req: &Req,
+        f: F,
+    ) -> Result<Resp, abi::Error>
+    where
+        Req: Message,
+        Resp: Message + Default,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<Resp, abi::Error>> + Send,
+    {
+        ReservationManager::idempotent(self, operation, key, req, f).await
+    }
+    // remove the idempotency keys older than the retention window
+    #[instrument(skip(self))]
+    async fn purge_idempotency_keys(&self) -> Result<u64, abi::Error> {
+        let expired_before: DateTime<Utc> = Utc::now() - self.idempotency_retention;
+        let purged = sqlx::query("DELETE FROM rsvp.idempotency_keys WHERE created_at < $1")
+            .bind(expired_before)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::Rsvp;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn replayed_request_should_get_original_response() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let req = make_request("just note");
+
+        let resp = reserve(&manager, &req).await.unwrap();
+        let rsvp = resp.reservation.clone().unwrap();
+        assert!(rsvp.id > 0);
+        // without the key the retry would conflict with the first reservation
+        let replayed = reserve(&manager, &req).await.unwrap();
+        assert_eq!(replayed, resp);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn reused_key_with_different_request_should_fail() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        reserve(&manager, &make_request("just note")).await.unwrap();
+
+        let err = reserve(&manager, &make_request("other note"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, abi::Error::IdempotencyKeyReused(_)));
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn failed_request_should_release_key() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let mut req = make_request("just note");
+        req.reservation.as_mut().unwrap().resource_id = String::new();
+        assert!(reserve(&manager, &req).await.is_err());
+
+        let keys: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rsvp.idempotency_keys")
+            .fetch_one(&migrated_pool)
+            .await
+            .unwrap();
+        assert_eq!(keys, 0);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn expired_key_should_be_usable_again() {
+        let manager = ReservationManager::new(migrated_pool.clone())
+            .await
+            .with_idempotency_retention(Duration::zero());
+        reserve(&manager, &make_request("just note")).await.unwrap();
+
+        let err = reserve(&manager, &make_request("just note"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, abi::Error::ConflictReservation(_)));
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn stale_claim_should_be_taken_over() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let req = make_request("just note");
+        // a request that was cancelled after claiming the key
+        let claim = |age: f64| {
+            sqlx::query(
+                "INSERT INTO rsvp.idempotency_keys (operation, idempotency_key, request, claimed_at)
+                VALUES ('reserve', $1, $2, now() - make_interval(secs => $3))
+                ON CONFLICT (operation, idempotency_key) DO UPDATE SET claimed_at = EXCLUDED.claimed_at",
+            )
+            .bind(&req.idempotency_key)
+            .bind(req.encode_to_vec())
+            .bind(age)
+            .execute(&migrated_pool)
+        };
+
+        claim(10.0).await.unwrap();
+        let err = reserve(&manager, &req).await.unwrap_err();
+        assert!(matches!(err, abi::Error::IdempotencyKeyInProgress(_)));
+
+        claim(120.0).await.unwrap();
+        let resp = reserve(&manager, &req).await.unwrap();
+        assert_eq!(reserve(&manager, &req).await.unwrap(), resp);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn purge_should_only_remove_expired_keys() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        reserve(&manager, &make_request("just note")).await.unwrap();
+        assert_eq!(manager.purge_idempotency_keys().await.unwrap(), 0);
+
+        let manager = manager.with_idempotency_retention(Duration::zero());
+        assert_eq!(manager.purge_idempotency_keys().await.unwrap(), 1);
+        let keys: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rsvp.idempotency_keys")
+            .fetch_one(&migrated_pool)
+            .await
+            .unwrap();
+        assert_eq!(keys, 0);
+    }
+
+    async fn reserve(
+        manager: &ReservationManager,
+        req: &abi::ReserveRequest,
+    ) -> Result<abi::ReserveResponse, abi::Error> {
+        manager
+            .idempotent("reserve", &req.idempotency_key, req, || async {
+                let rsvp = manager.reserve(req.reservation.clone().unwrap()).await?;
+                Ok(abi::ReserveResponse {
+                    reservation: Some(rsvp),
+                })
+            })
+            .await
+    }
+
+    fn make_request(note: &str) -> abi::ReserveRequest {
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-28T12:00:00-0700".parse().unwrap();
+        abi::ReserveRequest {
+            reservation: Some(abi::Reservation::new_pending(
+                "user1",
+                "resource1",
+                start,
+                end,
+                note,
+            )),
+            bypass_rules: false,
+            idempotency_key: "retry-1".to_string(),
+        }
+    }
+}
@@ -1,24 +1,118 @@
+mod bulk;
+mod cache;
+#[cfg(test)]
+mod conformance;
+mod idempotency;
+mod listen;
 mod manager;
+mod memory;
+mod outbox;
+mod quota;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod webhook;
+
+use std::{future::Future, sync::Arc};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use prost::Message;
 use sqlx::PgPool;
+use tokio::sync::mpsc;
+
+pub use bulk::BulkImport;
+pub use cache::CachedManager;
+pub use memory::InMemoryManager;
+pub use outbox::OutboxEvent;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteManager;
+pub use stats::{ChangeFeedLag, PoolStats};
+pub use webhook::WebhookJob;
 
 pub type ReservationId = String;
+// changes in sequence order, closed after an error
+pub type ChangeReceiver = mpsc::Receiver<Result<abi::ListenResponse, abi::Error>>;
+
+/// the booking rules of the resource a new reservation has to pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BookingRules {
+    All,
+    // imported events may well be in the past, the other rules still apply
+    AllowingPast,
+    // admins may bypass them
+    Bypass,
+}
+
+impl BookingRules {
+    pub(crate) fn check(
+        self,
+        resource: &abi::Resource,
+        rsvp: &abi::Reservation,
+        now: DateTime<Utc>,
+    ) -> Result<(), abi::Error> {
+        if self == BookingRules::Bypass {
+            return Ok(());
+        }
+        let (start, end) = rsvp.get_timespan()?;
+        match self {
+            BookingRules::AllowingPast => {
+                resource.check_booking_rules_allowing_past(start, end, now)
+            }
+            _ => resource.check_booking_rules(start, end, now),
+        }
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReservationManager {
     pool: PgPool,
+    // how long idempotency keys are kept
+    idempotency_retention: Duration,
+    // how long a request holds the key it claimed, a retry may take over an older claim
+    idempotency_lease: Duration,
+    // recorded in the audit history for every change made through this manager
+    actor: Option<String>,
+    // the connection listening for new changes, shared by all subscribers
+    notifier: Arc<listen::Notifier>,
 }
 #[async_trait]
 pub trait Rsvp {
     // make a reservation
     async fn reserve(&self, reservation: abi::Reservation) -> Result<abi::Reservation, abi::Error>;
-    // change reservation status (if current status is pending, change it to confirmed)
-    async fn change_status(&self, id: i64) -> Result<abi::Reservation, abi::Error>;
-    // update reservation
-    async fn update(&self, id: i64, note: String) -> Result<abi::Reservation, abi::Error>;
-    // delete reservation
-    async fn delete(&self, id: i64) -> Result<(), abi::Error>;
+    // make a reservation without checking the booking rules of the resource (admin only)
+    async fn reserve_bypassing_rules(
+        &self,
+        reservation: abi::Reservation,
+    ) -> Result<abi::Reservation, abi::Error>;
+    // make several reservations in one transaction, if any fails nothing is reserved
+    async fn reserve_batch(
+        &self,
+        reservations: Vec<abi::Reservation>,
+    ) -> Result<abi::ReserveBatchResponse, abi::Error>;
+    // change reservation status (if current status is pending, change it to confirmed),
+    // fails if an expected version is given and the reservation has changed since
+    async fn change_status(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error>;
+    // update the fields of a reservation listed in the mask (note, start, end, user_id, labels),
+    // a non-zero version on the reservation must match the current one
+    async fn update(
+        &self,
+        reservation: abi::Reservation,
+        mask: prost_types::FieldMask,
+    ) -> Result<abi::Reservation, abi::Error>;
+    // move or resize a reservation in one transaction, optionally to another resource
+    async fn reschedule(&self, req: abi::RescheduleRequest)
+        -> Result<abi::Reservation, abi::Error>;
+    // delete reservation, fails if an expected version is given and doesn't match
+    async fn delete(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error>;
     // get reservation by id
     async fn get(&self, id: i64) -> Result<abi::Reservation, abi::Error>;
     // query reservations
@@ -26,4 +120,96 @@ pub trait Rsvp {
         &self,
         query: abi::ReservationQuery,
     ) -> Result<Vec<abi::Reservation>, abi::Error>;
+    // listen to changes matching the request filters, the ones after `since` are replayed first.
+    // Without it only new changes
+    async fn listen(&self, req: abi::ListenRequest) -> Result<ChangeReceiver, abi::Error>;
+    // block resources for a (recurring) window, optionally cancel overlapping pending reservations
+    async fn block(&self, window: abi::BlockWindow) -> Result<abi::BlockResponse, abi::Error>;
+    // create or update resource settings
+    async fn upsert_resource(&self, resource: abi::Resource) -> Result<abi::Resource, abi::Error>;
+    // get resource settings, a resource without settings has no buffers
+    async fn get_resource(&self, id: String) -> Result<abi::Resource, abi::Error>;
+    // find free time slots of a resource, taking its buffers into account
+    async fn availability(
+        &self,
+        req: abi::AvailabilityRequest,
+    ) -> Result<Vec<abi::TimeSlot>, abi::Error>;
+    // create or update a quota policy
+    async fn upsert_quota_policy(
+        &self,
+        policy: abi::QuotaPolicy,
+    ) -> Result<abi::QuotaPolicy, abi::Error>;
+    // delete a quota policy
+    async fn delete_quota_policy(&self, id: i64) -> Result<(), abi::Error>;
+    // replace the groups a user belongs to
+    async fn set_user_groups(
+        &self,
+        user_id: String,
+        group_ids: Vec<String>,
+    ) -> Result<(), abi::Error>;
+    // get the current usage and remaining quota of a user
+    async fn get_quota(&self, user_id: String) -> Result<Vec<abi::QuotaUsage>, abi::Error>;
+    // get the change history of a reservation, oldest first
+    async fn get_history(&self, id: i64) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error>;
+    // query the change history by actor and time range
+    async fn query_history(
+        &self,
+        query: abi::HistoryQuery,
+    ) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error>;
+    // create or update a webhook subscription, it gets the changes from now on
+    async fn upsert_webhook(&self, webhook: abi::Webhook) -> Result<abi::Webhook, abi::Error>;
+    // delete a webhook subscription and its delivery log
+    async fn delete_webhook(&self, id: i64) -> Result<(), abi::Error>;
+    // list webhook subscriptions, without secrets
+    async fn list_webhooks(&self) -> Result<Vec<abi::Webhook>, abi::Error>;
+    // get the delivery log of a webhook, newest first
+    async fn list_webhook_deliveries(
+        &self,
+        req: abi::ListWebhookDeliveriesRequest,
+    ) -> Result<Vec<abi::WebhookDelivery>, abi::Error>;
+    // put a dead delivery back into the queue
+    async fn retry_webhook_delivery(&self, id: i64) -> Result<abi::WebhookDelivery, abi::Error>;
+    // import the events of an iCalendar file as reservations or blocked windows. A dry run
+    // reports what would fail, conflicts included, without creating anything
+    async fn import_calendar(
+        &self,
+        req: abi::ImportCalendarRequest,
+    ) -> Result<abi::ImportCalendarResponse, abi::Error>;
+    // remember a calendar feed token that was handed out
+    async fn add_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error>;
+    // whether the calendar feed token was handed out and isn't revoked
+    async fn has_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<bool, abi::Error>;
+    // revoke a calendar feed token, fails if it's unknown
+    async fn revoke_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error>;
+}
+
+/// a backend the service can run on
+#[async_trait]
+pub trait Storage: Rsvp + Clone + Send + Sync + 'static {
+    // a handle whose changes are recorded as made by `actor`
+    fn acting_as(&self, actor: &str) -> Self;
+    // run `f` at most once per operation and idempotency key, an empty key disables the check
+    async fn idempotent<Req, Resp, F, Fut>(
+        &self,
+        operation: &str,
+        key: &str,
+        req: &Req,
+        f: F,
+    ) -> Result<Resp, abi::Error>
+    where
+        Req: Message,
+        Resp: Message + Default,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<Resp, abi::Error>> + Send;
+    // remove the idempotency keys older than the retention window, returns how many were removed
+    async fn purge_idempotency_keys(&self) -> Result<u64, abi::Error>;
 }
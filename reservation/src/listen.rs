@@ -0,0 +1,291 @@
+use std::{sync::Arc, time::Duration};
+
+use sqlx::{
+    postgres::{PgListener, PgPoolOptions},
+    PgPool,
+};
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+use crate::{ChangeReceiver, ReservationManager};
+
+const CHANNEL: &str = "reservation_update";
+const BATCH_SIZE: i64 = 100;
+// notifications sent while the listener reconnects are lost, so look for changes now and then
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// one connection listening for notifications of new changes, shared by the subscribers of a
+/// manager and its clones. It's not one of the pool's, so subscribers can't starve queries. It's
+/// opened for the first subscriber and closed once they're all gone
+#[derive(Debug, Default)]
+pub(crate) struct Notifier {
+    tx: Mutex<Option<broadcast::Sender<()>>>,
+}
+
+impl Notifier {
+    /// a receiver told about every notification, listening is set up by the time it's returned
+    async fn subscribe(
+        self: &Arc<Self>,
+        pool: &PgPool,
+    ) -> Result<broadcast::Receiver<()>, abi::Error> {
+        let mut guard = self.tx.lock().await;
+        if let Some(tx) = guard.as_ref() {
+            return Ok(tx.subscribe());
+        }
+        // a connection of its own, outside the pool, so listening never takes one from queries
+        let dedicated = PgPoolOptions::new()
+            .max_connections(1)
+            .max_lifetime(None)
+            .idle_timeout(None)
+            .connect_with(pool.connect_options().clone())
+            .await?;
+        let mut listener = PgListener::connect_with(&dedicated).await?;
+        listener.listen(CHANNEL).await?;
+        // a notification is only a hint to look for changes, a few of them are as good as many
+        let (tx, rx) = broadcast::channel(1);
+        *guard = Some(tx.clone());
+        tokio::spawn(self.clone().run(listener, tx));
+        Ok(rx)
+    }
+
+    async fn run(self: Arc<Self>, mut listener: PgListener, tx: broadcast::Sender<()>) {
+        loop {
+            let notified = tokio::select! {
+                notification = listener.try_recv() => {
+                    // `None` is a lost connection, it's back on the next try but notifications
+                    // may have been missed, so subscribers look for changes either way
+                    if let Err(e) = notification {
+                        tracing::warn!(error = %e, "listening for changes failed");
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                    true
+                }
+                _ = tokio::time::sleep(POLL_INTERVAL) => false,
+            };
+            // the lock keeps a new subscriber from getting a sender that's about to go
+            let mut guard = self.tx.lock().await;
+            if tx.receiver_count() == 0 {
+                *guard = None;
+                return;
+            }
+            if notified {
+                let _ = tx.send(());
+            }
+        }
+    }
+}
+
+impl ReservationManager {
+    /// stream matching changes after `since` (or from now on) to the returned receiver, until
+    /// it's dropped
+    pub(crate) async fn subscribe(
+        &self,
+        req: abi::ListenRequest,
+    ) -> Result<ChangeReceiver, abi::Error> {
+        // listen before reading, so nothing committed in between is missed
+        let mut notifications = self.notifier.subscribe(&self.pool).await?;
+        let last = match req.since {
+            Some(since) => since,
+            None => {
+                sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM rsvp.reservation_changes")
+                    .fetch_one(&self.pool)
+                    .await?
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(BATCH_SIZE as usize);
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            if let Err(e) = forward_changes(&pool, &mut notifications, &req, last, &tx).await {
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+        Ok(rx)
+    }
+}
+
+/// send changes after `last` matching the request until the receiver is dropped
+async fn forward_changes(
+    pool: &PgPool,
+    notifications: &mut broadcast::Receiver<()>,
+    req: &abi::ListenRequest,
+    mut last: i64,
+    tx: &mpsc::Sender<Result<abi::ListenResponse, abi::Error>>,
+) -> Result<(), abi::Error> {
+    let status = abi::ReservationStatus::from_i32(req.status)
+        .unwrap_or(abi::ReservationStatus::Unknown)
+        .to_string();
+    let ops = req.get_ops();
+    loop {
+        // the next batch of changes, matching or not, so a filter that matches nothing doesn't
+        // have the same changes scanned over and over
+        let (scanned_to, scanned): (Option<i64>, i64) = sqlx::query_as(
+            "SELECT MAX(id), COUNT(*) FROM (
+                SELECT id FROM rsvp.reservation_changes WHERE id > $1 ORDER BY id LIMIT $2
+            ) AS batch",
+        )
+        .bind(last)
+        .bind(BATCH_SIZE)
+        .fetch_one(pool)
+        .await?;
+        let caught_up = scanned < BATCH_SIZE;
+        if let Some(scanned_to) = scanned_to {
+            // filters look at the reservation as of the change, empty ones match everything
+            let changes: Vec<abi::ListenResponse> = sqlx::query_as(
+                "SELECT id, reservation_id, op, committed_at, reservation FROM rsvp.reservation_changes
+                WHERE id > $1 AND id <= $2
+                AND (cardinality($3::VARCHAR[]) = 0 OR reservation->>'resource_id' = ANY($3))
+                AND ($4 = '' OR reservation->>'user_id' = $4)
+                AND ($5 = 'unknown' OR reservation->>'status' = $5)
+                AND (cardinality($6::VARCHAR[]) = 0 OR op::VARCHAR = ANY($6))
+                ORDER BY id",
+            )
+            .bind(last)
+            .bind(scanned_to)
+            .bind(&req.resource_ids)
+            .bind(&req.user_id)
+            .bind(&status)
+            .bind(&ops)
+            .fetch_all(pool)
+            .await?;
+            for change in changes {
+                if tx.send(Ok(change)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            last = scanned_to;
+        }
+        if caught_up {
+            tokio::select! {
+                // lagging behind only means there's more to look for
+                notification = notifications.recv() => {
+                    if let Err(broadcast::error::RecvError::Closed) = notification {
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                }
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = tx.closed() => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, FixedOffset, Utc};
+
+    use super::*;
+    use crate::Rsvp;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn listen_should_replay_missed_changes_then_go_live() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let first = manager.reserve(make_pending("room-1")).await.unwrap();
+        manager.change_status(first.id, None).await.unwrap();
+
+        let mut rx = manager.listen(since(0)).await.unwrap();
+        let created = rx.recv().await.unwrap().unwrap();
+        assert_eq!(created.op(), abi::ReservationUpdateType::Create);
+        assert_eq!(created.reservation.as_ref().unwrap().id, first.id);
+        let confirmed = rx.recv().await.unwrap().unwrap();
+        assert_eq!(confirmed.op(), abi::ReservationUpdateType::Update);
+        assert!(confirmed.sequence > created.sequence);
+        assert_eq!(
+            confirmed.reservation.as_ref().unwrap().status,
+            abi::ReservationStatus::Confirmed as i32
+        );
+
+        let second = manager.reserve(make_pending("room-2")).await.unwrap();
+        let live = rx.recv().await.unwrap().unwrap();
+        assert_eq!(live.reservation.unwrap().id, second.id);
+
+        // resuming from the first change only replays what came after it
+        let mut rx = manager.listen(since(created.sequence)).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().unwrap(), confirmed);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn listen_without_since_should_skip_past_changes() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        manager.reserve(make_pending("room-1")).await.unwrap();
+
+        let mut rx = manager.listen(Default::default()).await.unwrap();
+        let rsvp = manager.reserve(make_pending("room-2")).await.unwrap();
+        manager.delete(rsvp.id, None).await.unwrap();
+        let created = rx.recv().await.unwrap().unwrap();
+        assert_eq!(created.reservation.unwrap().id, rsvp.id);
+        let deleted = rx.recv().await.unwrap().unwrap();
+        assert_eq!(deleted.op(), abi::ReservationUpdateType::Delete);
+        assert_eq!(deleted.reservation.unwrap().id, rsvp.id);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn listen_should_only_send_matching_changes() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let other = manager.reserve(make_pending("room-2")).await.unwrap();
+        let rsvp = manager.reserve(make_pending("room-1")).await.unwrap();
+        manager.change_status(other.id, None).await.unwrap();
+        manager.change_status(rsvp.id, None).await.unwrap();
+        manager.delete(rsvp.id, None).await.unwrap();
+
+        let req = abi::ListenRequest {
+            resource_ids: vec!["room-1".to_string()],
+            status: abi::ReservationStatus::Confirmed as i32,
+            ops: vec![abi::ReservationUpdateType::Update as i32],
+            ..since(0)
+        };
+        let mut rx = manager.listen(req).await.unwrap();
+        let change = rx.recv().await.unwrap().unwrap();
+        assert_eq!(change.op(), abi::ReservationUpdateType::Update);
+        assert_eq!(change.reservation.unwrap().id, rsvp.id);
+
+        // nothing else matches, so the next change seen is a new one
+        let start: DateTime<FixedOffset> = "2099-12-26T15:00:00-0700".parse().unwrap();
+        let end: DateTime<FixedOffset> = "2099-12-26T18:00:00-0700".parse().unwrap();
+        let req = abi::RescheduleRequest {
+            id: other.id,
+            start: Some(abi::convert_to_timestamp(start.with_timezone(&Utc))),
+            end: Some(abi::convert_to_timestamp(end.with_timezone(&Utc))),
+            ..Default::default()
+        };
+        manager.reschedule(req).await.unwrap();
+        let rsvp = manager.reserve(make_pending("room-1")).await.unwrap();
+        manager.change_status(rsvp.id, None).await.unwrap();
+        let change = rx.recv().await.unwrap().unwrap();
+        assert_eq!(change.reservation.unwrap().id, rsvp.id);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn subscribers_should_not_take_connections_from_the_pool() {
+        let pool = PgPoolOptions::new()
+            .max_connections(2)
+            .acquire_timeout(Duration::from_secs(5))
+            .connect_with(migrated_pool.connect_options().clone())
+            .await
+            .unwrap();
+        let manager = ReservationManager::new(pool).await;
+        let mut receivers = Vec::new();
+        for _ in 0..5 {
+            receivers.push(manager.listen(Default::default()).await.unwrap());
+        }
+
+        // the pool still has a connection to spare for everything else
+        let rsvp = manager.reserve(make_pending("room-1")).await.unwrap();
+        for rx in &mut receivers {
+            let change = rx.recv().await.unwrap().unwrap();
+            assert_eq!(change.reservation.unwrap().id, rsvp.id);
+        }
+    }
+
+    fn since(sequence: i64) -> abi::ListenRequest {
+        abi::ListenRequest {
+            since: Some(sequence),
+            ..Default::default()
+        }
+    }
+
+    fn make_pending(rid: &str) -> abi::Reservation {
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        abi::Reservation::new_pending("user1", rid, start, end, "just note")
+    }
+}
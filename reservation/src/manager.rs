@@ -1,146 +1,1008 @@
-use crate::{ReservationManager, Rsvp};
+use crate::{BookingRules, ChangeReceiver, ReservationManager, Rsvp};
 use async_trait::async_trait;
-use sqlx::Row;
+use chrono::{DateTime, Duration, Utc};
+use prost_types::{FieldMask, Timestamp};
+use sqlx::{
+    postgres::types::PgRange, types::Json, Connection, PgConnection, Postgres, Row, Transaction,
+};
+use tracing::{field::Empty, instrument, Span};
 
 #[async_trait]
 impl Rsvp for ReservationManager {
     // make a reservation
-    async fn reserve(&self, mut rsvp: abi::Reservation) -> Result<abi::Reservation, abi::Error> {
-        rsvp.validate()?;
+    #[instrument(skip_all, fields(resource_id = %rsvp.resource_id, reservation_id = Empty))]
+    async fn reserve(&self, rsvp: abi::Reservation) -> Result<abi::Reservation, abi::Error> {
+        self.do_reserve(rsvp, BookingRules::All).await
+    }
+    // make a reservation without checking the booking rules of the resource
+    #[instrument(skip_all, fields(resource_id = %rsvp.resource_id, reservation_id = Empty))]
+    async fn reserve_bypassing_rules(
+        &self,
+        rsvp: abi::Reservation,
+    ) -> Result<abi::Reservation, abi::Error> {
+        self.do_reserve(rsvp, BookingRules::Bypass).await
+    }
+    // make several reservations in one transaction, if any fails nothing is reserved
+    #[instrument(skip_all, fields(count = rsvps.len()))]
+    async fn reserve_batch(
+        &self,
+        rsvps: Vec<abi::Reservation>,
+    ) -> Result<abi::ReserveBatchResponse, abi::Error> {
+        let mut tx = self.begin().await?;
+        let mut results = Vec::with_capacity(rsvps.len());
+        let mut committed = true;
+        for rsvp in rsvps {
+            // each reservation gets a savepoint, so the others can still be checked after a failure
+            let mut savepoint = tx.begin().await?;
+            match self
+                .reserve_in(&mut savepoint, rsvp.clone(), BookingRules::All)
+                .await
+            {
+                Ok(rsvp) => {
+                    savepoint.commit().await?;
+                    results.push(abi::ReserveBatchResult {
+                        reservation: Some(rsvp),
+                        error: String::new(),
+                    });
+                }
+                Err(e) => {
+                    savepoint.rollback().await?;
+                    committed = false;
+                    results.push(abi::ReserveBatchResult {
+                        reservation: Some(rsvp),
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
 
-        let status = abi::ReservationStatus::from_i32(rsvp.status)
-            .unwrap_or(abi::ReservationStatus::Pending);
-
-        let timespan = rsvp.get_timestamp();
-        // generate a insert sql for the reservation
-        let sql = "INSERT INTO rsvp.reservations (user_id, resource_id, timespan, note, status) VALUES ($1, $2,
-            $3, $4, $5::rsvp.reservation_status) RETURNING id";
-        // execute the sql
-        let id = sqlx::query(sql)
-            .bind(&rsvp.user_id.clone())
-            .bind(&rsvp.resource_id.clone())
-            .bind(timespan)
-            .bind(&rsvp.note.clone())
-            .bind(status.to_string())
-            .fetch_one(&self.pool)
-            .await?
-            .get(0);
-        rsvp.id = id;
-        Ok(rsvp)
+        if committed {
+            tx.commit().await?;
+        } else {
+            tx.rollback().await?;
+            // nothing was reserved, so don't hand out ids
+            for result in results.iter_mut() {
+                if let Some(rsvp) = result.reservation.as_mut() {
+                    rsvp.id = 0;
+                }
+            }
+        }
+
+        Ok(abi::ReserveBatchResponse { results, committed })
     }
 
     // change reservation status (if current status is pending, change it to confirmed)
-    async fn change_status(&self, id: i64) -> Result<abi::Reservation, abi::Error> {
+    #[instrument(skip(self))]
+    async fn change_status(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let mut tx = self.begin().await?;
+        check_version(&mut tx, id, expected_version).await?;
         // if current status is pending, change it to confirmed, otherwise do nothing
         let rsvp: abi::Reservation = sqlx::query_as(
             "UPDATE rsvp.reservations SET status = 'confirmed' WHERE id = $1 RETURNING
-            id, user_id, resource_id, timespan, note, status",
+            id, user_id, resource_id, timespan, note, status, labels, version",
         )
         .bind(id)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut tx)
         .await?;
+        tx.commit().await?;
 
         Ok(rsvp)
     }
-    // update reservation
-    async fn update(&self, id: i64, note: String) -> Result<abi::Reservation, abi::Error> {
-        let rsvp: abi::Reservation = sqlx::query_as(
-            "UPDATE rsvp.reservations SET note = $2 WHERE id = $1 RETURNING
-            id, user_id, resource_id, timespan, note, status",
+    // update the fields of a reservation listed in the mask
+    #[instrument(skip_all, fields(reservation_id = rsvp.id, resource_id = Empty))]
+    async fn update(
+        &self,
+        rsvp: abi::Reservation,
+        mask: FieldMask,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let fields = abi::UpdateField::parse_mask(&mask)?;
+        if fields.contains(&abi::UpdateField::UserId) && rsvp.user_id.is_empty() {
+            return Err(abi::Error::InvalidUserId(rsvp.user_id));
+        }
+
+        let mut tx = self.begin().await?;
+        // a version of 0 means the caller doesn't care about concurrent changes
+        let expected_version = (rsvp.version != 0).then_some(rsvp.version);
+        check_version(&mut tx, rsvp.id, expected_version).await?;
+        let current: abi::Reservation = sqlx::query_as(
+            "SELECT id, user_id, resource_id, timespan, note, status, labels, version FROM rsvp.reservations
+            WHERE id = $1 FOR UPDATE",
         )
-        .bind(id)
-        .bind(note)
-        .fetch_one(&self.pool)
+        .bind(rsvp.id)
+        .fetch_one(&mut tx)
+        .await?;
+        Span::current().record("resource_id", current.resource_id.as_str());
+        // blocked windows have no user
+        let changes_user = fields.contains(&abi::UpdateField::UserId);
+        if changes_user && current.is_blocked() {
+            return Err(abi::Error::InvalidUserId(rsvp.user_id));
+        }
+
+        let pick = |field, new: &Option<Timestamp>, old: &Option<Timestamp>| {
+            if fields.contains(&field) {
+                new.clone()
+            } else {
+                old.clone()
+            }
+        };
+        let updated = abi::Reservation {
+            user_id: if changes_user {
+                rsvp.user_id.clone()
+            } else {
+                current.user_id.clone()
+            },
+            start: pick(abi::UpdateField::Start, &rsvp.start, &current.start),
+            end: pick(abi::UpdateField::End, &rsvp.end, &current.end),
+            ..current.clone()
+        };
+        let changes_time =
+            fields.contains(&abi::UpdateField::Start) || fields.contains(&abi::UpdateField::End);
+        let timespan = if changes_time {
+            // the new timespan is merged with the current one and checked like a reschedule
+            let req = abi::RescheduleRequest {
+                id: rsvp.id,
+                start: updated.start.clone(),
+                end: updated.end.clone(),
+                ..Default::default()
+            };
+            Some(req.get_timespan()?)
+        } else {
+            None
+        };
+        if (changes_time || changes_user) && !current.is_blocked() {
+            let resource = self.get_resource(current.resource_id.clone()).await?;
+            if let Some((start, end)) = timespan {
+                let (from, _) = current.get_timespan()?;
+                resource.check_booking_rules_for_move(from, start, end, Utc::now())?;
+            }
+            self.check_quota(&mut tx, &updated, current.id, &resource)
+                .await?;
+        }
+
+        // in the order of the mask, so the same mask always makes the same statement
+        let mut sets: Vec<&str> = Vec::with_capacity(fields.len());
+        for field in &fields {
+            let set = match field {
+                abi::UpdateField::Note => "note = $2",
+                abi::UpdateField::UserId => "user_id = $3",
+                abi::UpdateField::Labels => "labels = $4",
+                abi::UpdateField::Start | abi::UpdateField::End => "timespan = $5",
+            };
+            if !sets.contains(&set) {
+                sets.push(set);
+            }
+        }
+        let sql = format!(
+            "UPDATE rsvp.reservations SET {} WHERE id = $1 RETURNING
+            id, user_id, resource_id, timespan, note, status, labels, version",
+            sets.join(", ")
+        );
+        let rsvp: abi::Reservation = sqlx::query_as(&sql)
+            .bind(rsvp.id)
+            .bind(&rsvp.note)
+            .bind(&rsvp.user_id)
+            .bind(Json(&rsvp.labels))
+            .bind(timespan.map(|(start, end)| PgRange::from(start..end)))
+            .fetch_one(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(rsvp)
+    }
+    // move or resize a reservation, optionally to another resource
+    #[instrument(skip_all, fields(reservation_id = req.id, resource_id = Empty))]
+    async fn reschedule(
+        &self,
+        req: abi::RescheduleRequest,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let (start, end) = req.get_timespan()?;
+
+        let mut tx = self.begin().await?;
+        let expected_version = (req.expected_version != 0).then_some(req.expected_version);
+        check_version(&mut tx, req.id, expected_version).await?;
+        let current: abi::Reservation = sqlx::query_as(
+            "SELECT id, user_id, resource_id, timespan, note, status, labels, version FROM rsvp.reservations
+            WHERE id = $1 FOR UPDATE",
+        )
+        .bind(req.id)
+        .fetch_one(&mut tx)
+        .await?;
+
+        let resource_id = if req.resource_id.is_empty() {
+            current.resource_id.clone()
+        } else {
+            req.resource_id
+        };
+        Span::current().record("resource_id", resource_id.as_str());
+        if !current.is_blocked() {
+            let resource = self.get_resource(resource_id.clone()).await?;
+            let (from, _) = current.get_timespan()?;
+            resource.check_booking_rules_for_move(from, start, end, Utc::now())?;
+            let moved = abi::Reservation {
+                resource_id: resource_id.clone(),
+                start: Some(abi::convert_to_timestamp(start)),
+                end: Some(abi::convert_to_timestamp(end)),
+                ..current.clone()
+            };
+            self.check_quota(&mut tx, &moved, current.id, &resource)
+                .await?;
+        }
+
+        // the row is replaced in place, so the exclusion constraint ignores its old timespan
+        let timespan: PgRange<DateTime<Utc>> = (start..end).into();
+        let rsvp = sqlx::query_as(
+            "UPDATE rsvp.reservations SET resource_id = $2, timespan = $3 WHERE id = $1 RETURNING
+            id, user_id, resource_id, timespan, note, status, labels, version",
+        )
+        .bind(req.id)
+        .bind(resource_id)
+        .bind(timespan)
+        .fetch_one(&mut tx)
         .await?;
+        tx.commit().await?;
 
         Ok(rsvp)
     }
     // delete reservation
-    async fn delete(&self, id: i64) -> Result<(), abi::Error> {
-        sqlx::query("DELETE FROM rsvp.reservations WHERE id = $1")
-            .bind(id)
-            .execute(&self.pool)
-            .await?;
+    #[instrument(skip(self), fields(reservation_id = id, resource_id = Empty))]
+    async fn delete(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let mut tx = self.begin().await?;
+        check_version(&mut tx, id, expected_version).await?;
+        let rsvp: abi::Reservation = sqlx::query_as(
+            "DELETE FROM rsvp.reservations WHERE id = $1 RETURNING
+            id, user_id, resource_id, timespan, note, status, labels, version",
+        )
+        .bind(id)
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
 
-        Ok(())
+        Span::current().record("resource_id", rsvp.resource_id.as_str());
+        Ok(rsvp)
     }
     // get reservation by id
+    #[instrument(skip(self), fields(reservation_id = id, resource_id = Empty))]
     async fn get(&self, id: i64) -> Result<abi::Reservation, abi::Error> {
         let rsvp: abi::Reservation = sqlx::query_as(
-            "SELECT id, user_id, resource_id, timespan, note, status FROM rsvp.reservations WHERE id = $1",
+            "SELECT id, user_id, resource_id, timespan, note, status, labels, version FROM rsvp.reservations WHERE id = $1",
         )
         .bind(id)
         .fetch_one(&self.pool)
         .await?;
 
+        Span::current().record("resource_id", rsvp.resource_id.as_str());
         Ok(rsvp)
     }
     // query reservations
+    #[instrument(skip_all, fields(user_id = %query.user_id, resource_id = %query.resource_id))]
     async fn query(
         &self,
-        _query: abi::ReservationQuery,
+        query: abi::ReservationQuery,
     ) -> Result<Vec<abi::Reservation>, abi::Error> {
-        todo!()
+        let status = abi::ReservationStatus::from_i32(query.status)
+            .unwrap_or(abi::ReservationStatus::Unknown);
+        let rsvps = sqlx::query_as(query_sql(query.desc))
+            .bind(query.user_id.clone())
+            .bind(query.resource_id.clone())
+            .bind(query.get_timespan()?)
+            .bind(status.to_string())
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rsvps)
+    }
+    // listen to changes matching the request filters, the ones after `since` are replayed first.
+    // Without it only new changes
+    #[instrument(skip_all, fields(since = ?req.since))]
+    async fn listen(&self, req: abi::ListenRequest) -> Result<ChangeReceiver, abi::Error> {
+        self.subscribe(req).await
+    }
+    // block resources for a (recurring) window, optionally cancel overlapping pending reservations
+    #[instrument(skip_all, fields(resource_ids = ?window.resource_ids))]
+    async fn block(&self, window: abi::BlockWindow) -> Result<abi::BlockResponse, abi::Error> {
+        let timespans = window.get_timespans()?;
+
+        let mut tx = self.begin().await?;
+        let mut cancelled = Vec::new();
+        if window.cancel_pending {
+            for timespan in &timespans {
+                // deleting fires the reservations trigger, so listeners are notified of the cancellation
+                let mut rsvps: Vec<abi::Reservation> = sqlx::query_as(
+                    "DELETE FROM rsvp.reservations
+                    WHERE resource_id = ANY($1) AND timespan && $2 AND status = 'pending' RETURNING
+                    id, user_id, resource_id, timespan, note, status, labels, version",
+                )
+                .bind(&window.resource_ids)
+                .bind(timespan)
+                .fetch_all(&mut tx)
+                .await?;
+                cancelled.append(&mut rsvps);
+            }
+        }
+
+        let mut blocks = Vec::with_capacity(timespans.len() * window.resource_ids.len());
+        for timespan in &timespans {
+            for rid in &window.resource_ids {
+                // anything still overlapping is rejected by reservations_conflict
+                let block: abi::Reservation = sqlx::query_as(
+                    "INSERT INTO rsvp.reservations (user_id, resource_id, timespan, note, status)
+                    VALUES (NULL, $1, $2, $3, 'blocked') RETURNING
+                    id, user_id, resource_id, timespan, note, status, labels, version",
+                )
+                .bind(rid)
+                .bind(timespan)
+                .bind(&window.reason)
+                .fetch_one(&mut tx)
+                .await?;
+                blocks.push(block);
+            }
+        }
+        tx.commit().await?;
+
+        Ok(abi::BlockResponse { blocks, cancelled })
+    }
+    // create or update resource settings
+    #[instrument(skip_all, fields(resource_id = %resource.id))]
+    async fn upsert_resource(&self, resource: abi::Resource) -> Result<abi::Resource, abi::Error> {
+        resource.validate()?;
+
+        let resource = sqlx::query_as(
+            "INSERT INTO rsvp.resources
+            (id, pre_buffer, post_buffer, min_duration, max_duration, booking_horizon, lead_time, kind)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (id) DO UPDATE SET
+            pre_buffer = EXCLUDED.pre_buffer, post_buffer = EXCLUDED.post_buffer,
+            min_duration = EXCLUDED.min_duration, max_duration = EXCLUDED.max_duration,
+            booking_horizon = EXCLUDED.booking_horizon, lead_time = EXCLUDED.lead_time,
+            kind = EXCLUDED.kind
+            RETURNING id, pre_buffer, post_buffer, min_duration, max_duration, booking_horizon,
+            lead_time, kind",
+        )
+        .bind(&resource.id)
+        .bind(resource.get_pre_buffer())
+        .bind(resource.get_post_buffer())
+        .bind(resource.get_min_duration())
+        .bind(resource.get_max_duration())
+        .bind(resource.get_booking_horizon())
+        .bind(resource.get_lead_time())
+        .bind(&resource.kind)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(resource)
+    }
+    // get resource settings, a resource without settings has no buffers
+    #[instrument(skip(self), fields(resource_id = %id))]
+    async fn get_resource(&self, id: String) -> Result<abi::Resource, abi::Error> {
+        let resource = sqlx::query_as(
+            "SELECT id, pre_buffer, post_buffer, min_duration, max_duration,
+                booking_horizon, lead_time, kind FROM rsvp.resources WHERE id = $1",
+        )
+        .bind(&id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(resource.unwrap_or_else(|| abi::Resource::new(id, Duration::zero(), Duration::zero())))
+    }
+    // find free time slots of a resource, taking its buffers into account
+    #[instrument(skip_all, fields(resource_id = %req.resource_id))]
+    async fn availability(
+        &self,
+        req: abi::AvailabilityRequest,
+    ) -> Result<Vec<abi::TimeSlot>, abi::Error> {
+        let (start, end) = req.get_timespan()?;
+        let resource = self.get_resource(req.resource_id).await?;
+
+        // a reservation in [start, end) could hit anything within its own buffers
+        let window: PgRange<DateTime<Utc>> =
+            (start - resource.get_pre_buffer()..end + resource.get_post_buffer()).into();
+        let busy: Vec<(DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(
+            "SELECT lower(buffered_timespan), upper(buffered_timespan) FROM rsvp.reservations
+            WHERE resource_id = $1 AND buffered_timespan && $2 ORDER BY lower(buffered_timespan)",
+        )
+        .bind(&resource.id)
+        .bind(window)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(resource.free_slots(start, end, &busy))
+    }
+    // create or update a quota policy
+    #[instrument(skip_all, fields(policy_id = policy.id))]
+    async fn upsert_quota_policy(
+        &self,
+        policy: abi::QuotaPolicy,
+    ) -> Result<abi::QuotaPolicy, abi::Error> {
+        policy.validate()?;
+
+        let user_id = (!policy.user_id.is_empty()).then_some(&policy.user_id);
+        let group_id = (!policy.group_id.is_empty()).then_some(&policy.group_id);
+        let sql = if policy.id == 0 {
+            "INSERT INTO rsvp.quota_policies (kind, user_id, group_id, quota_limit, resource_kind)
+            VALUES ($2::rsvp.quota_kind, $3, $4, $5, $6) RETURNING
+            id, kind, user_id, group_id, quota_limit, resource_kind"
+        } else {
+            "UPDATE rsvp.quota_policies SET kind = $2::rsvp.quota_kind, user_id = $3, group_id = $4,
+            quota_limit = $5, resource_kind = $6 WHERE id = $1 RETURNING
+            id, kind, user_id, group_id, quota_limit, resource_kind"
+        };
+        let policy = sqlx::query_as(sql)
+            .bind(policy.id)
+            .bind(policy.kind().to_string())
+            .bind(user_id)
+            .bind(group_id)
+            .bind(policy.limit)
+            .bind(&policy.resource_kind)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(policy)
+    }
+    // delete a quota policy
+    #[instrument(skip(self), fields(policy_id = id))]
+    async fn delete_quota_policy(&self, id: i64) -> Result<(), abi::Error> {
+        sqlx::query("DELETE FROM rsvp.quota_policies WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+    // replace the groups a user belongs to
+    #[instrument(skip(self))]
+    async fn set_user_groups(
+        &self,
+        user_id: String,
+        group_ids: Vec<String>,
+    ) -> Result<(), abi::Error> {
+        if user_id.is_empty() {
+            return Err(abi::Error::InvalidUserId(user_id));
+        }
+
+        let mut tx = self.begin().await?;
+        sqlx::query("DELETE FROM rsvp.user_groups WHERE user_id = $1")
+            .bind(&user_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO rsvp.user_groups (user_id, group_id)
+            SELECT $1, group_id FROM UNNEST($2::VARCHAR[]) AS group_id ON CONFLICT DO NOTHING",
+        )
+        .bind(&user_id)
+        .bind(&group_ids)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+    // get the current usage and remaining quota of a user
+    #[instrument(skip(self))]
+    async fn get_quota(&self, user_id: String) -> Result<Vec<abi::QuotaUsage>, abi::Error> {
+        self.quota_usages(&user_id).await
+    }
+    // get the change history of a reservation, oldest first
+    #[instrument(skip(self), fields(reservation_id = id))]
+    async fn get_history(&self, id: i64) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error> {
+        let entries = sqlx::query_as(
+            "SELECT id, reservation_id, op, actor, changed_at, before, after
+            FROM rsvp.reservation_history WHERE reservation_id = $1 ORDER BY id",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(entries)
+    }
+    // query the change history by actor and time range
+    #[instrument(skip_all, fields(actor = %query.actor))]
+    async fn query_history(
+        &self,
+        query: abi::HistoryQuery,
+    ) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error> {
+        let direction = if query.desc { "DESC" } else { "ASC" };
+        // empty actor matches everything
+        let sql = format!(
+            "SELECT id, reservation_id, op, actor, changed_at, before, after
+            FROM rsvp.reservation_history
+            WHERE ($1 = '' OR actor = $1) AND $2::TSTZRANGE @> changed_at
+            ORDER BY changed_at {direction}, id {direction}"
+        );
+        let entries = sqlx::query_as(&sql)
+            .bind(&query.actor)
+            .bind(query.get_timespan()?)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(entries)
+    }
+    // create or update a webhook subscription, it gets the changes from now on
+    #[instrument(skip_all, fields(webhook_id = webhook.id))]
+    async fn upsert_webhook(&self, webhook: abi::Webhook) -> Result<abi::Webhook, abi::Error> {
+        // the service checks the target against its config, the dispatcher again on delivery
+        webhook.validate(true)?;
+
+        let status = abi::ReservationStatus::from_i32(webhook.status)
+            .unwrap_or(abi::ReservationStatus::Unknown);
+        let sql = if webhook.id == 0 {
+            "INSERT INTO rsvp.webhooks (url, secret, resource_ids, user_id, status, ops, last_change_id)
+            VALUES ($2, $3, $4, $5, $6::rsvp.reservation_status, $7::rsvp.reservation_update_type[],
+            (SELECT COALESCE(MAX(id), 0) FROM rsvp.reservation_changes)) RETURNING
+            id, url, resource_ids, user_id, status, ops"
+        } else {
+            // an empty secret keeps the current one
+            "UPDATE rsvp.webhooks SET url = $2, secret = COALESCE(NULLIF($3, ''), secret),
+            resource_ids = $4, user_id = $5, status = $6::rsvp.reservation_status,
+            ops = $7::rsvp.reservation_update_type[] WHERE id = $1 RETURNING
+            id, url, resource_ids, user_id, status, ops"
+        };
+        let webhook = sqlx::query_as(sql)
+            .bind(webhook.id)
+            .bind(&webhook.url)
+            .bind(&webhook.secret)
+            .bind(&webhook.resource_ids)
+            .bind(&webhook.user_id)
+            .bind(status.to_string())
+            .bind(webhook.get_ops())
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(webhook)
+    }
+    // delete a webhook subscription and its delivery log
+    #[instrument(skip(self), fields(webhook_id = id))]
+    async fn delete_webhook(&self, id: i64) -> Result<(), abi::Error> {
+        sqlx::query("DELETE FROM rsvp.webhooks WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+    // list webhook subscriptions, without secrets
+    #[instrument(skip(self))]
+    async fn list_webhooks(&self) -> Result<Vec<abi::Webhook>, abi::Error> {
+        let webhooks = sqlx::query_as(
+            "SELECT id, url, resource_ids, user_id, status, ops FROM rsvp.webhooks ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(webhooks)
+    }
+    // get the delivery log of a webhook, newest first
+    #[instrument(skip_all, fields(webhook_id = req.webhook_id))]
+    async fn list_webhook_deliveries(
+        &self,
+        req: abi::ListWebhookDeliveriesRequest,
+    ) -> Result<Vec<abi::WebhookDelivery>, abi::Error> {
+        let status = abi::WebhookDeliveryStatus::from_i32(req.status)
+            .unwrap_or(abi::WebhookDeliveryStatus::Unknown);
+        let deliveries = sqlx::query_as(
+            "SELECT id, webhook_id, change_id, status, attempts, last_error, response_status,
+            created_at, next_attempt_at, delivered_at FROM rsvp.webhook_deliveries
+            WHERE webhook_id = $1 AND ($2 = 'unknown' OR status = $2::rsvp.webhook_delivery_status)
+            ORDER BY id DESC",
+        )
+        .bind(req.webhook_id)
+        .bind(status.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+    // put a dead delivery back into the queue
+    #[instrument(skip(self), fields(delivery_id = id))]
+    async fn retry_webhook_delivery(&self, id: i64) -> Result<abi::WebhookDelivery, abi::Error> {
+        let delivery = sqlx::query_as(
+            "UPDATE rsvp.webhook_deliveries SET status = 'pending', attempts = 0, next_attempt_at = now()
+            WHERE id = $1 AND status = 'dead' RETURNING
+            id, webhook_id, change_id, status, attempts, last_error, response_status,
+            created_at, next_attempt_at, delivered_at",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+    // import the events of an iCalendar file as reservations or blocked windows. A dry run
+    // reports what would fail, conflicts included, without creating anything
+    #[instrument(skip_all, fields(mode = ?req.mode()))]
+    async fn import_calendar(
+        &self,
+        req: abi::ImportCalendarRequest,
+    ) -> Result<abi::ImportCalendarResponse, abi::Error> {
+        let mut results = req.get_results(Utc::now())?;
+
+        let mut tx = self.begin().await?;
+        let mut failed = false;
+        for result in results.iter_mut() {
+            let rsvp = result.reservation.take().unwrap_or_default();
+            // each occurrence gets a savepoint, so the others can still be checked after a failure
+            let mut savepoint = tx.begin().await?;
+            let created = if rsvp.is_blocked() {
+                insert_block(&mut savepoint, rsvp.clone()).await
+            } else {
+                self.reserve_in(&mut savepoint, rsvp.clone(), BookingRules::AllowingPast)
+                    .await
+            };
+            match created {
+                Ok(rsvp) => {
+                    savepoint.commit().await?;
+                    result.reservation = Some(rsvp);
+                }
+                Err(e) => {
+                    savepoint.rollback().await?;
+                    failed = true;
+                    result.reservation = Some(rsvp);
+                    result.error = e.to_string();
+                }
+            }
+        }
+
+        let commit = match req.mode() {
+            abi::ImportMode::Transactional => !failed,
+            abi::ImportMode::BestEffort => true,
+            _ => false,
+        };
+        let mut created = 0;
+        if commit {
+            tx.commit().await?;
+            created = results.iter().filter(|r| r.error.is_empty()).count() as i32;
+        } else {
+            tx.rollback().await?;
+            // nothing was created, so don't hand out ids
+            for result in results.iter_mut() {
+                if let Some(rsvp) = result.reservation.as_mut() {
+                    rsvp.id = 0;
+                }
+            }
+        }
+
+        Ok(abi::ImportCalendarResponse { results, created })
+    }
+    // remember a calendar feed token that was handed out
+    #[instrument(skip_all)]
+    async fn add_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error> {
+        sqlx::query(
+            "INSERT INTO rsvp.calendar_feed_tokens (nonce, user_id, resource_id) VALUES ($1, $2, $3)",
+        )
+        .bind(&token.nonce)
+        .bind(&token.user_id)
+        .bind(&token.resource_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+    // whether the calendar feed token was handed out and isn't revoked
+    #[instrument(skip_all)]
+    async fn has_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<bool, abi::Error> {
+        let found = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM rsvp.calendar_feed_tokens
+            WHERE nonce = $1 AND user_id = $2 AND resource_id = $3)",
+        )
+        .bind(&token.nonce)
+        .bind(&token.user_id)
+        .bind(&token.resource_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(found)
+    }
+    // revoke a calendar feed token, fails if it's unknown
+    #[instrument(skip_all)]
+    async fn revoke_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error> {
+        // fails with RowNotFound when nothing was deleted
+        let _: String = sqlx::query_scalar(
+            "DELETE FROM rsvp.calendar_feed_tokens
+            WHERE nonce = $1 AND user_id = $2 AND resource_id = $3 RETURNING nonce",
+        )
+        .bind(&token.nonce)
+        .bind(&token.user_id)
+        .bind(&token.resource_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(())
     }
 }
 
 impl ReservationManager {
     pub async fn new(pool: sqlx::PgPool) -> Self {
-        Self { pool }
+        Self {
+            pool,
+            idempotency_retention: Duration::hours(24),
+            idempotency_lease: Duration::minutes(1),
+            actor: None,
+            notifier: Default::default(),
+        }
+    }
+
+    pub fn with_idempotency_retention(mut self, retention: Duration) -> Self {
+        self.idempotency_retention = retention;
+        self
+    }
+
+    /// a request that hasn't finished within the lease, e.g. because it was cancelled, no
+    /// longer holds its idempotency key
+    pub fn with_idempotency_lease(mut self, lease: Duration) -> Self {
+        self.idempotency_lease = lease;
+        self
+    }
+
+    /// changes made through the returned manager are recorded as made by `actor`
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    /// begin a transaction, tagged with the actor for the audit history
+    pub(crate) async fn begin(&self) -> Result<Transaction<'static, Postgres>, abi::Error> {
+        let mut tx = self.pool.begin().await?;
+        if let Some(actor) = &self.actor {
+            sqlx::query("SELECT set_config('rsvp.actor', $1, true)")
+                .bind(actor)
+                .execute(&mut tx)
+                .await?;
+        }
+        Ok(tx)
+    }
+
+    async fn do_reserve(
+        &self,
+        rsvp: abi::Reservation,
+        rules: BookingRules,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let mut tx = self.begin().await?;
+        let rsvp = self.reserve_in(&mut tx, rsvp, rules).await?;
+        tx.commit().await?;
+
+        Ok(rsvp)
+    }
+
+    /// validate and insert a reservation on a connection in a transaction
+    async fn reserve_in(
+        &self,
+        conn: &mut PgConnection,
+        rsvp: abi::Reservation,
+        rules: BookingRules,
+    ) -> Result<abi::Reservation, abi::Error> {
+        rsvp.validate()?;
+
+        let resource = self.get_resource(rsvp.resource_id.clone()).await?;
+        rules.check(&resource, &rsvp, Utc::now())?;
+
+        self.check_quota(&mut *conn, &rsvp, 0, &resource).await?;
+        insert(conn, rsvp).await
+    }
+}
+
+/// the query of reservations matching a `ReservationQuery`, binds user id, resource id, timespan
+/// and status
+pub(crate) fn query_sql(desc: bool) -> &'static str {
+    // empty user/resource id and unknown status match everything
+    if desc {
+        "SELECT id, user_id, resource_id, timespan, note, status, labels, version FROM rsvp.reservations
+        WHERE ($1 = '' OR user_id = $1) AND ($2 = '' OR resource_id = $2) AND timespan && $3
+        AND ($4 = 'unknown' OR status = $4::rsvp.reservation_status)
+        ORDER BY lower(timespan) DESC, id DESC"
+    } else {
+        "SELECT id, user_id, resource_id, timespan, note, status, labels, version FROM rsvp.reservations
+        WHERE ($1 = '' OR user_id = $1) AND ($2 = '' OR resource_id = $2) AND timespan && $3
+        AND ($4 = 'unknown' OR status = $4::rsvp.reservation_status)
+        ORDER BY lower(timespan) ASC, id ASC"
+    }
+}
+
+async fn insert(
+    conn: &mut PgConnection,
+    mut rsvp: abi::Reservation,
+) -> Result<abi::Reservation, abi::Error> {
+    let status =
+        abi::ReservationStatus::from_i32(rsvp.status).unwrap_or(abi::ReservationStatus::Pending);
+
+    let timespan = rsvp.get_timestamp()?;
+    // generate a insert sql for the reservation
+    let sql = "INSERT INTO rsvp.reservations (user_id, resource_id, timespan, note, status, labels)
+        VALUES ($1, $2, $3, $4, $5::rsvp.reservation_status, $6) RETURNING id, version";
+    // execute the sql
+    let row = sqlx::query(sql)
+        .bind(rsvp.user_id.clone())
+        .bind(rsvp.resource_id.clone())
+        .bind(timespan)
+        .bind(rsvp.note.clone())
+        .bind(status.to_string())
+        .bind(Json(&rsvp.labels))
+        .fetch_one(conn)
+        .await?;
+    rsvp.id = row.get(0);
+    rsvp.version = row.get(1);
+    Span::current().record("reservation_id", rsvp.id);
+    Ok(rsvp)
+}
+
+/// insert a blocked window, it has no user
+async fn insert_block(
+    conn: &mut PgConnection,
+    mut block: abi::Reservation,
+) -> Result<abi::Reservation, abi::Error> {
+    block.validate()?;
+    let row = sqlx::query(
+        "INSERT INTO rsvp.reservations (user_id, resource_id, timespan, note, status, labels)
+        VALUES (NULL, $1, $2, $3, 'blocked', $4) RETURNING id, version",
+    )
+    .bind(block.resource_id.clone())
+    .bind(block.get_timestamp()?)
+    .bind(block.note.clone())
+    .bind(Json(&block.labels))
+    .fetch_one(conn)
+    .await?;
+    block.id = row.get(0);
+    block.version = row.get(1);
+    Ok(block)
+}
+
+/// lock the reservation and make sure nobody changed it since the caller read it
+async fn check_version(
+    conn: &mut PgConnection,
+    id: i64,
+    expected: Option<i64>,
+) -> Result<(), abi::Error> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    let current: i64 =
+        sqlx::query_scalar("SELECT version FROM rsvp.reservations WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_one(conn)
+            .await?;
+    if current != expected {
+        return Err(abi::Error::VersionMismatch { expected, current });
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    // only what depends on Postgres itself: the exclusion constraint, the triggers and the
+    // advisory locks. What every backend shares is covered by the conformance cases
+
+    use chrono::FixedOffset;
     use sqlx::PgPool;
 
     use super::*;
+
     #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
-    async fn reserve_should_work_for_valid_windows() {
-        let (rsvp, _manager) = make_reservation(migrated_pool.clone()).await;
-        assert!(rsvp.id > 0);
+    async fn reserve_within_resource_buffer_should_conflict() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let resource = abi::Resource::new("room-1", Duration::zero(), Duration::minutes(15));
+        manager.upsert_resource(resource).await.unwrap();
+
+        let start = "2099-12-26T10:00:00-0700".parse().unwrap();
+        let end = "2099-12-26T11:00:00-0700".parse().unwrap();
+        let rsvp = abi::Reservation::new_pending("user1", "room-1", start, end, "meeting");
+        let rsvp = manager.reserve(rsvp).await.unwrap();
+        // the actual timespan is returned, not the buffered one the constraint checks
+        let rsvp = manager.get(rsvp.id).await.unwrap();
+        assert_eq!(
+            rsvp.end,
+            Some(abi::convert_to_timestamp(end.with_timezone(&Utc)))
+        );
+
+        let start = "2099-12-26T11:10:00-0700".parse().unwrap();
+        let end = "2099-12-26T12:00:00-0700".parse().unwrap();
+        let rsvp = abi::Reservation::new_pending("user2", "room-1", start, end, "too early");
+        let err = manager.reserve(rsvp).await.unwrap_err();
+        assert!(matches!(err, abi::Error::ConflictReservation(_)));
+
+        let start = "2099-12-26T11:15:00-0700".parse().unwrap();
+        let rsvp = abi::Reservation::new_pending("user2", "room-1", start, end, "after cleaning");
+        assert!(manager.reserve(rsvp).await.is_ok());
     }
 
     #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
-    async fn reserve_change_status_should_work() {
+    async fn reschedule_should_be_recorded_by_the_trigger() {
         let (rsvp, manager) = make_reservation(migrated_pool.clone()).await;
-        assert!(rsvp.id > 0);
-        let rsvp = manager.change_status(rsvp.id).await.unwrap();
-        assert_eq!(rsvp.status, abi::ReservationStatus::Confirmed as i32);
+        // overlaps the reservation's own current window
+        let req = make_reschedule(
+            rsvp.id,
+            "2099-12-26T15:00:00-0700",
+            "2099-12-29T12:00:00-0700",
+        );
+        manager.reschedule(req.clone()).await.unwrap();
+        let req = abi::RescheduleRequest {
+            resource_id: "resource2".to_string(),
+            ..req
+        };
+        manager.reschedule(req).await.unwrap();
+
+        let ops: Vec<String> = sqlx::query_scalar(
+            "SELECT op::TEXT FROM rsvp.reservation_changes WHERE reservation_id = $1 ORDER BY id",
+        )
+        .bind(rsvp.id)
+        .fetch_all(&migrated_pool)
+        .await
+        .unwrap();
+        assert_eq!(ops, vec!["create", "update", "update"]);
     }
 
     #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
-    async fn reserve_update_note_should_work() {
+    async fn reschedule_into_taken_window_should_conflict() {
         let (rsvp, manager) = make_reservation(migrated_pool.clone()).await;
-        assert!(rsvp.id > 0);
-        let rsvp = manager
-            .update(rsvp.id, "new note".to_string())
-            .await
-            .unwrap();
-        assert_eq!(rsvp.note, "new note");
+        let start = "2099-12-30T10:00:00-0700".parse().unwrap();
+        let end = "2099-12-30T12:00:00-0700".parse().unwrap();
+        let other = abi::Reservation::new_pending("user2", "resource1", start, end, "taken");
+        manager.reserve(other).await.unwrap();
+
+        let req = make_reschedule(
+            rsvp.id,
+            "2099-12-30T11:00:00-0700",
+            "2099-12-30T13:00:00-0700",
+        );
+        let err = manager.reschedule(req).await.unwrap_err();
+        assert!(matches!(err, abi::Error::ConflictReservation(_)));
+        assert_eq!(manager.get(rsvp.id).await.unwrap(), rsvp);
     }
 
     #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
-    async fn get_reserve_should_work() {
-        let (rsvp, manager) = make_reservation(migrated_pool.clone()).await;
-        assert!(rsvp.id > 0);
-        let rsvp = manager.get(rsvp.id).await.unwrap();
-        assert_eq!(rsvp.note, "just note");
+    async fn concurrent_reservations_should_not_both_pass_the_quota() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let policy = abi::QuotaPolicy {
+            kind: abi::QuotaKind::MaxActive as i32,
+            user_id: "user1".to_string(),
+            limit: 1,
+            ..Default::default()
+        };
+        manager.upsert_quota_policy(policy).await.unwrap();
+
+        let reserve = |rid: &str| {
+            let start = "2099-12-26T10:00:00-0700".parse().unwrap();
+            let end = "2099-12-26T11:00:00-0700".parse().unwrap();
+            let rsvp = abi::Reservation::new_pending("user1", rid, start, end, "racing");
+            let manager = manager.clone();
+            async move { manager.reserve(rsvp).await }
+        };
+        // the advisory lock of the user serializes the checks
+        let (first, second) = tokio::join!(reserve("room-1"), reserve("room-2"));
+        assert!(first.is_ok() != second.is_ok());
+        let err = first.and(second).unwrap_err();
+        assert!(matches!(err, abi::Error::QuotaExceeded { .. }));
     }
 
-    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
-    async fn delete_reserve_should_work() {
-        let (rsvp, manager) = make_reservation(migrated_pool.clone()).await;
-        assert!(rsvp.id > 0);
-        manager.delete(rsvp.id).await.unwrap();
-        let rsvp = manager.get(rsvp.id).await;
-        assert!(rsvp.is_err());
+    fn make_reschedule(id: i64, start: &str, end: &str) -> abi::RescheduleRequest {
+        let start: DateTime<FixedOffset> = start.parse().unwrap();
+        let end: DateTime<FixedOffset> = end.parse().unwrap();
+        abi::RescheduleRequest {
+            id,
+            start: Some(abi::convert_to_timestamp(start.with_timezone(&Utc))),
+            end: Some(abi::convert_to_timestamp(end.with_timezone(&Utc))),
+            ..Default::default()
+        }
     }
 
     async fn make_reservation(pool: PgPool) -> (abi::Reservation, ReservationManager) {
-        let manager = ReservationManager { pool: pool.clone() };
-        let start = "2022-12-25T15:00:00-0700".parse().unwrap();
-        let end = "2022-12-28T12:00:00-0700".parse().unwrap();
+        let manager = ReservationManager::new(pool.clone()).await;
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-28T12:00:00-0700".parse().unwrap();
         let rsvp = abi::Reservation::new_pending("user1", "resource1", start, end, "just note");
         (manager.reserve(rsvp).await.unwrap(), manager)
     }
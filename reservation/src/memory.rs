@@ -0,0 +1,1289 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use prost::Message;
+use prost_types::FieldMask;
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    quota::{hours, split_by_week, week_of},
+    BookingRules, ChangeReceiver, Rsvp, Storage,
+};
+
+// changes sent to a listener at a time
+const BATCH_SIZE: usize = 100;
+
+/// keeps everything in memory, for tests and local development. Conflicts (buffers included),
+/// versions, booking rules, quotas, the history and the change feed work like they do with
+/// Postgres. Webhooks are kept, but nothing delivers to them
+#[derive(Debug, Clone)]
+pub struct InMemoryManager {
+    shared: Arc<Shared>,
+    // how long idempotency keys are kept
+    idempotency_retention: Duration,
+    // how long a request holds the key it claimed
+    idempotency_lease: Duration,
+    // recorded in the audit history for every change made through this manager
+    actor: Option<String>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    // sequence of the last change, listeners wait for it to move
+    last_change: watch::Sender<i64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct State {
+    reservations: BTreeMap<i64, Row>,
+    // buffered timespans of every resource
+    timespans: HashMap<String, IntervalTree>,
+    resources: HashMap<String, abi::Resource>,
+    policies: BTreeMap<i64, abi::QuotaPolicy>,
+    // groups of every user
+    groups: HashMap<String, Vec<String>>,
+    history: Vec<abi::ReservationHistoryEntry>,
+    changes: Vec<abi::ListenResponse>,
+    webhooks: BTreeMap<i64, abi::Webhook>,
+    // by nonce
+    calendar_feed_tokens: HashMap<String, abi::CalendarFeedToken>,
+    idempotency_keys: HashMap<(String, String), IdempotencyKey>,
+    ids: Ids,
+}
+
+#[derive(Debug, Clone)]
+struct Row {
+    rsvp: abi::Reservation,
+    // the timespan extended by the buffers of the resource when it was made or moved
+    buffered: (DateTime<Utc>, DateTime<Utc>),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Ids {
+    reservation: i64,
+    policy: i64,
+    webhook: i64,
+    history: i64,
+    change: i64,
+}
+
+#[derive(Debug, Clone)]
+struct IdempotencyKey {
+    request: Vec<u8>,
+    response: Option<Vec<u8>>,
+    created_at: DateTime<Utc>,
+    // who holds the claim, a retry may take it over once the lease has passed
+    claimed_at: DateTime<Utc>,
+}
+
+/// the buffered timespans of a resource. Like with `reservations_conflict` they never overlap,
+/// so ordered by start the only one that can overlap [start, end) is the last one starting
+/// before end
+#[derive(Debug, Clone, Default)]
+struct IntervalTree {
+    spans: BTreeMap<DateTime<Utc>, (DateTime<Utc>, i64)>,
+}
+
+impl IntervalTree {
+    fn find_overlap(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        self.spans
+            .range(..end)
+            .next_back()
+            .filter(|(_, (e, _))| *e > start)
+            .map(|(s, (e, _))| (*s, *e))
+    }
+
+    /// the spans overlapping [start, end), ordered by start
+    fn overlapping(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> impl Iterator<Item = (DateTime<Utc>, DateTime<Utc>)> + '_ {
+        self.spans
+            .range(..end)
+            .filter(move |(_, (e, _))| *e > start)
+            .map(|(s, (e, _))| (*s, *e))
+    }
+}
+
+#[async_trait]
+impl Rsvp for InMemoryManager {
+    // make a reservation
+    async fn reserve(&self, rsvp: abi::Reservation) -> Result<abi::Reservation, abi::Error> {
+        let now = Utc::now();
+        self.write(|state| state.reserve(rsvp, BookingRules::All, now, self.actor()))
+    }
+    // make a reservation without checking the booking rules of the resource
+    async fn reserve_bypassing_rules(
+        &self,
+        rsvp: abi::Reservation,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let now = Utc::now();
+        self.write(|state| state.reserve(rsvp, BookingRules::Bypass, now, self.actor()))
+    }
+    // make several reservations in one transaction, if any fails nothing is reserved
+    async fn reserve_batch(
+        &self,
+        rsvps: Vec<abi::Reservation>,
+    ) -> Result<abi::ReserveBatchResponse, abi::Error> {
+        let now = Utc::now();
+        let mut state = self.lock();
+        // a failed reservation changes nothing, so the others can still be checked after it
+        let mut tx = state.clone();
+        let mut results = Vec::with_capacity(rsvps.len());
+        let mut committed = true;
+        for rsvp in rsvps {
+            let (reservation, error) =
+                match tx.reserve(rsvp.clone(), BookingRules::All, now, self.actor()) {
+                    Ok(rsvp) => (rsvp, String::new()),
+                    Err(e) => {
+                        committed = false;
+                        (rsvp, e.to_string())
+                    }
+                };
+            results.push(abi::ReserveBatchResult {
+                reservation: Some(reservation),
+                error,
+            });
+        }
+
+        if committed {
+            self.commit(&mut state, tx);
+        } else {
+            // nothing was reserved, so don't hand out ids
+            for result in results.iter_mut() {
+                if let Some(rsvp) = result.reservation.as_mut() {
+                    rsvp.id = 0;
+                }
+            }
+        }
+
+        Ok(abi::ReserveBatchResponse { results, committed })
+    }
+    // change reservation status (if current status is pending, change it to confirmed)
+    async fn change_status(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error> {
+        self.write(|state| {
+            let mut rsvp = state.get(id)?.rsvp.clone();
+            check_version(&rsvp, expected_version)?;
+            if rsvp.status == abi::ReservationStatus::Pending as i32 {
+                rsvp.status = abi::ReservationStatus::Confirmed as i32;
+            }
+            state.replace(rsvp, false, self.actor())
+        })
+    }
+    // update the fields of a reservation listed in the mask
+    async fn update(
+        &self,
+        rsvp: abi::Reservation,
+        mask: FieldMask,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let fields = abi::UpdateField::parse_mask(&mask)?;
+        if fields.contains(&abi::UpdateField::UserId) && rsvp.user_id.is_empty() {
+            return Err(abi::Error::InvalidUserId(rsvp.user_id));
+        }
+
+        let now = Utc::now();
+        self.write(|state| {
+            let current = state.get(rsvp.id)?.rsvp.clone();
+            // a version of 0 means the caller doesn't care about concurrent changes
+            check_version(&current, (rsvp.version != 0).then_some(rsvp.version))?;
+
+            let mut updated = current.clone();
+            let mut changes_time = false;
+            for field in &fields {
+                match field {
+                    abi::UpdateField::Note => updated.note = rsvp.note.clone(),
+                    // blocked windows have no user
+                    abi::UpdateField::UserId if current.is_blocked() => {
+                        return Err(abi::Error::InvalidUserId(rsvp.user_id.clone()));
+                    }
+                    abi::UpdateField::UserId => updated.user_id = rsvp.user_id.clone(),
+                    abi::UpdateField::Labels => updated.labels = rsvp.labels.clone(),
+                    abi::UpdateField::Start => {
+                        updated.start = rsvp.start.clone();
+                        changes_time = true;
+                    }
+                    abi::UpdateField::End => {
+                        updated.end = rsvp.end.clone();
+                        changes_time = true;
+                    }
+                }
+            }
+            // the new timespan is merged with the current one and checked like a reschedule
+            let req = abi::RescheduleRequest {
+                id: rsvp.id,
+                start: updated.start.clone(),
+                end: updated.end.clone(),
+                ..Default::default()
+            };
+            let span = changes_time.then(|| req.get_timespan()).transpose()?;
+            let changes_user = fields.contains(&abi::UpdateField::UserId);
+            if (changes_time || changes_user) && !current.is_blocked() {
+                let resource = state.get_resource(&current.resource_id);
+                if let Some((start, end)) = span {
+                    resource.check_booking_rules_for_move(timespan(&current).0, start, end, now)?;
+                }
+                state.check_quota(&updated, current.id, &resource, now)?;
+            }
+            state.replace(updated, changes_time, self.actor())
+        })
+    }
+    // move or resize a reservation, optionally to another resource
+    async fn reschedule(
+        &self,
+        req: abi::RescheduleRequest,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let (start, end) = req.get_timespan()?;
+
+        let now = Utc::now();
+        self.write(|state| {
+            let current = state.get(req.id)?.rsvp.clone();
+            check_version(
+                &current,
+                (req.expected_version != 0).then_some(req.expected_version),
+            )?;
+
+            let mut rsvp = current.clone();
+            if !req.resource_id.is_empty() {
+                rsvp.resource_id = req.resource_id.clone();
+            }
+            rsvp.start = Some(abi::convert_to_timestamp(start));
+            rsvp.end = Some(abi::convert_to_timestamp(end));
+            if !current.is_blocked() {
+                let resource = state.get_resource(&rsvp.resource_id);
+                resource.check_booking_rules_for_move(timespan(&current).0, start, end, now)?;
+                state.check_quota(&rsvp, current.id, &resource, now)?;
+            }
+            state.replace(rsvp, true, self.actor())
+        })
+    }
+    // delete reservation
+    async fn delete(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error> {
+        self.write(|state| {
+            check_version(&state.get(id)?.rsvp, expected_version)?;
+            Ok(state.remove(id, self.actor()))
+        })
+    }
+    // get reservation by id
+    async fn get(&self, id: i64) -> Result<abi::Reservation, abi::Error> {
+        Ok(self.lock().get(id)?.rsvp.clone())
+    }
+    // query reservations
+    async fn query(
+        &self,
+        query: abi::ReservationQuery,
+    ) -> Result<Vec<abi::Reservation>, abi::Error> {
+        let range = query.get_timespan()?;
+        let state = self.lock();
+        // empty user/resource id and unknown status match everything
+        let mut rsvps: Vec<abi::Reservation> = state
+            .reservations
+            .values()
+            .map(|row| &row.rsvp)
+            .filter(|rsvp| query.user_id.is_empty() || rsvp.user_id == query.user_id)
+            .filter(|rsvp| query.resource_id.is_empty() || rsvp.resource_id == query.resource_id)
+            .filter(|rsvp| {
+                query.status == abi::ReservationStatus::Unknown as i32
+                    || rsvp.status == query.status
+            })
+            .filter(|rsvp| {
+                let (start, end) = timespan(rsvp);
+                // `&&` of [start, end) with the query range
+                let after_start = match range.start {
+                    Bound::Included(s) => end > s,
+                    _ => true,
+                };
+                let before_end = match range.end {
+                    Bound::Excluded(e) => start < e,
+                    _ => true,
+                };
+                after_start && before_end
+            })
+            .cloned()
+            .collect();
+        rsvps.sort_by_key(|rsvp| (timespan(rsvp).0, rsvp.id));
+        if query.desc {
+            rsvps.reverse();
+        }
+
+        Ok(rsvps)
+    }
+    // listen to changes matching the request filters, the ones after `since` are replayed first.
+    // Without it only new changes
+    async fn listen(&self, req: abi::ListenRequest) -> Result<ChangeReceiver, abi::Error> {
+        let mut last_change = self.shared.last_change.subscribe();
+        let mut last = match req.since {
+            Some(since) => since,
+            None => *last_change.borrow_and_update(),
+        };
+
+        let (tx, rx) = mpsc::channel(BATCH_SIZE);
+        let shared = self.shared.clone();
+        tokio::spawn(async move {
+            let ops = req.get_ops();
+            let status = abi::ReservationStatus::from_i32(req.status)
+                .unwrap_or(abi::ReservationStatus::Unknown);
+            loop {
+                let (changes, scanned_to, scanned): (Vec<abi::ListenResponse>, _, _) = {
+                    let state = shared.state.lock().unwrap();
+                    let batch: Vec<_> = state
+                        .changes
+                        .iter()
+                        .filter(|change| change.sequence > last)
+                        .take(BATCH_SIZE)
+                        .collect();
+                    // filters look at the reservation as of the change, empty ones match everything
+                    let changes = batch
+                        .iter()
+                        .filter(|change| {
+                            let rsvp = change.reservation.as_ref().unwrap();
+                            (req.resource_ids.is_empty()
+                                || req.resource_ids.contains(&rsvp.resource_id))
+                                && (req.user_id.is_empty() || rsvp.user_id == req.user_id)
+                                && (status == abi::ReservationStatus::Unknown
+                                    || rsvp.status == status as i32)
+                                && (ops.is_empty() || ops.contains(&change.op().to_string()))
+                        })
+                        .map(|change| (*change).clone())
+                        .collect();
+                    (
+                        changes,
+                        batch.last().map(|change| change.sequence),
+                        batch.len(),
+                    )
+                };
+                let caught_up = scanned < BATCH_SIZE;
+                for change in changes {
+                    if tx.send(Ok(change)).await.is_err() {
+                        return;
+                    }
+                }
+                // past the changes that didn't match as well
+                if let Some(scanned_to) = scanned_to {
+                    last = scanned_to;
+                }
+                if caught_up {
+                    tokio::select! {
+                        changed = last_change.changed() => {
+                            if changed.is_err() {
+                                return;
+                            }
+                        }
+                        _ = tx.closed() => return,
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+    // block resources for a (recurring) window, optionally cancel overlapping pending reservations
+    async fn block(&self, window: abi::BlockWindow) -> Result<abi::BlockResponse, abi::Error> {
+        let timespans = window.get_timespans()?;
+        let spans: Vec<(DateTime<Utc>, DateTime<Utc>)> = timespans
+            .iter()
+            .map(|range| match (range.start, range.end) {
+                (Bound::Included(start), Bound::Excluded(end)) => (start, end),
+                _ => unreachable!("block windows are bounded"),
+            })
+            .collect();
+
+        self.transaction(|tx| {
+            let mut cancelled = Vec::new();
+            if window.cancel_pending {
+                for (start, end) in &spans {
+                    let ids: Vec<i64> = tx
+                        .reservations
+                        .values()
+                        .map(|row| &row.rsvp)
+                        .filter(|rsvp| {
+                            window.resource_ids.contains(&rsvp.resource_id)
+                                && rsvp.status == abi::ReservationStatus::Pending as i32
+                                && overlaps(timespan(rsvp), (*start, *end))
+                        })
+                        .map(|rsvp| rsvp.id)
+                        .collect();
+                    for id in ids {
+                        cancelled.push(tx.remove(id, self.actor()));
+                    }
+                }
+            }
+
+            let mut blocks = Vec::with_capacity(spans.len() * window.resource_ids.len());
+            for (start, end) in &spans {
+                for rid in &window.resource_ids {
+                    let block = abi::Reservation {
+                        resource_id: rid.clone(),
+                        start: Some(abi::convert_to_timestamp(*start)),
+                        end: Some(abi::convert_to_timestamp(*end)),
+                        note: window.reason.clone(),
+                        status: abi::ReservationStatus::Blocked as i32,
+                        ..Default::default()
+                    };
+                    // anything still overlapping conflicts
+                    blocks.push(tx.insert(block, self.actor())?);
+                }
+            }
+            Ok(abi::BlockResponse { blocks, cancelled })
+        })
+    }
+    // create or update resource settings
+    async fn upsert_resource(&self, resource: abi::Resource) -> Result<abi::Resource, abi::Error> {
+        resource.validate()?;
+
+        // buffers are always set, like the columns they'd be stored in
+        let pb = abi::convert_to_pb_duration;
+        let resource = abi::Resource {
+            pre_buffer: Some(pb(resource.get_pre_buffer())),
+            post_buffer: Some(pb(resource.get_post_buffer())),
+            min_duration: resource.get_min_duration().map(pb),
+            max_duration: resource.get_max_duration().map(pb),
+            booking_horizon: resource.get_booking_horizon().map(pb),
+            lead_time: resource.get_lead_time().map(pb),
+            ..resource
+        };
+        let mut state = self.lock();
+        state
+            .resources
+            .insert(resource.id.clone(), resource.clone());
+
+        Ok(resource)
+    }
+    // get resource settings, a resource without settings has no buffers
+    async fn get_resource(&self, id: String) -> Result<abi::Resource, abi::Error> {
+        Ok(self.lock().get_resource(&id))
+    }
+    // find free time slots of a resource, taking its buffers into account
+    async fn availability(
+        &self,
+        req: abi::AvailabilityRequest,
+    ) -> Result<Vec<abi::TimeSlot>, abi::Error> {
+        let (start, end) = req.get_timespan()?;
+        let state = self.lock();
+        let resource = state.get_resource(&req.resource_id);
+
+        // a reservation in [start, end) could hit anything within its own buffers
+        let busy: Vec<(DateTime<Utc>, DateTime<Utc>)> = match state.timespans.get(&resource.id) {
+            Some(tree) => tree
+                .overlapping(
+                    start - resource.get_pre_buffer(),
+                    end + resource.get_post_buffer(),
+                )
+                .collect(),
+            None => vec![],
+        };
+
+        Ok(resource.free_slots(start, end, &busy))
+    }
+    // create or update a quota policy
+    async fn upsert_quota_policy(
+        &self,
+        mut policy: abi::QuotaPolicy,
+    ) -> Result<abi::QuotaPolicy, abi::Error> {
+        policy.validate()?;
+
+        let mut state = self.lock();
+        if policy.id == 0 {
+            state.ids.policy += 1;
+            policy.id = state.ids.policy;
+        } else if !state.policies.contains_key(&policy.id) {
+            return Err(not_found());
+        }
+        state.policies.insert(policy.id, policy.clone());
+
+        Ok(policy)
+    }
+    // delete a quota policy
+    async fn delete_quota_policy(&self, id: i64) -> Result<(), abi::Error> {
+        self.lock().policies.remove(&id);
+
+        Ok(())
+    }
+    // replace the groups a user belongs to
+    async fn set_user_groups(
+        &self,
+        user_id: String,
+        mut group_ids: Vec<String>,
+    ) -> Result<(), abi::Error> {
+        if user_id.is_empty() {
+            return Err(abi::Error::InvalidUserId(user_id));
+        }
+
+        group_ids.sort();
+        group_ids.dedup();
+        self.lock().groups.insert(user_id, group_ids);
+
+        Ok(())
+    }
+    // get the current usage and remaining quota of a user
+    async fn get_quota(&self, user_id: String) -> Result<Vec<abi::QuotaUsage>, abi::Error> {
+        let state = self.lock();
+        let now = Utc::now();
+        let usages = state
+            .get_policies(&user_id)
+            .into_iter()
+            .map(|policy| {
+                let used = state.get_usage(&policy, &user_id, 0, now, now);
+                abi::QuotaUsage::new(policy, used)
+            })
+            .collect();
+
+        Ok(usages)
+    }
+    // get the change history of a reservation, oldest first
+    async fn get_history(&self, id: i64) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error> {
+        let state = self.lock();
+        let entries = state
+            .history
+            .iter()
+            .filter(|entry| entry.reservation_id == id)
+            .cloned()
+            .collect();
+
+        Ok(entries)
+    }
+    // query the change history by actor and time range
+    async fn query_history(
+        &self,
+        query: abi::HistoryQuery,
+    ) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error> {
+        let range = query.get_timespan()?;
+        let state = self.lock();
+        // empty actor matches everything
+        let mut entries: Vec<abi::ReservationHistoryEntry> = state
+            .history
+            .iter()
+            .filter(|entry| query.actor.is_empty() || entry.actor == query.actor)
+            .filter(|entry| {
+                let changed_at = entry.changed_at.clone().unwrap();
+                let changed_at = abi::convert_to_utc_timestamp(changed_at).unwrap();
+                range.contains(&changed_at)
+            })
+            .cloned()
+            .collect();
+        entries.sort_by_key(|entry| {
+            let changed_at = entry.changed_at.clone().unwrap();
+            ((changed_at.seconds, changed_at.nanos), entry.id)
+        });
+        if query.desc {
+            entries.reverse();
+        }
+
+        Ok(entries)
+    }
+    // create or update a webhook subscription, it gets the changes from now on
+    async fn upsert_webhook(&self, webhook: abi::Webhook) -> Result<abi::Webhook, abi::Error> {
+        webhook.validate(true)?;
+
+        let status = abi::ReservationStatus::from_i32(webhook.status)
+            .unwrap_or(abi::ReservationStatus::Unknown);
+        let ops = webhook
+            .get_ops()
+            .iter()
+            .filter_map(|op| match op.as_str() {
+                "create" => Some(abi::ReservationUpdateType::Create as i32),
+                "update" => Some(abi::ReservationUpdateType::Update as i32),
+                "delete" => Some(abi::ReservationUpdateType::Delete as i32),
+                _ => None,
+            })
+            .collect();
+        let mut webhook = abi::Webhook {
+            status: status as i32,
+            ops,
+            ..webhook
+        };
+        let mut state = self.lock();
+        if webhook.id == 0 {
+            state.ids.webhook += 1;
+            webhook.id = state.ids.webhook;
+        } else {
+            let current = state.webhooks.get(&webhook.id).ok_or_else(not_found)?;
+            // an empty secret keeps the current one
+            if webhook.secret.is_empty() {
+                webhook.secret = current.secret.clone();
+            }
+        }
+        state.webhooks.insert(webhook.id, webhook.clone());
+
+        // secrets are write only
+        webhook.secret.clear();
+        Ok(webhook)
+    }
+    // delete a webhook subscription and its delivery log
+    async fn delete_webhook(&self, id: i64) -> Result<(), abi::Error> {
+        self.lock().webhooks.remove(&id);
+
+        Ok(())
+    }
+    // list webhook subscriptions, without secrets
+    async fn list_webhooks(&self) -> Result<Vec<abi::Webhook>, abi::Error> {
+        let state = self.lock();
+        let webhooks = state
+            .webhooks
+            .values()
+            .map(|webhook| abi::Webhook {
+                secret: String::new(),
+                ..webhook.clone()
+            })
+            .collect();
+
+        Ok(webhooks)
+    }
+    // get the delivery log of a webhook, nothing is delivered from memory
+    async fn list_webhook_deliveries(
+        &self,
+        _req: abi::ListWebhookDeliveriesRequest,
+    ) -> Result<Vec<abi::WebhookDelivery>, abi::Error> {
+        Ok(vec![])
+    }
+    // put a dead delivery back into the queue, there are none in memory
+    async fn retry_webhook_delivery(&self, _id: i64) -> Result<abi::WebhookDelivery, abi::Error> {
+        Err(not_found())
+    }
+    // import the events of an iCalendar file as reservations or blocked windows. A dry run
+    // reports what would fail, conflicts included, without creating anything
+    async fn import_calendar(
+        &self,
+        req: abi::ImportCalendarRequest,
+    ) -> Result<abi::ImportCalendarResponse, abi::Error> {
+        let now = Utc::now();
+        let mut results = req.get_results(now)?;
+
+        let mut state = self.lock();
+        // a failed occurrence changes nothing, so the others can still be checked after it
+        let mut tx = state.clone();
+        let mut failed = false;
+        for result in results.iter_mut() {
+            let rsvp = result.reservation.take().unwrap_or_default();
+            let created = if rsvp.is_blocked() {
+                rsvp.validate()
+                    .and_then(|_| tx.insert(rsvp.clone(), self.actor()))
+            } else {
+                tx.reserve(rsvp.clone(), BookingRules::AllowingPast, now, self.actor())
+            };
+            match created {
+                Ok(rsvp) => result.reservation = Some(rsvp),
+                Err(e) => {
+                    failed = true;
+                    result.reservation = Some(rsvp);
+                    result.error = e.to_string();
+                }
+            }
+        }
+
+        let commit = match req.mode() {
+            abi::ImportMode::Transactional => !failed,
+            abi::ImportMode::BestEffort => true,
+            _ => false,
+        };
+        let mut created = 0;
+        if commit {
+            self.commit(&mut state, tx);
+            created = results.iter().filter(|r| r.error.is_empty()).count() as i32;
+        } else {
+            // nothing was created, so don't hand out ids
+            for result in results.iter_mut() {
+                if let Some(rsvp) = result.reservation.as_mut() {
+                    rsvp.id = 0;
+                }
+            }
+        }
+
+        Ok(abi::ImportCalendarResponse { results, created })
+    }
+    // remember a calendar feed token that was handed out
+    async fn add_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error> {
+        self.lock()
+            .calendar_feed_tokens
+            .insert(token.nonce.clone(), token);
+
+        Ok(())
+    }
+    // whether the calendar feed token was handed out and isn't revoked
+    async fn has_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<bool, abi::Error> {
+        Ok(self.lock().calendar_feed_tokens.get(&token.nonce) == Some(&token))
+    }
+    // revoke a calendar feed token, fails if it's unknown
+    async fn revoke_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error> {
+        let mut state = self.lock();
+        if state.calendar_feed_tokens.get(&token.nonce) != Some(&token) {
+            return Err(not_found());
+        }
+        state.calendar_feed_tokens.remove(&token.nonce);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for InMemoryManager {
+    // a handle whose changes are recorded as made by `actor`
+    fn acting_as(&self, actor: &str) -> Self {
+        self.clone().with_actor(actor)
+    }
+    // run `f` at most once per operation and idempotency key
+    async fn idempotent<Req, Resp, F, Fut>(
+        &self,
+        operation: &str,
+        key: &str,
+        req: &Req,
+        f: F,
+    ) -> Result<Resp, abi::Error>
+    where
+        Req: Message,
+        Resp: Message + Default,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<Resp, abi::Error>> + Send,
+    {
+        if key.is_empty() {
+            return f().await;
+        }
+
+        let request = req.encode_to_vec();
+        let id = (operation.to_string(), key.to_string());
+        let claimed_at = {
+            let mut state = self.lock();
+            let now = Utc::now();
+            let mut created_at = now;
+            if let Some(stored) = state.idempotency_keys.get(&id) {
+                if stored.created_at >= now - self.idempotency_retention {
+                    if stored.request != request {
+                        return Err(abi::Error::IdempotencyKeyReused(key.to_string()));
+                    }
+                    match &stored.response {
+                        Some(response) => {
+                            return Resp::decode(response.as_slice())
+                                .map_err(|_| abi::Error::Unknown);
+                        }
+                        // the request holding it was dropped or is stuck, take over
+                        None if stored.claimed_at < now - self.idempotency_lease => {
+                            created_at = stored.created_at;
+                        }
+                        None => return Err(abi::Error::IdempotencyKeyInProgress(key.to_string())),
+                    }
+                }
+            }
+            // claim the key, only one request can hold it at a time
+            state.idempotency_keys.insert(
+                id.clone(),
+                IdempotencyKey {
+                    request,
+                    response: None,
+                    created_at,
+                    claimed_at: now,
+                },
+            );
+            now
+        };
+
+        let result = f().await;
+        let mut state = self.lock();
+        // unless the claim was taken over in the meantime
+        let ours = matches!(state.idempotency_keys.get(&id), Some(stored) if stored.claimed_at == claimed_at);
+        if ours {
+            match &result {
+                Ok(resp) => {
+                    if let Some(stored) = state.idempotency_keys.get_mut(&id) {
+                        stored.response = Some(resp.encode_to_vec());
+                    }
+                }
+                Err(_) => {
+                    state.idempotency_keys.remove(&id);
+                }
+            }
+        }
+
+        result
+    }
+    // remove the idempotency keys older than the retention window
+    async fn purge_idempotency_keys(&self) -> Result<u64, abi::Error> {
+        let expired_before = Utc::now() - self.idempotency_retention;
+        let mut state = self.lock();
+        let before = state.idempotency_keys.len();
+        state
+            .idempotency_keys
+            .retain(|_, stored| stored.created_at >= expired_before);
+        Ok((before - state.idempotency_keys.len()) as u64)
+    }
+}
+
+impl InMemoryManager {
+    pub fn new() -> Self {
+        let (last_change, _) = watch::channel(0);
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                last_change,
+            }),
+            idempotency_retention: Duration::hours(24),
+            idempotency_lease: Duration::minutes(1),
+            actor: None,
+        }
+    }
+
+    pub fn with_idempotency_retention(mut self, retention: Duration) -> Self {
+        self.idempotency_retention = retention;
+        self
+    }
+
+    /// a request that hasn't finished within the lease no longer holds its idempotency key
+    pub fn with_idempotency_lease(mut self, lease: Duration) -> Self {
+        self.idempotency_lease = lease;
+        self
+    }
+
+    /// changes made through the returned manager are recorded as made by `actor`
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    fn actor(&self) -> &str {
+        self.actor.as_deref().unwrap_or_default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    /// run `f` on the state, it must not change anything when it fails
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut State) -> Result<T, abi::Error>,
+    ) -> Result<T, abi::Error> {
+        let mut state = self.lock();
+        let result = f(&mut state);
+        self.notify(&state);
+        result
+    }
+
+    /// run `f` on a copy of the state, which replaces the state only if `f` succeeds
+    fn transaction<T>(
+        &self,
+        f: impl FnOnce(&mut State) -> Result<T, abi::Error>,
+    ) -> Result<T, abi::Error> {
+        let mut state = self.lock();
+        let mut tx = state.clone();
+        let result = f(&mut tx)?;
+        self.commit(&mut state, tx);
+        Ok(result)
+    }
+
+    fn commit(&self, state: &mut MutexGuard<'_, State>, tx: State) {
+        **state = tx;
+        self.notify(state);
+    }
+
+    fn notify(&self, state: &State) {
+        self.shared.last_change.send_if_modified(|last| {
+            let changed = *last != state.ids.change;
+            *last = state.ids.change;
+            changed
+        });
+    }
+}
+
+impl Default for InMemoryManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn get(&self, id: i64) -> Result<&Row, abi::Error> {
+        self.reservations.get(&id).ok_or_else(not_found)
+    }
+
+    fn get_resource(&self, id: &str) -> abi::Resource {
+        self.resources
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| abi::Resource::new(id, Duration::zero(), Duration::zero()))
+    }
+
+    /// validate and insert a reservation, checking the booking rules and quotas
+    fn reserve(
+        &mut self,
+        rsvp: abi::Reservation,
+        rules: BookingRules,
+        now: DateTime<Utc>,
+        actor: &str,
+    ) -> Result<abi::Reservation, abi::Error> {
+        rsvp.validate()?;
+
+        let resource = self.get_resource(&rsvp.resource_id);
+        rules.check(&resource, &rsvp, now)?;
+
+        self.check_quota(&rsvp, 0, &resource, now)?;
+        self.insert(rsvp, actor)
+    }
+
+    fn insert(
+        &mut self,
+        mut rsvp: abi::Reservation,
+        actor: &str,
+    ) -> Result<abi::Reservation, abi::Error> {
+        if rsvp.status == abi::ReservationStatus::Unknown as i32 {
+            rsvp.status = abi::ReservationStatus::Pending as i32;
+        }
+        set_timespan(&mut rsvp)?;
+        let buffered = self.buffered(&rsvp);
+        self.check_conflict(&rsvp.resource_id, buffered)?;
+
+        self.ids.reservation += 1;
+        rsvp.id = self.ids.reservation;
+        rsvp.version = 1;
+        self.put(rsvp.clone(), buffered);
+        self.record(abi::ReservationUpdateType::Create, None, Some(&rsvp), actor);
+        Ok(rsvp)
+    }
+
+    /// replace a reservation with its changed version. Its buffers are applied again if it was
+    /// moved
+    fn replace(
+        &mut self,
+        mut rsvp: abi::Reservation,
+        moved: bool,
+        actor: &str,
+    ) -> Result<abi::Reservation, abi::Error> {
+        set_timespan(&mut rsvp)?;
+        let current = self.get(rsvp.id)?.clone();
+        let moved = moved || rsvp.resource_id != current.rsvp.resource_id;
+        let buffered = if moved {
+            self.buffered(&rsvp)
+        } else {
+            current.buffered
+        };
+        rsvp.version = current.rsvp.version;
+        if rsvp == current.rsvp && buffered == current.buffered {
+            return Ok(rsvp);
+        }
+
+        // the reservation doesn't conflict with its old timespan
+        self.take(rsvp.id);
+        if let Err(e) = self.check_conflict(&rsvp.resource_id, buffered) {
+            self.put(current.rsvp, current.buffered);
+            return Err(e);
+        }
+        rsvp.version += 1;
+        self.put(rsvp.clone(), buffered);
+        let op = abi::ReservationUpdateType::Update;
+        self.record(op, Some(&current.rsvp), Some(&rsvp), actor);
+        Ok(rsvp)
+    }
+
+    fn remove(&mut self, id: i64, actor: &str) -> abi::Reservation {
+        let rsvp = self.take(id).rsvp;
+        self.record(abi::ReservationUpdateType::Delete, Some(&rsvp), None, actor);
+        rsvp
+    }
+
+    fn put(&mut self, rsvp: abi::Reservation, buffered: (DateTime<Utc>, DateTime<Utc>)) {
+        let tree = self.timespans.entry(rsvp.resource_id.clone()).or_default();
+        tree.spans.insert(buffered.0, (buffered.1, rsvp.id));
+        self.reservations.insert(rsvp.id, Row { rsvp, buffered });
+    }
+
+    fn take(&mut self, id: i64) -> Row {
+        let row = self.reservations.remove(&id).unwrap();
+        if let Some(tree) = self.timespans.get_mut(&row.rsvp.resource_id) {
+            tree.spans.remove(&row.buffered.0);
+        }
+        row
+    }
+
+    /// the timespan extended by the current buffers of the resource
+    fn buffered(&self, rsvp: &abi::Reservation) -> (DateTime<Utc>, DateTime<Utc>) {
+        let resource = self.get_resource(&rsvp.resource_id);
+        let (start, end) = timespan(rsvp);
+        (
+            start - resource.get_pre_buffer(),
+            end + resource.get_post_buffer(),
+        )
+    }
+
+    fn check_conflict(
+        &self,
+        rid: &str,
+        (start, end): (DateTime<Utc>, DateTime<Utc>),
+    ) -> Result<(), abi::Error> {
+        let existing = self
+            .timespans
+            .get(rid)
+            .and_then(|tree| tree.find_overlap(start, end));
+        match existing {
+            Some((s, e)) => Err(abi::Error::ConflictReservation(format!(
+                "Key (resource_id, buffered_timespan)=({rid}, {}) conflicts with existing key \
+                (resource_id, buffered_timespan)=({rid}, {}).",
+                range(start, end),
+                range(s, e),
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// add the change to the history, and to the change feed unless nothing changed
+    fn record(
+        &mut self,
+        op: abi::ReservationUpdateType,
+        before: Option<&abi::Reservation>,
+        after: Option<&abi::Reservation>,
+        actor: &str,
+    ) {
+        let now = abi::convert_to_timestamp(Utc::now().trunc_subsecs(6));
+        let rsvp = after.or(before).unwrap();
+        self.ids.history += 1;
+        self.history.push(abi::ReservationHistoryEntry {
+            id: self.ids.history,
+            reservation_id: rsvp.id,
+            op: op as i32,
+            actor: actor.to_string(),
+            changed_at: Some(now.clone()),
+            before: before.cloned(),
+            after: after.cloned(),
+        });
+
+        if before.is_some() && before == after {
+            return;
+        }
+        self.ids.change += 1;
+        self.changes.push(abi::ListenResponse {
+            op: op as i32,
+            reservation: Some(rsvp.clone()),
+            sequence: self.ids.change,
+            committed_at: Some(now),
+        });
+    }
+
+    /// check the quota policies of the reservation's user. `except` is the id of a reservation
+    /// that's moved, so it isn't counted twice, 0 for new ones
+    fn check_quota(
+        &self,
+        rsvp: &abi::Reservation,
+        except: i64,
+        resource: &abi::Resource,
+        now: DateTime<Utc>,
+    ) -> Result<(), abi::Error> {
+        // blocked windows have no user, hence no quota
+        if rsvp.user_id.is_empty() {
+            return Ok(());
+        }
+
+        let (start, end) = rsvp.get_timespan()?;
+        for policy in self.get_policies(&rsvp.user_id) {
+            // (when, requested), weekly hours are checked in every week the reservation touches
+            let requests = match policy.kind() {
+                abi::QuotaKind::MaxActive => vec![(start, 1.0)],
+                abi::QuotaKind::MaxWeeklyHours => split_by_week(start, end)
+                    .into_iter()
+                    .map(|(from, to)| (from, hours(from, to)))
+                    .collect(),
+                abi::QuotaKind::MaxFuturePerKind if policy.resource_kind == resource.kind => {
+                    vec![(start, 1.0)]
+                }
+                _ => continue,
+            };
+            for (at, requested) in requests {
+                let used = self.get_usage(&policy, &rsvp.user_id, except, at, now);
+                if used + requested > policy.limit as f64 {
+                    return Err(abi::Error::QuotaExceeded {
+                        policy_id: policy.id,
+                        kind: policy.kind().to_string(),
+                        used,
+                        limit: policy.limit,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// policies of the user and of the groups the user belongs to
+    fn get_policies(&self, user_id: &str) -> Vec<abi::QuotaPolicy> {
+        let groups = self.groups.get(user_id).cloned().unwrap_or_default();
+        self.policies
+            .values()
+            .filter(|policy| policy.user_id == user_id || groups.contains(&policy.group_id))
+            .cloned()
+            .collect()
+    }
+
+    /// usage of a policy by the user, without the reservation `except`. Weekly hours are counted
+    /// in the week of `at`
+    fn get_usage(
+        &self,
+        policy: &abi::QuotaPolicy,
+        user_id: &str,
+        except: i64,
+        at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> f64 {
+        let active = self
+            .reservations
+            .values()
+            .map(|row| &row.rsvp)
+            .filter(|rsvp| {
+                rsvp.user_id == user_id
+                    && rsvp.id != except
+                    && (rsvp.status == abi::ReservationStatus::Pending as i32
+                        || rsvp.status == abi::ReservationStatus::Confirmed as i32)
+            });
+        match policy.kind() {
+            abi::QuotaKind::MaxActive => {
+                active.filter(|rsvp| timespan(rsvp).1 > now).count() as f64
+            }
+            abi::QuotaKind::MaxWeeklyHours => {
+                let (week_start, week_end) = week_of(at);
+                let seconds: f64 = active
+                    .map(timespan)
+                    .filter(|span| overlaps(*span, (week_start, week_end)))
+                    .map(|(start, end)| {
+                        let overlap = end.min(week_end) - start.max(week_start);
+                        overlap.num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6
+                    })
+                    .sum();
+                seconds / 3600.0
+            }
+            abi::QuotaKind::MaxFuturePerKind => active
+                .filter(|rsvp| timespan(rsvp).0 > now)
+                .filter(|rsvp| {
+                    self.resources
+                        .get(&rsvp.resource_id)
+                        .is_some_and(|resource| resource.kind == policy.resource_kind)
+                })
+                .count() as f64,
+            abi::QuotaKind::Unknown => 0.0,
+        }
+    }
+}
+
+/// timestamps are kept to the microsecond, like a TIMESTAMPTZ
+fn set_timespan(rsvp: &mut abi::Reservation) -> Result<(), abi::Error> {
+    let (start, end) = rsvp.get_timespan()?;
+    rsvp.start = Some(abi::convert_to_timestamp(start.trunc_subsecs(6)));
+    rsvp.end = Some(abi::convert_to_timestamp(end.trunc_subsecs(6)));
+    Ok(())
+}
+
+/// the timespan of a stored reservation, which is always valid
+fn timespan(rsvp: &abi::Reservation) -> (DateTime<Utc>, DateTime<Utc>) {
+    rsvp.get_timespan().unwrap()
+}
+
+fn overlaps(a: (DateTime<Utc>, DateTime<Utc>), b: (DateTime<Utc>, DateTime<Utc>)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+fn range(start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+    let format = "%Y-%m-%d %H:%M:%S%.f+00";
+    format!("[\"{}\",\"{}\")", start.format(format), end.format(format))
+}
+
+fn check_version(rsvp: &abi::Reservation, expected: Option<i64>) -> Result<(), abi::Error> {
+    match expected {
+        Some(expected) if expected != rsvp.version => Err(abi::Error::VersionMismatch {
+            expected,
+            current: rsvp.version,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// what Postgres reports for a missing row
+fn not_found() -> abi::Error {
+    abi::Error::DbError(sqlx::Error::RowNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_tree_should_find_overlaps() {
+        let day: DateTime<Utc> = "2099-12-25T00:00:00Z".parse().unwrap();
+        let at = |hour: i64| day + Duration::hours(hour);
+        let mut tree = IntervalTree::default();
+        tree.spans.insert(at(1), (at(2), 1));
+        tree.spans.insert(at(5), (at(6), 2));
+        tree.spans.insert(at(8), (at(10), 3));
+
+        assert_eq!(tree.find_overlap(at(2), at(5)), None);
+        assert_eq!(tree.find_overlap(at(0), at(1)), None);
+        assert_eq!(tree.find_overlap(at(0), at(12)), Some((at(8), at(10))));
+        assert_eq!(tree.find_overlap(at(3), at(6)), Some((at(5), at(6))));
+        assert_eq!(
+            tree.find_overlap(at(9), at(9) + Duration::minutes(1)),
+            Some((at(8), at(10)))
+        );
+        let spans: Vec<_> = tree.overlapping(at(1), at(9)).collect();
+        assert_eq!(spans, vec![(at(1), at(2)), (at(5), at(6)), (at(8), at(10))]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_should_only_hold_its_key_for_the_lease() {
+        let manager = InMemoryManager::new();
+        let req = &abi::GetRequest { id: 1 };
+        let get = |manager: InMemoryManager| async move {
+            manager
+                .idempotent("get", "retry-1", req, || async {
+                    Ok(abi::GetResponse::default())
+                })
+                .await
+        };
+        let stuck = manager.idempotent("get", "retry-1", req, || {
+            std::future::pending::<Result<abi::GetResponse, abi::Error>>()
+        });
+        let timeout = std::time::Duration::from_millis(10);
+        assert!(tokio::time::timeout(timeout, stuck).await.is_err());
+
+        let err = get(manager.clone()).await.unwrap_err();
+        assert!(matches!(err, abi::Error::IdempotencyKeyInProgress(_)));
+        let manager = manager.with_idempotency_lease(Duration::zero());
+        assert_eq!(get(manager).await.unwrap(), abi::GetResponse::default());
+    }
+
+    #[tokio::test]
+    async fn purge_should_evict_expired_keys() {
+        let manager = InMemoryManager::new();
+        let req = &abi::GetRequest { id: 1 };
+        manager
+            .idempotent("get", "retry-1", req, || async {
+                Ok(abi::GetResponse::default())
+            })
+            .await
+            .unwrap();
+        assert_eq!(manager.purge_idempotency_keys().await.unwrap(), 0);
+        assert_eq!(manager.lock().idempotency_keys.len(), 1);
+
+        let manager = manager.with_idempotency_retention(Duration::zero());
+        assert_eq!(manager.purge_idempotency_keys().await.unwrap(), 1);
+        assert!(manager.lock().idempotency_keys.is_empty());
+    }
+}
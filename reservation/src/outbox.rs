@@ -0,0 +1,144 @@
+use chrono::{DateTime, Utc};
+use tracing::instrument;
+
+use crate::ReservationManager;
+
+/// a reservation change waiting to be published to an event sink
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct OutboxEvent {
+    // sequence number of the change, consumers can use it to drop duplicates
+    pub sequence: i64,
+    // operation of the change, create, update or delete
+    pub op: String,
+    pub reservation_id: i64,
+    pub resource_id: String,
+    pub committed_at: DateTime<Utc>,
+    // JSON body of the event
+    pub payload: String,
+}
+
+impl ReservationManager {
+    /// up to `limit` changes the sink hasn't acknowledged yet, in sequence order. A new sink
+    /// starts at the current head and only sees changes committed after its first call
+    #[instrument(skip(self))]
+    pub async fn outbox_events(
+        &self,
+        sink: &str,
+        limit: i64,
+    ) -> Result<Vec<OutboxEvent>, abi::Error> {
+        sqlx::query(
+            "INSERT INTO rsvp.event_sink_offsets (name, last_change_id)
+            SELECT $1, COALESCE(MAX(id), 0) FROM rsvp.reservation_changes
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(sink)
+        .execute(&self.pool)
+        .await?;
+
+        // changes become visible in sequence order, so reading past the offset never skips one
+        let events = sqlx::query_as(
+            "SELECT c.id AS sequence, c.op::VARCHAR AS op, c.reservation_id,
+            COALESCE(c.reservation->>'resource_id', '') AS resource_id, c.committed_at,
+            jsonb_build_object(
+                'sequence', c.id, 'op', c.op, 'committed_at', c.committed_at,
+                'reservation', c.reservation
+            )::TEXT AS payload
+            FROM rsvp.reservation_changes c JOIN rsvp.event_sink_offsets o ON o.name = $1
+            WHERE c.id > o.last_change_id ORDER BY c.id LIMIT $2",
+        )
+        .bind(sink)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(events)
+    }
+
+    /// record that the sink published every change up to `sequence`. Until then the changes are
+    /// handed out again, so a sink sees every change at least once
+    #[instrument(skip(self))]
+    pub async fn ack_outbox_events(&self, sink: &str, sequence: i64) -> Result<(), abi::Error> {
+        // publishers of the same sink may race, the offset never moves back
+        sqlx::query(
+            "UPDATE rsvp.event_sink_offsets
+            SET last_change_id = GREATEST(last_change_id, $2), updated_at = now()
+            WHERE name = $1",
+        )
+        .bind(sink)
+        .bind(sequence)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rsvp;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn unacknowledged_events_should_be_handed_out_again() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        // changes before the sink existed are not published
+        manager.reserve(make_pending(24)).await.unwrap();
+        assert!(manager
+            .outbox_events("stdout", 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let rsvp = manager.reserve(make_pending(25)).await.unwrap();
+        manager.change_status(rsvp.id, None).await.unwrap();
+        let events = manager.outbox_events("stdout", 10).await.unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].op, "create");
+        assert_eq!(events[1].op, "update");
+        assert_eq!(events[1].reservation_id, rsvp.id);
+        assert_eq!(events[1].resource_id, "room-1");
+        assert!(events[1].payload.contains(r#""status": "confirmed""#));
+        assert_eq!(manager.outbox_events("stdout", 10).await.unwrap(), events);
+
+        manager
+            .ack_outbox_events("stdout", events[0].sequence)
+            .await
+            .unwrap();
+        let pending = manager.outbox_events("stdout", 10).await.unwrap();
+        assert_eq!(pending, events[1..]);
+
+        // a stale ack doesn't move the offset back
+        manager
+            .ack_outbox_events("stdout", events[1].sequence)
+            .await
+            .unwrap();
+        manager.ack_outbox_events("stdout", 0).await.unwrap();
+        assert!(manager
+            .outbox_events("stdout", 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn sinks_should_keep_their_own_offsets() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        manager.outbox_events("nats", 10).await.unwrap();
+        manager.outbox_events("kafka", 10).await.unwrap();
+        manager.reserve(make_pending(25)).await.unwrap();
+
+        let events = manager.outbox_events("nats", 10).await.unwrap();
+        manager
+            .ack_outbox_events("nats", events[0].sequence)
+            .await
+            .unwrap();
+        assert!(manager.outbox_events("nats", 10).await.unwrap().is_empty());
+        assert_eq!(manager.outbox_events("kafka", 10).await.unwrap(), events);
+    }
+
+    fn make_pending(day: u32) -> abi::Reservation {
+        let start = format!("2099-12-{day}T15:00:00-0700").parse().unwrap();
+        let end = format!("2099-12-{day}T18:00:00-0700").parse().unwrap();
+        abi::Reservation::new_pending("user1", "room-1", start, end, "just note")
+    }
+}
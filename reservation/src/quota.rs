@@ -0,0 +1,178 @@
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc};
+use sqlx::{postgres::types::PgRange, PgConnection};
+use tracing::instrument;
+
+use crate::ReservationManager;
+
+impl ReservationManager {
+    /// check the quota policies of the reservation's user, must run in the transaction that
+    /// inserts or moves the reservation. `except` is the id of a reservation that's moved, so
+    /// it isn't counted twice, 0 for new ones
+    #[instrument(skip_all, fields(user_id = %rsvp.user_id))]
+    pub(crate) async fn check_quota(
+        &self,
+        conn: &mut PgConnection,
+        rsvp: &abi::Reservation,
+        except: i64,
+        resource: &abi::Resource,
+    ) -> Result<(), abi::Error> {
+        // blocked windows have no user, hence no quota
+        if rsvp.user_id.is_empty() {
+            return Ok(());
+        }
+        // serialize reservations of the same user, so concurrent requests can't both pass the check
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(&rsvp.user_id)
+            .execute(&mut *conn)
+            .await?;
+
+        let (start, end) = rsvp.get_timespan()?;
+        let now = Utc::now();
+        for policy in get_policies(&mut *conn, &rsvp.user_id).await? {
+            // (when, requested), weekly hours are checked in every week the reservation touches
+            let requests = match policy.kind() {
+                abi::QuotaKind::MaxActive => vec![(start, 1.0)],
+                abi::QuotaKind::MaxWeeklyHours => split_by_week(start, end)
+                    .into_iter()
+                    .map(|(from, to)| (from, hours(from, to)))
+                    .collect(),
+                abi::QuotaKind::MaxFuturePerKind if policy.resource_kind == resource.kind => {
+                    vec![(start, 1.0)]
+                }
+                _ => continue,
+            };
+            for (at, requested) in requests {
+                let used = get_usage(&mut *conn, &policy, &rsvp.user_id, except, at, now).await?;
+                if used + requested > policy.limit as f64 {
+                    return Err(abi::Error::QuotaExceeded {
+                        policy_id: policy.id,
+                        kind: policy.kind().to_string(),
+                        used,
+                        limit: policy.limit,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// current usage of every policy applying to the user
+    #[instrument(skip(self))]
+    pub(crate) async fn quota_usages(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<abi::QuotaUsage>, abi::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let now = Utc::now();
+        let mut usages = Vec::new();
+        for policy in get_policies(&mut conn, user_id).await? {
+            let used = get_usage(&mut conn, &policy, user_id, 0, now, now).await?;
+            usages.push(abi::QuotaUsage::new(policy, used));
+        }
+        Ok(usages)
+    }
+}
+
+/// policies of the user and of the groups the user belongs to
+async fn get_policies(
+    conn: &mut PgConnection,
+    user_id: &str,
+) -> Result<Vec<abi::QuotaPolicy>, abi::Error> {
+    let policies = sqlx::query_as(
+        "SELECT id, kind, user_id, group_id, quota_limit, resource_kind FROM rsvp.quota_policies
+        WHERE user_id = $1 OR group_id IN (SELECT group_id FROM rsvp.user_groups WHERE user_id = $1)
+        ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(policies)
+}
+
+/// usage of a policy by the user, without the reservation `except`. Weekly hours are counted in
+/// the week of `at`
+async fn get_usage(
+    conn: &mut PgConnection,
+    policy: &abi::QuotaPolicy,
+    user_id: &str,
+    except: i64,
+    at: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<f64, abi::Error> {
+    let used = match policy.kind() {
+        abi::QuotaKind::MaxActive => {
+            let count: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM rsvp.reservations
+                WHERE user_id = $1 AND status IN ('pending', 'confirmed') AND upper(timespan) > $2
+                AND id <> $3",
+            )
+            .bind(user_id)
+            .bind(now)
+            .bind(except)
+            .fetch_one(conn)
+            .await?;
+            count as f64
+        }
+        abi::QuotaKind::MaxWeeklyHours => {
+            let (week_start, week_end) = week_of(at);
+            let week: PgRange<DateTime<Utc>> = (week_start..week_end).into();
+            let seconds: f64 = sqlx::query_scalar(
+                "SELECT COALESCE(SUM(EXTRACT(EPOCH FROM upper(timespan * $2) - lower(timespan * $2))), 0)::FLOAT8
+                FROM rsvp.reservations
+                WHERE user_id = $1 AND status IN ('pending', 'confirmed') AND timespan && $2
+                AND id <> $3",
+            )
+            .bind(user_id)
+            .bind(week)
+            .bind(except)
+            .fetch_one(conn)
+            .await?;
+            seconds / 3600.0
+        }
+        abi::QuotaKind::MaxFuturePerKind => {
+            let count: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM rsvp.reservations r JOIN rsvp.resources res ON res.id = r.resource_id
+                WHERE r.user_id = $1 AND r.status IN ('pending', 'confirmed') AND lower(r.timespan) > $2
+                AND res.kind = $3 AND r.id <> $4",
+            )
+            .bind(user_id)
+            .bind(now)
+            .bind(&policy.resource_kind)
+            .bind(except)
+            .fetch_one(conn)
+            .await?;
+            count as f64
+        }
+        abi::QuotaKind::Unknown => 0.0,
+    };
+    Ok(used)
+}
+
+/// the week (Monday to Sunday, UTC) containing `at`
+pub(crate) fn week_of(at: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let monday = at.date_naive() - Duration::days(at.weekday().num_days_from_monday() as i64);
+    let start = DateTime::<Utc>::from_utc(monday.and_time(NaiveTime::default()), Utc);
+    (start, start + Duration::weeks(1))
+}
+
+/// the parts of `start..end` in each week it touches
+pub(crate) fn split_by_week(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut parts = Vec::new();
+    let mut from = start;
+    loop {
+        let (_, week_end) = week_of(from);
+        parts.push((from, end.min(week_end)));
+        if end <= week_end {
+            return parts;
+        }
+        from = week_end;
+    }
+}
+
+pub(crate) fn hours(start: DateTime<Utc>, end: DateTime<Utc>) -> f64 {
+    (end - start).num_seconds() as f64 / 3600.0
+}
@@ -0,0 +1,1529 @@
+use std::{future::Future, ops::Bound, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use prost::Message;
+use prost_types::FieldMask;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow},
+    Connection, Row, SqliteConnection, SqlitePool,
+};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{
+    quota::{hours, split_by_week, week_of},
+    BookingRules, ChangeReceiver, Rsvp, Storage,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("../migrations/sqlite");
+
+const COLUMNS: &str =
+    "id, user_id, resource_id, start_at, end_at, note, status, labels, version, buffered_start, buffered_end";
+const BATCH_SIZE: i64 = 100;
+// raised by the triggers that stand in for the exclusion constraint
+const CONFLICT: &str = "reservations_conflict";
+
+/// keeps everything in a SQLite database, for single node deployments. Conflicts, versions,
+/// booking rules, quotas, the history and the change feed work like they do with Postgres.
+/// Listeners poll for changes. Webhooks are kept, but nothing delivers to them
+#[derive(Debug, Clone)]
+pub struct SqliteManager {
+    pool: SqlitePool,
+    // how long idempotency keys are kept
+    idempotency_retention: Duration,
+    // how long a request holds the key it claimed
+    idempotency_lease: Duration,
+    // recorded in the audit history for every change made through this manager
+    actor: Option<String>,
+    // how often listeners look for new changes
+    poll_interval: std::time::Duration,
+    // SQLite has a single writer, taking turns here saves transactions from failing when
+    // they find the database busy
+    writer: Arc<Mutex<()>>,
+}
+
+/// a reservation with its buffered timespan
+struct Stored {
+    rsvp: abi::Reservation,
+    buffered: (i64, i64),
+}
+
+#[async_trait]
+impl Rsvp for SqliteManager {
+    // make a reservation
+    async fn reserve(&self, rsvp: abi::Reservation) -> Result<abi::Reservation, abi::Error> {
+        self.do_reserve(rsvp, BookingRules::All).await
+    }
+    // make a reservation without checking the booking rules of the resource
+    async fn reserve_bypassing_rules(
+        &self,
+        rsvp: abi::Reservation,
+    ) -> Result<abi::Reservation, abi::Error> {
+        self.do_reserve(rsvp, BookingRules::Bypass).await
+    }
+    // make several reservations in one transaction, if any fails nothing is reserved
+    async fn reserve_batch(
+        &self,
+        rsvps: Vec<abi::Reservation>,
+    ) -> Result<abi::ReserveBatchResponse, abi::Error> {
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(rsvps.len());
+        let mut committed = true;
+        for rsvp in rsvps {
+            // each reservation gets a savepoint, so the others can still be checked after a failure
+            let mut savepoint = tx.begin().await?;
+            match reserve_in(
+                &mut savepoint,
+                rsvp.clone(),
+                BookingRules::All,
+                self.actor(),
+            )
+            .await
+            {
+                Ok(rsvp) => {
+                    savepoint.commit().await?;
+                    results.push(abi::ReserveBatchResult {
+                        reservation: Some(rsvp),
+                        error: String::new(),
+                    });
+                }
+                Err(e) => {
+                    savepoint.rollback().await?;
+                    committed = false;
+                    results.push(abi::ReserveBatchResult {
+                        reservation: Some(rsvp),
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        if committed {
+            tx.commit().await?;
+        } else {
+            tx.rollback().await?;
+            // nothing was reserved, so don't hand out ids
+            for result in results.iter_mut() {
+                if let Some(rsvp) = result.reservation.as_mut() {
+                    rsvp.id = 0;
+                }
+            }
+        }
+
+        Ok(abi::ReserveBatchResponse { results, committed })
+    }
+    // change reservation status (if current status is pending, change it to confirmed)
+    async fn change_status(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        let current = get_in(&mut tx, id).await?;
+        check_version(&current.rsvp, expected_version)?;
+        let mut rsvp = current.rsvp.clone();
+        if rsvp.status == abi::ReservationStatus::Pending as i32 {
+            rsvp.status = abi::ReservationStatus::Confirmed as i32;
+        }
+        let rsvp = replace_in(&mut tx, current, rsvp, false, self.actor()).await?;
+        tx.commit().await?;
+
+        Ok(rsvp)
+    }
+    // update the fields of a reservation listed in the mask
+    async fn update(
+        &self,
+        rsvp: abi::Reservation,
+        mask: FieldMask,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let fields = abi::UpdateField::parse_mask(&mask)?;
+        if fields.contains(&abi::UpdateField::UserId) && rsvp.user_id.is_empty() {
+            return Err(abi::Error::InvalidUserId(rsvp.user_id));
+        }
+
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        let current = get_in(&mut tx, rsvp.id).await?;
+        // a version of 0 means the caller doesn't care about concurrent changes
+        check_version(&current.rsvp, (rsvp.version != 0).then_some(rsvp.version))?;
+
+        let mut updated = current.rsvp.clone();
+        let mut changes_time = false;
+        for field in &fields {
+            match field {
+                abi::UpdateField::Note => updated.note = rsvp.note.clone(),
+                // blocked windows have no user
+                abi::UpdateField::UserId if current.rsvp.is_blocked() => {
+                    return Err(abi::Error::InvalidUserId(rsvp.user_id.clone()));
+                }
+                abi::UpdateField::UserId => updated.user_id = rsvp.user_id.clone(),
+                abi::UpdateField::Labels => updated.labels = rsvp.labels.clone(),
+                abi::UpdateField::Start => {
+                    updated.start = rsvp.start.clone();
+                    changes_time = true;
+                }
+                abi::UpdateField::End => {
+                    updated.end = rsvp.end.clone();
+                    changes_time = true;
+                }
+            }
+        }
+        // the new timespan is merged with the current one and checked like a reschedule
+        let req = abi::RescheduleRequest {
+            id: rsvp.id,
+            start: updated.start.clone(),
+            end: updated.end.clone(),
+            ..Default::default()
+        };
+        let timespan = changes_time.then(|| req.get_timespan()).transpose()?;
+        let changes_user = fields.contains(&abi::UpdateField::UserId);
+        if (changes_time || changes_user) && !current.rsvp.is_blocked() {
+            let resource = resource_in(&mut tx, &current.rsvp.resource_id).await?;
+            let now = Utc::now();
+            if let Some((start, end)) = timespan {
+                let (from, _) = current.rsvp.get_timespan()?;
+                resource.check_booking_rules_for_move(from, start, end, now)?;
+            }
+            check_quota(&mut tx, &updated, current.rsvp.id, &resource, now).await?;
+        }
+        let rsvp = replace_in(&mut tx, current, updated, changes_time, self.actor()).await?;
+        tx.commit().await?;
+
+        Ok(rsvp)
+    }
+    // move or resize a reservation, optionally to another resource
+    async fn reschedule(
+        &self,
+        req: abi::RescheduleRequest,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let (start, end) = req.get_timespan()?;
+
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        let current = get_in(&mut tx, req.id).await?;
+        check_version(
+            &current.rsvp,
+            (req.expected_version != 0).then_some(req.expected_version),
+        )?;
+
+        let mut rsvp = current.rsvp.clone();
+        if !req.resource_id.is_empty() {
+            rsvp.resource_id = req.resource_id.clone();
+        }
+        rsvp.start = Some(abi::convert_to_timestamp(start));
+        rsvp.end = Some(abi::convert_to_timestamp(end));
+        if !current.rsvp.is_blocked() {
+            let resource = resource_in(&mut tx, &rsvp.resource_id).await?;
+            let (from, _) = current.rsvp.get_timespan()?;
+            let now = Utc::now();
+            resource.check_booking_rules_for_move(from, start, end, now)?;
+            check_quota(&mut tx, &rsvp, current.rsvp.id, &resource, now).await?;
+        }
+        let rsvp = replace_in(&mut tx, current, rsvp, true, self.actor()).await?;
+        tx.commit().await?;
+
+        Ok(rsvp)
+    }
+    // delete reservation
+    async fn delete(
+        &self,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        let current = get_in(&mut tx, id).await?;
+        check_version(&current.rsvp, expected_version)?;
+        let rsvp = delete_in(&mut tx, current.rsvp, self.actor()).await?;
+        tx.commit().await?;
+
+        Ok(rsvp)
+    }
+    // get reservation by id
+    async fn get(&self, id: i64) -> Result<abi::Reservation, abi::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(get_in(&mut conn, id).await?.rsvp)
+    }
+    // query reservations
+    async fn query(
+        &self,
+        query: abi::ReservationQuery,
+    ) -> Result<Vec<abi::Reservation>, abi::Error> {
+        let range = query.get_timespan()?;
+        let status = abi::ReservationStatus::from_i32(query.status)
+            .unwrap_or(abi::ReservationStatus::Unknown);
+        let direction = if query.desc { "DESC" } else { "ASC" };
+        // empty user/resource id, unknown status and missing bounds match everything
+        let sql = format!(
+            "SELECT {COLUMNS} FROM reservations
+            WHERE (?1 = '' OR user_id = ?1) AND (?2 = '' OR resource_id = ?2)
+            AND (?3 IS NULL OR end_at > ?3) AND (?4 IS NULL OR start_at < ?4)
+            AND (?5 = 0 OR status = ?5)
+            ORDER BY start_at {direction}, id {direction}"
+        );
+        let rows = sqlx::query(&sql)
+            .bind(&query.user_id)
+            .bind(&query.resource_id)
+            .bind(bound(range.start))
+            .bind(bound(range.end))
+            .bind(status as i32)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter().map(reservation).collect()
+    }
+    // listen to changes matching the request filters, the ones after `since` are replayed first.
+    // Without it only new changes
+    async fn listen(&self, req: abi::ListenRequest) -> Result<ChangeReceiver, abi::Error> {
+        let last = match req.since {
+            Some(since) => since,
+            None => {
+                sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM reservation_changes")
+                    .fetch_one(&self.pool)
+                    .await?
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(BATCH_SIZE as usize);
+        let pool = self.pool.clone();
+        let poll_interval = self.poll_interval;
+        tokio::spawn(async move {
+            if let Err(e) = forward_changes(&pool, poll_interval, &req, last, &tx).await {
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+        Ok(rx)
+    }
+    // block resources for a (recurring) window, optionally cancel overlapping pending reservations
+    async fn block(&self, window: abi::BlockWindow) -> Result<abi::BlockResponse, abi::Error> {
+        let timespans = window.get_timespans()?;
+        let spans: Vec<(i64, i64)> = timespans
+            .iter()
+            .map(|range| match (range.start, range.end) {
+                (Bound::Included(start), Bound::Excluded(end)) => (micros(start), micros(end)),
+                _ => unreachable!("block windows are bounded"),
+            })
+            .collect();
+        let resource_ids = json(&window.resource_ids);
+
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        let mut cancelled = Vec::new();
+        if window.cancel_pending {
+            for (start, end) in &spans {
+                let sql = format!(
+                    "SELECT {COLUMNS} FROM reservations
+                    WHERE resource_id IN (SELECT value FROM json_each(?1))
+                    AND start_at < ?3 AND end_at > ?2 AND status = ?4 ORDER BY id"
+                );
+                let rows = sqlx::query(&sql)
+                    .bind(&resource_ids)
+                    .bind(start)
+                    .bind(end)
+                    .bind(abi::ReservationStatus::Pending as i32)
+                    .fetch_all(&mut tx)
+                    .await?;
+                for row in &rows {
+                    let rsvp = reservation(row)?;
+                    cancelled.push(delete_in(&mut tx, rsvp, self.actor()).await?);
+                }
+            }
+        }
+
+        let mut blocks = Vec::with_capacity(spans.len() * window.resource_ids.len());
+        for (start, end) in &spans {
+            for rid in &window.resource_ids {
+                let block = abi::Reservation {
+                    resource_id: rid.clone(),
+                    start: Some(timestamp(*start)),
+                    end: Some(timestamp(*end)),
+                    note: window.reason.clone(),
+                    status: abi::ReservationStatus::Blocked as i32,
+                    ..Default::default()
+                };
+                // anything still overlapping is rejected by reservations_conflict
+                blocks.push(insert_in(&mut tx, block, self.actor()).await?);
+            }
+        }
+        tx.commit().await?;
+
+        Ok(abi::BlockResponse { blocks, cancelled })
+    }
+    // create or update resource settings
+    async fn upsert_resource(&self, resource: abi::Resource) -> Result<abi::Resource, abi::Error> {
+        resource.validate()?;
+
+        let micros = |d: Option<Duration>| d.map(duration_micros);
+        sqlx::query(
+            "INSERT INTO resources
+            (id, pre_buffer, post_buffer, min_duration, max_duration, booking_horizon, lead_time, kind)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT (id) DO UPDATE SET
+            pre_buffer = excluded.pre_buffer, post_buffer = excluded.post_buffer,
+            min_duration = excluded.min_duration, max_duration = excluded.max_duration,
+            booking_horizon = excluded.booking_horizon, lead_time = excluded.lead_time,
+            kind = excluded.kind",
+        )
+        .bind(&resource.id)
+        .bind(duration_micros(resource.get_pre_buffer()))
+        .bind(duration_micros(resource.get_post_buffer()))
+        .bind(micros(resource.get_min_duration()))
+        .bind(micros(resource.get_max_duration()))
+        .bind(micros(resource.get_booking_horizon()))
+        .bind(micros(resource.get_lead_time()))
+        .bind(&resource.kind)
+        .execute(&self.pool)
+        .await?;
+
+        let mut conn = self.pool.acquire().await?;
+        resource_in(&mut conn, &resource.id).await
+    }
+    // get resource settings, a resource without settings has no buffers
+    async fn get_resource(&self, id: String) -> Result<abi::Resource, abi::Error> {
+        let mut conn = self.pool.acquire().await?;
+        resource_in(&mut conn, &id).await
+    }
+    // find free time slots of a resource, taking its buffers into account
+    async fn availability(
+        &self,
+        req: abi::AvailabilityRequest,
+    ) -> Result<Vec<abi::TimeSlot>, abi::Error> {
+        let (start, end) = req.get_timespan()?;
+        let mut conn = self.pool.acquire().await?;
+        let resource = resource_in(&mut conn, &req.resource_id).await?;
+
+        // a reservation in [start, end) could hit anything within its own buffers
+        let busy: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT buffered_start, buffered_end FROM reservations
+            WHERE resource_id = ?1 AND buffered_start < ?3 AND buffered_end > ?2
+            ORDER BY buffered_start",
+        )
+        .bind(&resource.id)
+        .bind(micros(start - resource.get_pre_buffer()))
+        .bind(micros(end + resource.get_post_buffer()))
+        .fetch_all(&mut conn)
+        .await?;
+        let busy: Vec<_> = busy
+            .into_iter()
+            .map(|(start, end)| (from_micros(start), from_micros(end)))
+            .collect();
+
+        Ok(resource.free_slots(start, end, &busy))
+    }
+    // create or update a quota policy
+    async fn upsert_quota_policy(
+        &self,
+        mut policy: abi::QuotaPolicy,
+    ) -> Result<abi::QuotaPolicy, abi::Error> {
+        policy.validate()?;
+
+        let user_id = (!policy.user_id.is_empty()).then_some(&policy.user_id);
+        let group_id = (!policy.group_id.is_empty()).then_some(&policy.group_id);
+        let sql = if policy.id == 0 {
+            "INSERT INTO quota_policies (kind, user_id, group_id, quota_limit, resource_kind)
+            VALUES (?2, ?3, ?4, ?5, ?6) RETURNING id"
+        } else {
+            "UPDATE quota_policies SET
+            kind = ?2, user_id = ?3, group_id = ?4, quota_limit = ?5, resource_kind = ?6
+            WHERE id = ?1 RETURNING id"
+        };
+        policy.id = sqlx::query_scalar(sql)
+            .bind(policy.id)
+            .bind(policy.kind)
+            .bind(user_id)
+            .bind(group_id)
+            .bind(policy.limit)
+            .bind(&policy.resource_kind)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(policy)
+    }
+    // delete a quota policy
+    async fn delete_quota_policy(&self, id: i64) -> Result<(), abi::Error> {
+        sqlx::query("DELETE FROM quota_policies WHERE id = ?1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+    // replace the groups a user belongs to
+    async fn set_user_groups(
+        &self,
+        user_id: String,
+        group_ids: Vec<String>,
+    ) -> Result<(), abi::Error> {
+        if user_id.is_empty() {
+            return Err(abi::Error::InvalidUserId(user_id));
+        }
+
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM user_groups WHERE user_id = ?1")
+            .bind(&user_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO user_groups (user_id, group_id)
+            SELECT ?1, value FROM json_each(?2)",
+        )
+        .bind(&user_id)
+        .bind(json(&group_ids))
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+    // get the current usage and remaining quota of a user
+    async fn get_quota(&self, user_id: String) -> Result<Vec<abi::QuotaUsage>, abi::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let now = Utc::now();
+        let mut usages = Vec::new();
+        for policy in get_policies(&mut conn, &user_id).await? {
+            let used = get_usage(&mut conn, &policy, &user_id, 0, now, now).await?;
+            usages.push(abi::QuotaUsage::new(policy, used));
+        }
+
+        Ok(usages)
+    }
+    // get the change history of a reservation, oldest first
+    async fn get_history(&self, id: i64) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error> {
+        let rows = sqlx::query(
+            "SELECT id, reservation_id, op, actor, changed_at, before, after
+            FROM reservation_history WHERE reservation_id = ?1 ORDER BY id",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(history_entry).collect()
+    }
+    // query the change history by actor and time range
+    async fn query_history(
+        &self,
+        query: abi::HistoryQuery,
+    ) -> Result<Vec<abi::ReservationHistoryEntry>, abi::Error> {
+        let range = query.get_timespan()?;
+        let direction = if query.desc { "DESC" } else { "ASC" };
+        // empty actor and missing bounds match everything
+        let sql = format!(
+            "SELECT id, reservation_id, op, actor, changed_at, before, after
+            FROM reservation_history
+            WHERE (?1 = '' OR actor = ?1) AND (?2 IS NULL OR changed_at >= ?2)
+            AND (?3 IS NULL OR changed_at < ?3)
+            ORDER BY changed_at {direction}, id {direction}"
+        );
+        let rows = sqlx::query(&sql)
+            .bind(&query.actor)
+            .bind(bound(range.start))
+            .bind(bound(range.end))
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter().map(history_entry).collect()
+    }
+    // create or update a webhook subscription, it gets the changes from now on
+    async fn upsert_webhook(&self, webhook: abi::Webhook) -> Result<abi::Webhook, abi::Error> {
+        webhook.validate(true)?;
+
+        let status = abi::ReservationStatus::from_i32(webhook.status)
+            .unwrap_or(abi::ReservationStatus::Unknown);
+        let ops: Vec<i32> = webhook
+            .get_ops()
+            .iter()
+            .filter_map(|op| match op.as_str() {
+                "create" => Some(abi::ReservationUpdateType::Create as i32),
+                "update" => Some(abi::ReservationUpdateType::Update as i32),
+                "delete" => Some(abi::ReservationUpdateType::Delete as i32),
+                _ => None,
+            })
+            .collect();
+        // an empty secret keeps the current one
+        let sql = if webhook.id == 0 {
+            "INSERT INTO webhooks (url, secret, resource_ids, user_id, status, ops)
+            VALUES (?2, ?3, ?4, ?5, ?6, ?7) RETURNING id"
+        } else {
+            "UPDATE webhooks SET url = ?2, secret = CASE WHEN ?3 = '' THEN secret ELSE ?3 END,
+            resource_ids = ?4, user_id = ?5, status = ?6, ops = ?7
+            WHERE id = ?1 RETURNING id"
+        };
+        let id = sqlx::query_scalar(sql)
+            .bind(webhook.id)
+            .bind(&webhook.url)
+            .bind(&webhook.secret)
+            .bind(json(&webhook.resource_ids))
+            .bind(&webhook.user_id)
+            .bind(status as i32)
+            .bind(json(&ops))
+            .fetch_one(&self.pool)
+            .await?;
+
+        // secrets are write only
+        Ok(abi::Webhook {
+            id,
+            secret: String::new(),
+            status: status as i32,
+            ops,
+            ..webhook
+        })
+    }
+    // delete a webhook subscription and its delivery log
+    async fn delete_webhook(&self, id: i64) -> Result<(), abi::Error> {
+        sqlx::query("DELETE FROM webhooks WHERE id = ?1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+    // list webhook subscriptions, without secrets
+    async fn list_webhooks(&self) -> Result<Vec<abi::Webhook>, abi::Error> {
+        let rows = sqlx::query(
+            "SELECT id, url, resource_ids, user_id, status, ops FROM webhooks ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let resource_ids: String = row.try_get("resource_ids")?;
+                let ops: String = row.try_get("ops")?;
+                Ok(abi::Webhook {
+                    id: row.try_get("id")?,
+                    url: row.try_get("url")?,
+                    secret: String::new(),
+                    resource_ids: from_json(&resource_ids)?,
+                    user_id: row.try_get("user_id")?,
+                    status: row.try_get("status")?,
+                    ops: from_json(&ops)?,
+                })
+            })
+            .collect()
+    }
+    // get the delivery log of a webhook, nothing is delivered from SQLite
+    async fn list_webhook_deliveries(
+        &self,
+        _req: abi::ListWebhookDeliveriesRequest,
+    ) -> Result<Vec<abi::WebhookDelivery>, abi::Error> {
+        Ok(vec![])
+    }
+    // put a dead delivery back into the queue, there are none in SQLite
+    async fn retry_webhook_delivery(&self, _id: i64) -> Result<abi::WebhookDelivery, abi::Error> {
+        Err(abi::Error::DbError(sqlx::Error::RowNotFound))
+    }
+    // import the events of an iCalendar file as reservations or blocked windows. A dry run
+    // reports what would fail, conflicts included, without creating anything
+    async fn import_calendar(
+        &self,
+        req: abi::ImportCalendarRequest,
+    ) -> Result<abi::ImportCalendarResponse, abi::Error> {
+        let mut results = req.get_results(Utc::now())?;
+
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        let mut failed = false;
+        for result in results.iter_mut() {
+            let rsvp = result.reservation.take().unwrap_or_default();
+            // each occurrence gets a savepoint, so the others can still be checked after a failure
+            let mut savepoint = tx.begin().await?;
+            let created = if rsvp.is_blocked() {
+                match rsvp.validate() {
+                    Ok(()) => insert_in(&mut savepoint, rsvp.clone(), self.actor()).await,
+                    Err(e) => Err(e),
+                }
+            } else {
+                reserve_in(
+                    &mut savepoint,
+                    rsvp.clone(),
+                    BookingRules::AllowingPast,
+                    self.actor(),
+                )
+                .await
+            };
+            match created {
+                Ok(rsvp) => {
+                    savepoint.commit().await?;
+                    result.reservation = Some(rsvp);
+                }
+                Err(e) => {
+                    savepoint.rollback().await?;
+                    failed = true;
+                    result.reservation = Some(rsvp);
+                    result.error = e.to_string();
+                }
+            }
+        }
+
+        let commit = match req.mode() {
+            abi::ImportMode::Transactional => !failed,
+            abi::ImportMode::BestEffort => true,
+            _ => false,
+        };
+        let mut created = 0;
+        if commit {
+            tx.commit().await?;
+            created = results.iter().filter(|r| r.error.is_empty()).count() as i32;
+        } else {
+            tx.rollback().await?;
+            // nothing was created, so don't hand out ids
+            for result in results.iter_mut() {
+                if let Some(rsvp) = result.reservation.as_mut() {
+                    rsvp.id = 0;
+                }
+            }
+        }
+
+        Ok(abi::ImportCalendarResponse { results, created })
+    }
+    // remember a calendar feed token that was handed out
+    async fn add_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error> {
+        let _writer = self.writer.lock().await;
+        sqlx::query(
+            "INSERT INTO calendar_feed_tokens (nonce, user_id, resource_id, created_at)
+            VALUES (?1, ?2, ?3, ?4)",
+        )
+        .bind(&token.nonce)
+        .bind(&token.user_id)
+        .bind(&token.resource_id)
+        .bind(micros(Utc::now()))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+    // whether the calendar feed token was handed out and isn't revoked
+    async fn has_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<bool, abi::Error> {
+        let found = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM calendar_feed_tokens
+            WHERE nonce = ?1 AND user_id = ?2 AND resource_id = ?3)",
+        )
+        .bind(&token.nonce)
+        .bind(&token.user_id)
+        .bind(&token.resource_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(found)
+    }
+    // revoke a calendar feed token, fails if it's unknown
+    async fn revoke_calendar_feed_token(
+        &self,
+        token: abi::CalendarFeedToken,
+    ) -> Result<(), abi::Error> {
+        let _writer = self.writer.lock().await;
+        // fails with RowNotFound when nothing was deleted
+        let _: String = sqlx::query_scalar(
+            "DELETE FROM calendar_feed_tokens
+            WHERE nonce = ?1 AND user_id = ?2 AND resource_id = ?3 RETURNING nonce",
+        )
+        .bind(&token.nonce)
+        .bind(&token.user_id)
+        .bind(&token.resource_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteManager {
+    // a handle whose changes are recorded as made by `actor`
+    fn acting_as(&self, actor: &str) -> Self {
+        self.clone().with_actor(actor)
+    }
+    // run `f` at most once per operation and idempotency key, an empty key disables the check
+    async fn idempotent<Req, Resp, F, Fut>(
+        &self,
+        operation: &str,
+        key: &str,
+        req: &Req,
+        f: F,
+    ) -> Result<Resp, abi::Error>
+    where
+        Req: Message,
+        Resp: Message + Default,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<Resp, abi::Error>> + Send,
+    {
+        if key.is_empty() {
+            return f().await;
+        }
+
+        let request = req.encode_to_vec();
+        let (claimed, claimed_at) = {
+            let _writer = self.writer.lock().await;
+            let now = Utc::now();
+            sqlx::query(
+                "DELETE FROM idempotency_keys
+                WHERE operation = ?1 AND idempotency_key = ?2 AND created_at < ?3",
+            )
+            .bind(operation)
+            .bind(key)
+            .bind(micros(now - self.idempotency_retention))
+            .execute(&self.pool)
+            .await?;
+
+            // claim the key, only one request can hold it at a time
+            let inserted = sqlx::query(
+                "INSERT OR IGNORE INTO idempotency_keys
+                (operation, idempotency_key, request, created_at, claimed_at)
+                VALUES (?1, ?2, ?3, ?4, ?4)",
+            )
+            .bind(operation)
+            .bind(key)
+            .bind(&request)
+            .bind(micros(now))
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+            // or take over the claim of a request that didn't finish within the lease
+            let taken_over = if inserted == 0 {
+                sqlx::query(
+                    "UPDATE idempotency_keys SET claimed_at = ?4
+                    WHERE operation = ?1 AND idempotency_key = ?2 AND request = ?3
+                        AND response IS NULL AND claimed_at < ?5",
+                )
+                .bind(operation)
+                .bind(key)
+                .bind(&request)
+                .bind(micros(now))
+                .bind(micros(now - self.idempotency_lease))
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+            } else {
+                0
+            };
+            (inserted + taken_over == 1, micros(now))
+        };
+
+        if !claimed {
+            let (stored, response): (Vec<u8>, Option<Vec<u8>>) = sqlx::query_as(
+                "SELECT request, response FROM idempotency_keys
+                WHERE operation = ?1 AND idempotency_key = ?2",
+            )
+            .bind(operation)
+            .bind(key)
+            .fetch_one(&self.pool)
+            .await?;
+            if stored != request {
+                return Err(abi::Error::IdempotencyKeyReused(key.to_string()));
+            }
+            return match response {
+                Some(response) => {
+                    Resp::decode(response.as_slice()).map_err(|_| abi::Error::Unknown)
+                }
+                None => Err(abi::Error::IdempotencyKeyInProgress(key.to_string())),
+            };
+        }
+
+        // unless the claim was taken over in the meantime
+        let result = f().await;
+        let sql = match result {
+            Ok(_) => {
+                "UPDATE idempotency_keys SET response = ?4
+                WHERE operation = ?1 AND idempotency_key = ?2 AND claimed_at = ?3"
+            }
+            Err(_) => {
+                "DELETE FROM idempotency_keys
+                WHERE operation = ?1 AND idempotency_key = ?2 AND claimed_at = ?3"
+            }
+        };
+        let _writer = self.writer.lock().await;
+        sqlx::query(sql)
+            .bind(operation)
+            .bind(key)
+            .bind(claimed_at)
+            .bind(result.as_ref().ok().map(|resp| resp.encode_to_vec()))
+            .execute(&self.pool)
+            .await?;
+
+        result
+    }
+    // remove the idempotency keys older than the retention window
+    async fn purge_idempotency_keys(&self) -> Result<u64, abi::Error> {
+        let _writer = self.writer.lock().await;
+        let purged = sqlx::query("DELETE FROM idempotency_keys WHERE created_at < ?1")
+            .bind(micros(Utc::now() - self.idempotency_retention))
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(purged)
+    }
+}
+
+impl SqliteManager {
+    /// open the database at `url` (e.g. `sqlite://reservation.db`), creating it if needed, and
+    /// bring its schema up to date. An in-memory database lives as long as the manager
+    pub async fn connect(url: &str, max_connections: u32) -> Result<Self, abi::Error> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        // every connection to :memory: gets a database of its own, so keep exactly one
+        let pool = if url.contains(":memory:") {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new().max_connections(max_connections)
+        }
+        .connect_with(options)
+        .await?;
+        MIGRATOR.run(&pool).await.map_err(sqlx::Error::from)?;
+
+        Ok(Self {
+            pool,
+            idempotency_retention: Duration::hours(24),
+            idempotency_lease: Duration::minutes(1),
+            actor: None,
+            poll_interval: std::time::Duration::from_millis(200),
+            writer: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn with_idempotency_retention(mut self, retention: Duration) -> Self {
+        self.idempotency_retention = retention;
+        self
+    }
+
+    /// a request that hasn't finished within the lease no longer holds its idempotency key
+    pub fn with_idempotency_lease(mut self, lease: Duration) -> Self {
+        self.idempotency_lease = lease;
+        self
+    }
+
+    /// changes made through the returned manager are recorded as made by `actor`
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    /// how often listeners look for new changes
+    pub fn with_poll_interval(mut self, interval: std::time::Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    fn actor(&self) -> &str {
+        self.actor.as_deref().unwrap_or_default()
+    }
+
+    async fn do_reserve(
+        &self,
+        rsvp: abi::Reservation,
+        rules: BookingRules,
+    ) -> Result<abi::Reservation, abi::Error> {
+        let _writer = self.writer.lock().await;
+        let mut tx = self.pool.begin().await?;
+        let rsvp = reserve_in(&mut tx, rsvp, rules, self.actor()).await?;
+        tx.commit().await?;
+
+        Ok(rsvp)
+    }
+}
+
+/// validate and insert a reservation, checking the booking rules and quotas
+async fn reserve_in(
+    conn: &mut SqliteConnection,
+    rsvp: abi::Reservation,
+    rules: BookingRules,
+    actor: &str,
+) -> Result<abi::Reservation, abi::Error> {
+    rsvp.validate()?;
+
+    let resource = resource_in(&mut *conn, &rsvp.resource_id).await?;
+    let now = Utc::now();
+    rules.check(&resource, &rsvp, now)?;
+
+    check_quota(&mut *conn, &rsvp, 0, &resource, now).await?;
+    insert_in(conn, rsvp, actor).await
+}
+
+async fn insert_in(
+    conn: &mut SqliteConnection,
+    mut rsvp: abi::Reservation,
+    actor: &str,
+) -> Result<abi::Reservation, abi::Error> {
+    if rsvp.status == abi::ReservationStatus::Unknown as i32 {
+        rsvp.status = abi::ReservationStatus::Pending as i32;
+    }
+    let resource = resource_in(&mut *conn, &rsvp.resource_id).await?;
+    let buffered = buffered(&rsvp, &resource)?;
+    let (start, end) = rsvp.get_timespan()?;
+    let sql = format!(
+        "INSERT INTO reservations
+        (user_id, resource_id, start_at, end_at, buffered_start, buffered_end, note, status, labels)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) RETURNING {COLUMNS}"
+    );
+    let row = sqlx::query(&sql)
+        .bind((!rsvp.is_blocked()).then_some(&rsvp.user_id))
+        .bind(&rsvp.resource_id)
+        .bind(micros(start))
+        .bind(micros(end))
+        .bind(buffered.0)
+        .bind(buffered.1)
+        .bind(&rsvp.note)
+        .bind(rsvp.status)
+        .bind(json(&rsvp.labels))
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| conflict(e, &rsvp.resource_id, buffered))?;
+    let rsvp = reservation(&row)?;
+    record(
+        conn,
+        abi::ReservationUpdateType::Create,
+        None,
+        Some(&rsvp),
+        actor,
+    )
+    .await?;
+
+    Ok(rsvp)
+}
+
+/// replace a reservation with its changed version, the version only goes up if something
+/// changed. Its buffers are applied again if it was moved
+async fn replace_in(
+    conn: &mut SqliteConnection,
+    current: Stored,
+    mut rsvp: abi::Reservation,
+    moved: bool,
+    actor: &str,
+) -> Result<abi::Reservation, abi::Error> {
+    let (start, end) = rsvp.get_timespan()?;
+    // timestamps are kept to the microsecond, like a TIMESTAMPTZ
+    rsvp.start = Some(timestamp(micros(start)));
+    rsvp.end = Some(timestamp(micros(end)));
+    let moved = moved || rsvp.resource_id != current.rsvp.resource_id;
+    let buffered = if moved {
+        let resource = resource_in(&mut *conn, &rsvp.resource_id).await?;
+        buffered(&rsvp, &resource)?
+    } else {
+        current.buffered
+    };
+    rsvp.version = current.rsvp.version;
+    if rsvp == current.rsvp && buffered == current.buffered {
+        return Ok(rsvp);
+    }
+
+    let sql = format!(
+        "UPDATE reservations SET user_id = ?2, resource_id = ?3, start_at = ?4, end_at = ?5,
+        buffered_start = ?6, buffered_end = ?7, note = ?8, status = ?9, labels = ?10,
+        version = version + 1
+        WHERE id = ?1 RETURNING {COLUMNS}"
+    );
+    let row = sqlx::query(&sql)
+        .bind(rsvp.id)
+        .bind((!rsvp.is_blocked()).then_some(&rsvp.user_id))
+        .bind(&rsvp.resource_id)
+        .bind(micros(start))
+        .bind(micros(end))
+        .bind(buffered.0)
+        .bind(buffered.1)
+        .bind(&rsvp.note)
+        .bind(rsvp.status)
+        .bind(json(&rsvp.labels))
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| conflict(e, &rsvp.resource_id, buffered))?;
+    let updated = reservation(&row)?;
+    let op = abi::ReservationUpdateType::Update;
+    record(conn, op, Some(&current.rsvp), Some(&updated), actor).await?;
+
+    Ok(updated)
+}
+
+async fn delete_in(
+    conn: &mut SqliteConnection,
+    rsvp: abi::Reservation,
+    actor: &str,
+) -> Result<abi::Reservation, abi::Error> {
+    sqlx::query("DELETE FROM reservations WHERE id = ?1")
+        .bind(rsvp.id)
+        .execute(&mut *conn)
+        .await?;
+    record(
+        conn,
+        abi::ReservationUpdateType::Delete,
+        Some(&rsvp),
+        None,
+        actor,
+    )
+    .await?;
+
+    Ok(rsvp)
+}
+
+async fn get_in(conn: &mut SqliteConnection, id: i64) -> Result<Stored, abi::Error> {
+    let sql = format!("SELECT {COLUMNS} FROM reservations WHERE id = ?1");
+    let row = sqlx::query(&sql).bind(id).fetch_one(conn).await?;
+    Ok(Stored {
+        rsvp: reservation(&row)?,
+        buffered: (row.try_get("buffered_start")?, row.try_get("buffered_end")?),
+    })
+}
+
+async fn resource_in(conn: &mut SqliteConnection, id: &str) -> Result<abi::Resource, abi::Error> {
+    let row = sqlx::query(
+        "SELECT id, pre_buffer, post_buffer, min_duration, max_duration, booking_horizon,
+        lead_time, kind FROM resources WHERE id = ?1",
+    )
+    .bind(id)
+    .fetch_optional(conn)
+    .await?;
+    let Some(row) = row else {
+        return Ok(abi::Resource::new(id, Duration::zero(), Duration::zero()));
+    };
+
+    // buffers are always set, like the columns they're stored in
+    let duration = |name: &str| -> Result<Option<prost_types::Duration>, sqlx::Error> {
+        let micros: Option<i64> = row.try_get(name)?;
+        Ok(micros.map(|d| abi::convert_to_pb_duration(Duration::microseconds(d))))
+    };
+    Ok(abi::Resource {
+        id: row.try_get("id")?,
+        pre_buffer: duration("pre_buffer")?,
+        post_buffer: duration("post_buffer")?,
+        min_duration: duration("min_duration")?,
+        max_duration: duration("max_duration")?,
+        booking_horizon: duration("booking_horizon")?,
+        lead_time: duration("lead_time")?,
+        kind: row.try_get("kind")?,
+    })
+}
+
+/// add the change to the history, and to the change feed unless nothing changed
+async fn record(
+    conn: &mut SqliteConnection,
+    op: abi::ReservationUpdateType,
+    before: Option<&abi::Reservation>,
+    after: Option<&abi::Reservation>,
+    actor: &str,
+) -> Result<(), abi::Error> {
+    let now = micros(Utc::now());
+    let rsvp = after.or(before).unwrap();
+    sqlx::query(
+        "INSERT INTO reservation_history (reservation_id, op, actor, changed_at, before, after)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )
+    .bind(rsvp.id)
+    .bind(op as i32)
+    .bind((!actor.is_empty()).then_some(actor))
+    .bind(now)
+    .bind(before.map(|rsvp| rsvp.encode_to_vec()))
+    .bind(after.map(|rsvp| rsvp.encode_to_vec()))
+    .execute(&mut *conn)
+    .await?;
+
+    if before.is_some() && before == after {
+        return Ok(());
+    }
+    sqlx::query(
+        "INSERT INTO reservation_changes
+        (reservation_id, op, committed_at, resource_id, user_id, status, reservation)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )
+    .bind(rsvp.id)
+    .bind(op as i32)
+    .bind(now)
+    .bind(&rsvp.resource_id)
+    .bind(&rsvp.user_id)
+    .bind(rsvp.status)
+    .bind(rsvp.encode_to_vec())
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// send changes after `last` matching the request until the receiver is dropped
+async fn forward_changes(
+    pool: &SqlitePool,
+    poll_interval: std::time::Duration,
+    req: &abi::ListenRequest,
+    mut last: i64,
+    tx: &mpsc::Sender<Result<abi::ListenResponse, abi::Error>>,
+) -> Result<(), abi::Error> {
+    let status =
+        abi::ReservationStatus::from_i32(req.status).unwrap_or(abi::ReservationStatus::Unknown);
+    let ops: Vec<i32> = req
+        .ops
+        .iter()
+        .copied()
+        .filter(|op| {
+            abi::ReservationUpdateType::from_i32(*op)
+                .is_some_and(|op| op != abi::ReservationUpdateType::Unknown)
+        })
+        .collect();
+    loop {
+        // the next batch of changes, matching or not, so a filter that matches nothing doesn't
+        // have the same changes scanned over and over
+        let (scanned_to, scanned): (Option<i64>, i64) = sqlx::query_as(
+            "SELECT MAX(id), COUNT(*) FROM (
+                SELECT id FROM reservation_changes WHERE id > ?1 ORDER BY id LIMIT ?2
+            )",
+        )
+        .bind(last)
+        .bind(BATCH_SIZE)
+        .fetch_one(pool)
+        .await?;
+        let caught_up = scanned < BATCH_SIZE;
+        if let Some(scanned_to) = scanned_to {
+            // filters look at the reservation as of the change, empty ones match everything
+            let rows = sqlx::query(
+                "SELECT id, op, committed_at, reservation FROM reservation_changes
+                WHERE id > ?1 AND id <= ?2
+                AND (json_array_length(?3) = 0 OR resource_id IN (SELECT value FROM json_each(?3)))
+                AND (?4 = '' OR user_id = ?4)
+                AND (?5 = 0 OR status = ?5)
+                AND (json_array_length(?6) = 0 OR op IN (SELECT value FROM json_each(?6)))
+                ORDER BY id",
+            )
+            .bind(last)
+            .bind(scanned_to)
+            .bind(json(&req.resource_ids))
+            .bind(&req.user_id)
+            .bind(status as i32)
+            .bind(json(&ops))
+            .fetch_all(pool)
+            .await?;
+            for row in rows {
+                let reservation: Vec<u8> = row.try_get("reservation")?;
+                let change = abi::ListenResponse {
+                    op: row.try_get("op")?,
+                    reservation: Some(decode(&reservation)?),
+                    sequence: row.try_get("id")?,
+                    committed_at: Some(timestamp(row.try_get("committed_at")?)),
+                };
+                if tx.send(Ok(change)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            last = scanned_to;
+        }
+        if caught_up {
+            tokio::select! {
+                _ = tokio::time::sleep(poll_interval) => {}
+                _ = tx.closed() => return Ok(()),
+            }
+        }
+    }
+}
+
+/// check the quota policies of the reservation's user. `except` is the id of a reservation that's
+/// moved, so it isn't counted twice, 0 for new ones
+async fn check_quota(
+    conn: &mut SqliteConnection,
+    rsvp: &abi::Reservation,
+    except: i64,
+    resource: &abi::Resource,
+    now: DateTime<Utc>,
+) -> Result<(), abi::Error> {
+    // blocked windows have no user, hence no quota
+    if rsvp.user_id.is_empty() {
+        return Ok(());
+    }
+
+    let (start, end) = rsvp.get_timespan()?;
+    for policy in get_policies(&mut *conn, &rsvp.user_id).await? {
+        // (when, requested), weekly hours are checked in every week the reservation touches
+        let requests = match policy.kind() {
+            abi::QuotaKind::MaxActive => vec![(start, 1.0)],
+            abi::QuotaKind::MaxWeeklyHours => split_by_week(start, end)
+                .into_iter()
+                .map(|(from, to)| (from, hours(from, to)))
+                .collect(),
+            abi::QuotaKind::MaxFuturePerKind if policy.resource_kind == resource.kind => {
+                vec![(start, 1.0)]
+            }
+            _ => continue,
+        };
+        for (at, requested) in requests {
+            let used = get_usage(&mut *conn, &policy, &rsvp.user_id, except, at, now).await?;
+            if used + requested > policy.limit as f64 {
+                return Err(abi::Error::QuotaExceeded {
+                    policy_id: policy.id,
+                    kind: policy.kind().to_string(),
+                    used,
+                    limit: policy.limit,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// policies of the user and of the groups the user belongs to
+async fn get_policies(
+    conn: &mut SqliteConnection,
+    user_id: &str,
+) -> Result<Vec<abi::QuotaPolicy>, abi::Error> {
+    let rows = sqlx::query(
+        "SELECT id, kind, user_id, group_id, quota_limit, resource_kind FROM quota_policies
+        WHERE user_id = ?1 OR group_id IN (SELECT group_id FROM user_groups WHERE user_id = ?1)
+        ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(conn)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(abi::QuotaPolicy {
+                id: row.try_get("id")?,
+                kind: row.try_get("kind")?,
+                user_id: row
+                    .try_get::<Option<String>, _>("user_id")?
+                    .unwrap_or_default(),
+                group_id: row
+                    .try_get::<Option<String>, _>("group_id")?
+                    .unwrap_or_default(),
+                limit: row.try_get("quota_limit")?,
+                resource_kind: row.try_get("resource_kind")?,
+            })
+        })
+        .collect()
+}
+
+/// usage of a policy by the user, without the reservation `except`. Weekly hours are counted in
+/// the week of `at`
+async fn get_usage(
+    conn: &mut SqliteConnection,
+    policy: &abi::QuotaPolicy,
+    user_id: &str,
+    except: i64,
+    at: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<f64, abi::Error> {
+    let active = (
+        abi::ReservationStatus::Pending as i32,
+        abi::ReservationStatus::Confirmed as i32,
+    );
+    let used = match policy.kind() {
+        abi::QuotaKind::MaxActive => {
+            let count: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM reservations
+                WHERE user_id = ?1 AND status IN (?2, ?3) AND end_at > ?4 AND id <> ?5",
+            )
+            .bind(user_id)
+            .bind(active.0)
+            .bind(active.1)
+            .bind(micros(now))
+            .bind(except)
+            .fetch_one(conn)
+            .await?;
+            count as f64
+        }
+        abi::QuotaKind::MaxWeeklyHours => {
+            let (week_start, week_end) = week_of(at);
+            let used: i64 = sqlx::query_scalar(
+                "SELECT COALESCE(SUM(MIN(end_at, ?5) - MAX(start_at, ?4)), 0) FROM reservations
+                WHERE user_id = ?1 AND status IN (?2, ?3) AND start_at < ?5 AND end_at > ?4
+                AND id <> ?6",
+            )
+            .bind(user_id)
+            .bind(active.0)
+            .bind(active.1)
+            .bind(micros(week_start))
+            .bind(micros(week_end))
+            .bind(except)
+            .fetch_one(conn)
+            .await?;
+            used as f64 / 3_600_000_000.0
+        }
+        abi::QuotaKind::MaxFuturePerKind => {
+            let count: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM reservations r JOIN resources res ON res.id = r.resource_id
+                WHERE r.user_id = ?1 AND r.status IN (?2, ?3) AND r.start_at > ?4
+                AND res.kind = ?5 AND r.id <> ?6",
+            )
+            .bind(user_id)
+            .bind(active.0)
+            .bind(active.1)
+            .bind(micros(now))
+            .bind(&policy.resource_kind)
+            .bind(except)
+            .fetch_one(conn)
+            .await?;
+            count as f64
+        }
+        abi::QuotaKind::Unknown => 0.0,
+    };
+    Ok(used)
+}
+
+fn reservation(row: &SqliteRow) -> Result<abi::Reservation, abi::Error> {
+    let labels: String = row.try_get("labels")?;
+    Ok(abi::Reservation {
+        id: row.try_get("id")?,
+        user_id: row
+            .try_get::<Option<String>, _>("user_id")?
+            .unwrap_or_default(),
+        status: row.try_get("status")?,
+        resource_id: row.try_get("resource_id")?,
+        start: Some(timestamp(row.try_get("start_at")?)),
+        end: Some(timestamp(row.try_get("end_at")?)),
+        note: row.try_get("note")?,
+        labels: from_json(&labels)?,
+        version: row.try_get("version")?,
+    })
+}
+
+fn history_entry(row: &SqliteRow) -> Result<abi::ReservationHistoryEntry, abi::Error> {
+    let snapshot = |name: &str| -> Result<Option<abi::Reservation>, abi::Error> {
+        let bytes: Option<Vec<u8>> = row.try_get(name)?;
+        bytes.as_deref().map(decode).transpose()
+    };
+    Ok(abi::ReservationHistoryEntry {
+        id: row.try_get("id")?,
+        reservation_id: row.try_get("reservation_id")?,
+        op: row.try_get("op")?,
+        actor: row
+            .try_get::<Option<String>, _>("actor")?
+            .unwrap_or_default(),
+        changed_at: Some(timestamp(row.try_get("changed_at")?)),
+        before: snapshot("before")?,
+        after: snapshot("after")?,
+    })
+}
+
+/// the timespan extended by the current buffers of the resource
+fn buffered(rsvp: &abi::Reservation, resource: &abi::Resource) -> Result<(i64, i64), abi::Error> {
+    let (start, end) = rsvp.get_timespan()?;
+    Ok((
+        micros(start - resource.get_pre_buffer()),
+        micros(end + resource.get_post_buffer()),
+    ))
+}
+
+/// the triggers can't tell which reservation is in the way, so only the rejected key is
+/// reported
+fn conflict(e: sqlx::Error, rid: &str, (start, end): (i64, i64)) -> abi::Error {
+    match &e {
+        sqlx::Error::Database(db) if db.message() == CONFLICT => {
+            let format = "%Y-%m-%d %H:%M:%S%.f+00";
+            abi::Error::ConflictReservation(format!(
+                "Key (resource_id, buffered_timespan)=({rid}, [\"{}\",\"{}\")) conflicts with \
+                existing key.",
+                from_micros(start).format(format),
+                from_micros(end).format(format),
+            ))
+        }
+        _ => e.into(),
+    }
+}
+
+fn check_version(rsvp: &abi::Reservation, expected: Option<i64>) -> Result<(), abi::Error> {
+    match expected {
+        Some(expected) if expected != rsvp.version => Err(abi::Error::VersionMismatch {
+            expected,
+            current: rsvp.version,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// times are stored as microseconds since the epoch, the precision of a TIMESTAMPTZ
+fn micros(at: DateTime<Utc>) -> i64 {
+    at.timestamp() * 1_000_000 + at.timestamp_subsec_micros() as i64
+}
+
+fn from_micros(micros: i64) -> DateTime<Utc> {
+    let nanos = micros.rem_euclid(1_000_000) as u32 * 1_000;
+    Utc.timestamp_opt(micros.div_euclid(1_000_000), nanos)
+        .unwrap()
+}
+
+fn timestamp(micros: i64) -> prost_types::Timestamp {
+    abi::convert_to_timestamp(from_micros(micros))
+}
+
+fn duration_micros(d: Duration) -> i64 {
+    d.num_microseconds().unwrap_or(i64::MAX)
+}
+
+/// the microseconds of a bound of a query range, `None` if unbounded
+fn bound(bound: Bound<DateTime<Utc>>) -> Option<i64> {
+    match bound {
+        Bound::Included(at) | Bound::Excluded(at) => Some(micros(at)),
+        Bound::Unbounded => None,
+    }
+}
+
+fn json<T: serde::Serialize + ?Sized>(value: &T) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(s: &str) -> Result<T, abi::Error> {
+    serde_json::from_str(s).map_err(|_| abi::Error::Unknown)
+}
+
+fn decode(bytes: &[u8]) -> Result<abi::Reservation, abi::Error> {
+    abi::Reservation::decode(bytes).map_err(|_| abi::Error::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reservations_should_survive_reconnecting() {
+        let path = std::env::temp_dir().join(format!("reservation-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let pending = abi::Reservation::new_pending("user1", "room-1", start, end, "just note");
+
+        let manager = SqliteManager::connect(&url, 4).await.unwrap();
+        let rsvp = manager.reserve(pending.clone()).await.unwrap();
+        manager.pool.close().await;
+
+        // the schema is already there, so connecting again only opens the database
+        let manager = SqliteManager::connect(&url, 4).await.unwrap();
+        assert_eq!(manager.get(rsvp.id).await.unwrap(), rsvp);
+        let err = manager.reserve(pending).await.unwrap_err();
+        assert!(matches!(err, abi::Error::ConflictReservation(_)));
+        manager.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_should_only_hold_its_key_for_the_lease() {
+        let manager = SqliteManager::connect("sqlite::memory:", 1).await.unwrap();
+        let req = &abi::GetRequest { id: 1 };
+        let get = |manager: SqliteManager| async move {
+            manager
+                .idempotent("get", "retry-1", req, || async {
+                    Ok(abi::GetResponse::default())
+                })
+                .await
+        };
+        let stuck = manager.idempotent("get", "retry-1", req, || {
+            std::future::pending::<Result<abi::GetResponse, abi::Error>>()
+        });
+        let timeout = std::time::Duration::from_millis(10);
+        assert!(tokio::time::timeout(timeout, stuck).await.is_err());
+
+        let err = get(manager.clone()).await.unwrap_err();
+        assert!(matches!(err, abi::Error::IdempotencyKeyInProgress(_)));
+        let manager = manager.with_idempotency_lease(Duration::zero());
+        assert_eq!(get(manager).await.unwrap(), abi::GetResponse::default());
+    }
+}
@@ -0,0 +1,82 @@
+use tracing::instrument;
+
+use crate::ReservationManager;
+
+/// how many changes a consumer of the change feed is behind its head
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct ChangeFeedLag {
+    // "sink/{name}" for event sinks, "webhook/{id}" for webhooks
+    pub consumer: String,
+    pub lag: i64,
+}
+
+/// connections of the database pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    // open connections, idle ones included
+    pub size: u32,
+    pub idle: usize,
+}
+
+impl ReservationManager {
+    /// the lag of every event sink and webhook. Webhooks consume a change when it's queued for
+    /// delivery, not when it's delivered
+    #[instrument(skip(self))]
+    pub async fn change_feed_lag(&self) -> Result<Vec<ChangeFeedLag>, abi::Error> {
+        let lags = sqlx::query_as(
+            "WITH head AS (SELECT COALESCE(MAX(id), 0) AS id FROM rsvp.reservation_changes)
+            SELECT 'sink/' || name AS consumer, GREATEST((SELECT id FROM head) - last_change_id, 0) AS lag
+            FROM rsvp.event_sink_offsets
+            UNION ALL
+            SELECT 'webhook/' || id, GREATEST((SELECT id FROM head) - last_change_id, 0)
+            FROM rsvp.webhooks
+            ORDER BY consumer",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(lags)
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.pool.size(),
+            idle: self.pool.num_idle(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rsvp;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn lag_should_count_unconsumed_changes() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        manager.outbox_events("stdout", 10).await.unwrap();
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let rsvp = abi::Reservation::new_pending("user1", "room-1", start, end, "just note");
+        let rsvp = manager.reserve(rsvp).await.unwrap();
+        manager.change_status(rsvp.id, None).await.unwrap();
+
+        let lag = manager.change_feed_lag().await.unwrap();
+        assert_eq!(
+            lag,
+            vec![ChangeFeedLag {
+                consumer: "sink/stdout".to_string(),
+                lag: 2,
+            }]
+        );
+
+        let events = manager.outbox_events("stdout", 10).await.unwrap();
+        manager
+            .ack_outbox_events("stdout", events[1].sequence)
+            .await
+            .unwrap();
+        assert_eq!(manager.change_feed_lag().await.unwrap()[0].lag, 0);
+        let stats = manager.pool_stats();
+        assert!(stats.idle <= stats.size as usize);
+    }
+}
@@ -0,0 +1,247 @@
+use chrono::Duration;
+use tracing::instrument;
+
+use crate::ReservationManager;
+
+/// a delivery claimed by a dispatcher
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct WebhookJob {
+    pub delivery_id: i64,
+    // starts at 1
+    pub attempt: i32,
+    pub url: String,
+    pub secret: String,
+    // operation of the change, create, update or delete
+    pub event: String,
+    // JSON body of the request
+    pub payload: String,
+}
+
+impl ReservationManager {
+    /// queue the changes since the last call for every webhook they match, returns the number of
+    /// new deliveries
+    #[instrument(skip(self))]
+    pub async fn queue_webhook_deliveries(&self) -> Result<u64, abi::Error> {
+        // one statement, so every change up to head is visible and none is queued twice
+        let queued: i64 = sqlx::query_scalar(
+            "WITH head AS (SELECT COALESCE(MAX(id), 0) AS id FROM rsvp.reservation_changes),
+            hooks AS (
+                SELECT * FROM rsvp.webhooks WHERE last_change_id < (SELECT id FROM head)
+                FOR UPDATE SKIP LOCKED
+            ),
+            queued AS (
+                INSERT INTO rsvp.webhook_deliveries (webhook_id, change_id)
+                SELECT h.id, c.id FROM hooks h JOIN rsvp.reservation_changes c
+                ON c.id > h.last_change_id AND c.id <= (SELECT id FROM head)
+                WHERE (cardinality(h.resource_ids) = 0 OR c.reservation->>'resource_id' = ANY(h.resource_ids))
+                AND (h.user_id = '' OR c.reservation->>'user_id' = h.user_id)
+                AND (h.status = 'unknown' OR c.reservation->>'status' = h.status::VARCHAR)
+                AND (cardinality(h.ops) = 0 OR c.op = ANY(h.ops))
+                ON CONFLICT DO NOTHING RETURNING 1
+            ),
+            moved AS (
+                UPDATE rsvp.webhooks w SET last_change_id = (SELECT id FROM head)
+                FROM hooks h WHERE w.id = h.id
+            )
+            SELECT COUNT(*) FROM queued",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(queued as u64)
+    }
+
+    /// claim up to `limit` due deliveries. Other dispatchers won't see them for `lease`, after
+    /// that they're due again in case this one died
+    #[instrument(skip(self))]
+    pub async fn claim_webhook_deliveries(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<WebhookJob>, abi::Error> {
+        let jobs = sqlx::query_as(
+            "WITH claimed AS (
+                UPDATE rsvp.webhook_deliveries SET attempts = attempts + 1, next_attempt_at = now() + $2
+                WHERE id IN (
+                    SELECT id FROM rsvp.webhook_deliveries
+                    WHERE status = 'pending' AND next_attempt_at <= now()
+                    ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED
+                ) RETURNING id, webhook_id, change_id, attempts
+            )
+            SELECT d.id AS delivery_id, d.attempts AS attempt, w.url, w.secret, c.op::VARCHAR AS event,
+            jsonb_build_object(
+                'delivery_id', d.id, 'sequence', c.id, 'op', c.op,
+                'committed_at', c.committed_at, 'reservation', c.reservation
+            )::TEXT AS payload
+            FROM claimed d JOIN rsvp.webhooks w ON w.id = d.webhook_id
+            JOIN rsvp.reservation_changes c ON c.id = d.change_id
+            ORDER BY d.id",
+        )
+        .bind(limit)
+        .bind(lease)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(jobs)
+    }
+
+    /// record a successful delivery
+    #[instrument(skip(self))]
+    pub async fn webhook_delivered(
+        &self,
+        delivery_id: i64,
+        response_status: i32,
+    ) -> Result<(), abi::Error> {
+        sqlx::query(
+            "UPDATE rsvp.webhook_deliveries SET status = 'delivered', response_status = $2,
+            delivered_at = now() WHERE id = $1",
+        )
+        .bind(delivery_id)
+        .bind(response_status)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// record a failed attempt. The delivery is retried after `retry_in`, or dead without it
+    #[instrument(skip(self))]
+    pub async fn webhook_failed(
+        &self,
+        delivery_id: i64,
+        error: &str,
+        response_status: Option<i32>,
+        retry_in: Option<Duration>,
+    ) -> Result<(), abi::Error> {
+        sqlx::query(
+            "UPDATE rsvp.webhook_deliveries SET last_error = $2, response_status = $3,
+            status = CASE WHEN $4::INTERVAL IS NULL THEN 'dead' ELSE 'pending' END::rsvp.webhook_delivery_status,
+            next_attempt_at = now() + COALESCE($4::INTERVAL, '0')
+            WHERE id = $1",
+        )
+        .bind(delivery_id)
+        .bind(error)
+        .bind(response_status)
+        .bind(retry_in)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rsvp;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn matching_changes_should_be_queued_once() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        // changes before the webhook existed are not delivered
+        manager.reserve(make_pending("room-1", 24)).await.unwrap();
+        let webhook = manager.upsert_webhook(make_webhook()).await.unwrap();
+        assert_eq!(webhook.secret, "");
+
+        let rsvp = manager.reserve(make_pending("room-1", 25)).await.unwrap();
+        manager.reserve(make_pending("room-2", 25)).await.unwrap();
+        manager.change_status(rsvp.id, None).await.unwrap();
+        assert_eq!(manager.queue_webhook_deliveries().await.unwrap(), 1);
+        assert_eq!(manager.queue_webhook_deliveries().await.unwrap(), 0);
+
+        let jobs = manager
+            .claim_webhook_deliveries(10, Duration::minutes(1))
+            .await
+            .unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].attempt, 1);
+        assert_eq!(jobs[0].event, "create");
+        assert_eq!(jobs[0].secret, "s3cret");
+        assert!(jobs[0].payload.contains(r#""resource_id": "room-1""#));
+        // claimed deliveries are leased
+        let jobs = manager
+            .claim_webhook_deliveries(10, Duration::minutes(1))
+            .await
+            .unwrap();
+        assert!(jobs.is_empty());
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn failed_delivery_should_be_retried_until_dead() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let webhook = manager.upsert_webhook(make_webhook()).await.unwrap();
+        manager.reserve(make_pending("room-1", 25)).await.unwrap();
+        manager.queue_webhook_deliveries().await.unwrap();
+
+        let job = claim(&manager).await;
+        manager
+            .webhook_failed(
+                job.delivery_id,
+                "HTTP 500",
+                Some(500),
+                Some(Duration::zero()),
+            )
+            .await
+            .unwrap();
+        let job = claim(&manager).await;
+        assert_eq!(job.attempt, 2);
+        manager
+            .webhook_failed(job.delivery_id, "timed out", None, None)
+            .await
+            .unwrap();
+        assert!(manager
+            .claim_webhook_deliveries(10, Duration::zero())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let req = abi::ListWebhookDeliveriesRequest {
+            webhook_id: webhook.id,
+            status: abi::WebhookDeliveryStatus::Dead as i32,
+        };
+        let dead = manager.list_webhook_deliveries(req).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].attempts, 2);
+        assert_eq!(dead[0].last_error, "timed out");
+
+        let delivery = manager.retry_webhook_delivery(dead[0].id).await.unwrap();
+        assert_eq!(delivery.status(), abi::WebhookDeliveryStatus::Pending);
+        let job = claim(&manager).await;
+        manager
+            .webhook_delivered(job.delivery_id, 204)
+            .await
+            .unwrap();
+        let req = abi::ListWebhookDeliveriesRequest {
+            webhook_id: webhook.id,
+            ..Default::default()
+        };
+        let delivered = manager.list_webhook_deliveries(req).await.unwrap();
+        assert_eq!(delivered[0].status(), abi::WebhookDeliveryStatus::Delivered);
+        assert_eq!(delivered[0].response_status, 204);
+    }
+
+    async fn claim(manager: &ReservationManager) -> WebhookJob {
+        let mut jobs = manager
+            .claim_webhook_deliveries(10, Duration::zero())
+            .await
+            .unwrap();
+        assert_eq!(jobs.len(), 1);
+        jobs.remove(0)
+    }
+
+    fn make_webhook() -> abi::Webhook {
+        abi::Webhook {
+            url: "http://localhost:8080/hook".to_string(),
+            secret: "s3cret".to_string(),
+            resource_ids: vec!["room-1".to_string()],
+            ops: vec![abi::ReservationUpdateType::Create as i32],
+            ..Default::default()
+        }
+    }
+
+    fn make_pending(rid: &str, day: u32) -> abi::Reservation {
+        let start = format!("2099-12-{day}T15:00:00-0700").parse().unwrap();
+        let end = format!("2099-12-{day}T18:00:00-0700").parse().unwrap();
+        abi::Reservation::new_pending("user1", rid, start, end, "just note")
+    }
+}
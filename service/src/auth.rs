@@ -0,0 +1,171 @@
+use std::{collections::HashMap, sync::Arc};
+
+use abi::AuthConfig;
+use sha2::{Digest, Sha256};
+use tonic::{service::Interceptor, Request, Status};
+
+/// the authenticated caller, put into the request extensions by the `Authenticator`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub user_id: String,
+    pub admin: bool,
+}
+
+/// checks the bearer token of every request against the tokens of the config. Requests
+/// without a token go through without a `Caller`, the ones with an unknown token are rejected
+#[derive(Debug, Clone)]
+pub struct Authenticator {
+    // by the digest of the token, so the lookup doesn't tell how much of a guess was right
+    tokens: Arc<HashMap<[u8; 32], Caller>>,
+}
+
+impl Authenticator {
+    /// fails if a token is empty or given twice
+    pub fn new(config: &AuthConfig) -> Result<Self, anyhow::Error> {
+        let mut tokens = HashMap::new();
+        for token in &config.tokens {
+            if token.token.is_empty() || token.user_id.is_empty() {
+                anyhow::bail!("auth.tokens: token and user_id must not be empty");
+            }
+            let caller = Caller {
+                user_id: token.user_id.clone(),
+                admin: token.admin,
+            };
+            if tokens.insert(digest(&token.token), caller).is_some() {
+                anyhow::bail!("auth.tokens: token of {} is given twice", token.user_id);
+            }
+        }
+        Ok(Self {
+            tokens: Arc::new(tokens),
+        })
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        let Some(value) = req.metadata().get("authorization") else {
+            return Ok(req);
+        };
+        let caller = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.tokens.get(&digest(token.trim())))
+            .cloned()
+            .ok_or(abi::Error::Unauthenticated)?;
+        req.extensions_mut().insert(caller);
+        Ok(req)
+    }
+}
+
+/// the authenticated caller of the request, if any
+pub fn caller<T>(req: &Request<T>) -> Option<&Caller> {
+    req.extensions().get::<Caller>()
+}
+
+/// fail unless the caller is an admin
+pub(crate) fn require_admin<T>(req: &Request<T>, what: &str) -> Result<(), abi::Error> {
+    match caller(req) {
+        Some(caller) if caller.admin => Ok(()),
+        _ => Err(abi::Error::PermissionDenied(format!(
+            "{what} needs an admin"
+        ))),
+    }
+}
+
+/// the authenticated caller, fails for anonymous requests
+pub(crate) fn require_caller<T>(req: &Request<T>) -> Result<&Caller, abi::Error> {
+    caller(req).ok_or(abi::Error::Unauthenticated)
+}
+
+/// fail unless the caller is the user or an admin
+pub(crate) fn require_user<T>(
+    req: &Request<T>,
+    user_id: &str,
+    what: &str,
+) -> Result<(), abi::Error> {
+    match caller(req) {
+        Some(caller) if caller.admin || (!user_id.is_empty() && caller.user_id == user_id) => {
+            Ok(())
+        }
+        _ => Err(abi::Error::PermissionDenied(format!(
+            "{what} needs the user or an admin"
+        ))),
+    }
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use abi::TokenConfig;
+
+    use super::*;
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            tokens: vec![TokenConfig {
+                token: "t0ken".to_string(),
+                user_id: "alice".to_string(),
+                admin: false,
+            }],
+        })
+        .unwrap()
+    }
+
+    fn request(authorization: Option<&str>) -> Request<()> {
+        let mut req = Request::new(());
+        if let Some(value) = authorization {
+            req.metadata_mut()
+                .insert("authorization", value.parse().unwrap());
+        }
+        req
+    }
+
+    #[test]
+    fn known_tokens_should_identify_the_caller() {
+        let req = authenticator().call(request(Some("Bearer t0ken"))).unwrap();
+        assert_eq!(
+            caller(&req),
+            Some(&Caller {
+                user_id: "alice".to_string(),
+                admin: false,
+            })
+        );
+        assert!(require_admin(&req, "block").is_err());
+        assert!(require_user(&req, "alice", "get_calendar_feed").is_ok());
+        assert!(require_user(&req, "bob", "get_calendar_feed").is_err());
+    }
+
+    #[test]
+    fn unknown_tokens_should_be_rejected() {
+        for value in ["Bearer t0ke", "t0ken", "Basic dDBrZW4="] {
+            let status = authenticator().call(request(Some(value))).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn requests_without_a_token_should_be_anonymous() {
+        let req = authenticator().call(request(None)).unwrap();
+        assert_eq!(caller(&req), None);
+    }
+
+    #[test]
+    fn empty_and_repeated_tokens_should_be_rejected() {
+        let token = TokenConfig {
+            token: "t0ken".to_string(),
+            user_id: "alice".to_string(),
+            admin: false,
+        };
+        let config = |tokens| AuthConfig { tokens };
+        let empty = TokenConfig {
+            token: String::new(),
+            ..token.clone()
+        };
+        assert!(Authenticator::new(&config(vec![empty])).is_err());
+        assert!(Authenticator::new(&config(vec![token.clone(), token])).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufRead, BufReader, Read, Write},
+    iter,
+    str::FromStr,
+};
+
+use abi::{convert_to_timestamp, Reservation, ReservationStatus};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use reservation::ReservationManager;
+use serde::{Deserialize, Serialize};
+
+const COLUMNS: [&str; 8] = [
+    "id",
+    "user_id",
+    "resource_id",
+    "start",
+    "end",
+    "status",
+    "note",
+    "labels",
+];
+// rows loaded with one COPY
+const BATCH_SIZE: usize = 10_000;
+
+/// file format of bulk exports and imports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkFormat {
+    // a header line, labels are a JSON object in one column
+    Csv,
+    // one JSON object per line
+    Jsonl,
+}
+
+impl FromStr for BulkFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(BulkFormat::Csv),
+            "jsonl" => Ok(BulkFormat::Jsonl),
+            _ => anyhow::bail!("unknown format {s}, expected csv or jsonl"),
+        }
+    }
+}
+
+/// a row of a bulk import that was skipped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowError {
+    pub line: u64,
+    pub error: String,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+/// outcome of a bulk import
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub created: u64,
+    pub errors: Vec<RowError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Row {
+    // assigned by the database, ignored on import
+    #[serde(default)]
+    id: Option<i64>,
+    // empty for blocked windows
+    #[serde(default)]
+    user_id: String,
+    resource_id: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    // pending if empty
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    note: String,
+    #[serde(default)]
+    labels: Labels,
+}
+
+// a JSON object in JSON Lines, a JSON string in a CSV column
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Labels {
+    Map(BTreeMap<String, String>),
+    Json(String),
+}
+
+impl Default for Labels {
+    fn default() -> Self {
+        Labels::Map(BTreeMap::new())
+    }
+}
+
+impl Row {
+    fn new(rsvp: Reservation, format: BulkFormat) -> Result<Self, abi::Error> {
+        let (start, end) = rsvp.get_timespan()?;
+        let status = ReservationStatus::from_i32(rsvp.status).unwrap_or(ReservationStatus::Unknown);
+        let labels = match format {
+            BulkFormat::Csv => Labels::Json(serde_json::to_string(&rsvp.labels).unwrap()),
+            BulkFormat::Jsonl => Labels::Map(rsvp.labels),
+        };
+        Ok(Row {
+            id: Some(rsvp.id),
+            user_id: rsvp.user_id,
+            resource_id: rsvp.resource_id,
+            start,
+            end,
+            status: status.to_string(),
+            note: rsvp.note,
+            labels,
+        })
+    }
+
+    fn into_reservation(self) -> Result<Reservation, String> {
+        let status = match self.status.as_str() {
+            "" => ReservationStatus::Pending,
+            s => s.parse().map_err(|e: abi::Error| e.to_string())?,
+        };
+        let labels = match self.labels {
+            Labels::Map(labels) => labels,
+            Labels::Json(json) if json.is_empty() => BTreeMap::new(),
+            Labels::Json(json) => {
+                serde_json::from_str(&json).map_err(|e| format!("invalid labels: {e}"))?
+            }
+        };
+        Ok(Reservation {
+            id: 0,
+            user_id: self.user_id,
+            resource_id: self.resource_id,
+            start: Some(convert_to_timestamp(self.start)),
+            end: Some(convert_to_timestamp(self.end)),
+            note: self.note,
+            status: status as i32,
+            labels,
+            version: 0,
+        })
+    }
+}
+
+/// write the reservations matching the query as they're read, returns how many were written
+pub async fn export(
+    manager: &ReservationManager,
+    query: abi::ReservationQuery,
+    format: BulkFormat,
+    out: impl Write,
+) -> Result<u64, anyhow::Error> {
+    let mut rsvps = manager.query_stream(query)?;
+    let mut count = 0;
+    match format {
+        BulkFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(out);
+            // written up front, so an empty export still has it
+            writer.write_record(COLUMNS)?;
+            while let Some(rsvp) = rsvps.try_next().await? {
+                writer.serialize(Row::new(rsvp, format)?)?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        BulkFormat::Jsonl => {
+            let mut out = out;
+            while let Some(rsvp) = rsvps.try_next().await? {
+                serde_json::to_writer(&mut out, &Row::new(rsvp, format)?)?;
+                out.write_all(b"\n")?;
+                count += 1;
+            }
+            out.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+/// load the reservations of the input with COPY. Rows that can't be parsed, fail validation or
+/// conflict are reported by line instead of aborting the import. Only I/O errors abort it
+pub async fn import(
+    manager: &ReservationManager,
+    format: BulkFormat,
+    input: impl Read + Send,
+) -> Result<ImportReport, anyhow::Error> {
+    let mut report = ImportReport::default();
+    let mut batch = Vec::new();
+    for row in read_rows(format, input) {
+        let (line, row) = row?;
+        match row.and_then(Row::into_reservation) {
+            Ok(rsvp) => batch.push((line, rsvp)),
+            Err(error) => report.errors.push(RowError { line, error }),
+        }
+        if batch.len() == BATCH_SIZE {
+            copy_in(manager, std::mem::take(&mut batch), &mut report).await?;
+        }
+    }
+    copy_in(manager, batch, &mut report).await?;
+    report.errors.sort_by_key(|e| e.line);
+    Ok(report)
+}
+
+async fn copy_in(
+    manager: &ReservationManager,
+    batch: Vec<(u64, Reservation)>,
+    report: &mut ImportReport,
+) -> Result<(), anyhow::Error> {
+    let result = manager.copy_in(batch).await?;
+    report.created += result.created;
+    report
+        .errors
+        .extend(result.errors.into_iter().map(|(line, e)| RowError {
+            line,
+            error: e.to_string(),
+        }));
+    Ok(())
+}
+
+// a row with its line, rows that can't be parsed are errors of their own
+type ParsedRow = (u64, Result<Row, String>);
+
+/// read the rows one by one, fails only on I/O errors
+fn read_rows<'a>(
+    format: BulkFormat,
+    input: impl Read + Send + 'a,
+) -> Box<dyn Iterator<Item = Result<ParsedRow, anyhow::Error>> + Send + 'a> {
+    match format {
+        BulkFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let headers = match reader.headers() {
+                Ok(headers) => headers.clone(),
+                Err(e) => return Box::new(iter::once(Err(e.into()))),
+            };
+            Box::new(reader.into_records().map(move |record| match record {
+                Ok(record) => {
+                    let line = record.position().map(|p| p.line()).unwrap_or_default();
+                    let row = record
+                        .deserialize(Some(&headers))
+                        .map_err(|e| e.to_string());
+                    Ok((line, row))
+                }
+                Err(e) if matches!(e.kind(), csv::ErrorKind::Io(_)) => Err(e.into()),
+                // the reader continues with the record after a malformed one
+                Err(e) => {
+                    let line = e.position().map(|p| p.line()).unwrap_or_default();
+                    Ok((line, Err(e.to_string())))
+                }
+            }))
+        }
+        BulkFormat::Jsonl => Box::new(BufReader::new(input).lines().enumerate().filter_map(
+            |(i, line)| match line {
+                Ok(line) if line.trim().is_empty() => None,
+                Ok(line) => {
+                    let row = serde_json::from_str(&line).map_err(|e| e.to_string());
+                    Some(Ok((i as u64 + 1, row)))
+                }
+                Err(e) => Some(Err(e.into())),
+            },
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reservation::Rsvp;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn exports_should_round_trip_through_import() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let mut rsvp = make_pending(24, "room-1");
+        rsvp.note = "needs a projector, \"HD\"\nand chairs".to_string();
+        rsvp.labels
+            .insert("cost-center".to_string(), "42".to_string());
+        manager.reserve(rsvp).await.unwrap();
+        let block = make_pending(24, "room-2");
+        let window = abi::BlockWindow {
+            resource_ids: vec![block.resource_id],
+            start: block.start,
+            end: block.end,
+            reason: "maintenance".to_string(),
+            ..Default::default()
+        };
+        manager.block(window).await.unwrap();
+        let exported = manager.query(Default::default()).await.unwrap();
+
+        for format in [BulkFormat::Csv, BulkFormat::Jsonl] {
+            let mut out = Vec::new();
+            let count = export(&manager, Default::default(), format, &mut out)
+                .await
+                .unwrap();
+            assert_eq!(count, 2);
+
+            sqlx::query("DELETE FROM rsvp.reservations")
+                .execute(&migrated_pool)
+                .await
+                .unwrap();
+            let report = import(&manager, format, out.as_slice()).await.unwrap();
+            assert_eq!(report.created, 2, "{format:?}");
+            assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+            let imported = manager.query(Default::default()).await.unwrap();
+            for (a, b) in imported.iter().zip(&exported) {
+                assert_eq!(
+                    (&a.user_id, &a.start, &a.end),
+                    (&b.user_id, &b.start, &b.end)
+                );
+                assert_eq!(
+                    (&a.note, a.status, &a.labels),
+                    (&b.note, b.status, &b.labels)
+                );
+            }
+        }
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn import_should_report_bad_rows_by_line() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let csv = "\
+user_id,resource_id,start,end,status,note,labels
+user1,room-1,2099-12-24T15:00:00-07:00,2099-12-24T18:00:00-07:00,,ok,
+user1,room-1,2099-12-24T16:00:00-07:00,2099-12-24T17:00:00-07:00,,conflict,
+user1,room-1,yesterday,2099-12-25T18:00:00-07:00,,bad start,
+user1,room-1,2099-12-25T18:00:00-07:00,2099-12-25T15:00:00-07:00,,backwards,
+user1,room-1,2099-12-26T15:00:00-07:00,2099-12-26T18:00:00-07:00,cancelled,,
+user1,room-1,2099-12-27T15:00:00-07:00,2099-12-27T18:00:00-07:00,,,{oops
+user1,room-1
+,room-2,2099-12-27T15:00:00-07:00,2099-12-27T18:00:00-07:00,confirmed,no user,
+user2,room-2,2099-12-28T15:00:00-07:00,2099-12-28T18:00:00-07:00,confirmed,ok,\"{\"\"a\"\":\"\"b\"\"}\"
+";
+        let report = import(&manager, BulkFormat::Csv, csv.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(report.created, 2);
+        let lines: Vec<u64> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6, 7, 8, 9]);
+        assert!(report.errors[0].error.starts_with("Conflict reservation"));
+        assert_eq!(
+            report.errors[3].to_string(),
+            "line 6: Invalid status cancelled"
+        );
+
+        let jsonl = r#"{"user_id":"user3","resource_id":"room-3","start":"2099-12-24T15:00:00Z","end":"2099-12-24T18:00:00Z","labels":{"a":"b"}}
+
+{"user_id":"user3","resource_id":"room-3"}
+"#;
+        let report = import(&manager, BulkFormat::Jsonl, jsonl.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(report.created, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 3);
+    }
+
+    fn make_pending(day: u32, resource_id: &str) -> Reservation {
+        let start = format!("2099-12-{day}T15:00:00-0700").parse().unwrap();
+        let end = format!("2099-12-{day}T18:00:00-0700").parse().unwrap();
+        Reservation::new_pending("user1", resource_id, start, end, "just note")
+    }
+}
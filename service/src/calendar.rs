@@ -0,0 +1,310 @@
+use std::convert::Infallible;
+
+use abi::{convert_to_timestamp, CalendarConfig, ReservationQuery};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reservation::{Rsvp, Storage};
+use sha2::Sha256;
+
+// ids are kept readable in feed paths
+const ID: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// whose reservations a calendar feed shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalendarFeed {
+    User(String),
+    Resource(String),
+}
+
+impl CalendarFeed {
+    /// exactly one of the ids must be set
+    pub fn new(user_id: String, resource_id: String) -> Result<Self, abi::Error> {
+        match (user_id.is_empty(), resource_id.is_empty()) {
+            (false, true) => Ok(CalendarFeed::User(user_id)),
+            (true, false) => Ok(CalendarFeed::Resource(resource_id)),
+            _ => Err(abi::Error::InvalidCalendarFeed(
+                "exactly one of user_id and resource_id must be set".to_string(),
+            )),
+        }
+    }
+
+    /// path of the feed on the calendar server. Its token is the nonce, signed along with the
+    /// feed by the secret
+    pub fn path(&self, secret: &str, nonce: &str) -> String {
+        let (kind, id) = self.parts();
+        let mac = hex::encode(self.mac(secret, nonce).finalize().into_bytes());
+        format!(
+            "/calendars/{kind}/{}.ics?token={nonce}.{mac}",
+            utf8_percent_encode(id, ID)
+        )
+    }
+
+    /// the token to keep for the nonce, a feed works as long as it's kept
+    pub fn token(&self, nonce: &str) -> abi::CalendarFeedToken {
+        let (user_id, resource_id) = match self {
+            CalendarFeed::User(id) => (id.clone(), String::new()),
+            CalendarFeed::Resource(id) => (String::new(), id.clone()),
+        };
+        abi::CalendarFeedToken {
+            nonce: nonce.to_string(),
+            user_id,
+            resource_id,
+        }
+    }
+
+    /// parse a path handed out by `path`, returns the feed and its token
+    pub fn from_path(path: &str) -> Option<(Self, String)> {
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+        Self::parse(path, query)
+    }
+
+    /// parse a feed path, returns the feed and its token
+    fn parse(path: &str, query: Option<&str>) -> Option<(Self, String)> {
+        let (kind, id) = path.strip_prefix("/calendars/")?.split_once('/')?;
+        let id = percent_decode_str(id.strip_suffix(".ics")?)
+            .decode_utf8()
+            .ok()?
+            .into_owned();
+        let feed = match kind {
+            "users" if !id.is_empty() => CalendarFeed::User(id),
+            "resources" if !id.is_empty() => CalendarFeed::Resource(id),
+            _ => return None,
+        };
+        let token = query?
+            .split('&')
+            .find_map(|param| param.strip_prefix("token="))?;
+        Some((feed, token.to_string()))
+    }
+
+    /// the nonce of the token if the secret signed it for this feed. Whether it's revoked is up
+    /// to the storage
+    pub(crate) fn verify(&self, secret: &str, token: &str) -> Option<String> {
+        let (nonce, mac) = token.split_once('.')?;
+        let mac = hex::decode(mac).ok()?;
+        self.mac(secret, nonce)
+            .verify_slice(&mac)
+            .ok()
+            .map(|_| nonce.to_string())
+    }
+
+    fn mac(&self, secret: &str, nonce: &str) -> Hmac<Sha256> {
+        let (kind, id) = self.parts();
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(format!("{kind}/{id}/{nonce}").as_bytes());
+        mac
+    }
+
+    fn parts(&self) -> (&'static str, &str) {
+        match self {
+            CalendarFeed::User(id) => ("users", id),
+            CalendarFeed::Resource(id) => ("resources", id),
+        }
+    }
+}
+
+/// serve the calendar feeds until the server fails
+pub async fn serve_calendar_feeds<S: Storage>(
+    manager: S,
+    config: CalendarConfig,
+) -> Result<(), anyhow::Error> {
+    let addr = config.addr().parse()?;
+    let make_svc = make_service_fn(move |_| {
+        let manager = manager.clone();
+        let config = config.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let manager = manager.clone();
+                let config = config.clone();
+                async move { Ok::<_, Infallible>(respond(&manager, &config, req).await) }
+            }))
+        }
+    });
+    Server::try_bind(&addr)?.serve(make_svc).await?;
+    Ok(())
+}
+
+/// the .ics file of the requested feed, its reservations from `past_days` ago to `future_days`
+/// ahead
+async fn respond<S: Rsvp>(
+    manager: &S,
+    config: &CalendarConfig,
+    req: Request<Body>,
+) -> Response<Body> {
+    if req.method() != Method::GET {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let (feed, token) = match CalendarFeed::parse(req.uri().path(), req.uri().query()) {
+        Some(feed) => feed,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    let Some(nonce) = feed.verify(&config.secret, &token) else {
+        return status(StatusCode::FORBIDDEN);
+    };
+    match manager.has_calendar_feed_token(feed.token(&nonce)).await {
+        Ok(true) => {}
+        Ok(false) => return status(StatusCode::FORBIDDEN),
+        Err(e) => return problem(e),
+    }
+
+    let now = Utc::now();
+    let mut query = ReservationQuery {
+        start: Some(convert_to_timestamp(now - Duration::days(config.past_days))),
+        end: Some(convert_to_timestamp(
+            now + Duration::days(config.future_days),
+        )),
+        ..Default::default()
+    };
+    let name = match &feed {
+        CalendarFeed::User(id) => {
+            query.user_id = id.clone();
+            id
+        }
+        CalendarFeed::Resource(id) => {
+            query.resource_id = id.clone();
+            id
+        }
+    };
+    if let Err(e) = query.validate() {
+        return problem(e);
+    }
+    match manager.query(query).await {
+        Ok(rsvps) => Response::builder()
+            .header(CONTENT_TYPE, "text/calendar; charset=utf-8")
+            .body(Body::from(abi::to_ics(name, &rsvps, now)))
+            .unwrap(),
+        Err(e) => problem(e),
+    }
+}
+
+/// an `application/problem+json` response describing the error
+fn problem(e: abi::Error) -> Response<Body> {
+    let problem = abi::Problem::from(e);
+    Response::builder()
+        .status(problem.status)
+        .header(CONTENT_TYPE, "application/problem+json")
+        .body(Body::from(serde_json::to_vec(&problem).unwrap()))
+        .unwrap()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use reservation::ReservationManager;
+
+    use super::*;
+
+    #[test]
+    fn feed_paths_should_round_trip() {
+        let feed = CalendarFeed::User("alice@example.com".to_string());
+        let path = feed.path("s3cret", "n0nce");
+        assert!(path.starts_with("/calendars/users/alice%40example.com.ics?token=n0nce."));
+
+        let (parsed, token) = CalendarFeed::from_path(&path).unwrap();
+        assert_eq!(parsed, feed);
+        assert_eq!(parsed.verify("s3cret", &token), Some("n0nce".to_string()));
+        assert_eq!(parsed.verify("other", &token), None);
+        // a token only opens its own feed, with its own nonce
+        assert_eq!(
+            CalendarFeed::User("bob".to_string()).verify("s3cret", &token),
+            None
+        );
+        let resource = CalendarFeed::Resource("alice@example.com".to_string());
+        assert_eq!(resource.verify("s3cret", &token), None);
+        let forged = token.replacen("n0nce", "other", 1);
+        assert_eq!(parsed.verify("s3cret", &forged), None);
+    }
+
+    #[test]
+    fn feed_should_need_exactly_one_id() {
+        assert!(CalendarFeed::new("".to_string(), "".to_string()).is_err());
+        assert!(CalendarFeed::new("u".to_string(), "r".to_string()).is_err());
+        assert_eq!(
+            CalendarFeed::new("".to_string(), "room-1".to_string()).unwrap(),
+            CalendarFeed::Resource("room-1".to_string())
+        );
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn feed_should_serve_the_reservations_with_a_valid_token() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let now = Utc::now();
+        let start = (now + Duration::days(1)).into();
+        let end = (now + Duration::days(1) + Duration::hours(2)).into();
+        let rsvp = abi::Reservation::new_pending("user1", "room-1", start, end, "standup");
+        let rsvp = manager.reserve(rsvp).await.unwrap();
+        let config = CalendarConfig {
+            secret: "s3cret".to_string(),
+            ..Default::default()
+        };
+
+        let feed = CalendarFeed::Resource("room-1".to_string());
+        manager
+            .add_calendar_feed_token(feed.token("n1"))
+            .await
+            .unwrap();
+        let path = feed.path("s3cret", "n1");
+        let resp = respond(&manager, &config, get(&path)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/calendar; charset=utf-8");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(&format!("UID:reservation-{}@rsvp\r\n", rsvp.id)));
+        assert!(body.contains("DESCRIPTION:standup\r\n"));
+        assert!(body.contains("STATUS:TENTATIVE\r\n"));
+
+        let path = feed.path("guess", "n1");
+        let resp = respond(&manager, &config, get(&path)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = respond(&manager, &config, get("/calendars/rooms/room-1.ics")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        // signed, but never handed out or revoked since
+        let path = feed.path("s3cret", "n2");
+        let resp = respond(&manager, &config, get(&path)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        manager
+            .revoke_calendar_feed_token(feed.token("n1"))
+            .await
+            .unwrap();
+        let path = feed.path("s3cret", "n1");
+        let resp = respond(&manager, &config, get(&path)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let long = CalendarFeed::Resource("r".repeat(65));
+        manager
+            .add_calendar_feed_token(long.token("n3"))
+            .await
+            .unwrap();
+        let path = long.path("s3cret", "n3");
+        let resp = respond(&manager, &config, get(&path)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/problem+json");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["violations"][0]["field"], "resource_id");
+        assert_eq!(problem["violations"][0]["code"], "too_long");
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+}
@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use reservation::OutboxEvent;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use super::EventSink;
+
+/// appends one JSON event per line to a file
+pub struct JsonlSink {
+    path: PathBuf,
+}
+
+impl JsonlSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl EventSink for JsonlSink {
+    async fn publish(&self, events: &[OutboxEvent]) -> Result<(), anyhow::Error> {
+        let lines: String = events.iter().map(|e| format!("{}\n", e.payload)).collect();
+        // reopened every time so the file can be rotated underneath us
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(lines.as_bytes()).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::make_events;
+
+    #[tokio::test]
+    async fn events_should_be_appended_as_lines() {
+        let path = std::env::temp_dir().join(format!("rsvp-events-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sink = JsonlSink::new(&path);
+        let events = make_events(3);
+        sink.publish(&events[..2]).await.unwrap();
+        sink.publish(&events[2..]).await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"sequence": 1}"#,
+                r#"{"sequence": 2}"#,
+                r#"{"sequence": 3}"#
+            ]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use reservation::OutboxEvent;
+use rskafka::{
+    client::{
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+        ClientBuilder,
+    },
+    record::Record,
+};
+
+use super::EventSink;
+
+/// produces the events to one partition of a topic, keyed by reservation id
+pub struct KafkaSink {
+    client: PartitionClient,
+}
+
+impl KafkaSink {
+    pub async fn connect(
+        brokers: Vec<String>,
+        topic: &str,
+        partition: i32,
+    ) -> Result<Self, anyhow::Error> {
+        let client = ClientBuilder::new(brokers).build().await?;
+        let client = client
+            .partition_client(topic, partition, UnknownTopicHandling::Retry)
+            .await?;
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl EventSink for KafkaSink {
+    async fn publish(&self, events: &[OutboxEvent]) -> Result<(), anyhow::Error> {
+        let records = events
+            .iter()
+            .map(|event| Record {
+                key: Some(event.reservation_id.to_string().into_bytes()),
+                value: Some(event.payload.clone().into_bytes()),
+                headers: BTreeMap::from([
+                    ("op".to_string(), event.op.clone().into_bytes()),
+                    (
+                        "sequence".to_string(),
+                        event.sequence.to_string().into_bytes(),
+                    ),
+                ]),
+                timestamp: event.committed_at,
+            })
+            .collect();
+        // returns once the partition leader has the whole batch
+        self.client
+            .produce(records, Compression::NoCompression)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::make_events;
+
+    /// needs a local broker, e.g. `docker run -p 9092:9092 apache/kafka`. Set KAFKA_BROKERS to
+    /// use other ones
+    #[tokio::test]
+    #[ignore]
+    async fn events_should_be_produced_in_order() {
+        let brokers = std::env::var("KAFKA_BROKERS").unwrap_or_else(|_| "localhost:9092".into());
+        let brokers: Vec<String> = brokers.split(',').map(String::from).collect();
+        let topic = format!("rsvp-test-{}", std::process::id());
+        let client = ClientBuilder::new(brokers.clone()).build().await.unwrap();
+        client
+            .controller_client()
+            .unwrap()
+            .create_topic(&topic, 1, 1, 5_000)
+            .await
+            .unwrap();
+
+        let sink = KafkaSink::connect(brokers, &topic, 0).await.unwrap();
+        sink.publish(&make_events(2)).await.unwrap();
+
+        let (records, _) = sink
+            .client
+            .fetch_records(0, 1..1_000_000, 1_000)
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        for (i, record) in records.iter().enumerate() {
+            let sequence = i + 1;
+            assert_eq!(record.record.key, Some(sequence.to_string().into_bytes()));
+            assert_eq!(
+                record.record.value,
+                Some(format!(r#"{{"sequence": {sequence}}}"#).into_bytes())
+            );
+            assert_eq!(record.record.headers["op"], b"create");
+        }
+    }
+}
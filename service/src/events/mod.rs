@@ -0,0 +1,243 @@
+mod jsonl;
+mod kafka;
+mod nats;
+mod stdout;
+
+use std::time::Duration;
+
+use abi::{EventsConfig, SinkKind};
+use async_trait::async_trait;
+use reservation::{OutboxEvent, ReservationManager};
+
+pub use jsonl::JsonlSink;
+pub use kafka::KafkaSink;
+pub use nats::NatsSink;
+pub use stdout::StdoutSink;
+
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    // publish the events in order and only return once the other side has them. On error the
+    // whole batch is published again, so consumers should drop duplicates by sequence number
+    async fn publish(&self, events: &[OutboxEvent]) -> Result<(), anyhow::Error>;
+}
+
+/// connect to the sink described by the config
+pub async fn connect_sink(kind: &SinkKind) -> Result<Box<dyn EventSink>, anyhow::Error> {
+    let sink: Box<dyn EventSink> = match kind {
+        SinkKind::Stdout => Box::new(StdoutSink),
+        SinkKind::Jsonl { path } => Box::new(JsonlSink::new(path)),
+        SinkKind::Nats {
+            url,
+            subject,
+            jetstream,
+        } => Box::new(NatsSink::connect(url, subject, *jetstream).await?),
+        SinkKind::Kafka {
+            brokers,
+            topic,
+            partition,
+        } => Box::new(KafkaSink::connect(brokers.clone(), topic, *partition).await?),
+    };
+    Ok(sink)
+}
+
+// how long to wait before connecting to an unreachable sink again, doubled up to the max
+const CONNECT_BACKOFF_BASE: Duration = Duration::from_secs(1);
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// drains the reservation changes into one sink
+pub struct EventPublisher {
+    name: String,
+    manager: ReservationManager,
+    // None until `run` has connected to the sink of `kind`
+    sink: Option<Box<dyn EventSink>>,
+    kind: Option<SinkKind>,
+    config: EventsConfig,
+}
+
+impl EventPublisher {
+    /// `name` keeps the position of the sink in the change feed across restarts
+    pub fn new(
+        name: impl Into<String>,
+        manager: ReservationManager,
+        sink: Box<dyn EventSink>,
+        config: EventsConfig,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            manager,
+            sink: Some(sink),
+            kind: None,
+            config,
+        }
+    }
+
+    /// a publisher that connects to the sink once it runs, so a sink that's down doesn't keep
+    /// the service from starting. Events wait in the change feed until it's reachable
+    pub fn connecting(
+        name: impl Into<String>,
+        manager: ReservationManager,
+        kind: SinkKind,
+        config: EventsConfig,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            manager,
+            sink: None,
+            kind: Some(kind),
+            config,
+        }
+    }
+
+    /// publish events until the process exits
+    pub async fn run(mut self) {
+        let idle = Duration::from_millis(self.config.poll_interval_ms);
+        let mut backoff = CONNECT_BACKOFF_BASE;
+        loop {
+            if let (None, Some(kind)) = (&self.sink, &self.kind) {
+                match connect_sink(kind).await {
+                    Ok(sink) => {
+                        tracing::info!(sink = %self.name, "connected to event sink");
+                        self.sink = Some(sink);
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            sink = %self.name,
+                            error = %e,
+                            retry_in_ms = backoff.as_millis() as u64,
+                            "event sink is unreachable"
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(CONNECT_BACKOFF_MAX);
+                        continue;
+                    }
+                }
+            }
+
+            match self.publish().await {
+                Ok(0) => tokio::time::sleep(idle).await,
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!(sink = %self.name, error = %e, "publishing events failed");
+                    tokio::time::sleep(idle).await;
+                }
+            }
+        }
+    }
+
+    /// publish the next batch of changes, returns the number of events. The batch is only
+    /// acknowledged once the sink has it, a failed one is published again next time
+    pub async fn publish(&self) -> Result<usize, anyhow::Error> {
+        let Some(sink) = &self.sink else {
+            anyhow::bail!("event sink {} is not connected", self.name);
+        };
+        let events = self
+            .manager
+            .outbox_events(&self.name, self.config.batch_size)
+            .await?;
+        if let Some(last) = events.last() {
+            sink.publish(&events).await?;
+            self.manager
+                .ack_outbox_events(&self.name, last.sequence)
+                .await?;
+        }
+        Ok(events.len())
+    }
+}
+
+#[cfg(test)]
+fn make_events(n: i64) -> Vec<OutboxEvent> {
+    (1..=n)
+        .map(|sequence| OutboxEvent {
+            sequence,
+            op: "create".to_string(),
+            reservation_id: sequence,
+            resource_id: "room-1".to_string(),
+            committed_at: "2099-12-25T15:00:00Z".parse().unwrap(),
+            payload: format!(r#"{{"sequence": {sequence}}}"#),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use reservation::Rsvp;
+
+    use super::*;
+
+    /// records the events, fails the first `failures` batches
+    #[derive(Default, Clone)]
+    struct FlakySink {
+        failures: Arc<Mutex<usize>>,
+        published: Arc<Mutex<Vec<i64>>>,
+    }
+
+    #[async_trait]
+    impl EventSink for FlakySink {
+        async fn publish(&self, events: &[OutboxEvent]) -> Result<(), anyhow::Error> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                anyhow::bail!("broker unavailable");
+            }
+            let mut published = self.published.lock().unwrap();
+            published.extend(events.iter().map(|e| e.sequence));
+            Ok(())
+        }
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn failed_batch_should_be_published_again() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let sink = FlakySink {
+            failures: Arc::new(Mutex::new(1)),
+            ..Default::default()
+        };
+        let publisher = EventPublisher::new(
+            "flaky",
+            manager.clone(),
+            Box::new(sink.clone()),
+            EventsConfig::default(),
+        );
+        assert_eq!(publisher.publish().await.unwrap(), 0);
+
+        let rsvp = manager.reserve(make_pending()).await.unwrap();
+        manager.change_status(rsvp.id, None).await.unwrap();
+        assert!(publisher.publish().await.is_err());
+        assert!(sink.published.lock().unwrap().is_empty());
+
+        assert_eq!(publisher.publish().await.unwrap(), 2);
+        assert_eq!(publisher.publish().await.unwrap(), 0);
+        let published = sink.published.lock().unwrap().clone();
+        assert_eq!(published.len(), 2);
+        assert!(published[0] < published[1]);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn unreachable_sink_should_be_retried_in_the_background() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        // a new sink starts at the head of the change feed
+        assert!(manager.outbox_events("down", 10).await.unwrap().is_empty());
+        manager.reserve(make_pending()).await.unwrap();
+        let kind = SinkKind::Nats {
+            url: "nats://127.0.0.1:1".to_string(),
+            subject: "rsvp.reservations".to_string(),
+            jetstream: false,
+        };
+        let publisher =
+            EventPublisher::connecting("down", manager.clone(), kind, EventsConfig::default());
+        assert!(publisher.publish().await.is_err());
+
+        // it keeps trying rather than giving up, and nothing is acknowledged meanwhile
+        let run = tokio::time::timeout(Duration::from_millis(200), publisher.run());
+        assert!(run.await.is_err());
+        assert_eq!(manager.outbox_events("down", 10).await.unwrap().len(), 1);
+    }
+
+    fn make_pending() -> abi::Reservation {
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        abi::Reservation::new_pending("user1", "room-1", start, end, "just note")
+    }
+}
@@ -0,0 +1,83 @@
+use async_nats::{jetstream, HeaderMap};
+use async_trait::async_trait;
+use reservation::OutboxEvent;
+
+use super::EventSink;
+
+/// publishes every event to "{subject}.{op}". Without jetstream an event is only as durable as
+/// the subscribers listening at the time
+pub struct NatsSink {
+    client: async_nats::Client,
+    jetstream: Option<jetstream::Context>,
+    subject: String,
+}
+
+impl NatsSink {
+    pub async fn connect(url: &str, subject: &str, jetstream: bool) -> Result<Self, anyhow::Error> {
+        let client = async_nats::connect(url).await?;
+        Ok(Self {
+            jetstream: jetstream.then(|| jetstream::new(client.clone())),
+            client,
+            subject: subject.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl EventSink for NatsSink {
+    async fn publish(&self, events: &[OutboxEvent]) -> Result<(), anyhow::Error> {
+        let mut acks = Vec::new();
+        for event in events {
+            let subject = format!("{}.{}", self.subject, event.op);
+            // jetstream drops redeliveries within its duplicate window
+            let mut headers = HeaderMap::new();
+            headers.insert("Nats-Msg-Id", event.sequence.to_string().as_str());
+            let payload = event.payload.clone().into();
+            match &self.jetstream {
+                Some(js) => acks.push(js.publish_with_headers(subject, headers, payload).await?),
+                None => {
+                    self.client
+                        .publish_with_headers(subject, headers, payload)
+                        .await?
+                }
+            }
+        }
+        for ack in acks {
+            ack.await?;
+        }
+        self.client.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::events::make_events;
+
+    /// needs a local server, e.g. `docker run -p 4222:4222 nats`. Set NATS_URL to use another one
+    #[tokio::test]
+    #[ignore]
+    async fn events_should_be_published_by_op() {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "nats://localhost:4222".into());
+        let subject = format!("rsvp-test-{}", std::process::id());
+        let sink = NatsSink::connect(&url, &subject, false).await.unwrap();
+        let client = async_nats::connect(&url).await.unwrap();
+        let mut subscriber = client.subscribe(format!("{subject}.>")).await.unwrap();
+        client.flush().await.unwrap();
+
+        sink.publish(&make_events(2)).await.unwrap();
+        for sequence in 1..=2 {
+            let msg = subscriber.next().await.unwrap();
+            assert_eq!(msg.subject.as_str(), format!("{subject}.create"));
+            assert_eq!(
+                msg.payload.as_ref(),
+                format!(r#"{{"sequence": {sequence}}}"#).as_bytes()
+            );
+            let id = msg.headers.unwrap().get("Nats-Msg-Id").unwrap().to_string();
+            assert_eq!(id, sequence.to_string());
+        }
+    }
+}
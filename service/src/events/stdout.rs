@@ -0,0 +1,21 @@
+use std::io::Write;
+
+use async_trait::async_trait;
+use reservation::OutboxEvent;
+
+use super::EventSink;
+
+/// writes one JSON event per line to stdout
+pub struct StdoutSink;
+
+#[async_trait]
+impl EventSink for StdoutSink {
+    async fn publish(&self, events: &[OutboxEvent]) -> Result<(), anyhow::Error> {
+        let mut out = std::io::stdout().lock();
+        for event in events {
+            writeln!(out, "{}", event.payload)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+mod auth;
+mod bulk;
+mod calendar;
+mod events;
+mod limit;
+mod metrics;
+mod service;
+mod telemetry;
+mod webhook;
+
+use std::{pin::Pin, str::FromStr, time::Duration};
+
+use abi::{
+    reservation_service_server::ReservationServiceServer, CalendarConfig, Config, WebhookConfig,
+};
+use futures::Stream;
+use reservation::{CachedManager, InMemoryManager, ReservationManager, Storage};
+use sqlx::postgres::PgPoolOptions;
+use tonic::{service::interceptor, transport::Server, Status};
+
+pub use auth::{caller, Authenticator, Caller};
+pub use bulk::{export, import, BulkFormat, ImportReport, RowError};
+pub use calendar::{serve_calendar_feeds, CalendarFeed};
+pub use events::{
+    connect_sink, EventPublisher, EventSink, JsonlSink, KafkaSink, NatsSink, StdoutSink,
+};
+pub use limit::{LimitBody, LimitLayer, LimitService, RateLimiter};
+pub use metrics::{serve_metrics, Metrics, MetricsLayer, MetricsService, Subscriber};
+pub use telemetry::{init_tracing, shutdown_tracing, RequestIdLayer, RequestIdService, REQUEST_ID};
+pub use webhook::{webhook_signature, WebhookDispatcher};
+
+pub struct RsvpService<S = ReservationManager> {
+    manager: S,
+    calendar: CalendarConfig,
+    webhook: WebhookConfig,
+    metrics: Metrics,
+}
+
+/// where the service keeps its data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageKind {
+    // the database of the config
+    Postgres,
+    // lost on restart, for tests and local development
+    Memory,
+    // a SQLite database url, for single node deployments
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
+}
+
+type ReservationStream = Pin<Box<dyn Stream<Item = Result<abi::Reservation, Status>> + Send>>;
+type ListenStream = Pin<Box<dyn Stream<Item = Result<abi::ListenResponse, Status>> + Send>>;
+
+impl<S> RsvpService<S> {
+    pub fn new(manager: S) -> Self {
+        Self {
+            manager,
+            calendar: CalendarConfig::default(),
+            webhook: WebhookConfig::default(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// record the domain metrics in `metrics`, the service has metrics of its own otherwise
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// hand out calendar feeds signed with the secret of the config, if they're enabled
+    pub fn with_calendar(mut self, config: CalendarConfig) -> Self {
+        self.calendar = config;
+        self
+    }
+
+    /// check the targets of new webhooks against the config
+    pub fn with_webhook(mut self, config: WebhookConfig) -> Self {
+        self.webhook = config;
+        self
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(StorageKind::Postgres),
+            "memory" => Ok(StorageKind::Memory),
+            #[cfg(feature = "sqlite")]
+            _ if s.starts_with("sqlite:") => Ok(StorageKind::Sqlite(s.to_string())),
+            _ => anyhow::bail!("unknown storage {s}, expected postgres, memory or a sqlite: url"),
+        }
+    }
+}
+
+/// connect to the database of the config
+pub async fn connect(config: &Config) -> Result<ReservationManager, anyhow::Error> {
+    let pool = PgPoolOptions::new()
+        .max_connections(config.db.max_connections)
+        .connect(&config.db.url())
+        .await?;
+    let manager = ReservationManager::new(pool)
+        .await
+        .with_idempotency_retention(idempotency_retention(config));
+    Ok(manager)
+}
+
+fn idempotency_retention(config: &Config) -> chrono::Duration {
+    chrono::Duration::milliseconds(config.idempotency.retention_ms as i64)
+}
+
+/// start the webhook dispatcher and the calendar server (if enabled) and the event publishers,
+/// then serve the gRPC api until the server fails. Webhooks and event sinks need Postgres
+pub async fn start_server(config: &Config, storage: StorageKind) -> Result<(), anyhow::Error> {
+    let manager = match storage {
+        StorageKind::Postgres => connect(config).await?,
+        StorageKind::Memory => {
+            if config.webhook.enabled || !config.events.sinks.is_empty() {
+                anyhow::bail!("webhooks and event sinks are not supported with memory storage");
+            }
+            let manager =
+                InMemoryManager::new().with_idempotency_retention(idempotency_retention(config));
+            return serve(manager, config, None).await;
+        }
+        #[cfg(feature = "sqlite")]
+        StorageKind::Sqlite(url) => {
+            if config.webhook.enabled || !config.events.sinks.is_empty() {
+                anyhow::bail!("webhooks and event sinks are not supported with sqlite storage");
+            }
+            let manager = reservation::SqliteManager::connect(&url, config.db.max_connections)
+                .await?
+                .with_idempotency_retention(idempotency_retention(config));
+            return serve(manager, config, None).await;
+        }
+    };
+
+    if config.webhook.enabled {
+        let dispatcher = WebhookDispatcher::new(manager.clone(), config.webhook.clone())?;
+        tokio::spawn(dispatcher.run());
+    }
+
+    for sink in &config.events.sinks {
+        let publisher = EventPublisher::connecting(
+            &sink.name,
+            manager.clone(),
+            sink.kind.clone(),
+            config.events.clone(),
+        );
+        tokio::spawn(publisher.run());
+    }
+
+    serve(manager.clone(), config, Some(manager)).await
+}
+
+/// start the calendar and metrics servers (if enabled) and the purge of expired idempotency
+/// keys, then serve the gRPC api with the limits of the config, behind the cache if it's enabled,
+/// until the server fails. The metrics of the database are only collected from Postgres
+async fn serve<S: Storage>(
+    manager: S,
+    config: &Config,
+    database: Option<ReservationManager>,
+) -> Result<(), anyhow::Error> {
+    let metrics = Metrics::new();
+    if config.metrics.enabled {
+        let database = database.map(|manager| (manager, config.db.max_connections));
+        tokio::spawn(serve_metrics(
+            metrics.clone(),
+            database,
+            config.metrics.clone(),
+        ));
+    }
+
+    if config.calendar.enabled {
+        if config.calendar.secret.is_empty() {
+            return Err(abi::Error::MissingField("calendar.secret".to_string()).into());
+        }
+        tokio::spawn(serve_calendar_feeds(
+            manager.clone(),
+            config.calendar.clone(),
+        ));
+    }
+
+    if config.idempotency.purge_interval_ms == 0 {
+        anyhow::bail!("idempotency.purge_interval_ms must be positive");
+    }
+    let interval = Duration::from_millis(config.idempotency.purge_interval_ms);
+    tokio::spawn(purge_idempotency_keys(manager.clone(), interval));
+
+    if !config.cache.enabled {
+        return serve_grpc(manager, config, metrics).await;
+    }
+    let ttl = Duration::from_millis(config.cache.ttl_ms);
+    let observer = metrics.clone();
+    let manager = CachedManager::new(manager, config.cache.capacity, ttl)
+        .await?
+        .with_observer(move |kind, hit| observer.cache_lookup(kind, hit));
+    serve_grpc(manager, config, metrics).await
+}
+
+/// remove the expired idempotency keys every `interval`, forever
+async fn purge_idempotency_keys<S: Storage>(manager: S, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match manager.purge_idempotency_keys().await {
+            Ok(purged) => tracing::debug!(purged, "purged expired idempotency keys"),
+            // the keys are purged again on the next tick
+            Err(e) => tracing::warn!(error = %e, "purging idempotency keys failed"),
+        }
+    }
+}
+
+async fn serve_grpc<S: Storage>(
+    manager: S,
+    config: &Config,
+    metrics: Metrics,
+) -> Result<(), anyhow::Error> {
+    let addr = config.server.addr().parse()?;
+    let auth = Authenticator::new(&config.auth)?;
+    let limits = LimitLayer::new(&config.limits)?;
+    let svc = RsvpService::new(manager)
+        .with_calendar(config.calendar.clone())
+        .with_webhook(config.webhook.clone())
+        .with_metrics(metrics.clone());
+    let svc = ReservationServiceServer::new(svc);
+    Server::builder()
+        .layer(RequestIdLayer)
+        .layer(MetricsLayer::new(metrics))
+        .layer(interceptor(auth))
+        .layer(limits)
+        .add_service(svc)
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,471 @@
+use std::{
+    collections::HashMap,
+    future::{ready, Future},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use abi::{LimitsConfig, RateLimit};
+use hyper::{
+    body::{HttpBody, SizeHint},
+    HeaderMap, Request, Response,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::{metadata::MetadataValue, transport::server::TcpConnectInfo, Status};
+use tower::{Layer, Service};
+
+use crate::Caller;
+
+// the rpcs that don't change anything, the others count against the write limit
+const READS: &[&str] = &[
+    "get",
+    "query",
+    "filter",
+    "listen",
+    "get_resource",
+    "availability",
+    "get_quota",
+    "get_history",
+    "query_history",
+    "list_webhooks",
+    "list_webhook_deliveries",
+    "export_calendar",
+];
+
+// the rpcs that stream for as long as the caller likes, they're limited by the streams open
+const STREAMS: &[&str] = &["listen"];
+
+// the most callers with a bucket, the ones whose buckets have filled up again are forgotten
+// first, then the ones seen least recently
+const MAX_CALLERS: usize = 10_000;
+
+/// token buckets by caller
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// take a token from the bucket of the caller, or tell how long until there's one
+    pub fn acquire(&self, caller: &str) -> Result<(), Duration> {
+        self.acquire_at(caller, Instant::now())
+    }
+
+    fn acquire_at(&self, caller: &str, now: Instant) -> Result<(), Duration> {
+        let rate = self.limit.per_second as f64;
+        let burst = self.limit.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CALLERS && !buckets.contains_key(caller) {
+            // a full bucket is no different from a new one
+            let refill = burst / rate;
+            buckets.retain(|_, b| now.duration_since(b.updated).as_secs_f64() < refill);
+            if buckets.len() >= MAX_CALLERS {
+                let oldest = buckets
+                    .iter()
+                    .min_by_key(|(_, b)| b.updated)
+                    .map(|(caller, _)| caller.clone());
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+        }
+
+        let bucket = buckets.entry(caller.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// rejects the requests of callers over their rate limit and sheds the requests above the
+/// concurrency limit and the streams above theirs, all with RESOURCE_EXHAUSTED and a
+/// `retry-after` in seconds. Callers are told apart by the user they're authenticated as, or
+/// else by their address
+#[derive(Debug, Clone)]
+pub struct LimitLayer {
+    limits: Arc<Limits>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitService<S> {
+    inner: S,
+    limits: Arc<Limits>,
+}
+
+/// the body of a limited response. The body of a stream holds its permit until it's done, so
+/// open streams count against their limit
+#[derive(Debug, Default)]
+pub struct LimitBody<B> {
+    inner: B,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug)]
+struct Limits {
+    read: Option<RateLimiter>,
+    write: Option<RateLimiter>,
+    concurrency: Option<Arc<Semaphore>>,
+    streams: Option<Arc<Semaphore>>,
+    retry_after: Duration,
+}
+
+impl LimitLayer {
+    /// fails if a rate limit has no tokens to hand out or the concurrency or stream limit is 0
+    pub fn new(config: &LimitsConfig) -> Result<Self, anyhow::Error> {
+        let limiter = |name: &str, limit: Option<RateLimit>| match limit {
+            Some(limit) if limit.per_second == 0 || limit.burst == 0 => {
+                anyhow::bail!("limits.{name}: per_second and burst must be positive")
+            }
+            limit => Ok(limit.map(RateLimiter::new)),
+        };
+        if config.max_concurrent_requests == Some(0) {
+            anyhow::bail!("limits.max_concurrent_requests must be positive");
+        }
+        if config.max_open_streams == Some(0) {
+            anyhow::bail!("limits.max_open_streams must be positive");
+        }
+        let semaphore = |max: Option<usize>| max.map(|max| Arc::new(Semaphore::new(max)));
+
+        Ok(Self {
+            limits: Arc::new(Limits {
+                read: limiter("read", config.read)?,
+                write: limiter("write", config.write)?,
+                concurrency: semaphore(config.max_concurrent_requests),
+                streams: semaphore(config.max_open_streams),
+                retry_after: Duration::from_millis(config.retry_after_ms),
+            }),
+        })
+    }
+}
+
+impl<S> Layer<S> for LimitLayer {
+    type Service = LimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LimitService {
+            inner,
+            limits: self.limits.clone(),
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for LimitService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = Response<LimitBody<ResBody>>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let method = req.uri().path().rsplit('/').next().unwrap_or_default();
+        let limiter = if READS.contains(&method) {
+            &self.limits.read
+        } else {
+            &self.limits.write
+        };
+        if let Some(limiter) = limiter {
+            if let Err(wait) = limiter.acquire(&caller(&req)) {
+                return Box::pin(ready(Ok(exhausted("rate limit exceeded", wait))));
+            }
+        }
+
+        let stream = STREAMS.contains(&method);
+        let (semaphore, message) = if stream {
+            (&self.limits.streams, "too many open streams")
+        } else {
+            (&self.limits.concurrency, "server is overloaded")
+        };
+        let permit = match semaphore {
+            Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    let wait = self.limits.retry_after;
+                    return Box::pin(ready(Ok(exhausted(message, wait))));
+                }
+            },
+            None => None,
+        };
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let resp = fut.await?;
+            // a stream holds its permit until it's done or dropped, any other request gives it
+            // back as soon as its response starts
+            let permit = permit.filter(|_| stream);
+            Ok(resp.map(|inner| LimitBody { inner, permit }))
+        })
+    }
+}
+
+impl<B: HttpBody + Unpin> HttpBody for LimitBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let data = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(None) = data {
+            self.permit = None;
+        }
+        data
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let trailers = Pin::new(&mut self.inner).poll_trailers(cx);
+        if trailers.is_ready() {
+            self.permit = None;
+        }
+        trailers
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// the user the request is authenticated as, or else its address. Headers can't be trusted to
+/// tell callers apart, anyone could send a new one with every request
+fn caller<B>(req: &Request<B>) -> String {
+    if let Some(caller) = req.extensions().get::<Caller>() {
+        return format!("user/{}", caller.user_id);
+    }
+    match req
+        .extensions()
+        .get::<TcpConnectInfo>()
+        .and_then(|info| info.remote_addr())
+    {
+        Some(addr) => format!("ip/{}", addr.ip()),
+        None => "anonymous".to_string(),
+    }
+}
+
+fn exhausted<B: Default>(message: &str, wait: Duration) -> Response<B> {
+    let mut status = Status::resource_exhausted(message);
+    // whole seconds like the http header, rounded up so a retry doesn't come too early
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    status
+        .metadata_mut()
+        .insert("retry-after", MetadataValue::from(seconds.max(1)));
+    let (parts, _) = status.to_http().into_parts();
+    Response::from_parts(parts, B::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Ready;
+
+    use hyper::Body;
+
+    use super::*;
+
+    struct Accept;
+
+    impl Service<Request<()>> for Accept {
+        type Response = Response<Body>;
+        type Error = ();
+        type Future = Ready<Result<Response<Body>, ()>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<()>) -> Self::Future {
+            ready(Ok(Response::new(Body::empty())))
+        }
+    }
+
+    fn request(method: &str, user_id: &str) -> Request<()> {
+        let mut req = Request::builder()
+            .uri(format!("/reservation.ReservationService/{method}"))
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(Caller {
+            user_id: user_id.to_string(),
+            admin: false,
+        });
+        req
+    }
+
+    fn status<B>(resp: &Response<B>) -> Option<&str> {
+        resp.headers()
+            .get("grpc-status")
+            .map(|code| code.to_str().unwrap())
+    }
+
+    #[test]
+    fn buckets_should_refill_at_the_rate() {
+        let limiter = RateLimiter::new(RateLimit {
+            per_second: 2,
+            burst: 2,
+        });
+        let now = Instant::now();
+        assert!(limiter.acquire_at("alice", now).is_ok());
+        assert!(limiter.acquire_at("alice", now).is_ok());
+        assert_eq!(
+            limiter.acquire_at("alice", now),
+            Err(Duration::from_millis(500))
+        );
+        // every caller has a bucket of their own
+        assert!(limiter.acquire_at("bob", now).is_ok());
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.acquire_at("alice", later).is_ok());
+        assert!(limiter.acquire_at("alice", later).is_err());
+    }
+
+    #[test]
+    fn callers_seen_least_recently_should_be_forgotten_when_full() {
+        let limiter = RateLimiter::new(RateLimit {
+            per_second: 1,
+            burst: 1,
+        });
+        let now = Instant::now();
+        for i in 0..MAX_CALLERS {
+            let at = now + Duration::from_micros(i as u64);
+            assert!(limiter.acquire_at(&format!("caller-{i}"), at).is_ok());
+        }
+        let at = now + Duration::from_millis(100);
+        assert!(limiter.acquire_at("caller-1", at).is_err());
+        assert!(limiter.acquire_at("newcomer", at).is_ok());
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_CALLERS);
+        assert!(!buckets.contains_key("caller-0"));
+        assert!(buckets.contains_key("caller-1"));
+    }
+
+    #[test]
+    fn callers_should_be_told_apart_by_user_before_address() {
+        assert_eq!(caller(&request("get", "alice")), "user/alice");
+        assert_eq!(caller(&Request::new(())), "anonymous");
+    }
+
+    #[tokio::test]
+    async fn callers_over_the_limit_should_be_told_to_retry() {
+        let config = LimitsConfig {
+            write: Some(RateLimit {
+                per_second: 1,
+                burst: 1,
+            }),
+            ..Default::default()
+        };
+        let mut svc = LimitLayer::new(&config).unwrap().layer(Accept);
+
+        let resp = svc.call(request("reserve", "app-1")).await.unwrap();
+        assert_eq!(status(&resp), None);
+        let resp = svc.call(request("reserve", "app-1")).await.unwrap();
+        assert_eq!(status(&resp), Some("8"));
+        assert_eq!(resp.headers()["retry-after"], "1");
+        // reads and other callers have limits of their own
+        let resp = svc.call(request("get", "app-1")).await.unwrap();
+        assert_eq!(status(&resp), None);
+        let resp = svc.call(request("reserve", "app-2")).await.unwrap();
+        assert_eq!(status(&resp), None);
+    }
+
+    #[tokio::test]
+    async fn requests_above_the_concurrency_limit_should_be_shed() {
+        let config = LimitsConfig {
+            max_concurrent_requests: Some(1),
+            retry_after_ms: 2500,
+            ..Default::default()
+        };
+        let mut svc = LimitLayer::new(&config).unwrap().layer(Accept);
+
+        let first = svc.call(request("query", "app-1"));
+        let resp = svc.call(request("query", "app-2")).await.unwrap();
+        assert_eq!(status(&resp), Some("8"));
+        assert_eq!(resp.headers()["retry-after"], "3");
+
+        // a response that started doesn't count any more, nor do streams
+        let _first = first.await.unwrap();
+        let _stream = svc.call(request("listen", "app-1")).await.unwrap();
+        let resp = svc.call(request("query", "app-2")).await.unwrap();
+        assert_eq!(status(&resp), None);
+    }
+
+    #[tokio::test]
+    async fn streams_above_their_limit_should_be_shed() {
+        let config = LimitsConfig {
+            max_concurrent_requests: Some(1),
+            max_open_streams: Some(1),
+            ..Default::default()
+        };
+        let mut svc = LimitLayer::new(&config).unwrap().layer(Accept);
+
+        // an open stream counts until its body is done
+        let mut body = svc
+            .call(request("listen", "app-1"))
+            .await
+            .unwrap()
+            .into_body();
+        let resp = svc.call(request("listen", "app-2")).await.unwrap();
+        assert_eq!(status(&resp), Some("8"));
+        assert_eq!(
+            status(&svc.call(request("get", "app-2")).await.unwrap()),
+            None
+        );
+        assert!(body.data().await.is_none());
+        let second = svc.call(request("listen", "app-2")).await.unwrap();
+        assert_eq!(status(&second), None);
+
+        // or until it's dropped
+        let resp = svc.call(request("listen", "app-1")).await.unwrap();
+        assert_eq!(status(&resp), Some("8"));
+        drop(second);
+        let resp = svc.call(request("listen", "app-1")).await.unwrap();
+        assert_eq!(status(&resp), None);
+    }
+
+    #[test]
+    fn empty_limits_should_be_rejected() {
+        let config = LimitsConfig {
+            read: Some(RateLimit {
+                per_second: 0,
+                burst: 10,
+            }),
+            ..Default::default()
+        };
+        assert!(LimitLayer::new(&config).is_err());
+    }
+}
@@ -1,3 +1,140 @@
-fn main() {
-    println!("Hello, world!");
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Read},
+    path::{Path, PathBuf},
+};
+
+use abi::{convert_to_timestamp, Config, ReservationQuery, ReservationStatus};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use reservation_service::{BulkFormat, StorageKind};
+
+#[derive(Debug, Parser)]
+#[command(about = "Reservation service")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve the gRPC api (the default)
+    Serve {
+        /// postgres, memory or a sqlite: url (with the sqlite feature), memory storage is lost on
+        /// restart
+        #[arg(long, default_value = "postgres")]
+        storage: StorageKind,
+    },
+    /// Write the reservations matching the filters to a file or stdout
+    Export {
+        /// csv or jsonl
+        #[arg(long, default_value = "csv")]
+        format: BulkFormat,
+        #[arg(long, default_value = "")]
+        user_id: String,
+        #[arg(long, default_value = "")]
+        resource_id: String,
+        /// pending, confirmed or blocked
+        #[arg(long)]
+        status: Option<ReservationStatus>,
+        /// only reservations overlapping [start, end), RFC 3339
+        #[arg(long)]
+        start: Option<DateTime<Utc>>,
+        #[arg(long)]
+        end: Option<DateTime<Utc>>,
+        /// latest reservations first
+        #[arg(long)]
+        desc: bool,
+        /// stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Load reservations from a file or stdin, bad rows are reported and skipped
+    Import {
+        /// csv or jsonl
+        #[arg(long, default_value = "csv")]
+        format: BulkFormat,
+        /// stdin if not given
+        file: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+    // the config file is taken from RESERVATION_CONFIG, ./reservation.yml or /etc/reservation.yml
+    let filename = env::var("RESERVATION_CONFIG").unwrap_or_else(|_| {
+        let local = Path::new("reservation.yml");
+        if local.exists() {
+            local.to_string_lossy().into_owned()
+        } else {
+            "/etc/reservation.yml".to_string()
+        }
+    });
+    let config = Config::load(&filename)?;
+
+    let serve = Command::Serve {
+        storage: StorageKind::Postgres,
+    };
+    match args.command.unwrap_or(serve) {
+        Command::Serve { storage } => {
+            reservation_service::init_tracing(&config.tracing)?;
+            let result = reservation_service::start_server(&config, storage).await;
+            reservation_service::shutdown_tracing();
+            result
+        }
+        Command::Export {
+            format,
+            user_id,
+            resource_id,
+            status,
+            start,
+            end,
+            desc,
+            output,
+        } => {
+            let query = ReservationQuery {
+                user_id,
+                resource_id,
+                status: status.unwrap_or(ReservationStatus::Unknown) as i32,
+                start: start.map(convert_to_timestamp),
+                end: end.map(convert_to_timestamp),
+                desc,
+            };
+            let manager = reservation_service::connect(&config).await?;
+            let count = match output {
+                Some(path) => {
+                    let out = BufWriter::new(File::create(path)?);
+                    reservation_service::export(&manager, query, format, out).await?
+                }
+                None => {
+                    let out = BufWriter::new(io::stdout().lock());
+                    reservation_service::export(&manager, query, format, out).await?
+                }
+            };
+            eprintln!("exported {count} reservations");
+            Ok(())
+        }
+        Command::Import { format, file } => {
+            let input: Box<dyn Read + Send> = match file {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+            let manager = reservation_service::connect(&config).await?;
+            let report = reservation_service::import(&manager, format, input).await?;
+            for error in &report.errors {
+                eprintln!("{error}");
+            }
+            eprintln!(
+                "imported {} reservations, {} rows failed",
+                report.created,
+                report.errors.len()
+            );
+            if !report.errors.is_empty() {
+                anyhow::bail!("{} rows were not imported", report.errors.len());
+            }
+            Ok(())
+        }
+    }
 }
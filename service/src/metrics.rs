@@ -0,0 +1,439 @@
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use abi::MetricsConfig;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use reservation::ReservationManager;
+use tower::{Layer, Service};
+
+/// conflicts are counted for at most this many resources, the rest are counted as "other"
+const MAX_CONFLICT_RESOURCES: usize = 1000;
+
+/// the prometheus metrics of the service. Clones share the same metrics
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    created: IntCounter,
+    confirmed: IntCounter,
+    cancelled: IntCounter,
+    conflicts: IntCounterVec,
+    conflict_resources: Mutex<HashSet<String>>,
+    subscribers: IntGauge,
+    feed_lag: IntGaugeVec,
+    pool_connections: IntGaugeVec,
+    cache_lookups: IntCounterVec,
+}
+
+/// counts a listen subscriber until it's dropped
+#[derive(Debug)]
+pub struct Subscriber {
+    gauge: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "rpc_requests_total",
+                "gRPC requests by method and status code",
+            ),
+            &["method", "code"],
+        )
+        .unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "rpc_request_duration_seconds",
+                "time until the response of a gRPC request started, by method",
+            ),
+            &["method"],
+        )
+        .unwrap();
+        let created = IntCounter::new("created_total", "reservations made").unwrap();
+        let confirmed = IntCounter::new("confirmed_total", "reservations confirmed").unwrap();
+        let cancelled = IntCounter::new("cancelled_total", "reservations cancelled").unwrap();
+        let conflicts = IntCounterVec::new(
+            Opts::new(
+                "conflicts_total",
+                format!(
+                    "requests rejected for conflicting with a reservation, by resource (at most \
+                     {MAX_CONFLICT_RESOURCES}, the rest are counted as other)"
+                ),
+            ),
+            &["resource_id"],
+        )
+        .unwrap();
+        let subscribers = IntGauge::new("listen_subscribers", "open listen streams").unwrap();
+        let feed_lag = IntGaugeVec::new(
+            Opts::new(
+                "change_feed_lag",
+                "changes a consumer of the change feed is behind its head",
+            ),
+            &["consumer"],
+        )
+        .unwrap();
+        let pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "connections of the database pool by state (active, idle or max)",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "cache_lookups_total",
+                "lookups in the cache by kind (get or query) and result (hit or miss)",
+            ),
+            &["kind", "result"],
+        )
+        .unwrap();
+
+        let registry = Registry::new_custom(Some("reservation".to_string()), None).unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(created.clone())).unwrap();
+        registry.register(Box::new(confirmed.clone())).unwrap();
+        registry.register(Box::new(cancelled.clone())).unwrap();
+        registry.register(Box::new(conflicts.clone())).unwrap();
+        registry.register(Box::new(subscribers.clone())).unwrap();
+        registry.register(Box::new(feed_lag.clone())).unwrap();
+        registry
+            .register(Box::new(pool_connections.clone()))
+            .unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+
+        Self {
+            inner: Arc::new(Inner {
+                registry,
+                requests,
+                latency,
+                created,
+                confirmed,
+                cancelled,
+                conflicts,
+                conflict_resources: Mutex::new(HashSet::new()),
+                subscribers,
+                feed_lag,
+                pool_connections,
+                cache_lookups,
+            }),
+        }
+    }
+
+    pub fn created(&self, n: usize) {
+        self.inner.created.inc_by(n as u64);
+    }
+
+    pub fn confirmed(&self) {
+        self.inner.confirmed.inc();
+    }
+
+    pub fn cancelled(&self, n: usize) {
+        self.inner.cancelled.inc_by(n as u64);
+    }
+
+    /// count the error if it's a conflict, by the resource named in its detail
+    pub fn observe(&self, e: &abi::Error) {
+        if let abi::Error::ConflictReservation(detail) = e {
+            let rid = conflicting_resource(detail).unwrap_or("unknown");
+            let rid = self.conflict_label(rid);
+            self.inner.conflicts.with_label_values(&[rid]).inc();
+        }
+    }
+
+    /// a lookup of `kind` in the cache
+    pub fn cache_lookup(&self, kind: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.inner
+            .cache_lookups
+            .with_label_values(&[kind, result])
+            .inc();
+    }
+
+    /// a new listen subscriber, counted until the returned guard is dropped
+    pub fn subscriber(&self) -> Subscriber {
+        self.inner.subscribers.inc();
+        Subscriber {
+            gauge: self.inner.subscribers.clone(),
+        }
+    }
+
+    /// update the change feed lag and the pool connections from the database
+    pub async fn collect_database(
+        &self,
+        manager: &ReservationManager,
+        max_connections: u32,
+    ) -> Result<(), abi::Error> {
+        // consumers that are gone shouldn't be reported any more
+        self.inner.feed_lag.reset();
+        for lag in manager.change_feed_lag().await? {
+            self.inner
+                .feed_lag
+                .with_label_values(&[&lag.consumer])
+                .set(lag.lag);
+        }
+
+        let stats = manager.pool_stats();
+        let connections = &self.inner.pool_connections;
+        let idle = stats.idle as i64;
+        connections
+            .with_label_values(&["active"])
+            .set(stats.size as i64 - idle);
+        connections.with_label_values(&["idle"]).set(idle);
+        connections
+            .with_label_values(&["max"])
+            .set(max_connections as i64);
+        Ok(())
+    }
+
+    /// the metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.inner.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    /// the label of a conflicting resource, so the number of series stays bounded
+    fn conflict_label<'a>(&self, rid: &'a str) -> &'a str {
+        let mut seen = self.inner.conflict_resources.lock().unwrap();
+        if seen.contains(rid) || seen.len() < MAX_CONFLICT_RESOURCES && seen.insert(rid.to_string())
+        {
+            rid
+        } else {
+            "other"
+        }
+    }
+
+    fn observe_request(&self, method: &str, code: tonic::Code, elapsed: f64) {
+        self.inner
+            .requests
+            .with_label_values(&[method, &format!("{code:?}")])
+            .inc();
+        self.inner
+            .latency
+            .with_label_values(&[method])
+            .observe(elapsed);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// counts the requests of the wrapped gRPC server by method and status code, and observes
+/// how long they take. Streams are timed until their response starts and count as ok unless
+/// they fail before that
+#[derive(Debug, Clone)]
+pub struct MetricsLayer {
+    metrics: Metrics,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // the path is /{package}.{service}/{method}
+        let method = req.uri().path().rsplit('/').next().unwrap_or_default();
+        let method = method.to_string();
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let resp = fut.await;
+            let code = match &resp {
+                Ok(resp) => grpc_code(resp),
+                Err(_) => tonic::Code::Unavailable,
+            };
+            metrics.observe_request(&method, code, start.elapsed().as_secs_f64());
+            resp
+        })
+    }
+}
+
+/// serve `/metrics` until the server fails. The database gauges are collected on every scrape
+/// if there's a Postgres `manager`
+pub async fn serve_metrics(
+    metrics: Metrics,
+    manager: Option<(ReservationManager, u32)>,
+    config: MetricsConfig,
+) -> Result<(), anyhow::Error> {
+    let addr = config.addr().parse()?;
+    let make_svc = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        let manager = manager.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let metrics = metrics.clone();
+                let manager = manager.clone();
+                async move { Ok::<_, Infallible>(respond(&metrics, manager.as_ref(), req).await) }
+            }))
+        }
+    });
+    Server::try_bind(&addr)?.serve(make_svc).await?;
+    Ok(())
+}
+
+async fn respond(
+    metrics: &Metrics,
+    manager: Option<&(ReservationManager, u32)>,
+    req: Request<Body>,
+) -> Response<Body> {
+    if req.uri().path() != "/metrics" {
+        return status(StatusCode::NOT_FOUND);
+    }
+    if req.method() != Method::GET {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    if let Some((manager, max_connections)) = manager {
+        // a scrape without the database gauges beats no scrape
+        if let Err(e) = metrics.collect_database(manager, *max_connections).await {
+            tracing::warn!(error = %e, "collecting database metrics failed");
+        }
+    }
+
+    Response::builder()
+        .header(CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(Body::from(metrics.render()))
+        .unwrap()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// the status code of a gRPC response. Errors come back without a body, their status is in the
+/// headers
+pub(crate) fn grpc_code<B>(resp: &Response<B>) -> tonic::Code {
+    resp.headers()
+        .get("grpc-status")
+        .and_then(|code| code.to_str().ok())
+        .and_then(|code| code.parse::<i32>().ok())
+        .map_or(tonic::Code::Ok, tonic::Code::from_i32)
+}
+
+/// the resource in a conflict detail like `Key (resource_id, timespan)=(room-1, [...)) ...`
+fn conflicting_resource(detail: &str) -> Option<&str> {
+    let (_, key) = detail.split_once(")=(")?;
+    key.split_once(", ").map(|(rid, _)| rid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicts_should_be_counted_by_resource() {
+        let metrics = Metrics::new();
+        let detail = r#"Key (resource_id, buffered_timespan)=(room-1, ["2099-12-26 10:00:00+00","2099-12-26 11:00:00+00")) conflicts with existing key."#;
+        metrics.observe(&abi::Error::ConflictReservation(detail.to_string()));
+        metrics.observe(&abi::Error::ConflictReservation("no detail".to_string()));
+        metrics.observe(&abi::Error::InvalidReservation);
+        metrics.created(2);
+        metrics.cache_lookup("get", true);
+
+        let text = metrics.render();
+        assert!(text.contains(r#"reservation_conflicts_total{resource_id="room-1"} 1"#));
+        assert!(text.contains(r#"reservation_conflicts_total{resource_id="unknown"} 1"#));
+        assert!(text.contains("reservation_created_total 2"));
+        assert!(text.contains(r#"reservation_cache_lookups_total{kind="get",result="hit"} 1"#));
+    }
+
+    #[test]
+    fn conflicts_should_be_counted_for_a_bounded_number_of_resources() {
+        let metrics = Metrics::new();
+        let conflict =
+            |rid: &str| abi::Error::ConflictReservation(format!("Key (resource_id)=({rid}, ...)"));
+        for i in 0..MAX_CONFLICT_RESOURCES + 2 {
+            metrics.observe(&conflict(&format!("room-{i}")));
+        }
+        metrics.observe(&conflict("room-0"));
+
+        let text = metrics.render();
+        assert!(text.contains(r#"reservation_conflicts_total{resource_id="room-0"} 2"#));
+        assert!(text.contains(r#"reservation_conflicts_total{resource_id="other"} 2"#));
+        assert!(!text.contains(r#"resource_id="room-1000""#));
+    }
+
+    #[test]
+    fn subscribers_should_be_counted_until_dropped() {
+        let metrics = Metrics::new();
+        let first = metrics.subscriber();
+        let second = metrics.subscriber();
+        assert!(metrics
+            .render()
+            .contains("reservation_listen_subscribers 2"));
+        drop(first);
+        drop(second);
+        assert!(metrics
+            .render()
+            .contains("reservation_listen_subscribers 0"));
+    }
+}
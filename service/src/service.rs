@@ -0,0 +1,1313 @@
+use abi::{
+    reservation_service_server::ReservationService, AvailabilityRequest, AvailabilityResponse,
+    BlockRequest, BlockResponse, CancelRequest, CancelResponse, ConfirmRequest, ConfirmResponse,
+    DeleteQuotaPolicyRequest, DeleteQuotaPolicyResponse, DeleteWebhookRequest,
+    DeleteWebhookResponse, ExportCalendarRequest, ExportCalendarResponse, FilterRequest,
+    FilterResponse, GetCalendarFeedRequest, GetCalendarFeedResponse, GetHistoryRequest,
+    GetHistoryResponse, GetQuotaRequest, GetQuotaResponse, GetRequest, GetResourceRequest,
+    GetResourceResponse, GetResponse, ImportCalendarRequest, ImportCalendarResponse,
+    ListWebhookDeliveriesRequest, ListWebhookDeliveriesResponse, ListWebhooksRequest,
+    ListWebhooksResponse, ListenRequest, QueryHistoryRequest, QueryHistoryResponse, QueryRequest,
+    RescheduleRequest, RescheduleResponse, ReserveBatchRequest, ReserveBatchResponse,
+    ReserveRequest, ReserveResponse, RetryWebhookDeliveryRequest, RetryWebhookDeliveryResponse,
+    RevokeCalendarFeedRequest, RevokeCalendarFeedResponse, SetUserGroupsRequest,
+    SetUserGroupsResponse, UpdateField, UpdateRequest, UpdateResponse, UpsertQuotaPolicyRequest,
+    UpsertQuotaPolicyResponse, UpsertResourceRequest, UpsertResourceResponse, UpsertWebhookRequest,
+    UpsertWebhookResponse,
+};
+use chrono::Utc;
+use reservation::Storage;
+use tonic::{async_trait, Request, Response, Status};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    auth::{caller, require_admin, require_caller, require_user},
+    CalendarFeed, ListenStream, ReservationStream, RsvpService,
+};
+
+#[async_trait]
+impl<S: Storage> ReservationService for RsvpService<S> {
+    #[instrument(skip_all)]
+    async fn reserve(
+        &self,
+        request: Request<ReserveRequest>,
+    ) -> Result<Response<ReserveResponse>, Status> {
+        require_caller(&request)?;
+        if request.get_ref().bypass_rules {
+            require_admin(&request, "bypass_rules")?;
+        }
+        if let Some(rsvp) = &request.get_ref().reservation {
+            require_owner(&request, rsvp, "reserve")?;
+        }
+        let manager = self.manager(&request);
+        let key = idempotency_key(&request, &request.get_ref().idempotency_key);
+        let req = request.into_inner();
+        let resp = self.observe(
+            manager
+                .idempotent("reserve", &key, &req, || async {
+                    let rsvp = required(req.reservation.clone(), "reservation")?;
+                    let rsvp = if req.bypass_rules {
+                        manager.reserve_bypassing_rules(rsvp).await?
+                    } else {
+                        manager.reserve(rsvp).await?
+                    };
+                    self.metrics.created(1);
+                    Ok(ReserveResponse {
+                        reservation: Some(rsvp),
+                    })
+                })
+                .await,
+        )?;
+        Ok(Response::new(resp))
+    }
+
+    #[instrument(skip_all)]
+    async fn reserve_batch(
+        &self,
+        request: Request<ReserveBatchRequest>,
+    ) -> Result<Response<ReserveBatchResponse>, Status> {
+        require_caller(&request)?;
+        for rsvp in &request.get_ref().reservations {
+            require_owner(&request, rsvp, "reserve_batch")?;
+        }
+        let manager = self.manager(&request);
+        let key = idempotency_key(&request, &request.get_ref().idempotency_key);
+        let req = request.into_inner();
+        let resp = self.observe(
+            manager
+                .idempotent("reserve_batch", &key, &req, || async {
+                    let resp = manager.reserve_batch(req.reservations.clone()).await?;
+                    if resp.committed {
+                        self.metrics.created(resp.results.len());
+                    }
+                    Ok(resp)
+                })
+                .await,
+        )?;
+        Ok(Response::new(resp))
+    }
+
+    #[instrument(skip_all, fields(reservation_id = request.get_ref().id))]
+    async fn confirm(
+        &self,
+        request: Request<ConfirmRequest>,
+    ) -> Result<Response<ConfirmResponse>, Status> {
+        let before = self
+            .require_owner_of(&request, request.get_ref().id, "confirm")
+            .await?;
+        let manager = self.manager(&request);
+        let req = request.into_inner();
+        let rsvp = manager
+            .change_status(req.id, expected(req.expected_version))
+            .await?;
+        // confirming a confirmed reservation changes nothing
+        if before.status == abi::ReservationStatus::Pending as i32
+            && rsvp.status == abi::ReservationStatus::Confirmed as i32
+        {
+            self.metrics.confirmed();
+        }
+        Ok(Response::new(ConfirmResponse {
+            reservation: Some(rsvp),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn update(
+        &self,
+        request: Request<UpdateRequest>,
+    ) -> Result<Response<UpdateResponse>, Status> {
+        let req = request.get_ref();
+        req.validate()?;
+        let rsvp = required(req.reservation.as_ref(), "reservation")?;
+        self.require_owner_of(&request, rsvp.id, "update").await?;
+        // giving a reservation to someone else is like making one for them
+        let mask = req.update_mask.clone().unwrap_or_default();
+        if UpdateField::parse_mask(&mask)?.contains(&UpdateField::UserId) {
+            require_user(&request, &rsvp.user_id, "update")?;
+        }
+        let manager = self.manager(&request);
+        let key = idempotency_key(&request, &request.get_ref().idempotency_key);
+        let req = request.into_inner();
+        let resp = self.observe(
+            manager
+                .idempotent("update", &key, &req, || async {
+                    let rsvp = required(req.reservation.clone(), "reservation")?;
+                    let mask = req.update_mask.clone().unwrap_or_default();
+                    let rsvp = manager.update(rsvp, mask).await?;
+                    Ok(UpdateResponse {
+                        reservation: Some(rsvp),
+                    })
+                })
+                .await,
+        )?;
+        Ok(Response::new(resp))
+    }
+
+    #[instrument(skip_all)]
+    async fn reschedule(
+        &self,
+        request: Request<RescheduleRequest>,
+    ) -> Result<Response<RescheduleResponse>, Status> {
+        self.require_owner_of(&request, request.get_ref().id, "reschedule")
+            .await?;
+        let manager = self.manager(&request);
+        let key = idempotency_key(&request, &request.get_ref().idempotency_key);
+        let req = request.into_inner();
+        let resp = self.observe(
+            manager
+                .idempotent("reschedule", &key, &req, || async {
+                    let rsvp = manager.reschedule(req.clone()).await?;
+                    Ok(RescheduleResponse {
+                        reservation: Some(rsvp),
+                    })
+                })
+                .await,
+        )?;
+        Ok(Response::new(resp))
+    }
+
+    #[instrument(skip_all, fields(reservation_id = request.get_ref().id))]
+    async fn cancel(
+        &self,
+        request: Request<CancelRequest>,
+    ) -> Result<Response<CancelResponse>, Status> {
+        self.require_owner_of(&request, request.get_ref().id, "cancel")
+            .await?;
+        let manager = self.manager(&request);
+        let req = request.into_inner();
+        let rsvp = manager
+            .delete(req.id, expected(req.expected_version))
+            .await?;
+        self.metrics.cancelled(1);
+        Ok(Response::new(CancelResponse {
+            reservation: Some(rsvp),
+        }))
+    }
+
+    #[instrument(skip_all, fields(reservation_id = request.get_ref().id))]
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let rsvp = self.manager.get(request.into_inner().id).await?;
+        Ok(Response::new(GetResponse {
+            reservation: Some(rsvp),
+        }))
+    }
+
+    type queryStream = ReservationStream;
+
+    #[instrument(skip_all)]
+    async fn query(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<Self::queryStream>, Status> {
+        let query = request.into_inner().query.unwrap_or_default();
+        query.validate()?;
+        let rsvps = self.manager.query(query).await?;
+        let stream = futures::stream::iter(rsvps.into_iter().map(Ok));
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    #[instrument(skip_all)]
+    async fn filter(
+        &self,
+        request: Request<FilterRequest>,
+    ) -> Result<Response<FilterResponse>, Status> {
+        let filter = request.into_inner().filter.unwrap_or_default();
+        filter.validate()?;
+        Err(Status::unimplemented("filter is not supported yet"))
+    }
+
+    type listenStream = ListenStream;
+
+    #[instrument(skip_all)]
+    async fn listen(
+        &self,
+        request: Request<ListenRequest>,
+    ) -> Result<Response<Self::listenStream>, Status> {
+        let rx = self.manager.listen(request.into_inner()).await?;
+        // the subscriber is kept in the state of the stream, so it's counted until it's dropped
+        let subscriber = self.metrics.subscriber();
+        let stream = futures::stream::unfold((rx, subscriber), |(mut rx, subscriber)| async move {
+            let change = rx.recv().await?;
+            Some((change.map_err(Status::from), (rx, subscriber)))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    #[instrument(skip_all)]
+    async fn block(
+        &self,
+        request: Request<BlockRequest>,
+    ) -> Result<Response<BlockResponse>, Status> {
+        require_admin(&request, "block")?;
+        let manager = self.manager(&request);
+        let key = idempotency_key(&request, &request.get_ref().idempotency_key);
+        let req = request.into_inner();
+        let resp = self.observe(
+            manager
+                .idempotent("block", &key, &req, || async {
+                    let window = required(req.window.clone(), "window")?;
+                    let resp = manager.block(window).await?;
+                    self.metrics.cancelled(resp.cancelled.len());
+                    Ok(resp)
+                })
+                .await,
+        )?;
+        Ok(Response::new(resp))
+    }
+
+    #[instrument(skip_all)]
+    async fn upsert_resource(
+        &self,
+        request: Request<UpsertResourceRequest>,
+    ) -> Result<Response<UpsertResourceResponse>, Status> {
+        require_admin(&request, "upsert_resource")?;
+        let resource = required(request.into_inner().resource, "resource")?;
+        let resource = self.manager.upsert_resource(resource).await?;
+        Ok(Response::new(UpsertResourceResponse {
+            resource: Some(resource),
+        }))
+    }
+
+    #[instrument(skip_all, fields(resource_id = %request.get_ref().id))]
+    async fn get_resource(
+        &self,
+        request: Request<GetResourceRequest>,
+    ) -> Result<Response<GetResourceResponse>, Status> {
+        let resource = self.manager.get_resource(request.into_inner().id).await?;
+        Ok(Response::new(GetResourceResponse {
+            resource: Some(resource),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn availability(
+        &self,
+        request: Request<AvailabilityRequest>,
+    ) -> Result<Response<AvailabilityResponse>, Status> {
+        let slots = self.manager.availability(request.into_inner()).await?;
+        Ok(Response::new(AvailabilityResponse { slots }))
+    }
+
+    #[instrument(skip_all)]
+    async fn upsert_quota_policy(
+        &self,
+        request: Request<UpsertQuotaPolicyRequest>,
+    ) -> Result<Response<UpsertQuotaPolicyResponse>, Status> {
+        require_admin(&request, "upsert_quota_policy")?;
+        let policy = required(request.into_inner().policy, "policy")?;
+        let policy = self.manager.upsert_quota_policy(policy).await?;
+        Ok(Response::new(UpsertQuotaPolicyResponse {
+            policy: Some(policy),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_quota_policy(
+        &self,
+        request: Request<DeleteQuotaPolicyRequest>,
+    ) -> Result<Response<DeleteQuotaPolicyResponse>, Status> {
+        require_admin(&request, "delete_quota_policy")?;
+        self.manager
+            .delete_quota_policy(request.into_inner().id)
+            .await?;
+        Ok(Response::new(DeleteQuotaPolicyResponse {}))
+    }
+
+    #[instrument(skip_all, fields(user_id = %request.get_ref().user_id))]
+    async fn set_user_groups(
+        &self,
+        request: Request<SetUserGroupsRequest>,
+    ) -> Result<Response<SetUserGroupsResponse>, Status> {
+        require_admin(&request, "set_user_groups")?;
+        let req = request.into_inner();
+        self.manager
+            .set_user_groups(req.user_id, req.group_ids)
+            .await?;
+        Ok(Response::new(SetUserGroupsResponse {}))
+    }
+
+    #[instrument(skip_all, fields(user_id = %request.get_ref().user_id))]
+    async fn get_quota(
+        &self,
+        request: Request<GetQuotaRequest>,
+    ) -> Result<Response<GetQuotaResponse>, Status> {
+        let usages = self.manager.get_quota(request.into_inner().user_id).await?;
+        Ok(Response::new(GetQuotaResponse { usages }))
+    }
+
+    #[instrument(skip_all, fields(reservation_id = request.get_ref().id))]
+    async fn get_history(
+        &self,
+        request: Request<GetHistoryRequest>,
+    ) -> Result<Response<GetHistoryResponse>, Status> {
+        let entries = self.manager.get_history(request.into_inner().id).await?;
+        Ok(Response::new(GetHistoryResponse { entries }))
+    }
+
+    #[instrument(skip_all)]
+    async fn query_history(
+        &self,
+        request: Request<QueryHistoryRequest>,
+    ) -> Result<Response<QueryHistoryResponse>, Status> {
+        let query = request.into_inner().query.unwrap_or_default();
+        let entries = self.manager.query_history(query).await?;
+        Ok(Response::new(QueryHistoryResponse { entries }))
+    }
+
+    #[instrument(skip_all)]
+    async fn upsert_webhook(
+        &self,
+        request: Request<UpsertWebhookRequest>,
+    ) -> Result<Response<UpsertWebhookResponse>, Status> {
+        require_admin(&request, "upsert_webhook")?;
+        let webhook = required(request.into_inner().webhook, "webhook")?;
+        webhook.validate(self.webhook.allow_private_targets)?;
+        let webhook = self.manager.upsert_webhook(webhook).await?;
+        Ok(Response::new(UpsertWebhookResponse {
+            webhook: Some(webhook),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn delete_webhook(
+        &self,
+        request: Request<DeleteWebhookRequest>,
+    ) -> Result<Response<DeleteWebhookResponse>, Status> {
+        require_admin(&request, "delete_webhook")?;
+        self.manager.delete_webhook(request.into_inner().id).await?;
+        Ok(Response::new(DeleteWebhookResponse {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_webhooks(
+        &self,
+        request: Request<ListWebhooksRequest>,
+    ) -> Result<Response<ListWebhooksResponse>, Status> {
+        require_admin(&request, "list_webhooks")?;
+        let webhooks = self.manager.list_webhooks().await?;
+        Ok(Response::new(ListWebhooksResponse { webhooks }))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_webhook_deliveries(
+        &self,
+        request: Request<ListWebhookDeliveriesRequest>,
+    ) -> Result<Response<ListWebhookDeliveriesResponse>, Status> {
+        require_admin(&request, "list_webhook_deliveries")?;
+        let deliveries = self
+            .manager
+            .list_webhook_deliveries(request.into_inner())
+            .await?;
+        Ok(Response::new(ListWebhookDeliveriesResponse { deliveries }))
+    }
+
+    #[instrument(skip_all)]
+    async fn retry_webhook_delivery(
+        &self,
+        request: Request<RetryWebhookDeliveryRequest>,
+    ) -> Result<Response<RetryWebhookDeliveryResponse>, Status> {
+        require_admin(&request, "retry_webhook_delivery")?;
+        let delivery = self
+            .manager
+            .retry_webhook_delivery(request.into_inner().id)
+            .await?;
+        Ok(Response::new(RetryWebhookDeliveryResponse {
+            delivery: Some(delivery),
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn export_calendar(
+        &self,
+        request: Request<ExportCalendarRequest>,
+    ) -> Result<Response<ExportCalendarResponse>, Status> {
+        let query = request.get_ref().query.clone().unwrap_or_default();
+        query.validate()?;
+        // like the feeds, a user's calendar is theirs and the admins', any other only the admins'
+        if query.user_id.is_empty() {
+            require_admin(&request, "export_calendar")?;
+        } else {
+            require_user(&request, &query.user_id, "export_calendar")?;
+        }
+        let name = match (query.user_id.is_empty(), query.resource_id.is_empty()) {
+            (false, _) => query.user_id.clone(),
+            (true, false) => query.resource_id.clone(),
+            (true, true) => "reservations".to_string(),
+        };
+        let rsvps = self.manager.query(query).await?;
+        let ics = abi::to_ics(&name, &rsvps, Utc::now()).into_bytes();
+        Ok(Response::new(ExportCalendarResponse { ics }))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_calendar_feed(
+        &self,
+        request: Request<GetCalendarFeedRequest>,
+    ) -> Result<Response<GetCalendarFeedResponse>, Status> {
+        if !self.calendar.enabled {
+            return Err(abi::Error::CalendarFeedsDisabled.into());
+        }
+        let req = request.get_ref();
+        let feed = CalendarFeed::new(req.user_id.clone(), req.resource_id.clone())?;
+        require_feed_access(&request, &feed, "get_calendar_feed")?;
+        // every feed handed out gets a nonce of its own, so it can be revoked on its own
+        let nonce = Uuid::new_v4().simple().to_string();
+        self.manager
+            .add_calendar_feed_token(feed.token(&nonce))
+            .await?;
+        let path = feed.path(&self.calendar.secret, &nonce);
+        Ok(Response::new(GetCalendarFeedResponse { path }))
+    }
+
+    #[instrument(skip_all)]
+    async fn revoke_calendar_feed(
+        &self,
+        request: Request<RevokeCalendarFeedRequest>,
+    ) -> Result<Response<RevokeCalendarFeedResponse>, Status> {
+        if !self.calendar.enabled {
+            return Err(abi::Error::CalendarFeedsDisabled.into());
+        }
+        let invalid = || abi::Error::InvalidCalendarFeed("not a calendar feed path".to_string());
+        let (feed, token) = CalendarFeed::from_path(&request.get_ref().path).ok_or_else(invalid)?;
+        require_feed_access(&request, &feed, "revoke_calendar_feed")?;
+        let nonce = feed
+            .verify(&self.calendar.secret, &token)
+            .ok_or_else(invalid)?;
+        self.manager
+            .revoke_calendar_feed_token(feed.token(&nonce))
+            .await?;
+        Ok(Response::new(RevokeCalendarFeedResponse {}))
+    }
+
+    #[instrument(skip_all)]
+    async fn import_calendar(
+        &self,
+        request: Request<ImportCalendarRequest>,
+    ) -> Result<Response<ImportCalendarResponse>, Status> {
+        // blocked windows, imported without a user, are left to admins
+        require_user(&request, &request.get_ref().user_id, "import_calendar")?;
+        let manager = self.manager(&request);
+        let resp = self.observe(manager.import_calendar(request.into_inner()).await)?;
+        let created = resp
+            .results
+            .iter()
+            .filter_map(|result| result.reservation.as_ref())
+            .filter(|rsvp| rsvp.id != 0 && !rsvp.is_blocked())
+            .count();
+        self.metrics.created(created);
+        Ok(Response::new(resp))
+    }
+}
+
+impl<S: Storage> RsvpService<S> {
+    /// count the conflict if the result is one
+    fn observe<T>(&self, result: Result<T, abi::Error>) -> Result<T, abi::Error> {
+        if let Err(e) = &result {
+            self.metrics.observe(e);
+        }
+        result
+    }
+
+    /// the stored reservation, if the caller may change it. Anonymous callers can't change any
+    async fn require_owner_of<T>(
+        &self,
+        request: &Request<T>,
+        id: i64,
+        what: &str,
+    ) -> Result<abi::Reservation, abi::Error> {
+        require_caller(request)?;
+        let rsvp = self.manager.get(id).await?;
+        require_owner(request, &rsvp, what)?;
+        Ok(rsvp)
+    }
+
+    /// the manager acting for the authenticated caller, for the audit history. Admins can act
+    /// for someone else with the x-actor header
+    fn manager<T>(&self, request: &Request<T>) -> S {
+        let Some(caller) = caller(request) else {
+            return self.manager.clone();
+        };
+        let actor = request
+            .metadata()
+            .get("x-actor")
+            .and_then(|actor| actor.to_str().ok())
+            .filter(|_| caller.admin);
+        self.manager.acting_as(actor.unwrap_or(&caller.user_id))
+    }
+}
+
+/// a reservation is its user's and the admins', a blocked window only the admins'
+fn require_owner<T>(
+    request: &Request<T>,
+    rsvp: &abi::Reservation,
+    what: &str,
+) -> Result<(), abi::Error> {
+    if rsvp.is_blocked() {
+        require_admin(request, what)
+    } else {
+        require_user(request, &rsvp.user_id, what)
+    }
+}
+
+/// the feed of a user is theirs and the admins', the feed of a resource only the admins'
+fn require_feed_access<T>(
+    request: &Request<T>,
+    feed: &CalendarFeed,
+    what: &str,
+) -> Result<(), abi::Error> {
+    match feed {
+        CalendarFeed::User(user_id) => require_user(request, user_id, what),
+        CalendarFeed::Resource(_) => require_admin(request, what),
+    }
+}
+
+/// idempotency keys are kept per caller, so one caller's key can't replay another caller's
+/// response. An empty key stays empty and disables the check
+fn idempotency_key<T>(request: &Request<T>, key: &str) -> String {
+    match caller(request) {
+        _ if key.is_empty() => String::new(),
+        // the length keeps a user id with a "/" from passing for another user's prefix
+        Some(caller) => format!("{}:{}/{key}", caller.user_id.len(), caller.user_id),
+        None => format!("-/{key}"),
+    }
+}
+
+/// expected versions of 0 are not checked
+fn expected(version: i64) -> Option<i64> {
+    (version != 0).then_some(version)
+}
+
+fn required<T>(field: Option<T>, name: &str) -> Result<T, abi::Error> {
+    field.ok_or_else(|| abi::Error::MissingField(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use reservation::{InMemoryManager, ReservationManager, Rsvp};
+
+    use super::*;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn rpc_should_act_for_the_authenticated_caller() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        act_for_the_authenticated_caller(RsvpService::new(manager)).await;
+    }
+
+    #[tokio::test]
+    async fn rpc_should_act_for_the_caller_on_memory_storage() {
+        act_for_the_authenticated_caller(RsvpService::new(InMemoryManager::new())).await;
+    }
+
+    async fn act_for_the_authenticated_caller<S: Storage>(service: RsvpService<S>) {
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let reserve = |user_id, admin| {
+            let mut request = as_caller(
+                ReserveRequest {
+                    reservation: Some(abi::Reservation::new_pending(
+                        user_id,
+                        if admin { "room-2" } else { "room-1" },
+                        start,
+                        end,
+                        "note",
+                    )),
+                    ..Default::default()
+                },
+                user_id,
+                admin,
+            );
+            // only admins can act for someone else
+            request
+                .metadata_mut()
+                .insert("x-actor", "mallory".parse().unwrap());
+            request
+        };
+        let rsvp = service
+            .reserve(reserve("alice", false))
+            .await
+            .unwrap()
+            .into_inner()
+            .reservation
+            .unwrap();
+
+        let stale = CancelRequest {
+            id: rsvp.id,
+            expected_version: rsvp.version + 1,
+        };
+        let status = service
+            .cancel(as_caller(stale, "alice", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        let service = &service;
+        let actor = |id| async move {
+            let history = Request::new(GetHistoryRequest { id });
+            let entries = service
+                .get_history(history)
+                .await
+                .unwrap()
+                .into_inner()
+                .entries;
+            assert_eq!(entries.len(), 1);
+            entries[0].actor.clone()
+        };
+        assert_eq!(actor(rsvp.id).await, "alice");
+        let rsvp = service
+            .reserve(reserve("admin", true))
+            .await
+            .unwrap()
+            .into_inner()
+            .reservation
+            .unwrap();
+        assert_eq!(actor(rsvp.id).await, "mallory");
+    }
+
+    #[tokio::test]
+    async fn admin_rpcs_should_need_an_admin() {
+        let service = RsvpService::new(InMemoryManager::new());
+        let resource = || UpsertResourceRequest {
+            resource: Some(abi::Resource {
+                id: "room-1".to_string(),
+                ..Default::default()
+            }),
+        };
+        let status = service
+            .upsert_resource(Request::new(resource()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let status = service
+            .upsert_resource(as_caller(resource(), "alice", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        service
+            .upsert_resource(as_caller(resource(), "admin", true))
+            .await
+            .unwrap();
+
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let bypass = ReserveRequest {
+            reservation: Some(abi::Reservation::new_pending(
+                "alice", "room-1", start, end, "note",
+            )),
+            bypass_rules: true,
+            ..Default::default()
+        };
+        let status = service
+            .reserve(as_caller(bypass, "alice", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let status = service
+            .list_webhooks(Request::new(ListWebhooksRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_should_be_kept_per_caller() {
+        let service = RsvpService::new(InMemoryManager::new());
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let reserve = ReserveRequest {
+            reservation: Some(abi::Reservation::new_pending(
+                "alice", "room-1", start, end, "note",
+            )),
+            idempotency_key: "retry-1".to_string(),
+            ..Default::default()
+        };
+        let resp = service
+            .reserve(as_caller(reserve.clone(), "alice", false))
+            .await
+            .unwrap()
+            .into_inner();
+        let replayed = service
+            .reserve(as_caller(reserve.clone(), "alice", false))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(replayed, resp);
+
+        // the same key from someone else is a request of its own, it conflicts
+        let status = service.reserve(as_admin(reserve)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn reservations_should_only_be_changed_by_their_user_or_an_admin() {
+        let service = RsvpService::new(InMemoryManager::new());
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let reserve = |rsvp: abi::Reservation| ReserveRequest {
+            reservation: Some(rsvp),
+            ..Default::default()
+        };
+        let pending = abi::Reservation::new_pending("alice", "room-1", start, end, "note");
+        let status = service
+            .reserve(Request::new(reserve(pending.clone())))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let status = service
+            .reserve(as_caller(reserve(pending.clone()), "bob", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let rsvp = service
+            .reserve(as_caller(reserve(pending), "alice", false))
+            .await
+            .unwrap()
+            .into_inner()
+            .reservation
+            .unwrap();
+
+        let id = rsvp.id;
+        let denied = |status: Status| assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        denied(
+            service
+                .confirm(as_caller(
+                    ConfirmRequest {
+                        id,
+                        ..Default::default()
+                    },
+                    "bob",
+                    false,
+                ))
+                .await
+                .unwrap_err(),
+        );
+        let note = abi::Reservation {
+            note: "mine now".to_string(),
+            ..rsvp.clone()
+        };
+        let update = |rsvp, path: &str| UpdateRequest {
+            reservation: Some(rsvp),
+            update_mask: Some(prost_types::FieldMask {
+                paths: vec![path.to_string()],
+            }),
+            ..Default::default()
+        };
+        denied(
+            service
+                .update(as_caller(update(note, "note"), "bob", false))
+                .await
+                .unwrap_err(),
+        );
+        let reschedule = RescheduleRequest {
+            id,
+            start: rsvp.start.clone(),
+            end: rsvp.end.clone(),
+            resource_id: "room-2".to_string(),
+            ..Default::default()
+        };
+        denied(
+            service
+                .reschedule(as_caller(reschedule.clone(), "bob", false))
+                .await
+                .unwrap_err(),
+        );
+        let cancel = CancelRequest {
+            id,
+            ..Default::default()
+        };
+        denied(
+            service
+                .cancel(as_caller(cancel.clone(), "bob", false))
+                .await
+                .unwrap_err(),
+        );
+        let status = service
+            .cancel(Request::new(cancel.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        // giving a reservation away is like making one for someone else
+        let transfer = abi::Reservation {
+            user_id: "bob".to_string(),
+            ..rsvp.clone()
+        };
+        denied(
+            service
+                .update(as_caller(
+                    update(transfer.clone(), "user_id"),
+                    "alice",
+                    false,
+                ))
+                .await
+                .unwrap_err(),
+        );
+        service
+            .update(as_admin(update(transfer, "user_id")))
+            .await
+            .unwrap();
+        service
+            .cancel(as_caller(cancel, "bob", false))
+            .await
+            .unwrap();
+
+        // blocked windows are the admins' only
+        let blocked = abi::Reservation {
+            user_id: String::new(),
+            status: abi::ReservationStatus::Blocked as i32,
+            ..rsvp
+        };
+        denied(
+            service
+                .reserve(as_caller(reserve(blocked.clone()), "alice", false))
+                .await
+                .unwrap_err(),
+        );
+        let block = service
+            .block(as_admin(BlockRequest {
+                window: Some(abi::BlockWindow {
+                    resource_ids: vec!["room-2".to_string()],
+                    start: blocked.start,
+                    end: blocked.end,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .blocks
+            .remove(0);
+        let reschedule = RescheduleRequest {
+            id: block.id,
+            resource_id: "room-1".to_string(),
+            ..reschedule
+        };
+        denied(
+            service
+                .reschedule(as_caller(reschedule.clone(), "alice", false))
+                .await
+                .unwrap_err(),
+        );
+        service.reschedule(as_admin(reschedule)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rpc_should_record_domain_metrics() {
+        let metrics = crate::Metrics::new();
+        let service = RsvpService::new(InMemoryManager::new()).with_metrics(metrics.clone());
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let reserve = || {
+            let rsvp = abi::Reservation::new_pending("user1", "room-1", start, end, "note");
+            let req = ReserveRequest {
+                reservation: Some(rsvp),
+                ..Default::default()
+            };
+            as_caller(req, "user1", false)
+        };
+        let rsvp = service
+            .reserve(reserve())
+            .await
+            .unwrap()
+            .into_inner()
+            .reservation
+            .unwrap();
+        let status = service.reserve(reserve()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        let confirm = ConfirmRequest {
+            id: rsvp.id,
+            ..Default::default()
+        };
+        service
+            .confirm(as_caller(confirm.clone(), "user1", false))
+            .await
+            .unwrap();
+        // already confirmed, so it isn't counted again
+        service
+            .confirm(as_caller(confirm, "user1", false))
+            .await
+            .unwrap();
+        let listen = service
+            .listen(Request::new(ListenRequest::default()))
+            .await
+            .unwrap();
+        assert!(metrics
+            .render()
+            .contains("reservation_listen_subscribers 1"));
+        drop(listen);
+
+        let text = metrics.render();
+        assert!(text.contains("reservation_created_total 1"));
+        assert!(text.contains("reservation_confirmed_total 1"));
+        assert!(text.contains(r#"reservation_conflicts_total{resource_id="room-1"} 1"#));
+        assert!(text.contains("reservation_listen_subscribers 0"));
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn calendar_feeds_should_only_be_handed_out_when_enabled() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let req = || {
+            let feed = GetCalendarFeedRequest {
+                user_id: "user1".to_string(),
+                ..Default::default()
+            };
+            as_caller(feed, "user1", false)
+        };
+        let service = RsvpService::new(manager.clone());
+        let status = service.get_calendar_feed(req()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        let service = RsvpService::new(manager).with_calendar(abi::CalendarConfig {
+            enabled: true,
+            secret: "s3cret".to_string(),
+            ..Default::default()
+        });
+        let path = service
+            .get_calendar_feed(req())
+            .await
+            .unwrap()
+            .into_inner()
+            .path;
+        assert!(path.starts_with("/calendars/users/user1.ics?token="));
+        let status = service
+            .get_calendar_feed(Request::new(GetCalendarFeedRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn calendar_feeds_should_go_to_their_owners_and_be_revocable() {
+        let service = RsvpService::new(InMemoryManager::new()).with_calendar(abi::CalendarConfig {
+            enabled: true,
+            secret: "s3cret".to_string(),
+            ..Default::default()
+        });
+        let feed = |user_id: &str, resource_id: &str| GetCalendarFeedRequest {
+            user_id: user_id.to_string(),
+            resource_id: resource_id.to_string(),
+        };
+        let path = |resp: Response<GetCalendarFeedResponse>| resp.into_inner().path;
+        for (req, caller) in [
+            (feed("alice", ""), None),
+            (feed("alice", ""), Some("bob")),
+            (feed("", "room-1"), Some("alice")),
+        ] {
+            let req = match caller {
+                Some(user_id) => as_caller(req, user_id, false),
+                None => Request::new(req),
+            };
+            let status = service.get_calendar_feed(req).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        }
+        service
+            .get_calendar_feed(as_admin(feed("", "room-1")))
+            .await
+            .unwrap();
+
+        // every feed has a token of its own, revoking one leaves the other
+        let first = path(
+            service
+                .get_calendar_feed(as_caller(feed("alice", ""), "alice", false))
+                .await
+                .unwrap(),
+        );
+        let second = path(
+            service
+                .get_calendar_feed(as_admin(feed("alice", "")))
+                .await
+                .unwrap(),
+        );
+        assert_ne!(first, second);
+        let revoke = |path: &str| RevokeCalendarFeedRequest {
+            path: path.to_string(),
+        };
+        let status = service
+            .revoke_calendar_feed(as_caller(revoke(&first), "bob", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        service
+            .revoke_calendar_feed(as_caller(revoke(&first), "alice", false))
+            .await
+            .unwrap();
+        let token = |path: &str| {
+            let (feed, token) = CalendarFeed::from_path(path).unwrap();
+            feed.token(&feed.verify("s3cret", &token).unwrap())
+        };
+        assert!(!service
+            .manager
+            .has_calendar_feed_token(token(&first))
+            .await
+            .unwrap());
+        assert!(service
+            .manager
+            .has_calendar_feed_token(token(&second))
+            .await
+            .unwrap());
+        let status = service
+            .revoke_calendar_feed(as_admin(revoke(&first)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let forged = second.replace("token=", "token=0");
+        let status = service
+            .revoke_calendar_feed(as_admin(revoke(&forged)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn calendar_exports_should_go_to_their_owners() {
+        let service = RsvpService::new(InMemoryManager::new());
+        let export = |user_id: &str, resource_id: &str| ExportCalendarRequest {
+            query: Some(abi::ReservationQuery {
+                user_id: user_id.to_string(),
+                resource_id: resource_id.to_string(),
+                ..Default::default()
+            }),
+        };
+        for req in [
+            Request::new(export("alice", "")),
+            as_caller(export("alice", ""), "bob", false),
+            as_caller(export("", "room-1"), "alice", false),
+            as_caller(export("", ""), "alice", false),
+        ] {
+            let status = service.export_calendar(req).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        }
+        for req in [
+            as_caller(export("alice", ""), "alice", false),
+            as_caller(export("alice", "room-1"), "alice", false),
+            as_admin(export("", "room-1")),
+            as_admin(export("", "")),
+        ] {
+            service.export_calendar(req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn calendar_imports_should_be_left_to_their_user_or_an_admin() {
+        let service = RsvpService::new(InMemoryManager::new());
+        let import = |user_id: &str| ImportCalendarRequest {
+            ics: b"BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:a\r\n\
+                DTSTART:20990101T090000Z\r\nDTEND:20990101T100000Z\r\n\
+                END:VEVENT\r\nEND:VCALENDAR\r\n"
+                .to_vec(),
+            resource_id: "room-1".to_string(),
+            user_id: user_id.to_string(),
+            mode: abi::ImportMode::DryRun as i32,
+            ..Default::default()
+        };
+        for req in [
+            Request::new(import("alice")),
+            as_caller(import("alice"), "bob", false),
+            as_caller(import(""), "alice", false),
+        ] {
+            let status = service.import_calendar(req).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        }
+        for req in [
+            as_caller(import("alice"), "alice", false),
+            as_admin(import("alice")),
+            as_admin(import("")),
+        ] {
+            service.import_calendar(req).await.unwrap();
+        }
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn hostile_timestamps_and_durations_should_be_rejected_without_panic() {
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let service = RsvpService::new(manager);
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        let rsvp = abi::Reservation::new_pending("user1", "room-1", start, end, "note");
+        let saved = service
+            .reserve(as_caller(
+                ReserveRequest {
+                    reservation: Some(rsvp),
+                    ..Default::default()
+                },
+                "user1",
+                false,
+            ))
+            .await
+            .unwrap()
+            .into_inner()
+            .reservation
+            .unwrap();
+
+        let ts = |seconds, nanos| Some(prost_types::Timestamp { seconds, nanos });
+        // the edges of the protobuf range (0001-01-01 to 9999-12-31) and beyond
+        let timestamps = [
+            None,
+            ts(i64::MIN, 0),
+            ts(-62_135_596_801, 0),
+            ts(-62_135_596_800, 0),
+            ts(0, -1),
+            ts(0, 1_000_000_000),
+            ts(0, i32::MIN),
+            ts(4_102_444_800, 0),
+            ts(253_402_300_799, 999_999_999),
+            ts(253_402_300_800, 0),
+            ts(i64::MAX, i32::MAX),
+        ];
+        let d = |seconds, nanos| Some(prost_types::Duration { seconds, nanos });
+        let durations = [
+            None,
+            d(0, 1),
+            d(1, -1),
+            d(3600, 0),
+            d(10_000_000_000, 0),
+            d(315_576_000_001, 0),
+            d(i64::MIN, i32::MIN),
+            d(i64::MAX, i32::MAX),
+        ];
+
+        for (i, duration) in durations.iter().enumerate() {
+            let resource = abi::Resource {
+                id: "room-1".to_string(),
+                pre_buffer: duration.clone(),
+                post_buffer: duration.clone(),
+                lead_time: duration.clone(),
+                booking_horizon: duration.clone(),
+                min_duration: duration.clone(),
+                max_duration: duration.clone(),
+                kind: format!("kind-{i}"),
+            };
+            let req = as_admin(UpsertResourceRequest {
+                resource: Some(resource),
+            });
+            let _ = service.upsert_resource(req).await;
+        }
+
+        for start in &timestamps {
+            for end in &timestamps {
+                let (start, end) = (start.clone(), end.clone());
+                let rsvp = abi::Reservation {
+                    user_id: "user2".to_string(),
+                    resource_id: "room-2".to_string(),
+                    start: start.clone(),
+                    end: end.clone(),
+                    ..Default::default()
+                };
+                for bypass_rules in [false, true] {
+                    let req = as_admin(ReserveRequest {
+                        reservation: Some(rsvp.clone()),
+                        bypass_rules,
+                        ..Default::default()
+                    });
+                    let _ = service.reserve(req).await;
+                }
+                let req = as_admin(ReserveBatchRequest {
+                    reservations: vec![rsvp.clone()],
+                    ..Default::default()
+                });
+                let _ = service.reserve_batch(req).await;
+                let req = as_admin(UpdateRequest {
+                    reservation: Some(abi::Reservation {
+                        id: saved.id,
+                        ..rsvp.clone()
+                    }),
+                    update_mask: Some(prost_types::FieldMask {
+                        paths: vec!["start".to_string(), "end".to_string()],
+                    }),
+                    ..Default::default()
+                });
+                let _ = service.update(req).await;
+                let req = as_admin(RescheduleRequest {
+                    id: saved.id,
+                    start: start.clone(),
+                    end: end.clone(),
+                    ..Default::default()
+                });
+                let _ = service.reschedule(req).await;
+
+                let query = abi::ReservationQuery {
+                    start: start.clone(),
+                    end: end.clone(),
+                    ..Default::default()
+                };
+                let req = Request::new(QueryRequest {
+                    query: Some(query.clone()),
+                });
+                let _ = service.query(req).await;
+                let req = as_admin(ExportCalendarRequest { query: Some(query) });
+                let _ = service.export_calendar(req).await;
+                let req = Request::new(QueryHistoryRequest {
+                    query: Some(abi::HistoryQuery {
+                        start: start.clone(),
+                        end: end.clone(),
+                        ..Default::default()
+                    }),
+                });
+                let _ = service.query_history(req).await;
+                let req = Request::new(AvailabilityRequest {
+                    resource_id: "room-1".to_string(),
+                    start: start.clone(),
+                    end: end.clone(),
+                });
+                let _ = service.availability(req).await;
+
+                let recurrence = abi::Recurrence {
+                    frequency: abi::RecurrenceFrequency::Weekly as i32,
+                    interval: u32::MAX,
+                    count: 3,
+                    until: end.clone(),
+                };
+                let req = as_admin(BlockRequest {
+                    window: Some(abi::BlockWindow {
+                        resource_ids: vec!["room-3".to_string()],
+                        start: start.clone(),
+                        end: end.clone(),
+                        recurrence: Some(recurrence),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                });
+                let _ = service.block(req).await;
+                let req = as_admin(ImportCalendarRequest {
+                    ics: b"BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:a\r\n\
+                        DTSTART:99991231T230000Z\r\nDURATION:P99999999999W\r\n\
+                        RRULE:FREQ=YEARLY;INTERVAL=9223372036854775807\r\n\
+                        END:VEVENT\r\nEND:VCALENDAR\r\n"
+                        .to_vec(),
+                    resource_id: "room-4".to_string(),
+                    mode: abi::ImportMode::DryRun as i32,
+                    until: end,
+                    ..Default::default()
+                });
+                let _ = service.import_calendar(req).await;
+            }
+        }
+
+        // nothing out of range made it into the database
+        let req = Request::new(QueryRequest { query: None });
+        let rsvps: Vec<abi::Reservation> = service
+            .query(req)
+            .await
+            .unwrap()
+            .into_inner()
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(rsvps.iter().all(|rsvp| rsvp.get_timespan().is_ok()));
+    }
+
+    fn as_caller<T>(message: T, user_id: &str, admin: bool) -> Request<T> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(crate::Caller {
+            user_id: user_id.to_string(),
+            admin,
+        });
+        request
+    }
+
+    fn as_admin<T>(message: T) -> Request<T> {
+        as_caller(message, "admin", true)
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use abi::TracingConfig;
+use hyper::{header::HeaderValue, Request, Response};
+use tower::{Layer, Service};
+use tracing::{info_span, Instrument};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer as _,
+};
+use uuid::Uuid;
+
+use crate::metrics::grpc_code;
+
+/// the metadata key of the request id, it's taken from the request or made up and echoed back
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// log to stderr and export spans as configured. Log records of the `log` crate, the ones of
+/// sqlx included, end up in the same place. RUST_LOG overrides the filter of the config
+pub fn init_tracing(config: &TracingConfig) -> Result<(), anyhow::Error> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.filter)?,
+    };
+    // stdout is left to the stdout event sink and span exporter
+    let logs = if config.json {
+        fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(io::stderr)
+            .boxed()
+    } else {
+        fmt::layer().with_writer(io::stderr).boxed()
+    };
+    let registry = tracing_subscriber::registry().with(filter).with(logs);
+
+    #[cfg(feature = "otlp")]
+    {
+        let spans = match &config.exporter {
+            Some(exporter) => {
+                let tracer = otel::tracer(exporter)?;
+                Some(tracing_opentelemetry::layer().with_tracer(tracer))
+            }
+            None => None,
+        };
+        registry.with(spans).try_init()?;
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        if config.exporter.is_some() {
+            anyhow::bail!("exporting spans needs the otlp feature");
+        }
+        registry.try_init()?;
+    }
+    Ok(())
+}
+
+/// export the spans that are still buffered, before the process exits
+pub fn shutdown_tracing() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// runs every request of the wrapped gRPC server in an `rpc` span with its method and request
+/// id, and logs the requests that fail
+#[derive(Debug, Clone, Default)]
+pub struct RequestIdLayer;
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let id = match req.headers().get(REQUEST_ID) {
+            Some(id) if !id.is_empty() && id.len() <= 128 => id.clone(),
+            _ => HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap(),
+        };
+        // handlers see the made up ids as well
+        req.headers_mut().insert(REQUEST_ID, id.clone());
+
+        let method = req.uri().path().rsplit('/').next().unwrap_or_default();
+        let span = info_span!("rpc", method, request_id = id.to_str().unwrap_or_default());
+        let fut = span.in_scope(|| self.inner.call(req));
+        Box::pin(
+            async move {
+                let mut resp = fut.await?;
+                let code = grpc_code(&resp);
+                if code != tonic::Code::Ok {
+                    let detail = resp
+                        .headers()
+                        .get("grpc-message")
+                        .and_then(|message| message.to_str().ok())
+                        .unwrap_or_default();
+                    tracing::warn!(?code, detail, "rpc failed");
+                }
+                resp.headers_mut().insert(REQUEST_ID, id);
+                Ok(resp)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otel {
+    use std::{fmt::Debug, future::Future, io::Write, pin::Pin, time::SystemTime};
+
+    use abi::TraceExporter;
+    use chrono::{DateTime, Utc};
+    use opentelemetry::{
+        sdk::{
+            export::trace::{ExportResult, SpanData, SpanExporter},
+            trace::{self, Tracer, TracerProvider},
+            Resource,
+        },
+        trace::{Status, TraceError, TracerProvider as _},
+        KeyValue,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+    use serde_json::{json, Map, Value};
+
+    pub(super) fn tracer(exporter: &TraceExporter) -> Result<Tracer, TraceError> {
+        let config = trace::config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            "reservation",
+        )]));
+        match exporter {
+            TraceExporter::Otlp { endpoint } => opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint.clone()),
+                )
+                .with_trace_config(config)
+                .install_batch(opentelemetry::runtime::Tokio),
+            TraceExporter::Stdout => {
+                let provider = TracerProvider::builder()
+                    .with_simple_exporter(JsonExporter::new(std::io::stdout()))
+                    .with_config(config)
+                    .build();
+                let tracer = provider.tracer("reservation-service");
+                opentelemetry::global::set_tracer_provider(provider);
+                Ok(tracer)
+            }
+        }
+    }
+
+    /// writes every span as a line of JSON
+    #[derive(Debug)]
+    pub(super) struct JsonExporter<W> {
+        out: W,
+    }
+
+    impl<W> JsonExporter<W> {
+        pub(super) fn new(out: W) -> Self {
+            Self { out }
+        }
+    }
+
+    impl<W: Write + Send + Debug> SpanExporter for JsonExporter<W> {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+            let result = batch.iter().try_for_each(|span| {
+                serde_json::to_writer(&mut self.out, &to_json(span))?;
+                self.out.write_all(b"\n")
+            });
+            let result = result
+                .and_then(|_| self.out.flush())
+                .map_err(|e| TraceError::Other(Box::new(e)));
+            Box::pin(std::future::ready(result))
+        }
+    }
+
+    fn to_json(span: &SpanData) -> Value {
+        let attributes: Map<String, Value> = span
+            .attributes
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.as_str().into()))
+            .collect();
+        let mut json = json!({
+            "trace_id": span.span_context.trace_id().to_string(),
+            "span_id": span.span_context.span_id().to_string(),
+            "parent_span_id": span.parent_span_id.to_string(),
+            "name": span.name,
+            "start": timestamp(span.start_time),
+            "end": timestamp(span.end_time),
+            "attributes": attributes,
+        });
+        if let Status::Error { description } = &span.status {
+            json["error"] = description.as_ref().into();
+        }
+        json
+    }
+
+    fn timestamp(time: SystemTime) -> String {
+        DateTime::<Utc>::from(time).to_rfc3339()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{
+            io,
+            sync::{Arc, Mutex},
+        };
+
+        use opentelemetry::trace::{Span, Tracer};
+
+        use super::*;
+
+        #[derive(Debug, Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn spans_should_be_exported_as_json_lines() {
+            let buffer = Buffer::default();
+            let provider = TracerProvider::builder()
+                .with_simple_exporter(JsonExporter::new(buffer.clone()))
+                .build();
+            let tracer = provider.tracer("test");
+            let mut span = tracer.start("reserve");
+            span.set_attribute(KeyValue::new("resource_id", "room-1"));
+            span.end();
+            provider.force_flush();
+
+            let out = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+            let lines: Vec<Value> = out
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            assert_eq!(lines.len(), 1);
+            assert_eq!(lines[0]["name"], "reserve");
+            assert_eq!(lines[0]["attributes"]["resource_id"], "room-1");
+            assert_eq!(lines[0]["trace_id"].as_str().unwrap().len(), 32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{ready, Ready};
+
+    use super::*;
+
+    // answers with the request id it got in a header of its own
+    struct Echo;
+
+    impl Service<Request<()>> for Echo {
+        type Response = Response<()>;
+        type Error = ();
+        type Future = Ready<Result<Response<()>, ()>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<()>) -> Self::Future {
+            let mut resp = Response::new(());
+            if let Some(id) = req.headers().get(REQUEST_ID) {
+                resp.headers_mut().insert("x-seen", id.clone());
+            }
+            ready(Ok(resp))
+        }
+    }
+
+    #[tokio::test]
+    async fn request_ids_should_be_passed_on_and_echoed() {
+        let mut svc = RequestIdLayer.layer(Echo);
+        let req = Request::builder()
+            .uri("/reservation.ReservationService/reserve")
+            .header(REQUEST_ID, "booking-42")
+            .body(())
+            .unwrap();
+        let resp = svc.call(req).await.unwrap();
+        assert_eq!(resp.headers()[REQUEST_ID], "booking-42");
+        assert_eq!(resp.headers()["x-seen"], "booking-42");
+    }
+
+    #[tokio::test]
+    async fn missing_request_ids_should_be_made_up() {
+        let mut svc = RequestIdLayer.layer(Echo);
+        let req = Request::builder()
+            .uri("/reservation.ReservationService/get")
+            .body(())
+            .unwrap();
+        let resp = svc.call(req).await.unwrap();
+        let id = resp.headers()[REQUEST_ID].to_str().unwrap();
+        assert!(Uuid::parse_str(id).is_ok());
+        assert_eq!(resp.headers()["x-seen"], id);
+    }
+}
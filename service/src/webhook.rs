@@ -0,0 +1,322 @@
+use std::time::Duration as StdDuration;
+
+use abi::{is_private_address, WebhookConfig};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use reservation::{ReservationManager, WebhookJob};
+use sha2::Sha256;
+
+/// POSTs the queued reservation changes to their webhooks
+pub struct WebhookDispatcher {
+    manager: ReservationManager,
+    client: reqwest::Client,
+    config: WebhookConfig,
+}
+
+impl WebhookDispatcher {
+    pub fn new(manager: ReservationManager, config: WebhookConfig) -> Result<Self, reqwest::Error> {
+        // a redirect could lead anywhere, it counts as a failed attempt
+        let client = reqwest::Client::builder()
+            .timeout(StdDuration::from_millis(config.timeout_ms))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            manager,
+            client,
+            config,
+        })
+    }
+
+    /// deliver events until the process exits
+    pub async fn run(self) {
+        let idle = StdDuration::from_millis(self.config.poll_interval_ms);
+        loop {
+            match self.dispatch().await {
+                Ok(0) => tokio::time::sleep(idle).await,
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!(error = %e, "webhook dispatch failed");
+                    tokio::time::sleep(idle).await;
+                }
+            }
+        }
+    }
+
+    /// queue new changes and make one attempt for every due delivery, returns the number of
+    /// attempts
+    pub async fn dispatch(&self) -> Result<usize, abi::Error> {
+        self.manager.queue_webhook_deliveries().await?;
+        // an attempt can't outlast the request timeout, so the lease only runs out if we die
+        let lease = Duration::milliseconds(self.config.timeout_ms as i64 * 2);
+        let jobs = self
+            .manager
+            .claim_webhook_deliveries(self.config.batch_size, lease)
+            .await?;
+
+        let results = futures::future::join_all(jobs.iter().map(|job| self.deliver(job))).await;
+        for (job, result) in jobs.iter().zip(results) {
+            match result {
+                Ok(status) => {
+                    self.manager
+                        .webhook_delivered(job.delivery_id, status)
+                        .await?
+                }
+                Err((error, status)) => {
+                    let retry_in = (job.attempt < self.config.max_attempts)
+                        .then(|| backoff(&self.config, job.attempt));
+                    self.manager
+                        .webhook_failed(job.delivery_id, &error, status, retry_in)
+                        .await?
+                }
+            }
+        }
+        Ok(jobs.len())
+    }
+
+    /// POST the event, any 2xx response counts as delivered
+    async fn deliver(&self, job: &WebhookJob) -> Result<i32, (String, Option<i32>)> {
+        if !self.config.allow_private_targets {
+            check_target(&job.url).await.map_err(|e| (e, None))?;
+        }
+        let timestamp = Utc::now().timestamp();
+        let resp = self
+            .client
+            .post(&job.url)
+            .header("content-type", "application/json")
+            .header("x-rsvp-event", &job.event)
+            .header("x-rsvp-delivery", job.delivery_id.to_string())
+            .header("x-rsvp-timestamp", timestamp.to_string())
+            .header(
+                "x-rsvp-signature",
+                webhook_signature(&job.secret, timestamp, &job.payload),
+            )
+            .body(job.payload.clone())
+            .send()
+            .await
+            .map_err(|e| (e.to_string(), None))?;
+
+        let status = resp.status();
+        if status.is_success() {
+            Ok(status.as_u16() as i32)
+        } else {
+            Err((format!("HTTP {status}"), Some(status.as_u16() as i32)))
+        }
+    }
+}
+
+/// refuse urls whose host resolves to a loopback, link-local or private address, webhooks
+/// were validated when they were saved but names can resolve to anything later on
+async fn check_target(url: &str) -> Result<(), String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let host = url.host_str().ok_or("url has no host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut addrs = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| e.to_string())?;
+    match addrs.find(|addr| is_private_address(addr.ip())) {
+        Some(addr) => Err(format!("{host} resolves to private address {}", addr.ip())),
+        None => Ok(()),
+    }
+}
+
+/// delay after a failed attempt, doubling every time up to the max
+fn backoff(config: &WebhookConfig, attempt: i32) -> Duration {
+    let factor = 2u64.saturating_pow(attempt.saturating_sub(1) as u32);
+    let delay = config
+        .backoff_base_ms
+        .saturating_mul(factor)
+        .min(config.backoff_max_ms);
+    Duration::milliseconds(delay as i64)
+}
+
+/// the x-rsvp-signature header: hex encoded HMAC-SHA256 of "{timestamp}.{body}" with the webhook
+/// secret. Receivers should recompute it and reject old timestamps to prevent replays
+pub fn webhook_signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use hyper::{
+        header::HeaderMap,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use reservation::Rsvp;
+
+    use super::*;
+
+    type Requests = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn dispatcher_should_post_signed_events() {
+        let (addr, requests) = start_stub(vec![204]);
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let webhook = manager.upsert_webhook(make_webhook(addr)).await.unwrap();
+        let rsvp = manager.reserve(make_pending()).await.unwrap();
+
+        let dispatcher = WebhookDispatcher::new(manager.clone(), make_config()).unwrap();
+        assert_eq!(dispatcher.dispatch().await.unwrap(), 1);
+        assert_eq!(dispatcher.dispatch().await.unwrap(), 0);
+
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1);
+        let (headers, body) = &requests[0];
+        let header = |name: &str| headers[name].to_str().unwrap().to_string();
+        assert_eq!(header("x-rsvp-event"), "create");
+        let timestamp: i64 = header("x-rsvp-timestamp").parse().unwrap();
+        assert_eq!(
+            header("x-rsvp-signature"),
+            webhook_signature("s3cret", timestamp, body)
+        );
+        assert!(body.contains(&format!(r#""id": {}"#, rsvp.id)));
+
+        let req = abi::ListWebhookDeliveriesRequest {
+            webhook_id: webhook.id,
+            ..Default::default()
+        };
+        let deliveries = manager.list_webhook_deliveries(req).await.unwrap();
+        assert_eq!(
+            deliveries[0].status(),
+            abi::WebhookDeliveryStatus::Delivered
+        );
+        assert_eq!(deliveries[0].response_status, 204);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn failing_endpoint_should_be_retried_then_dead_lettered() {
+        let (addr, requests) = start_stub(vec![500]);
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let webhook = manager.upsert_webhook(make_webhook(addr)).await.unwrap();
+        manager.reserve(make_pending()).await.unwrap();
+
+        let config = WebhookConfig {
+            max_attempts: 2,
+            backoff_base_ms: 0,
+            ..make_config()
+        };
+        let dispatcher = WebhookDispatcher::new(manager.clone(), config).unwrap();
+        assert_eq!(dispatcher.dispatch().await.unwrap(), 1);
+        assert_eq!(dispatcher.dispatch().await.unwrap(), 1);
+        assert_eq!(dispatcher.dispatch().await.unwrap(), 0);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let req = abi::ListWebhookDeliveriesRequest {
+            webhook_id: webhook.id,
+            ..Default::default()
+        };
+        let deliveries = manager.list_webhook_deliveries(req).await.unwrap();
+        assert_eq!(deliveries[0].status(), abi::WebhookDeliveryStatus::Dead);
+        assert_eq!(deliveries[0].attempts, 2);
+        assert_eq!(deliveries[0].response_status, 500);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "migrated_pool", migrations = "../migrations"))]
+    async fn private_targets_should_be_refused_unless_allowed() {
+        let (addr, requests) = start_stub(vec![204]);
+        let manager = ReservationManager::new(migrated_pool.clone()).await;
+        let webhook = manager.upsert_webhook(make_webhook(addr)).await.unwrap();
+        manager.reserve(make_pending()).await.unwrap();
+
+        let config = WebhookConfig {
+            allow_private_targets: false,
+            ..make_config()
+        };
+        let dispatcher = WebhookDispatcher::new(manager.clone(), config).unwrap();
+        assert_eq!(dispatcher.dispatch().await.unwrap(), 1);
+        assert!(requests.lock().unwrap().is_empty());
+
+        let req = abi::ListWebhookDeliveriesRequest {
+            webhook_id: webhook.id,
+            ..Default::default()
+        };
+        let deliveries = manager.list_webhook_deliveries(req).await.unwrap();
+        assert_eq!(deliveries[0].status(), abi::WebhookDeliveryStatus::Pending);
+        assert!(deliveries[0].last_error.contains("private address"));
+    }
+
+    #[test]
+    fn backoff_should_double_up_to_the_max() {
+        let config = WebhookConfig {
+            backoff_base_ms: 1000,
+            backoff_max_ms: 5000,
+            ..Default::default()
+        };
+        let delays: Vec<i64> = (1..=4)
+            .map(|attempt| backoff(&config, attempt).num_milliseconds())
+            .collect();
+        assert_eq!(delays, vec![1000, 2000, 4000, 5000]);
+    }
+
+    /// HTTP server recording the requests, answering with the statuses in turn. The last one
+    /// is repeated
+    fn start_stub(statuses: Vec<u16>) -> (SocketAddr, Requests) {
+        let requests: Requests = Default::default();
+        let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+        let recorded = requests.clone();
+        let make_svc = make_service_fn(move |_| {
+            let requests = recorded.clone();
+            let statuses = statuses.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let requests = requests.clone();
+                    let statuses = statuses.clone();
+                    async move {
+                        let (parts, body) = req.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap();
+                        let body = String::from_utf8(body.to_vec()).unwrap();
+                        requests.lock().unwrap().push((parts.headers, body));
+                        let status = {
+                            let mut statuses = statuses.lock().unwrap();
+                            if statuses.len() > 1 {
+                                statuses.pop_front().unwrap()
+                            } else {
+                                statuses[0]
+                            }
+                        };
+                        let resp = Response::builder().status(status).body(Body::empty());
+                        Ok::<_, Infallible>(resp.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    fn make_config() -> WebhookConfig {
+        WebhookConfig {
+            timeout_ms: 1000,
+            // the stub listens on localhost
+            allow_private_targets: true,
+            ..Default::default()
+        }
+    }
+
+    fn make_webhook(addr: SocketAddr) -> abi::Webhook {
+        abi::Webhook {
+            url: format!("http://{addr}/hook"),
+            secret: "s3cret".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn make_pending() -> abi::Reservation {
+        let start = "2099-12-25T15:00:00-0700".parse().unwrap();
+        let end = "2099-12-25T18:00:00-0700".parse().unwrap();
+        abi::Reservation::new_pending("user1", "room-1", start, end, "just note")
+    }
+}